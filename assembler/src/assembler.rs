@@ -4,7 +4,7 @@ use std::io::prelude::*;
 
 use log::info;
 
-use lib_rv32_common::constants::*;
+use lib_rv32_common::{constants::*, parse_int};
 
 use crate::{
     encode_b_imm, encode_func3, encode_func7, encode_i_imm, encode_j_imm, encode_opcode, encode_rd,
@@ -30,35 +30,359 @@ enum InstructionFormat {
 ///
 /// Returns:
 ///     `Result<Option<u32>>`: The assembled binary instruction, an error, or nothing.
+///
+/// Pseudo-instructions are accepted if they expand to a single instruction;
+/// `la`, `call`, `tail`, large `li`s and directives need the program
+/// assemblers.
 pub fn assemble_ir(
     ir_string: &str,
     labels: &mut HashMap<String, u32>,
     pc: u32,
 ) -> Result<Option<u32>, AssemblerError> {
-    let mut msg = String::new();
-    let mut ir: u32 = 0;
+    let tokens = split_labels(ir_string, labels, pc);
 
-    let mut tokens: Vec<String> = tokenize!(ir_string);
-
-    if tokens.is_empty() {
+    if tokens.is_empty() || is_directive(&tokens) {
         return Ok(None);
-    } else if tokens.len() > 5 {
-        return Err(AssemblerError::TooManyTokensError);
     }
 
-    // Add and remove leading label.
-    if tokens[0].ends_with(':') {
+    let irs = expand(tokens, Some(labels), pc)?;
+    if irs.len() != 1 {
+        return Err(AssemblerError::InvalidOperationError);
+    }
+
+    let ir = assemble_base(&irs[0], labels, pc)?;
+    info!("{:18} -> [{:02x}] {:08x}", ir_string.trim(), pc, ir);
+
+    Ok(Some(ir))
+}
+
+/// Tokenize a line without its comment, and record the labels leading it
+/// at `pc`.
+fn split_labels(line: &str, labels: &mut HashMap<String, u32>, pc: u32) -> Vec<String> {
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let mut tokens: Vec<String> = tokenize!(line);
+
+    while !tokens.is_empty() && tokens[0].ends_with(':') {
         labels.insert(tokens[0].strip_suffix(':').unwrap().to_owned(), pc);
         tokens.remove(0);
     }
 
-    if tokens.is_empty() {
-        return Ok(None);
+    tokens
+}
+
+fn is_directive(tokens: &[String]) -> bool {
+    tokens[0].starts_with('.')
+}
+
+/// Look up `label`, or use a placeholder if labels are not known yet.
+fn resolve_label(
+    label: &str,
+    labels: Option<&HashMap<String, u32>>,
+) -> Result<u32, AssemblerError> {
+    match labels {
+        Some(labels) => labels
+            .get(label)
+            .copied()
+            .ok_or(AssemblerError::NoSuchLabelError),
+        None => Ok(0),
+    }
+}
+
+/// Split a 32-bit value into the upper immediate of a `lui` or `auipc` and
+/// the sign-extended lower immediate added to it.
+fn split_imm(n: u32) -> (u32, u32) {
+    let hi = n.wrapping_add(0x800) >> 12;
+    (hi, n.wrapping_sub(hi << 12))
+}
+
+/// Expand pseudo-instructions into base instructions, and put `jalr`
+/// operands in the order `jalr rd, rs1, imm`. `labels` is `None` while
+/// the program is only being measured.
+fn expand(
+    tokens: Vec<String>,
+    labels: Option<&HashMap<String, u32>>,
+    pc: u32,
+) -> Result<Vec<Vec<String>>, AssemblerError> {
+    macro_rules! ir {
+        ($($t:expr),*) => {
+            vec![$($t.to_string()),*]
+        };
+    }
+
+    let arg = |i: usize| -> Result<&str, AssemblerError> {
+        tokens
+            .get(i)
+            .map(|s| &s[..])
+            .ok_or(AssemblerError::TooFewTokensError)
+    };
+
+    Ok(match (&tokens[0][..], tokens.len()) {
+        ("nop", 1) => vec![ir!("addi", "zero", "zero", 0)],
+        ("li", 3) => {
+            let n = parse_int!(i64, arg(2)?).map_err(|_| AssemblerError::InvalidImmediateError)?;
+            if !(-(1 << 31)..1 << 32).contains(&n) {
+                return Err(AssemblerError::ImmediateTooLargeError);
+            }
+            let (hi, lo) = split_imm(n as u32);
+            if (-2048..2048).contains(&n) {
+                vec![ir!("addi", arg(1)?, "zero", n)]
+            } else if lo == 0 {
+                vec![ir!("lui", arg(1)?, hi)]
+            } else {
+                vec![
+                    ir!("lui", arg(1)?, hi),
+                    ir!("addi", arg(1)?, arg(1)?, lo as i32),
+                ]
+            }
+        }
+        ("la", 3) => {
+            let (hi, lo) = split_imm(resolve_label(arg(2)?, labels)?.wrapping_sub(pc));
+            vec![
+                ir!("auipc", arg(1)?, hi),
+                ir!("addi", arg(1)?, arg(1)?, lo as i32),
+            ]
+        }
+        ("call", 2) | ("tail", 2) => {
+            let (rd, tmp) = match &tokens[0][..] {
+                "call" => ("ra", "ra"),
+                _ => ("zero", "t1"),
+            };
+            let (hi, lo) = split_imm(resolve_label(arg(1)?, labels)?.wrapping_sub(pc));
+            vec![ir!("auipc", tmp, hi), ir!("jalr", rd, tmp, lo as i32)]
+        }
+        ("mv", 3) => vec![ir!("addi", arg(1)?, arg(2)?, 0)],
+        ("not", 3) => vec![ir!("xori", arg(1)?, arg(2)?, -1)],
+        ("neg", 3) => vec![ir!("sub", arg(1)?, "zero", arg(2)?)],
+        ("seqz", 3) => vec![ir!("sltiu", arg(1)?, arg(2)?, 1)],
+        ("snez", 3) => vec![ir!("sltu", arg(1)?, "zero", arg(2)?)],
+        ("sltz", 3) => vec![ir!("slt", arg(1)?, arg(2)?, "zero")],
+        ("sgtz", 3) => vec![ir!("slt", arg(1)?, "zero", arg(2)?)],
+        ("beqz", 3) => vec![ir!("beq", arg(1)?, "zero", arg(2)?)],
+        ("bnez", 3) => vec![ir!("bne", arg(1)?, "zero", arg(2)?)],
+        ("blez", 3) => vec![ir!("bge", "zero", arg(1)?, arg(2)?)],
+        ("bgez", 3) => vec![ir!("bge", arg(1)?, "zero", arg(2)?)],
+        ("bltz", 3) => vec![ir!("blt", arg(1)?, "zero", arg(2)?)],
+        ("bgtz", 3) => vec![ir!("blt", "zero", arg(1)?, arg(2)?)],
+        ("bgt", 4) => vec![ir!("blt", arg(2)?, arg(1)?, arg(3)?)],
+        ("ble", 4) => vec![ir!("bge", arg(2)?, arg(1)?, arg(3)?)],
+        ("bgtu", 4) => vec![ir!("bltu", arg(2)?, arg(1)?, arg(3)?)],
+        ("bleu", 4) => vec![ir!("bgeu", arg(2)?, arg(1)?, arg(3)?)],
+        ("j", 2) => vec![ir!("jal", "zero", arg(1)?)],
+        ("jal", 2) => vec![ir!("jal", "ra", arg(1)?)],
+        ("jr", 2) => vec![ir!("jalr", "zero", arg(1)?, 0)],
+        ("jalr", 2) => vec![ir!("jalr", "ra", arg(1)?, 0)],
+        ("ret", 1) => vec![ir!("jalr", "zero", "ra", 0)],
+        ("fmv.s", 3) => vec![ir!("fsgnj.s", arg(1)?, arg(2)?, arg(2)?)],
+        ("fneg.s", 3) => vec![ir!("fsgnjn.s", arg(1)?, arg(2)?, arg(2)?)],
+        ("fabs.s", 3) => vec![ir!("fsgnjx.s", arg(1)?, arg(2)?, arg(2)?)],
+        ("fmv.d", 3) => vec![ir!("fsgnj.d", arg(1)?, arg(2)?, arg(2)?)],
+        ("fneg.d", 3) => vec![ir!("fsgnjn.d", arg(1)?, arg(2)?, arg(2)?)],
+        ("fabs.d", 3) => vec![ir!("fsgnjx.d", arg(1)?, arg(2)?, arg(2)?)],
+        // `jalr rd, imm(rs1)`
+        ("jalr", 4) if match_register(arg(2)?).is_err() => {
+            vec![ir!("jalr", arg(1)?, arg(3)?, arg(2)?)]
+        }
+        _ => vec![tokens],
+    })
+}
+
+/// Assemble a directive, or measure it if `labels` is `None`. Directives
+/// other than data and alignment are ignored.
+fn assemble_directive(
+    tokens: &[String],
+    labels: Option<&HashMap<String, u32>>,
+    pc: u32,
+) -> Result<Vec<u32>, AssemblerError> {
+    let count = |s: &String| -> Result<u32, AssemblerError> {
+        parse_int!(u32, s).map_err(|_| AssemblerError::InvalidImmediateError)
+    };
+    let pad_to = |align: u32| -> Vec<u32> {
+        let align = align.max(4);
+        vec![0; (((align - pc % align) % align) / 4) as usize]
+    };
+
+    Ok(match &tokens[0][..] {
+        ".word" => tokens[1..]
+            .iter()
+            .map(|s| match parse_int!(i64, s) {
+                Ok(n) => Ok(n as u32),
+                Err(_) => resolve_label(s, labels),
+            })
+            .collect::<Result<_, _>>()?,
+        ".zero" | ".space" => {
+            let bytes = count(tokens.get(1).ok_or(AssemblerError::TooFewTokensError)?)?;
+            vec![0; bytes.div_ceil(4) as usize]
+        }
+        ".align" | ".p2align" => {
+            let n = count(tokens.get(1).ok_or(AssemblerError::TooFewTokensError)?)?;
+            if n > 16 {
+                return Err(AssemblerError::ImmediateTooLargeError);
+            }
+            pad_to(1 << n)
+        }
+        ".balign" => pad_to(count(
+            tokens.get(1).ok_or(AssemblerError::TooFewTokensError)?,
+        )?),
+        _ => Vec::new(),
+    })
+}
+
+/// Assemble one line of a program, or measure it if `labels` is `None`.
+fn assemble_statement(
+    tokens: Vec<String>,
+    labels: Option<&HashMap<String, u32>>,
+    pc: u32,
+) -> Result<Vec<u32>, AssemblerError> {
+    if is_directive(&tokens) {
+        return assemble_directive(&tokens, labels, pc);
+    }
+
+    let irs = expand(tokens, labels, pc)?;
+    match labels {
+        Some(labels) => irs
+            .iter()
+            .enumerate()
+            .map(|(i, ir)| assemble_base(ir, labels, pc + 4 * i as u32))
+            .collect(),
+        None => Ok(vec![0; irs.len()]),
+    }
+}
+
+/// Assemble `lr.w rd, (rs1)` or an atomic `op rd, rs2, (rs1)`. The
+/// address may also be written `0(rs1)`.
+fn assemble_atomic(tokens: &[String], func5: u8, ordering: u8) -> Result<u32, AssemblerError> {
+    let mut operands: Vec<&str> = tokens[1..].iter().map(|s| &s[..]).collect();
+    let count = match func5 {
+        FUNC5_LR => 2,
+        _ => 3,
+    };
+    if operands.len() == count + 1 && matches!(parse_int!(i64, operands[count - 1]), Ok(0)) {
+        operands.remove(count - 1);
+    }
+    if operands.len() < count {
+        return Err(AssemblerError::TooFewTokensError);
+    } else if operands.len() > count {
+        return Err(AssemblerError::TooManyTokensError);
+    }
+
+    let rd = match_register(operands[0])?;
+    let rs1 = match_register(operands[count - 1])?;
+    let rs2 = match count {
+        3 => match_register(operands[1])?,
+        _ => 0,
+    };
+    Ok(encode_opcode!(OPCODE_AMO)
+        | encode_rd!(rd)
+        | encode_func3!(FUNC3_AMO_W)
+        | encode_rs1!(rs1)
+        | encode_rs2!(rs2)
+        | encode_func7!(func5 << 2 | ordering))
+}
+
+/// Assemble a floating-point instruction from its encoding `base` and
+/// operands. A rounding mode may follow the registers, e.g.
+/// `fadd.s fa0, fa1, fa2, rtz`.
+fn assemble_float(
+    tokens: &[String],
+    base: u32,
+    operands: FloatOperands,
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<u32, AssemblerError> {
+    let (sources, int_rd, int_rs1, rm) = match operands {
+        FloatOperands::Load | FloatOperands::Store => {
+            if tokens.len() < 4 {
+                return Err(AssemblerError::TooFewTokensError);
+            } else if tokens.len() > 4 {
+                return Err(AssemblerError::TooManyTokensError);
+            }
+            let reg = match_fp_register(&tokens[1])?;
+            let imm = parse_imm(&tokens[2], labels, pc)?;
+            let rs1 = match_register(&tokens[3])?;
+            return Ok(match operands {
+                FloatOperands::Load => base | encode_rd!(reg) | encode_i_imm!(imm),
+                _ => base | encode_rs2!(reg) | encode_s_imm!(imm),
+            } | encode_rs1!(rs1));
+        }
+        FloatOperands::Registers {
+            sources,
+            int_rd,
+            int_rs1,
+            rm,
+        } => (sources as usize, int_rd, int_rs1, rm),
+    };
+
+    let count = 2 + sources;
+    let rm = match (rm, tokens.len()) {
+        (_, n) if n < count => return Err(AssemblerError::TooFewTokensError),
+        (Some(_), n) if n == count + 1 => match_rounding_mode(&tokens[count])?,
+        (Some(rm), n) if n == count => rm,
+        (None, n) if n == count => 0,
+        _ => return Err(AssemblerError::TooManyTokensError),
+    };
+    let reg = |i: usize, int: bool| match int {
+        true => match_register(&tokens[i]),
+        false => match_fp_register(&tokens[i]),
+    };
+
+    let mut ir = base | encode_rd!(reg(1, int_rd)?) | encode_rs1!(reg(2, int_rs1)?);
+    if sources >= 2 {
+        ir |= encode_rs2!(reg(3, false)?);
+    }
+    if sources == 3 {
+        ir |= (reg(4, false)? as u32) << 27;
+    }
+    Ok(ir | encode_func3!(rm))
+}
+
+/// Assemble the tokens of a base instruction.
+fn assemble_base(
+    tokens: &[String],
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<u32, AssemblerError> {
+    let mut ir: u32 = 0;
+
+    if let Some((base, operands)) = match_float(&tokens[0]) {
+        return assemble_float(tokens, base, operands, labels, pc);
     }
 
-    msg += &format!("{:18} -> [{:02x}] ", ir_string, pc);
+    if tokens.len() > 5 {
+        return Err(AssemblerError::TooManyTokensError);
+    }
 
     let op = &tokens[0][..];
+
+    match op {
+        "ecall" => return Ok(encode_opcode!(OPCODE_SYSTEM)),
+        "ebreak" => return Ok(encode_opcode!(OPCODE_SYSTEM) | encode_i_imm!(1)),
+        "sret" => return Ok(encode_opcode!(OPCODE_SYSTEM) | encode_i_imm!(0x102)),
+        "mret" => return Ok(encode_opcode!(OPCODE_SYSTEM) | encode_i_imm!(0x302)),
+        "sfence.vma" => {
+            if tokens.len() > 3 {
+                return Err(AssemblerError::TooManyTokensError);
+            }
+            let rs1 = match tokens.get(1) {
+                Some(reg) => match_register(reg)?,
+                None => 0,
+            };
+            let rs2 = match tokens.get(2) {
+                Some(reg) => match_register(reg)?,
+                None => 0,
+            };
+            return Ok(encode_opcode!(OPCODE_SYSTEM)
+                | encode_func7!(FUNC7_SFENCE_VMA)
+                | encode_rs1!(rs1)
+                | encode_rs2!(rs2));
+        }
+        _ => (),
+    }
+    if let Some((func5, ordering)) = match_atomic(op) {
+        return assemble_atomic(tokens, func5, ordering);
+    }
     let opcode = match_opcode(op);
     if let Err(why) = opcode {
         return Err(why);
@@ -77,8 +401,22 @@ pub fn assemble_ir(
         _ => unreachable!(),
     };
 
+    let operands = match format {
+        InstructionFormat::Utype | InstructionFormat::Jtype => 3,
+        _ => 4,
+    };
+    if tokens.len() < operands {
+        return Err(AssemblerError::TooFewTokensError);
+    } else if tokens.len() > operands {
+        return Err(AssemblerError::TooManyTokensError);
+    }
+
     // Use the destination register field.
-    if let InstructionFormat::Rtype | InstructionFormat::Itype | InstructionFormat::Utype = format {
+    if let InstructionFormat::Rtype
+    | InstructionFormat::Itype
+    | InstructionFormat::Utype
+    | InstructionFormat::Jtype = format
+    {
         let rd = match_register(&tokens[1]);
         if let Err(why) = rd {
             return Err(why);
@@ -94,7 +432,7 @@ pub fn assemble_ir(
     {
         let rs1 = match_register(
             &tokens[match opcode {
-                OPCODE_LOAD => 3,
+                OPCODE_LOAD | OPCODE_STORE => 3,
                 OPCODE_BRANCH => 1,
                 _ => 2,
             }],
@@ -141,7 +479,15 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_i_imm!(imm);
+            match op {
+                "slli" | "srli" | "srai" => {
+                    if imm >= 32 {
+                        return Err(AssemblerError::ImmediateTooLargeError);
+                    }
+                    ir |= encode_i_imm!(imm) | encode_func7!(match_func7!(op));
+                }
+                _ => ir |= encode_i_imm!(imm),
+            }
         }
         InstructionFormat::Utype => {
             let imm = parse_imm(&tokens[2], labels, pc);
@@ -178,10 +524,7 @@ pub fn assemble_ir(
         InstructionFormat::Rtype => (),
     }
 
-    msg += &format!("{:08x}", ir);
-    info!("{}", msg);
-
-    Ok(Some(ir))
+    Ok(ir)
 }
 
 /// Assemble a `BufRead` down to a vector of words. The input should contain
@@ -191,56 +534,57 @@ pub fn assemble_program_buf<R>(reader: &mut R) -> Result<Vec<u32>, AssemblerErro
 where
     R: BufRead,
 {
-    let mut prog = Vec::new();
-    let mut buf = String::new();
-    let mut labels = HashMap::new();
-    let mut pc: u32 = 0;
-
-    loop {
-        let bytes_rd = reader.read_line(&mut buf);
-
-        if bytes_rd.is_err() {
-            return Err(AssemblerError::IOError);
-        }
-
-        if bytes_rd.unwrap() == 0 {
-            break;
-        }
-
-        let ir = assemble_ir(buf.trim_end(), &mut labels, pc);
+    let mut program = String::new();
 
-        if let Err(why) = ir {
-            return Err(why);
-        }
-
-        if let Some(i) = ir.unwrap() {
-            prog.push(i);
-            pc += 4;
-        }
-        buf.clear();
+    if reader.read_to_string(&mut program).is_err() {
+        return Err(AssemblerError::IOError);
     }
 
-    Ok(prog)
+    assemble_program(&program)
 }
 
 /// Assemble a full program of newline-separated instructions.
 pub fn assemble_program(program: &str) -> Result<Vec<u32>, AssemblerError> {
-    let mut prog = Vec::new();
+    assemble_program_with_lines(program).map(|(prog, _)| prog)
+}
+
+/// Assemble a full program of newline-separated instructions, and return
+/// the (1-based) source line of every word alongside the words.
+///
+/// Labels may be used before they are defined, and `#` starts a comment.
+/// Besides instructions, lines may hold common pseudo-instructions (`li`,
+/// `la`, `mv`, `j`, `call`, `ret`, ...) and the `.word`, `.zero`, `.space`,
+/// `.align`, `.p2align` and `.balign` directives. Other directives, such as
+/// `.section` and `.globl`, are ignored.
+pub fn assemble_program_with_lines(
+    program: &str,
+) -> Result<(Vec<u32>, Vec<usize>), AssemblerError> {
+    let mut statements = Vec::new();
     let mut labels = HashMap::new();
     let mut pc: u32 = 0;
 
-    for line in program.split("\n") {
-        let ir = assemble_ir(line, &mut labels, pc);
-
-        if let Err(why) = ir {
-            return Err(why);
+    // Find the address of every label.
+    for (n, line) in program.split('\n').enumerate() {
+        let tokens = split_labels(line, &mut labels, pc);
+        if tokens.is_empty() {
+            continue;
         }
+        pc += 4 * assemble_statement(tokens.clone(), None, pc)?.len() as u32;
+        statements.push((n + 1, tokens));
+    }
 
-        if let Some(i) = ir.unwrap() {
-            prog.push(i);
+    let mut prog = Vec::new();
+    let mut lines = Vec::new();
+    pc = 0;
+
+    for (n, tokens) in statements {
+        for ir in assemble_statement(tokens, Some(&labels), pc)? {
+            info!("{:4} -> [{:02x}] {:08x}", n, pc, ir);
+            prog.push(ir);
+            lines.push(n);
             pc += 4;
         }
     }
 
-    Ok(prog)
+    Ok((prog, lines))
 }
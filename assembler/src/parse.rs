@@ -16,20 +16,25 @@ macro_rules! tokenize {
             .to_ascii_lowercase()
             .split_whitespace()
             .map(|s| s.to_owned())
-            .collect();
+            .collect()
     };
 }
 
 /// Match an operation to the correct opcode.
 pub fn match_opcode(op: &str) -> Result<u8, AssemblerError> {
     let opcode = match op {
-        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "sra" | "or" | "and" => OPCODE_ARITHMETIC,
-        "addi" | "slli" | "slti" | "xori" | "srai" | "ori" | "andi" => OPCODE_ARITHMETIC_IMM,
+        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" => {
+            OPCODE_ARITHMETIC
+        }
+        "addi" | "slli" | "slti" | "sltiu" | "xori" | "srli" | "srai" | "ori" | "andi" => {
+            OPCODE_ARITHMETIC_IMM
+        }
         "lui" => OPCODE_LUI,
         "auipc" => OPCODE_AUIPC,
         "jal" => OPCODE_JAL,
         "jalr" => OPCODE_JALR,
-        "beq" | "bne" | "blt" | "bge" | "bgeu" => OPCODE_BRANCH,
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => OPCODE_BRANCH,
+        "ecall" | "ebreak" | "sret" | "mret" | "sfence.vma" => OPCODE_SYSTEM,
         "lb" | "lbu" | "lh" | "lhu" | "lw" => OPCODE_LOAD,
         "sb" | "sh" | "sw" => OPCODE_STORE,
         _ => return Err(AssemblerError::InvalidOperationError),
@@ -37,6 +42,29 @@ pub fn match_opcode(op: &str) -> Result<u8, AssemblerError> {
     Ok(opcode)
 }
 
+/// Match an atomic operation, optionally suffixed with `.aq`, `.rl` or
+/// `.aqrl`, to its func5 and ordering bits.
+pub fn match_atomic(op: &str) -> Option<(u8, u8)> {
+    for (suffix, ordering) in [(".aqrl", 0b11), (".aq", 0b10), (".rl", 0b01), ("", 0b00)] {
+        let func5 = match op.strip_suffix(suffix) {
+            Some("lr.w") => FUNC5_LR,
+            Some("sc.w") => FUNC5_SC,
+            Some("amoswap.w") => FUNC5_AMOSWAP,
+            Some("amoadd.w") => FUNC5_AMOADD,
+            Some("amoxor.w") => FUNC5_AMOXOR,
+            Some("amoand.w") => FUNC5_AMOAND,
+            Some("amoor.w") => FUNC5_AMOOR,
+            Some("amomin.w") => FUNC5_AMOMIN,
+            Some("amomax.w") => FUNC5_AMOMAX,
+            Some("amominu.w") => FUNC5_AMOMINU,
+            Some("amomaxu.w") => FUNC5_AMOMAXU,
+            _ => continue,
+        };
+        return Some((func5, ordering));
+    }
+    None
+}
+
 /// Match a register number or name to its integer number.
 pub fn match_register(reg: &str) -> Result<u8, AssemblerError> {
     if reg.starts_with('x') {
@@ -44,6 +72,8 @@ pub fn match_register(reg: &str) -> Result<u8, AssemblerError> {
             Ok(n) => Ok(n),
             Err(_) => Err(AssemblerError::NoSuchRegisterError),
         }
+    } else if reg == "fp" {
+        Ok(8)
     } else {
         match REG_NAMES.iter().position(|e| *e == reg) {
             Some(n) => Ok(n as u8),
@@ -52,6 +82,145 @@ pub fn match_register(reg: &str) -> Result<u8, AssemblerError> {
     }
 }
 
+/// Match a floating-point register number or name, e.g. `f10` or `fa0`,
+/// to its number.
+pub fn match_fp_register(reg: &str) -> Result<u8, AssemblerError> {
+    if let Some(n) = reg.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return match n {
+            0..=31 => Ok(n),
+            _ => Err(AssemblerError::NoSuchRegisterError),
+        };
+    }
+    match FP_REG_NAMES.iter().position(|e| *e == reg) {
+        Some(n) => Ok(n as u8),
+        None => Err(AssemblerError::NoSuchRegisterError),
+    }
+}
+
+/// Match a rounding mode name, e.g. `rtz`, to its encoding.
+pub fn match_rounding_mode(rm: &str) -> Result<u8, AssemblerError> {
+    Ok(match rm {
+        "rne" => 0,
+        "rtz" => 1,
+        "rdn" => 2,
+        "rup" => 3,
+        "rmm" => 4,
+        "dyn" => RM_DYNAMIC,
+        _ => return Err(AssemblerError::WrongOperandTypeError),
+    })
+}
+
+/// Operands of a floating-point instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatOperands {
+    /// `flw fd, imm(rs1)`
+    Load,
+    /// `fsw fs2, imm(rs1)`
+    Store,
+    /// A destination and one to three sources, which are floating-point
+    /// registers unless marked as integer ones. If `rm` is the default
+    /// rounding mode, one may follow them.
+    Registers {
+        sources: u8,
+        int_rd: bool,
+        int_rs1: bool,
+        rm: Option<u8>,
+    },
+}
+
+/// Match a floating-point operation to its encoding, with the register,
+/// immediate and rounding mode fields clear, and its operands.
+pub fn match_float(op: &str) -> Option<(u32, FloatOperands)> {
+    let regs = |sources, int_rd, int_rs1, rm| FloatOperands::Registers {
+        sources,
+        int_rd,
+        int_rs1,
+        rm,
+    };
+    let op_fp = |func5: u8, fmt: u32, rs2: u32| {
+        (func5 as u32) << 27 | fmt << 25 | rs2 << 20 | OPCODE_OP_FP as u32
+    };
+    let dynamic = Some(RM_DYNAMIC);
+
+    // Conversions between formats, and from integers to doubles, are
+    // exact, so their rounding mode defaults to `rne`.
+    Some(match op {
+        "flw" => (
+            (FUNC3_FW as u32) << 12 | OPCODE_LOAD_FP as u32,
+            FloatOperands::Load,
+        ),
+        "fld" => (
+            (FUNC3_FD as u32) << 12 | OPCODE_LOAD_FP as u32,
+            FloatOperands::Load,
+        ),
+        "fsw" => (
+            (FUNC3_FW as u32) << 12 | OPCODE_STORE_FP as u32,
+            FloatOperands::Store,
+        ),
+        "fsd" => (
+            (FUNC3_FD as u32) << 12 | OPCODE_STORE_FP as u32,
+            FloatOperands::Store,
+        ),
+        "fmv.x.w" => (op_fp(FUNC5_FMV_XF, 0, 0), regs(1, true, false, None)),
+        "fmv.w.x" => (op_fp(FUNC5_FMV_FX, 0, 0), regs(1, false, true, None)),
+        "fcvt.s.d" => (op_fp(FUNC5_FCVT_FF, 0, 1), regs(1, false, false, dynamic)),
+        "fcvt.d.s" => (op_fp(FUNC5_FCVT_FF, 1, 0), regs(1, false, false, Some(0))),
+        "fcvt.s.w" => (op_fp(FUNC5_FCVT_FW, 0, 0), regs(1, false, true, dynamic)),
+        "fcvt.s.wu" => (op_fp(FUNC5_FCVT_FW, 0, 1), regs(1, false, true, dynamic)),
+        "fcvt.d.w" => (op_fp(FUNC5_FCVT_FW, 1, 0), regs(1, false, true, Some(0))),
+        "fcvt.d.wu" => (op_fp(FUNC5_FCVT_FW, 1, 1), regs(1, false, true, Some(0))),
+        _ => {
+            let (name, fmt) = match (op.strip_suffix(".s"), op.strip_suffix(".d")) {
+                (Some(name), _) => (name, 0),
+                (_, Some(name)) => (name, 1),
+                _ => return None,
+            };
+            let fused = |opcode: u8| (fmt << 25 | opcode as u32, regs(3, false, false, dynamic));
+            match name {
+                "fmadd" => fused(OPCODE_MADD),
+                "fmsub" => fused(OPCODE_MSUB),
+                "fnmsub" => fused(OPCODE_NMSUB),
+                "fnmadd" => fused(OPCODE_NMADD),
+                "fadd" => (op_fp(FUNC5_FADD, fmt, 0), regs(2, false, false, dynamic)),
+                "fsub" => (op_fp(FUNC5_FSUB, fmt, 0), regs(2, false, false, dynamic)),
+                "fmul" => (op_fp(FUNC5_FMUL, fmt, 0), regs(2, false, false, dynamic)),
+                "fdiv" => (op_fp(FUNC5_FDIV, fmt, 0), regs(2, false, false, dynamic)),
+                "fsqrt" => (op_fp(FUNC5_FSQRT, fmt, 0), regs(1, false, false, dynamic)),
+                "fsgnj" => (op_fp(FUNC5_FSGNJ, fmt, 0), regs(2, false, false, None)),
+                "fsgnjn" => (
+                    op_fp(FUNC5_FSGNJ, fmt, 0) | 1 << 12,
+                    regs(2, false, false, None),
+                ),
+                "fsgnjx" => (
+                    op_fp(FUNC5_FSGNJ, fmt, 0) | 2 << 12,
+                    regs(2, false, false, None),
+                ),
+                "fmin" => (op_fp(FUNC5_FMINMAX, fmt, 0), regs(2, false, false, None)),
+                "fmax" => (
+                    op_fp(FUNC5_FMINMAX, fmt, 0) | 1 << 12,
+                    regs(2, false, false, None),
+                ),
+                "feq" => (
+                    op_fp(FUNC5_FCMP, fmt, 0) | 2 << 12,
+                    regs(2, true, false, None),
+                ),
+                "flt" => (
+                    op_fp(FUNC5_FCMP, fmt, 0) | 1 << 12,
+                    regs(2, true, false, None),
+                ),
+                "fle" => (op_fp(FUNC5_FCMP, fmt, 0), regs(2, true, false, None)),
+                "fclass" => (
+                    op_fp(FUNC5_FMV_XF, fmt, 0) | 1 << 12,
+                    regs(1, true, false, None),
+                ),
+                "fcvt.w" => (op_fp(FUNC5_FCVT_WF, fmt, 0), regs(1, true, false, dynamic)),
+                "fcvt.wu" => (op_fp(FUNC5_FCVT_WF, fmt, 1), regs(1, true, false, dynamic)),
+                _ => return None,
+            }
+        }
+    })
+}
+
 /// Parse a label or an immediate literal into an integer.
 pub fn parse_imm(s: &str, labels: &HashMap<String, u32>, pc: u32) -> Result<u32, AssemblerError> {
     let num = parse_int!(i64, s);
@@ -73,6 +242,7 @@ pub fn parse_imm(s: &str, labels: &HashMap<String, u32>, pc: u32) -> Result<u32,
 macro_rules! match_func3 {
     ($t:expr) => {
         match $t {
+            "jalr" => FUNC3_JALR,
             "beq" => FUNC3_BEQ,
             "bne" => FUNC3_BNE,
             "blt" => FUNC3_BLT,
@@ -90,7 +260,7 @@ macro_rules! match_func3 {
             "add" | "addi" | "sub" => FUNC3_ADD_SUB,
             "sll" | "slli" => FUNC3_SLL,
             "slt" | "slti" => FUNC3_SLT,
-            "sltu" => FUNC3_SLTU,
+            "sltu" | "sltiu" => FUNC3_SLTU,
             "xor" | "xori" => FUNC3_XOR,
             "sra" | "srai" | "srl" | "srli" => FUNC3_SR,
             "or" | "ori" => FUNC3_OR,
@@ -105,7 +275,7 @@ macro_rules! match_func3 {
 macro_rules! match_func7 {
     ($t:expr) => {
         match $t {
-            "add" | "addi" => FUNC7_ADD,
+            "add" | "addi" | "sll" | "slli" | "slt" | "sltu" | "xor" | "or" | "and" => FUNC7_ADD,
             "sub" => FUNC7_SUB,
             "sra" | "srai" => FUNC7_SRA,
             "srl" | "srli" => FUNC7_SRL,
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use lib_rv32_common::{constants::*, instructions};
 
-use crate::{parse::*, *};
+use crate::{error::AssemblerError, parse::*, *};
 
 #[test]
 fn test_tokenize() {
@@ -209,6 +209,33 @@ fn test_assemble_u_type() {
     );
 }
 
+#[test]
+fn test_assemble_program_with_lines() {
+    let (words, lines) = assemble_program_with_lines("start:\nauipc x5, 4\n\nlui x5, 4").unwrap();
+    std::assert_eq!(
+        vec![instructions::AUIPC_X5_4, instructions::LUI_X5_4],
+        words
+    );
+    std::assert_eq!(vec![2, 4], lines);
+}
+
+#[test]
+fn test_assemble_s_type() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    assert_eq!(
+        instructions::SW_X5_0_X5,
+        assemble_ir("sw x5, 0(x5)", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+    assert_eq!(
+        instructions::SW_A0_NEG_20_S0,
+        assemble_ir("sw a0, -20(s0)", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+}
+
 #[test]
 fn test_assemble_b_type() {
     let mut empty_hash: HashMap<String, u32> = HashMap::new();
@@ -291,3 +318,127 @@ fn test_encode_func3() {
     test_field!(encode_func3!(FUNC3_BEQ), instructions::BEQ_X5_X5_12);
     test_field!(encode_func3!(FUNC3_BNE), instructions::BNE_X5_X5_76);
 }
+
+#[test]
+fn test_assemble_more_operations() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    for (ir, expect) in [
+        ("srli t0, t1, 3", 0x00335293),
+        ("srai t0, t1, 3", 0x40335293),
+        ("and t0, t1, t2", 0x007372b3),
+        ("jal ra, 8", 0x008000ef),
+        ("jalr ra, 4(t0)", 0x004280e7),
+        ("jalr ra, t0, 4", 0x004280e7),
+        ("ret", 0x00008067),
+        ("ecall", 0x00000073),
+        ("sret", 0x10200073),
+        ("mret", 0x30200073),
+        ("sfence.vma", 0x12000073),
+        ("sfence.vma a0", 0x12050073),
+        ("sfence.vma a0, a1", 0x12b50073),
+        ("lr.w a0, (a1)", 0x1005a52f),
+        ("lr.w a0, 0(a1)", 0x1005a52f),
+        ("sc.w a0, a2, (a1)", 0x18c5a52f),
+        ("amoadd.w.aqrl a0, a2, (a1)", 0x06c5a52f),
+        ("amoswap.w.aq t0, t1, (t2)", 0x0c63a2af),
+        ("amomaxu.w.rl zero, t1, 0(t2)", 0xe263a02f),
+        ("ebreak  # stop", 0x00100073),
+    ] {
+        assert_eq!(
+            expect,
+            assemble_ir(ir, &mut empty_hash, 0).unwrap().unwrap()
+        );
+    }
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_ir("slli t0, t0, 32", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        assemble_ir("addi t0, t1", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooManyTokensError),
+        assemble_ir("lr.w a0, a2, (a1)", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError),
+        assemble_ir("amoadd.w.qa a0, a2, (a1)", &mut empty_hash, 0)
+    );
+}
+
+#[test]
+fn test_assemble_pseudo_instructions() {
+    std::assert_eq!(
+        vec![0x12345537, 0x67850513, 0x12346537, 0x80050513, 0xfff00513, 0x00100073],
+        assemble_program("li a0, 0x12345678\nli a0, 0x12345800\nli a0, -1\nebreak").unwrap()
+    );
+    std::assert_eq!(
+        vec![0x00000297, 0x00c28293, 0x00100073, 0xdeadbeef],
+        assemble_program("la t0, data\nebreak\ndata: .word 0xdeadbeef").unwrap()
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError),
+        assemble_program("la t0, nowhere")
+    );
+}
+
+#[test]
+fn test_assemble_forward_labels() {
+    std::assert_eq!(
+        vec![0x0080006f, 0x00000013, 0x00100073],
+        assemble_program("    j end  # skip the nop\n    nop\nend:\n    ebreak").unwrap()
+    );
+}
+
+#[test]
+fn test_assemble_directives() {
+    let (words, lines) = assemble_program_with_lines(
+        ".section .text\n.globl start\nstart: nop\n.align 4\n.word start, -1\n.zero 5",
+    )
+    .unwrap();
+    std::assert_eq!(vec![0x13, 0, 0, 0, 0, 0xffffffff, 0, 0], words);
+    std::assert_eq!(vec![3, 4, 4, 4, 5, 5, 6, 6], lines);
+}
+
+#[test]
+fn test_assemble_float() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    for (ir, expect) in [
+        ("fadd.s fa0, fa1, fa2", 0x00c5f553),
+        ("fadd.s fa0, fa1, fa2, rtz", 0x00c59553),
+        ("flw fa0, 8(sp)", 0x00812507),
+        ("fsd fs0, -8(sp)", 0xfe813c27),
+        ("fmadd.d fa0, fa1, fa2, fa3", 0x6ac5f543),
+        ("fcvt.w.s a0, fa0, rtz", 0xc0051553),
+        ("fcvt.d.w fa0, a0", 0xd2050553),
+        ("fmv.x.w a0, fa0", 0xe0050553),
+        ("feq.d a0, fa0, fa1", 0xa2b52553),
+        ("fsqrt.d f10, f11", 0x5a05f553),
+        ("fclass.s a0, fa0", 0xe0051553),
+        ("fneg.s fa0, fa1", 0x20b59553),
+    ] {
+        assert_eq!(
+            expect,
+            assemble_ir(ir, &mut empty_hash, 0).unwrap().unwrap()
+        );
+    }
+    std::assert_eq!(Ok(8), match_fp_register("fs0"));
+    std::assert_eq!(Ok(31), match_fp_register("f31"));
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError),
+        match_fp_register("f32")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError),
+        assemble_ir("fadd.s a0, fa1, fa2", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::WrongOperandTypeError),
+        assemble_ir("fadd.s fa0, fa1, fa2, up", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooManyTokensError),
+        assemble_ir("fmv.x.w a0, fa0, rtz", &mut empty_hash, 0)
+    );
+}
@@ -1,18 +1,163 @@
-use std::{fs, path::Path};
+use std::{fmt::Write as _, fs, path::Path};
 
-use lib_rv32_mcu::{
-    common::{constants::*, parse_int},
-    isa::traits::*,
-};
+use serde_json::{json, Value};
+
+use lib_rv32_mcu::{common::constants::*, elf::Symbol, isa::traits::*, Mcu};
+
+/// How an actual value is compared with the expected one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    fn symbol(self) -> &'static str {
+        Comparison::ALL.iter().find(|(_, c)| *c == self).unwrap().0
+    }
+
+    fn holds<T: PartialOrd>(self, actual: T, expected: T) -> bool {
+        match self {
+            Comparison::Eq => actual == expected,
+            Comparison::Ne => actual != expected,
+            Comparison::Lt => actual < expected,
+            Comparison::Le => actual <= expected,
+            Comparison::Gt => actual > expected,
+            Comparison::Ge => actual >= expected,
+        }
+    }
+}
+
+/// What an assertion looks at once emulation stops.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Register(u8),
+    /// A byte, half-word or word of memory.
+    Memory {
+        addr: u32,
+        size: u8,
+    },
+    /// Consecutive bytes of memory, compared as a whole.
+    Range {
+        addr: u32,
+        bytes: Vec<u8>,
+    },
+    Pc,
+    Instructions,
+}
+
+/// A single check of the MCU state.
+#[derive(Debug, Clone)]
+pub struct Assertion {
+    /// What is checked, e.g. `a0`, `*0x00001000` or `*buffer (byte)`.
+    pub name: String,
+    pub target: Target,
+    pub comparison: Comparison,
+    pub expected: u64,
+    /// Bits of the value that are compared.
+    pub mask: u64,
+    /// Compare as two's complement numbers of the target's width.
+    pub signed: bool,
+    /// What was found, once checked.
+    pub actual: Option<String>,
+    pub passed: bool,
+}
+
+impl Assertion {
+    fn width(&self) -> u32 {
+        match self.target {
+            Target::Memory { size, .. } => 8 * size as u32,
+            Target::Instructions => 64,
+            _ => 32,
+        }
+    }
+
+    /// The expectation as text, e.g. `== 0x14` or `matches 4 bytes`.
+    pub fn expectation(&self) -> String {
+        let mut text = match &self.target {
+            Target::Range { bytes, .. } => return format!("matches {} bytes", bytes.len()),
+            Target::Instructions => format!("{} {}", self.comparison.symbol(), self.expected),
+            _ if self.signed => format!(
+                "{} {}",
+                self.comparison.symbol(),
+                sign_extend(self.expected, self.width())
+            ),
+            _ => format!("{} 0x{:x}", self.comparison.symbol(), self.expected),
+        };
+        if self.mask != u64::MAX >> (64 - self.width()) {
+            write!(text, " (mask 0x{:x})", self.mask).unwrap();
+        }
+        text
+    }
+
+    fn check(&mut self, mcu: &Mcu) {
+        let actual = match &self.target {
+            Target::Register(reg) => mcu.rf.read(*reg).ok().map(|v| v as u64),
+            Target::Memory { addr, size } => {
+                mcu.mem.peek(*addr, *size as usize).ok().map(|v| v as u64)
+            }
+            Target::Pc => Some(mcu.pc as u64),
+            Target::Instructions => Some(mcu.instructions),
+            Target::Range { addr, bytes } => {
+                let actual: Option<Vec<u8>> = (0..bytes.len() as u32)
+                    .map(|i| mcu.mem.peek(addr.wrapping_add(i), 1).ok().map(|b| b as u8))
+                    .collect();
+                self.passed = actual.as_ref() == Some(bytes);
+                self.actual = Some(match actual {
+                    Some(actual) => match actual.iter().zip(bytes).position(|(a, e)| a != e) {
+                        Some(i) => format!(
+                            "0x{:02x} at +{} instead of 0x{:02x}",
+                            actual[i], i, bytes[i]
+                        ),
+                        None => "match".to_string(),
+                    },
+                    None => "out of bounds".to_string(),
+                });
+                return;
+            }
+        };
+
+        let width = self.width();
+        self.passed = match actual {
+            Some(actual) if self.signed => self.comparison.holds(
+                sign_extend(actual & self.mask, width),
+                sign_extend(self.expected & self.mask, width),
+            ),
+            Some(actual) => self
+                .comparison
+                .holds(actual & self.mask, self.expected & self.mask),
+            None => false,
+        };
+        self.actual = Some(match actual {
+            Some(actual) if self.signed => sign_extend(actual, width).to_string(),
+            Some(actual) if self.target == Target::Instructions => actual.to_string(),
+            Some(actual) => format!("0x{:x}", actual),
+            None => "out of bounds".to_string(),
+        });
+    }
+}
 
 /// Used to contain a set of assertions about the state of an MCU.
 pub struct Assertions {
-    pub register_assertions: Vec<(u8, u32, bool)>,
-    pub memory_assertions: Vec<(u32, u32, bool)>,
+    pub assertions: Vec<Assertion>,
 }
 
 impl Assertions {
-    /// Construct an `Assertion` from a JSON file.
+    /// Construct an `Assertion` from a JSON file. Memory addresses may
+    /// be given as symbols of `symbols`, optionally with `+offset`.
     ///
     /// Example:
     ///
@@ -21,62 +166,671 @@ impl Assertions {
     /// {
     ///     "registers": {
     ///         "a0": "10",
-    ///         "t0": "0"
+    ///         "a1": "!= 0",
+    ///         "a2": { "value": "-1", "op": "<", "signed": true },
+    ///         "t0": { "value": "0x80", "mask": "0xf0" }
     ///     },
     ///     "memory": {
     ///         "0x1000": "0x10",
-    ///         "0x1004": "0x4"
-    ///     }
+    ///         "0x1004": { "size": "byte", "value": "4" },
+    ///         "buffer+4": { "string": "hello" },
+    ///         "0x2000": { "hex": "deadbeef" },
+    ///         "0x3000": { "file": "expected.hex" }
+    ///     },
+    ///     "pc": "0x24",
+    ///     "instructions": "<= 100"
     /// }
     /// ```
     ///
-    /// ```no_run
-    /// # use lib_rv32::Assertions;
+    /// A value is a number, optionally preceded by `==`, `!=`, `<`, `<=`,
+    /// `>` or `>=`, or an object with `value`, `op`, `mask`, `signed` and,
+    /// for memory, `size` (`byte`, `half` or `word`). Memory can instead
+    /// be compared byte for byte with a `string`, a `hex` string or a
+    /// `file` of hex, relative to the assertions file.
+    ///
+    /// ```ignore
+    /// use crate::assertions::Assertions;
     /// use std::path::PathBuf;
     ///
-    /// let asserts = Assertions::load(&PathBuf::from("assert.json"));
+    /// let asserts = Assertions::load(&PathBuf::from("assert.json"), &[]).unwrap();
     /// ```
-    pub fn load(path: &Path) -> Self {
-        let test_params: serde_json::Value =
-            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    pub fn load(path: &Path, symbols: &[Symbol]) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let test_params: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
 
-        let mut register_assertions: Vec<(u8, u32, bool)> = Vec::new();
+        let mut assertions = Vec::new();
         for (i, name) in REG_NAMES.iter().enumerate() {
-            if let Some(s) = test_params["registers"][*name].as_str() {
-                let d = parse_int!(u32, s).unwrap();
-                register_assertions.push((i as u8, d as u32, true));
+            // Registers go by their ABI names or as `xN`.
+            for name in [name.to_string(), format!("x{}", i)] {
+                if let Some(v) = test_params["registers"].get(&name) {
+                    assertions.push(parse_check(name, Target::Register(i as u8), v)?);
+                }
             }
         }
 
-        let mut memory_assertions: Vec<(u32, u32, bool)> = Vec::new();
-        let kvs = test_params["memory"].as_object();
-        if kvs.is_some() {
-            for (k, v) in kvs.unwrap() {
-                let addr = parse_int!(u32, k).unwrap();
-                let data = parse_int!(u32, v.as_str().unwrap()).unwrap();
-                memory_assertions.push((addr, data, true));
+        if let Some(kvs) = test_params["memory"].as_object() {
+            for (k, v) in kvs {
+                let addr = parse_address(k, symbols)?;
+                let name = match k.chars().next() {
+                    Some('0'..='9') => format!("*0x{:08x}", addr),
+                    _ => format!("*{}", k),
+                };
+                let bytes = match (v.get("string"), v.get("hex"), v.get("file")) {
+                    (Some(Value::String(s)), _, _) => Some(s.as_bytes().to_vec()),
+                    (_, Some(Value::String(hex)), _) => Some(parse_hex(hex)?),
+                    (_, _, Some(Value::String(file))) => Some(parse_hex(
+                        &fs::read_to_string(dir.join(file))
+                            .map_err(|e| format!("{}: {}", file, e))?,
+                    )?),
+                    _ => None,
+                };
+                if let Some(bytes) = bytes {
+                    assertions.push(Assertion {
+                        name,
+                        target: Target::Range { addr, bytes },
+                        comparison: Comparison::Eq,
+                        expected: 0,
+                        mask: u64::MAX,
+                        signed: false,
+                        actual: None,
+                        passed: false,
+                    });
+                    continue;
+                }
+
+                let size = match v.get("size").map(|s| s.as_str().unwrap_or_default()) {
+                    None | Some("word") | Some("4") => 4,
+                    Some("half") | Some("2") => 2,
+                    Some("byte") | Some("1") => 1,
+                    Some(s) => return Err(format!("{} is not a valid size", s)),
+                };
+                let name = match size {
+                    4 => name,
+                    2 => format!("{} (half)", name),
+                    _ => format!("{} (byte)", name),
+                };
+                assertions.push(parse_check(name, Target::Memory { addr, size }, v)?);
             }
         }
 
-        Assertions {
-            register_assertions,
-            memory_assertions,
+        if let Some(v) = test_params.get("pc") {
+            // The pc may also be a symbol.
+            let v = match v.as_str().map(|s| parse_address(s, symbols)) {
+                Some(Ok(addr)) => Value::String(addr.to_string()),
+                _ => v.clone(),
+            };
+            assertions.push(parse_check("pc".to_string(), Target::Pc, &v)?);
         }
+        if let Some(v) = test_params.get("instructions") {
+            let target = Target::Instructions;
+            assertions.push(parse_check("instructions".to_string(), target, v)?);
+        }
+
+        Ok(Assertions { assertions })
     }
 
-    /// Iterate through all assertions and compare their expected values
-    /// with the actual. If the assertion fails, the third member of the
-    /// tuple is populated with `false`.
-    pub fn assert_all<M, R>(&mut self, mem: &mut M, rf: &mut R)
-    where
-        M: Memory,
-        R: RegisterFile,
-    {
-        for a in self.register_assertions.iter_mut() {
-            a.2 = rf.read(a.0).unwrap() == a.1;
+    /// Check every assertion against the state of `mcu`.
+    pub fn assert_all(&mut self, mcu: &Mcu) {
+        for a in self.assertions.iter_mut() {
+            a.check(mcu);
         }
-        for a in self.memory_assertions.iter_mut() {
-            a.2 = mem.fetch(a.0).unwrap() == a.1;
+    }
+
+    /// Whether every assertion held when last checked.
+    pub fn passed(&self) -> bool {
+        self.assertions.iter().all(|a| a.passed)
+    }
+
+    /// The results as a JUnit XML test suite named `suite`, with one
+    /// test case per assertion.
+    pub fn junit(&self, suite: &str) -> String {
+        let failures = self.assertions.iter().filter(|a| !a.passed).count();
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            out,
+            "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">",
+            escape_xml(suite),
+            self.assertions.len(),
+            failures
+        )
+        .unwrap();
+        for a in self.assertions.iter() {
+            let name = escape_xml(&format!("{} {}", a.name, a.expectation()));
+            match a.passed {
+                true => writeln!(out, "  <testcase name=\"{}\"/>", name).unwrap(),
+                false => writeln!(
+                    out,
+                    "  <testcase name=\"{}\">\n    <failure message=\"{}\"/>\n  </testcase>",
+                    name,
+                    escape_xml(&format!("got {}", a.actual.as_deref().unwrap_or("nothing")))
+                )
+                .unwrap(),
+            }
+        }
+        out.push_str("</testsuite>\n");
+        out
+    }
+
+    /// The results as JSON.
+    pub fn to_json(&self, suite: &str) -> String {
+        let assertions: Vec<Value> = self
+            .assertions
+            .iter()
+            .map(|a| {
+                json!({
+                    "name": a.name,
+                    "expected": a.expectation(),
+                    "actual": a.actual,
+                    "passed": a.passed,
+                })
+            })
+            .collect();
+        json!({
+            "name": suite,
+            "passed": self.passed(),
+            "assertions": assertions,
+        })
+        .to_string()
+    }
+}
+
+/// Parse a comparison from a string such as `"!= 0x10"` or an object
+/// such as `{ "value": "16", "op": "<", "mask": "0xff" }`.
+fn parse_check(name: String, target: Target, v: &Value) -> Result<Assertion, String> {
+    // Negative numbers become two's complement of the target's width.
+    let number = match (v.as_u64(), v.as_i64()) {
+        (Some(n), _) => Some(n.to_string()),
+        (_, Some(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    let (text, op, mask, signed) = match v {
+        Value::String(s) => (s.as_str(), None, None, false),
+        Value::Number(_) if number.is_some() => (number.as_deref().unwrap(), None, None, false),
+        Value::Object(o) => (
+            o.get("value")
+                .and_then(|v| v.as_str())
+                .ok_or(format!("{} has no value", name))?,
+            o.get("op").and_then(|v| v.as_str()),
+            o.get("mask").and_then(|v| v.as_str()),
+            o.get("signed").and_then(|v| v.as_bool()).unwrap_or(false),
+        ),
+        _ => return Err(format!("{} is not a string or an object", name)),
+    };
+    let text = text.trim();
+    let (comparison, text) = match Comparison::ALL.iter().find(|(s, _)| text.starts_with(s)) {
+        Some((s, c)) => (*c, text[s.len()..].trim()),
+        None => (Comparison::Eq, text),
+    };
+    let comparison = match op {
+        Some(op) => {
+            Comparison::ALL
+                .iter()
+                .find(|(s, _)| *s == op)
+                .ok_or(format!("{} is not a valid comparison", op))?
+                .1
         }
+        None => comparison,
+    };
+
+    let mut assertion = Assertion {
+        name,
+        target,
+        comparison,
+        expected: 0,
+        mask: 0,
+        signed,
+        actual: None,
+        passed: false,
+    };
+    let all = u64::MAX >> (64 - assertion.width());
+    assertion.expected = parse_number(text)? as u64 & all;
+    assertion.mask = match mask {
+        Some(mask) => parse_number(mask)? as u64 & all,
+        None => all,
+    };
+    Ok(assertion)
+}
+
+/// Parse a decimal, `0x` hexadecimal or `0b` binary number, which may be
+/// negative.
+fn parse_number(s: &str) -> Result<i64, String> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let value = match (lower.strip_prefix("0x"), lower.strip_prefix("0b")) {
+        (Some(hex), _) => i64::from_str_radix(hex, 16),
+        (_, Some(bin)) => i64::from_str_radix(bin, 2),
+        _ => lower.parse(),
+    }
+    .map_err(|_| format!("{} is not a valid number", s))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parse an address or a symbol with an optional `+offset`.
+fn parse_address(s: &str, symbols: &[Symbol]) -> Result<u32, String> {
+    if let Ok(addr) = parse_number(s) {
+        return Ok(addr as u32);
+    }
+    let (name, offset) = match s.split_once('+') {
+        Some((name, offset)) => (name.trim(), parse_number(offset.trim())? as u32),
+        None => (s, 0),
+    };
+    match symbols.iter().find(|sym| sym.name == name) {
+        Some(sym) => Ok(sym.addr.wrapping_add(offset)),
+        None => Err(format!("{} is not an address or a known symbol", s)),
+    }
+}
+
+/// Parse bytes written as hex digits, ignoring whitespace.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("hex data has an odd number of digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("{} is not a hex byte", pair))
+        })
+        .collect()
+}
+
+fn sign_extend(value: u64, width: u32) -> i64 {
+    ((value << (64 - width)) as i64) >> (64 - width)
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use lib_rv32_mcu::elf::SymbolKind;
+
+    use super::*;
+
+    fn check(target: Target, v: Value) -> Assertion {
+        parse_check("check".to_string(), target, &v).unwrap()
+    }
+
+    fn mcu() -> Mcu {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.rf.write(10, 20).unwrap();
+        mcu.rf.write(11, -2i32 as u32).unwrap();
+        mcu.mem.write_word(0x100, 0x6c6c6568).unwrap();
+        mcu.mem.write_word(0x104, 0x0000006f).unwrap();
+        mcu
+    }
+
+    fn passes(mut assertion: Assertion) -> bool {
+        assertion.check(&mcu());
+        assertion.passed
+    }
+
+    /// Write `json` to a temporary file and load assertions from it.
+    fn load(name: &str, json: &str, symbols: &[Symbol]) -> Result<Assertions, String> {
+        let path = env::temp_dir().join(format!(
+            "lib-rv32-assertions-{}-{}.json",
+            name,
+            process::id()
+        ));
+        fs::write(&path, json).unwrap();
+        let assertions = Assertions::load(&path, symbols);
+        fs::remove_file(&path).unwrap();
+        assertions
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(Ok(17), parse_number("17"));
+        assert_eq!(Ok(-17), parse_number("-17"));
+        assert_eq!(Ok(0xff), parse_number("0xFF"));
+        assert_eq!(Ok(0xff), parse_number("0XfF"));
+        assert_eq!(Ok(-0x10), parse_number("-0x10"));
+        assert_eq!(Ok(5), parse_number("0b101"));
+        assert_eq!(
+            Err("0xg is not a valid number".to_string()),
+            parse_number("0xg")
+        );
+        assert_eq!(
+            Err("ten is not a valid number".to_string()),
+            parse_number("ten")
+        );
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let symbols = [Symbol {
+            name: "buffer".to_string(),
+            addr: 0x100,
+            size: 8,
+            kind: SymbolKind::Object,
+        }];
+        assert_eq!(Ok(0x20), parse_address("0x20", &symbols));
+        assert_eq!(Ok(0x100), parse_address("buffer", &symbols));
+        assert_eq!(Ok(0x104), parse_address("buffer + 4", &symbols));
+        assert_eq!(Ok(0x110), parse_address("buffer+0x10", &symbols));
+        assert_eq!(
+            Err("stack is not an address or a known symbol".to_string()),
+            parse_address("stack", &symbols)
+        );
+        assert_eq!(
+            Err("x is not a valid number".to_string()),
+            parse_address("buffer+x", &symbols)
+        );
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(Ok(vec![0xde, 0xad, 0xbe, 0xef]), parse_hex("de ad\nBEEF"));
+        assert_eq!(
+            Err("hex data has an odd number of digits".to_string()),
+            parse_hex("abc")
+        );
+        assert_eq!(Err("zz is not a hex byte".to_string()), parse_hex("zz"));
+    }
+
+    #[test]
+    fn test_parse_check() {
+        let a = check(Target::Register(10), json!("20"));
+        assert_eq!(
+            (Comparison::Eq, 20, 0xffffffff),
+            (a.comparison, a.expected, a.mask)
+        );
+        assert!(!a.signed);
+
+        for (text, comparison) in [
+            ("== 1", Comparison::Eq),
+            ("!= 1", Comparison::Ne),
+            ("< 1", Comparison::Lt),
+            ("<= 1", Comparison::Le),
+            ("> 1", Comparison::Gt),
+            (">=1", Comparison::Ge),
+        ] {
+            let a = check(Target::Pc, json!(text));
+            assert_eq!((comparison, 1), (a.comparison, a.expected));
+        }
+
+        let a = check(
+            Target::Register(5),
+            json!({ "value": "0x80", "op": "!=", "mask": "0xf0", "signed": true }),
+        );
+        assert_eq!(
+            (Comparison::Ne, 0x80, 0xf0, true),
+            (a.comparison, a.expected, a.mask, a.signed)
+        );
+
+        // Numbers may be given as JSON numbers, and negative ones are two's
+        // complement of the target's width.
+        assert_eq!(16, check(Target::Pc, json!(16)).expected);
+        assert_eq!(0xffffffff, check(Target::Register(10), json!(-1)).expected);
+        let byte = Target::Memory {
+            addr: 0x100,
+            size: 1,
+        };
+        assert_eq!(0xff, check(byte, json!(-1)).expected);
+        assert_eq!(u64::MAX, check(Target::Instructions, json!("-1")).expected);
+    }
+
+    #[test]
+    fn test_parse_check_errors() {
+        let error = |v: Value| parse_check("a0".to_string(), Target::Register(10), &v).unwrap_err();
+        assert_eq!("a0 is not a string or an object", error(json!(true)));
+        assert_eq!("a0 is not a string or an object", error(json!(1.5)));
+        assert_eq!("a0 has no value", error(json!({ "op": "==" })));
+        assert_eq!(
+            "=< is not a valid comparison",
+            error(json!({ "value": "1", "op": "=<" }))
+        );
+        assert_eq!(
+            "0xq is not a valid number",
+            error(json!({ "value": "1", "mask": "0xq" }))
+        );
+        assert_eq!("twenty is not a valid number", error(json!("== twenty")));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert!(passes(check(Target::Register(10), json!("20"))));
+        assert!(!passes(check(Target::Register(10), json!("21"))));
+        assert!(passes(check(Target::Register(10), json!("!= 0"))));
+        assert!(passes(check(Target::Register(10), json!("< 21"))));
+        assert!(!passes(check(Target::Register(10), json!("< 20"))));
+        assert!(passes(check(Target::Register(10), json!("<= 20"))));
+        assert!(passes(check(Target::Register(10), json!("> 0x13"))));
+        assert!(passes(check(Target::Register(10), json!(">= 20"))));
+        assert!(passes(check(Target::Pc, json!(0))));
+        assert!(passes(check(Target::Instructions, json!("<= 0"))));
+    }
+
+    #[test]
+    fn test_signed_comparisons() {
+        // Unsigned, -2 is larger than anything positive.
+        assert!(passes(check(Target::Register(11), json!("> 100"))));
+        let signed = |value: &str, op: &str| {
+            check(
+                Target::Register(11),
+                json!({ "value": value, "op": op, "signed": true }),
+            )
+        };
+        assert!(!passes(signed("100", ">")));
+        assert!(passes(signed("0", "<")));
+        assert!(passes(signed("-2", "==")));
+        assert!(passes(signed("-3", ">")));
+
+        let mut a = signed("-3", ">");
+        assert_eq!("> -3", a.expectation());
+        a.check(&mcu());
+        assert_eq!(Some("-2".to_string()), a.actual);
+    }
+
+    #[test]
+    fn test_masks() {
+        // 0x14 masked with 0xf0 is 0x10.
+        let a = check(
+            Target::Register(10),
+            json!({ "value": "0x1f", "mask": "0xf0" }),
+        );
+        assert_eq!("== 0x1f (mask 0xf0)", a.expectation());
+        assert!(passes(a));
+        assert!(!passes(check(
+            Target::Register(10),
+            json!({ "value": "0x24", "mask": "0xf0" })
+        )));
+    }
+
+    #[test]
+    fn test_memory_sizes() {
+        let word = Target::Memory {
+            addr: 0x100,
+            size: 4,
+        };
+        let half = Target::Memory {
+            addr: 0x102,
+            size: 2,
+        };
+        let byte = Target::Memory {
+            addr: 0x104,
+            size: 1,
+        };
+        assert!(passes(check(word, json!("0x6c6c6568"))));
+        assert!(passes(check(half, json!("0x6c6c"))));
+        assert!(passes(check(byte.clone(), json!("0x6f"))));
+        assert!(!passes(check(byte, json!("0x6e"))));
+
+        let mut a = check(
+            Target::Memory {
+                addr: 0x2000,
+                size: 4,
+            },
+            json!("0"),
+        );
+        a.check(&mcu());
+        assert!(!a.passed);
+        assert_eq!(Some("out of bounds".to_string()), a.actual);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let range = |bytes: &[u8]| Assertion {
+            name: "*buffer".to_string(),
+            target: Target::Range {
+                addr: 0x100,
+                bytes: bytes.to_vec(),
+            },
+            comparison: Comparison::Eq,
+            expected: 0,
+            mask: u64::MAX,
+            signed: false,
+            actual: None,
+            passed: false,
+        };
+
+        let mut a = range(b"hello");
+        assert_eq!("matches 5 bytes", a.expectation());
+        a.check(&mcu());
+        assert!(a.passed);
+        assert_eq!(Some("match".to_string()), a.actual);
+
+        let mut a = range(b"help");
+        a.check(&mcu());
+        assert!(!a.passed);
+        assert_eq!(Some("0x6c at +3 instead of 0x70".to_string()), a.actual);
+    }
+
+    #[test]
+    fn test_load() {
+        let symbols = [Symbol {
+            name: "buffer".to_string(),
+            addr: 0x100,
+            size: 8,
+            kind: SymbolKind::Object,
+        }];
+        let mut assertions = load(
+            "load",
+            r#"{
+                "registers": { "a0": 20, "x11": { "value": "-2", "signed": true } },
+                "memory": {
+                    "0x104": { "size": "byte", "value": "0x6f" },
+                    "buffer": { "string": "hello" },
+                    "buffer+2": { "hex": "6c 6c" },
+                    "buffer+4": { "size": "half", "value": "0x6f" }
+                },
+                "pc": "buffer",
+                "instructions": "0"
+            }"#,
+            &symbols,
+        )
+        .unwrap();
+        let names: Vec<&str> = assertions
+            .assertions
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "a0",
+                "x11",
+                "*0x00000104 (byte)",
+                "*buffer",
+                "*buffer+2",
+                "*buffer+4 (half)",
+                "pc",
+                "instructions"
+            ],
+            names
+        );
+
+        assertions.assert_all(&mcu());
+        assert!(!assertions.passed());
+        // Only the pc, which is 0 rather than 0x100, fails.
+        let failed: Vec<&str> = assertions
+            .assertions
+            .iter()
+            .filter(|a| !a.passed)
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(vec!["pc"], failed);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(
+            Err("nibble is not a valid size".to_string()),
+            load(
+                "bad_size",
+                r#"{ "memory": { "0x0": { "size": "nibble", "value": "1" } } }"#,
+                &[]
+            )
+            .map(|_| ())
+        );
+        assert_eq!(
+            Err("top is not an address or a known symbol".to_string()),
+            load("bad_symbol", r#"{ "memory": { "top": "1" } }"#, &[]).map(|_| ())
+        );
+        assert!(load("bad_json", "{", &[]).is_err());
+        assert!(load(
+            "missing_file",
+            r#"{ "memory": { "0x0": { "file": "missing.hex" } } }"#,
+            &[]
+        )
+        .err()
+        .unwrap()
+        .starts_with("missing.hex: "));
+    }
+
+    #[test]
+    fn test_reports() {
+        let mut assertions = Assertions {
+            assertions: vec![
+                check(Target::Register(10), json!("< 0x15")),
+                check(Target::Register(11), json!("0")),
+            ],
+        };
+        assertions.assertions[0].name = "a0 \"<&>\"".to_string();
+        assertions.assert_all(&mcu());
+
+        assert_eq!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuite name=\"prog &amp; co\" tests=\"2\" failures=\"1\">\n  \
+             <testcase name=\"a0 &quot;&lt;&amp;&gt;&quot; &lt; 0x15\"/>\n  \
+             <testcase name=\"check == 0x0\">\n    \
+             <failure message=\"got 0xfffffffe\"/>\n  \
+             </testcase>\n\
+             </testsuite>\n",
+            assertions.junit("prog & co")
+        );
+
+        let report: Value = serde_json::from_str(&assertions.to_json("prog")).unwrap();
+        assert_eq!(
+            json!({
+                "name": "prog",
+                "passed": false,
+                "assertions": [
+                    {
+                        "name": "a0 \"<&>\"",
+                        "expected": "< 0x15",
+                        "actual": "0x14",
+                        "passed": true
+                    },
+                    {
+                        "name": "check",
+                        "expected": "== 0x0",
+                        "actual": "0xfffffffe",
+                        "passed": false
+                    }
+                ]
+            }),
+            report
+        );
     }
 }
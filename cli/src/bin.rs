@@ -3,20 +3,35 @@ pub mod assertions;
 use std::{
     fs,
     io::{prelude::*, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
 };
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use lazy_static::lazy_static;
 use log::{info, Level, LevelFilter, Metadata, Record};
 
-use lib_rv32_asm::assemble_program_buf;
-use lib_rv32_mcu::{common::constants::*, isa::exec_one, *};
+use lib_rv32_asm::{assemble_program_buf, assemble_program_with_lines};
+use lib_rv32_mcu::{
+    common::parse_int,
+    isa::RiscvError,
+    test_runner::{TestOutcome, TestResult},
+    *,
+};
 
 use assertions::Assertions;
 
 const DEFAULT_MEM_SIZE: usize = 1024 * 64;
 
+// Process exit statuses. Without assertions, a program that exits gives
+// its own status, so the simulator's own are kept clear of the usual small
+// ones; 124 is also what `timeout(1)` uses. A program can still exit with
+// them itself, which the message on stderr tells apart.
+const EXIT_ASSERTIONS_FAILED: i32 = 1;
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_FAULT: i32 = 125;
+
 lazy_static! {
     static ref CFG: Config = Config::new();
 }
@@ -26,14 +41,53 @@ static LOGGER: Logger = Logger;
 enum Mode {
     Emulator,
     Assembler,
+    /// Run the test directories under `dir`, `jobs` at a time.
+    Test {
+        dir: PathBuf,
+        jobs: usize,
+    },
+}
+
+/// Why emulation stopped.
+enum StopReason {
+    StopPc,
+    Exit(i32),
+    Breakpoint(u32),
+    SelfLoop(u32),
+    InstructionLimit(u64),
+    Timeout,
+    Fault(u32, RiscvError),
 }
 
 struct Config {
     file: PathBuf,
     mem_size: usize,
+    regions: Vec<Region>,
+    misaligned: MisalignedPolicy,
+    protect: bool,
     stop_pc: Option<u32>,
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    stop_on_ebreak: bool,
+    stop_on_self_loop: bool,
     assertions: Option<PathBuf>,
+    report: Option<PathBuf>,
     output: Option<PathBuf>,
+    load_snapshot: Option<PathBuf>,
+    save_snapshot: Option<PathBuf>,
+    timing: Option<Latencies>,
+    pipeline: Option<PipelineConfig>,
+    cache: Option<HierarchyConfig>,
+    tlb: Option<usize>,
+    predictors: Vec<PredictorKind>,
+    counters: Option<Vec<HpmEvent>>,
+    profile: bool,
+    folded: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    annotate: Option<PathBuf>,
+    source: Option<PathBuf>,
+    isa_coverage: Option<Option<PathBuf>>,
+    sandbox: Option<PathBuf>,
     mode: Mode,
 }
 
@@ -43,10 +97,29 @@ impl Config {
             .version("0.2.0")
             .author("Trevor McKay <tm@trmckay.com>")
             .about("Emulate RISC-V")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(
+                SubCommand::with_name("test")
+                    .about("Run every test directory (one with a test_case.json) under DIR")
+                    .arg(
+                        Arg::with_name("dir")
+                            .help("Directory to search for tests")
+                            .required(true)
+                            .index(1),
+                    )
+                    .arg(
+                        Arg::with_name("jobs")
+                            .short("j")
+                            .long("jobs")
+                            .value_name("JOBS")
+                            .help("Number of tests to run at once (default: one per CPU)")
+                            .takes_value(true),
+                    ),
+            )
             .arg(
                 Arg::with_name("file")
                     .help("File on which to act")
-                    .required(true)
+                    .required_unless("load-snapshot")
                     .index(1),
             )
             .arg(
@@ -57,6 +130,28 @@ impl Config {
                     .help("Set the size of the MCU memory (default 64 KB)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("region")
+                    .long("region")
+                    .value_name("BASE:SIZE[:PERMS]")
+                    .help("Map SIZE bytes of memory at BASE instead of --mem bytes where the program is loaded, with PERMS such as rx (default rwx); repeat to map more regions")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("misaligned")
+                    .long("misaligned")
+                    .value_name("POLICY")
+                    .help("Handle misaligned loads and stores: trap (the default), split into byte accesses, or emulate")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("protect")
+                    .long("protect")
+                    .help("Apply the permissions of the ELF's segments, so that e.g. stores into .text fault")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::with_name("stop")
                     .short("s")
@@ -65,6 +160,30 @@ impl Config {
                     .help("Set the program counter at which to stop emulation")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("max-instructions")
+                    .long("max-instructions")
+                    .value_name("COUNT")
+                    .help("Stop after executing COUNT instructions")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Stop after running for SECONDS of wall-clock time")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("stop-on")
+                    .long("stop-on")
+                    .value_name("EVENTS")
+                    .help(
+                        "Comma-separated events that also end emulation: ebreak and \
+                         self-loop (a jump to itself); none by default",
+                    )
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("assertions")
                     .short("a")
@@ -73,6 +192,13 @@ impl Config {
                     .help("A JSON formatted set of assertions")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("report")
+                    .long("report")
+                    .value_name("REPORT_FILE")
+                    .help("Write the assertion results as JUnit XML (if the name ends in .xml) or JSON")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("output")
                     .short("o")
@@ -81,6 +207,140 @@ impl Config {
                     .help("Out-file for binary in assembler mode, or memory dump in emulator mode")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("load-snapshot")
+                    .long("load-snapshot")
+                    .value_name("SNAPSHOT_FILE")
+                    .help("Start emulation from a saved snapshot instead of a binary")
+                    .takes_value(true)
+                    .conflicts_with_all(&["file", "mem", "region", "misaligned", "protect"]),
+            )
+            .arg(
+                Arg::with_name("save-snapshot")
+                    .long("save-snapshot")
+                    .value_name("SNAPSHOT_FILE")
+                    .help("Save a snapshot when emulation stops (JSON if the name ends in .json)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("timing")
+                    .short("t")
+                    .long("timing")
+                    .help("Estimate cycles and CPI and print them when emulation stops")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("latencies")
+                    .long("latencies")
+                    .value_name("LATENCIES_FILE")
+                    .help("A JSON formatted set of instruction latencies (implies --timing)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("cache")
+                    .long("cache")
+                    .value_name("CACHE_FILE")
+                    .help("Simulate the caches described by a JSON file and print their hit rates")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tlb")
+                    .long("tlb")
+                    .value_name("ENTRIES")
+                    .help("Cache address translations in a TLB of ENTRIES and print its hit rate")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("predictor")
+                    .long("predictor")
+                    .value_name("PREDICTOR")
+                    .help(
+                        "Simulate a branch predictor (not-taken, btfn, 1bit, 2bit or gshare, \
+                         with an optional :ENTRIES); repeat to compare predictors",
+                    )
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("pipeline")
+                    .short("p")
+                    .long("pipeline")
+                    .value_name("HISTORY")
+                    .help("Print a 5-stage pipeline diagram of the last instructions (default 64)")
+                    .min_values(0)
+                    .max_values(1),
+            )
+            .arg(
+                Arg::with_name("no-forwarding")
+                    .long("no-forwarding")
+                    .help("Stall on data hazards in the pipeline diagram instead of forwarding")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("counters")
+                    .long("counters")
+                    .value_name("EVENTS")
+                    .help(
+                        "Count comma-separated events on mhpmcounter3 onwards and print the \
+                         counters when emulation stops (default: loads,stores,branches,\
+                         taken-branches,jumps,cache-misses,traps)",
+                    )
+                    .min_values(0)
+                    .max_values(1),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .help("Print a per-function profile and the hottest basic blocks when emulation stops")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("folded")
+                    .long("folded")
+                    .value_name("FOLDED_FILE")
+                    .help("Write the profile as folded stacks for flamegraph tools")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("coverage")
+                    .long("coverage")
+                    .value_name("LCOV_FILE")
+                    .help("Write line and branch coverage in lcov format")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("annotate")
+                    .long("annotate")
+                    .value_name("ANNOTATED_FILE")
+                    .help("Write a disassembly annotated with execution counts")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("source")
+                    .long("source")
+                    .value_name("ASM_FILE")
+                    .help("Assembly the binary was built from, to map coverage to its lines")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("isa-coverage")
+                    .long("isa-coverage")
+                    .value_name("JSON_FILE")
+                    .help(
+                        "Print which parts of the instruction set were exercised, and optionally \
+                         write every coverage bin as JSON",
+                    )
+                    .min_values(0)
+                    .max_values(1),
+            )
+            .arg(
+                Arg::with_name("sandbox")
+                    .long("sandbox")
+                    .value_name("DIR")
+                    .help("Let the program open files under DIR through syscalls and semihosting")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("verbose")
                     .short("v")
@@ -99,35 +359,171 @@ impl Config {
                 Arg::with_name("emulate")
                     .short("e")
                     .long("--emulate")
-                    .default_value("e")
-                    .help("Launch in emulator mode")
+                    .help("Launch in emulator mode (the default)")
                     .takes_value(false),
             )
             .get_matches();
 
-        let mem_size = match matches.value_of("config") {
-            Some(s) => str::parse(s).unwrap(),
+        let mem_size = match matches.value_of("mem") {
+            Some(s) => str::parse(s).unwrap_or_else(|_| panic!("{} is not a valid size.", s)),
             None => DEFAULT_MEM_SIZE,
         };
+        let regions = matches.values_of("region").map_or(Vec::new(), |regions| {
+            regions
+                .map(|region| {
+                    let fields: Vec<&str> = region.split(':').collect();
+                    let parsed = match fields[..] {
+                        [base, size] => Some((base, size, "rwx")),
+                        [base, size, perms] => Some((base, size, perms)),
+                        _ => None,
+                    }
+                    .and_then(|(base, size, perms)| {
+                        Some(Region {
+                            base: parse_int!(u32, base).ok()?,
+                            size: parse_int!(u32, size).ok()?,
+                            perms: perms.parse().ok()?,
+                        })
+                    });
+                    match parsed {
+                        Some(r)
+                            if r.base % 4 == 0
+                                && r.size % 4 == 0
+                                && r.size > 0
+                                && r.base as u64 + r.size as u64 <= 1 << 32 =>
+                        {
+                            r
+                        }
+                        _ => panic!("{} is not a valid region.", region),
+                    }
+                })
+                .collect()
+        });
+        let misaligned = matches
+            .value_of("misaligned")
+            .map_or(MisalignedPolicy::Trap, |s| {
+                s.parse().unwrap_or_else(|why: String| panic!("{}.", why))
+            });
+        let max_instructions = matches.value_of("max-instructions").map(|s| {
+            str::parse(s).unwrap_or_else(|_| panic!("{} is not a valid instruction count.", s))
+        });
+        let timeout = matches.value_of("timeout").map(|s| {
+            Duration::from_secs_f64(
+                str::parse(s).unwrap_or_else(|_| panic!("{} is not a valid timeout.", s)),
+            )
+        });
+        let stop_on: Vec<&str> = matches
+            .value_of("stop-on")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        if let Some(event) = stop_on
+            .iter()
+            .find(|e| !["ebreak", "self-loop"].contains(e))
+        {
+            panic!("{} is not a valid stop event.", event);
+        }
         let stop_pc = matches.value_of("stop").map(|s| {
             u32::from_str_radix(s, 16)
                 .unwrap_or_else(|_| panic!("{} is not a valid hex literal.", s))
         });
         let verbose = !matches!(matches.occurrences_of("verbose"), 0);
-        let path = PathBuf::from(matches.value_of("file").unwrap());
+        let path = matches
+            .value_of("file")
+            .map(PathBuf::from)
+            .unwrap_or_default();
         let assertions = matches.value_of("assertions").map(PathBuf::from);
+        let report = matches.value_of("report").map(PathBuf::from);
         let output = matches.value_of("output").map(PathBuf::from);
+        let load_snapshot = matches.value_of("load-snapshot").map(PathBuf::from);
+        let save_snapshot = matches.value_of("save-snapshot").map(PathBuf::from);
+        let timing = match matches.value_of("latencies") {
+            Some(path) => {
+                let text = fs::read_to_string(path).expect("Could not read latencies.");
+                Some(serde_json::from_str(&text).expect("Could not parse latencies."))
+            }
+            None if matches.is_present("timing") => Some(Latencies::default()),
+            None => None,
+        };
 
-        let mode: Mode = if matches.occurrences_of("emulate") == 1 {
-            Mode::Emulator
-        } else if matches.occurrences_of("assemble") == 1 {
-            Mode::Assembler
+        let cache = matches.value_of("cache").map(|path| {
+            let text = fs::read_to_string(path).expect("Could not read cache configuration.");
+            serde_json::from_str(&text).expect("Could not parse cache configuration.")
+        });
+        let tlb = matches.value_of("tlb").map(|s| match str::parse(s) {
+            Ok(entries) if entries > 0 => entries,
+            _ => panic!("{} is not a valid number of TLB entries.", s),
+        });
+        let predictors = matches
+            .values_of("predictor")
+            .map(|values| {
+                values
+                    .map(|s| {
+                        PredictorKind::parse(s)
+                            .unwrap_or_else(|| panic!("{} is not a valid branch predictor.", s))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let counters = if matches.is_present("counters") {
+            Some(match matches.value_of("counters") {
+                Some(list) => list
+                    .split(',')
+                    .map(|s| {
+                        HpmEvent::parse(s.trim())
+                            .unwrap_or_else(|| panic!("{} is not a valid counter event.", s))
+                    })
+                    .collect(),
+                None => HpmEvent::ALL[1..].to_vec(),
+            })
+        } else {
+            None
+        };
+        let coverage = matches.value_of("coverage").map(PathBuf::from);
+        let annotate = matches.value_of("annotate").map(PathBuf::from);
+        let source = matches.value_of("source").map(PathBuf::from);
+        let isa_coverage = matches
+            .is_present("isa-coverage")
+            .then(|| matches.value_of("isa-coverage").map(PathBuf::from));
+        let sandbox = matches.value_of("sandbox").map(PathBuf::from);
+        let profile = matches.is_present("profile");
+        let folded = matches.value_of("folded").map(PathBuf::from);
+        let pipeline = if matches.is_present("pipeline") {
+            let mut config = PipelineConfig {
+                forwarding: !matches.is_present("no-forwarding"),
+                ..PipelineConfig::default()
+            };
+            if let Some(s) = matches.value_of("pipeline") {
+                config.history = match str::parse(s) {
+                    Ok(history) if history > 0 => history,
+                    _ => panic!("{} is not a valid history length.", s),
+                };
+            }
+            Some(config)
         } else {
-            panic!("No mode provided.");
+            None
         };
-        if matches.occurrences_of("emulate") == matches.occurrences_of("assemble") {
+
+        if matches.is_present("emulate") && matches.is_present("assemble") {
             panic!("Cannot launch in both modes.");
         }
+        let mode = match (
+            matches.subcommand_matches("test"),
+            matches.is_present("assemble"),
+        ) {
+            (Some(test), _) => Mode::Test {
+                dir: PathBuf::from(test.value_of("dir").unwrap()),
+                jobs: match test.value_of("jobs") {
+                    Some(s) => {
+                        str::parse(s).unwrap_or_else(|_| panic!("{} is not a valid job count.", s))
+                    }
+                    None => thread::available_parallelism().map_or(1, |n| n.get()),
+                },
+            },
+            (None, true) => Mode::Assembler,
+            (None, false) => Mode::Emulator,
+        };
 
         if verbose {
             log::set_logger(&LOGGER)
@@ -138,10 +534,33 @@ impl Config {
         Config {
             file: path,
             mem_size,
+            regions,
+            misaligned,
+            protect: matches.is_present("protect"),
             stop_pc,
+            max_instructions,
+            timeout,
+            stop_on_ebreak: stop_on.contains(&"ebreak"),
+            stop_on_self_loop: stop_on.contains(&"self-loop"),
             assertions,
+            report,
             mode,
             output,
+            load_snapshot,
+            save_snapshot,
+            timing,
+            pipeline,
+            cache,
+            tlb,
+            predictors,
+            counters,
+            profile,
+            folded,
+            coverage,
+            annotate,
+            source,
+            isa_coverage,
+            sandbox,
         }
     }
 }
@@ -163,51 +582,408 @@ impl log::Log for Logger {
 }
 
 fn emu() {
-    let assertions = CFG.assertions.as_ref().map(|p| Assertions::load(p));
+    let mut symbols = Vec::new();
+    let mut lines = LineTable::new();
+    // Address ranges of the program, for the annotated disassembly.
+    let mut program = Vec::new();
+    // Where `brk` starts the heap: the end of the program.
+    let heap_start;
+    let mut htif = None;
+    let mut mcu: Mcu = match &CFG.load_snapshot {
+        Some(path) => {
+            let mcu = Mcu::load_snapshot(path).expect("Could not load snapshot.");
+            symbols = mcu.program.symbols.clone();
+            heap_start = mcu.program.heap_start;
+            htif = mcu.program.htif;
+            mcu
+        }
+        None => {
+            let bytes = fs::read(&CFG.file).expect("Could not read binary.");
+            let elf =
+                Elf::is_elf(&bytes).then(|| Elf::parse(&bytes).expect("Could not parse ELF."));
+            let mut mem = Memory::sparse();
+            if CFG.regions.is_empty() {
+                // Map the memory from the page the program is loaded at.
+                let base = elf.as_ref().map_or(0, |elf| elf.load_base()) & !(PAGE_SIZE as u32 - 1);
+                let size = (CFG.mem_size as u64).min((1 << 32) - base as u64);
+                mem.map(base, size as u32);
+            }
+            for r in CFG.regions.iter() {
+                mem.map_with_permissions(r.base, r.size, r.perms);
+            }
+            mem.set_misaligned_policy(CFG.misaligned);
+            let mut mcu = Mcu::with_memory(mem);
+            if let Some(elf) = elf {
+                mcu.program_elf(&elf).expect("Could not program MCU.");
+                if CFG.protect {
+                    mcu.mem.protect_elf(&elf);
+                }
+                // Lines are only needed for coverage, and not every DWARF
+                // form is understood, so a bad table is not fatal.
+                if CFG.coverage.is_some() || CFG.annotate.is_some() {
+                    lines = LineTable::from_elf(&elf).unwrap_or_else(|why| {
+                        eprintln!(
+                            "Could not read line table ({:?}), continuing without line information.",
+                            why
+                        );
+                        LineTable::new()
+                    });
+                }
+                // program_elf has rejected segments that wrap around.
+                program = elf
+                    .segments
+                    .iter()
+                    .filter_map(|s| Some((s.addr, s.addr.checked_add(s.data.len() as u32)?)))
+                    .collect();
+                heap_start = ["_end", "end"]
+                    .iter()
+                    .find_map(|name| elf.symbol(name))
+                    .unwrap_or_else(|| program.iter().map(|(_, end)| *end).max().unwrap_or(0));
+                htif = elf.symbol("tohost").map(|t| (t, elf.symbol("fromhost")));
+                symbols = elf.symbols;
+            } else {
+                // Raw binaries are loaded at the start of the first region.
+                let base = mcu.mem.regions()[0].base;
+                if base as u64 + bytes.len() as u64 >= 1 << 32 {
+                    panic!("Binary does not fit in the address space.");
+                }
+                let end = base + bytes.len() as u32;
+                mcu.mem
+                    .program_le_bytes_at(base, &bytes)
+                    .expect("Could not program MCU.");
+                mcu.pc = base;
+                program.push((base, end));
+                heap_start = end;
+            }
+            mcu.program = ProgramInfo {
+                symbols: symbols.clone(),
+                heap_start,
+                brk: heap_start,
+                htif,
+            };
+            mcu
+        }
+    };
+
+    if let Some(entries) = CFG.tlb {
+        mcu.enable_tlb(entries);
+    }
+
+    let assertions = CFG.assertions.as_ref().map(|path| {
+        Assertions::load(path, &symbols)
+            .unwrap_or_else(|why| panic!("Could not load assertions: {}", why))
+    });
 
-    let mut mcu: Mcu = Mcu::new(CFG.mem_size);
-    mcu.mem
-        .program_from_file(&CFG.file)
-        .expect("Could not program MCU.");
+    // With a timing model, the caches add their stalls to it.
+    let mut caches = CFG.cache.clone().map(CacheHierarchy::new);
+    let mut timing = CFG.timing.clone().map(|latencies| match caches.take() {
+        Some(caches) => LatencyModel::new(latencies).with_caches(caches),
+        None => LatencyModel::new(latencies),
+    });
+    let mut pipeline = CFG.pipeline.map(Pipeline::new);
+    let mut predictors: Vec<BranchSimulator> = CFG
+        .predictors
+        .iter()
+        .map(|kind| BranchSimulator::new(*kind))
+        .collect();
+    if let Some(path) = &CFG.source {
+        let text = fs::read_to_string(path).expect("Could not read source.");
+        let (_, source_lines) = assemble_program_with_lines(&text).unwrap();
+        lines = LineTable::from_listing(&path.to_string_lossy(), &source_lines);
+    }
+    let mut coverage = (CFG.coverage.is_some() || CFG.annotate.is_some()).then(Coverage::new);
+    let mut isa_coverage = CFG.isa_coverage.as_ref().map(|_| IsaCoverage::new());
+    let mut profiler = (CFG.profile || CFG.folded.is_some()).then(|| Profiler::new(&symbols));
+    if let Some(events) = &CFG.counters {
+        for (i, event) in events.iter().enumerate().take(csr::HPM_COUNTERS) {
+            mcu.rf.csrs.set_event(i + 3, *event);
+        }
+    }
+
+    // The heap can grow to the end of the region the program ends in.
+    let heap_end = mcu.mem.region(heap_start).map_or(heap_start, |r| {
+        (r.base as u64 + r.size as u64).min(u32::MAX as u64) as u32
+    });
+    let mut host = Host::new()
+        .with_heap(heap_start, heap_end)
+        .with_brk(mcu.program.brk);
+    if let Some(dir) = &CFG.sandbox {
+        host = host.with_sandbox(dir);
+    }
+    if let Some((tohost, fromhost)) = htif {
+        host = host.with_htif(tohost, fromhost);
+    }
 
-    loop {
-        exec_one(&mut mcu.pc, &mut mcu.mem, &mut mcu.rf).unwrap();
+    let start = Instant::now();
+    let mut executed = 0;
+    let stop = loop {
+        if CFG.max_instructions.is_some_and(|max| executed >= max) {
+            break StopReason::InstructionLimit(executed);
+        }
+        if CFG
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            break StopReason::Timeout;
+        }
+        let pc = mcu.pc;
+        let step = match mcu.step_with_host(&mut host) {
+            Ok(step) => step,
+            Err(RiscvError::BreakpointError(pc)) if CFG.stop_on_ebreak => {
+                break StopReason::Breakpoint(pc)
+            }
+            Err(why) => break StopReason::Fault(pc, why),
+        };
+        executed += 1;
+        if let Some(trap) = step.trap.as_ref() {
+            info!(
+                "Trap: {} at 0x{:08x}, mtval 0x{:x}, entering {} mode",
+                trap.cause, step.pc, trap.tval, step.next_privilege
+            );
+        }
+        let misses = cache_misses(caches.as_ref().or_else(|| timing.as_ref()?.caches()));
+        let cycles = match timing.as_mut() {
+            Some(timing) => timing.retire(&step),
+            None => 1,
+        };
+        // The MCU counts one cycle per instruction on its own.
+        mcu.rf.csrs.add_cycles(cycles.saturating_sub(1));
+        if let Some(caches) = caches.as_mut() {
+            caches.retire(&step);
+        }
+        let misses = cache_misses(caches.as_ref().or_else(|| timing.as_ref()?.caches())) - misses;
+        mcu.rf.csrs.record(HpmEvent::CacheMisses, misses);
+        if let Some(pipeline) = pipeline.as_mut() {
+            pipeline.retire(&step);
+        }
+        for predictor in predictors.iter_mut() {
+            predictor.retire(&step);
+        }
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.retire(&step, cycles);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.retire(&step);
+        }
+        if let Some(isa_coverage) = isa_coverage.as_mut() {
+            isa_coverage.retire(&step);
+        }
+        if let Some(code) = host.exit_code() {
+            break StopReason::Exit(code);
+        }
         if Some(mcu.pc) == CFG.stop_pc {
-            info!("\nReached stop-PC.\n");
-            break;
+            break StopReason::StopPc;
         }
+        if CFG.stop_on_self_loop && step.next_pc == step.pc {
+            break StopReason::SelfLoop(step.pc);
+        }
+    };
+
+    match &stop {
+        StopReason::StopPc => info!("\nReached stop-PC.\n"),
+        StopReason::Exit(code) => info!("\nExited with status {}.\n", code),
+        StopReason::Breakpoint(pc) => info!("\nReached ebreak at 0x{:08x}.\n", pc),
+        StopReason::SelfLoop(pc) => info!("\nReached a jump to itself at 0x{:08x}.\n", pc),
+        StopReason::InstructionLimit(n) => eprintln!("Stopped after {} instructions.", n),
+        StopReason::Timeout => eprintln!("Timed out after {:.1}s.", start.elapsed().as_secs_f64()),
+        StopReason::Fault(pc, why) => eprintln!("Fault at 0x{:08x}: {:?}", pc, why),
     }
 
-    if let Some(mut assertions) = assertions {
-        assertions.assert_all(&mut mcu.mem, &mut mcu.rf);
+    if let Some(pipeline) = pipeline {
         println!();
+        print!("{}", pipeline.diagram());
+    }
 
-        for assert in assertions.register_assertions {
-            if assert.2 {
-                println!("{} == {}", REG_NAMES[assert.0 as usize], assert.1)
-            } else {
-                println!(
-                    "({} = {}) != {}",
-                    REG_NAMES[assert.0 as usize],
-                    mcu.rf.read(assert.0).unwrap(),
-                    assert.1
-                );
-            }
+    if let Some(timing) = &timing {
+        let stalls = timing.stalls();
+        println!();
+        println!("cycles:       {}", timing.cycles());
+        println!("instructions: {}", timing.instructions());
+        println!("CPI:          {:.3}", timing.cpi());
+        println!(
+            "stalls:       {} load-use, {} taken-branch, {} jump, {} memory",
+            stalls.load_use, stalls.taken_branch, stalls.jump, stalls.memory
+        );
+    }
+
+    if let Some(caches) = caches.as_ref().or_else(|| timing.as_ref()?.caches()) {
+        println!();
+        if let Some(icache) = caches.icache() {
+            print_cache_stats("icache", icache);
+            print_cache_stats("dcache", caches.dcache());
+        } else {
+            print_cache_stats("cache", caches.dcache());
         }
+        println!("memory stall cycles: {}", caches.stall_cycles());
+    }
+
+    if let Some(tlb) = mcu.tlb() {
+        let stats = tlb.stats();
         println!();
+        println!(
+            "tlb  {:8} hits  {:8} misses  {:6.2}% hit rate",
+            stats.hits,
+            stats.misses,
+            100.0 * stats.hit_rate()
+        );
+        println!("tlb flushes: {}", stats.flushes);
+    }
 
-        for assert in assertions.memory_assertions {
-            if assert.2 {
-                println!("*0x{:08x} == {}", assert.0, assert.1)
-            } else {
-                println!(
-                    "(*0x{:08x} = {}) != {}",
-                    assert.0,
-                    mcu.mem.fetch(assert.0).unwrap(),
-                    assert.1
-                );
+    for predictor in predictors.iter() {
+        print_branch_stats(predictor);
+    }
+
+    if CFG.counters.is_some() {
+        print_counters(&mcu.rf.csrs);
+    }
+
+    if let Some(profiler) = &profiler {
+        if CFG.profile {
+            println!();
+            print!("{}", profiler.report(10));
+        }
+        if let Some(path) = &CFG.folded {
+            fs::write(path, profiler.folded()).expect("Could not write folded stacks.");
+        }
+    }
+
+    if let Some(coverage) = &coverage {
+        if let Some(path) = &CFG.coverage {
+            fs::write(path, coverage.lcov(&lines, &mcu.mem)).expect("Could not write coverage.");
+        }
+        if let Some(path) = &CFG.annotate {
+            let text: String = program
+                .iter()
+                .filter(|(start, end)| coverage.hits().range(start..end).next().is_some())
+                .map(|(start, end)| coverage.annotate(&mcu.mem, *start, *end, Some(&lines)))
+                .collect::<Vec<_>>()
+                .join("\n");
+            fs::write(path, text).expect("Could not write annotated disassembly.");
+        }
+    }
+
+    if let Some(isa_coverage) = &isa_coverage {
+        println!();
+        print!("{}", isa_coverage.report());
+        if let Some(Some(path)) = &CFG.isa_coverage {
+            fs::write(path, isa_coverage.to_json()).expect("Could not write ISA coverage.");
+        }
+    }
+
+    if let Some(path) = &CFG.save_snapshot {
+        mcu.program.brk = host.brk();
+        mcu.save_snapshot(path, SnapshotFormat::from_path(path))
+            .expect("Could not save snapshot.");
+    }
+
+    let mut passed = None;
+    if let Some(mut assertions) = assertions {
+        assertions.assert_all(&mcu);
+        passed = Some(assertions.passed());
+        println!();
+
+        for a in assertions.assertions.iter() {
+            match a.passed {
+                true => println!("{} {}", a.name, a.expectation()),
+                false => println!(
+                    "{} {} failed: got {}",
+                    a.name,
+                    a.expectation(),
+                    a.actual.as_deref().unwrap_or_default()
+                ),
             }
         }
+
+        if let Some(path) = &CFG.report {
+            let suite = CFG.file.to_string_lossy();
+            let report = match path.extension().and_then(|e| e.to_str()) {
+                Some("xml") => assertions.junit(&suite),
+                _ => assertions.to_json(&suite),
+            };
+            fs::write(path, report).expect("Could not write report.");
+        }
+    }
+    process::exit(match (stop, passed) {
+        (StopReason::Fault(..), _) => EXIT_FAULT,
+        (StopReason::InstructionLimit(_) | StopReason::Timeout, _) => EXIT_TIMEOUT,
+        (_, Some(true)) => 0,
+        (_, Some(false)) => EXIT_ASSERTIONS_FAILED,
+        (StopReason::Exit(code), None) => code,
+        _ => 0,
+    });
+}
+
+fn print_cache_stats(name: &str, cache: &lib_rv32_mcu::cache::Cache) {
+    let stats = cache.stats();
+    for (kind, hm) in [
+        ("fetch", stats.fetch),
+        ("read", stats.read),
+        ("write", stats.write),
+        ("total", stats.total()),
+    ] {
+        if hm.hits + hm.misses > 0 {
+            println!(
+                "{} {:5}  {:8} hits  {:8} misses  {:6.2}% hit rate",
+                name,
+                kind,
+                hm.hits,
+                hm.misses,
+                100.0 * hm.hit_rate()
+            );
+        }
+    }
+    println!(
+        "{} writebacks: {}, memory writes: {}",
+        name, stats.writebacks, stats.memory_writes
+    );
+}
+
+/// Misses in every cache of a hierarchy so far.
+fn cache_misses(caches: Option<&CacheHierarchy>) -> u64 {
+    match caches {
+        Some(caches) => caches
+            .icache()
+            .into_iter()
+            .chain(Some(caches.dcache()))
+            .map(|c| c.stats().total().misses)
+            .sum(),
+        None => 0,
+    }
+}
+
+fn print_counters(csrs: &CsrFile) {
+    println!("\ncounters:");
+    println!("  {:14} {}", "cycle", csrs.cycles());
+    println!("  {:14} {}", "instret", csrs.instret());
+    for (n, event, count) in csrs.active_counters() {
+        println!("  {:14} {} ({})", format!("hpmcounter{}", n), count, event);
+    }
+}
+
+fn print_branch_stats(sim: &BranchSimulator) {
+    println!("\nbranch predictor {}:", sim.kind());
+    for (name, total, records) in [
+        ("branches", sim.branch_total(), sim.branches()),
+        ("jumps", sim.jump_total(), sim.jumps()),
+    ] {
+        println!(
+            "  {}: {} executed, {} taken, {} mispredicted, {:.2}% accuracy",
+            name,
+            total.executed,
+            total.taken,
+            total.mispredicted,
+            100.0 * total.accuracy()
+        );
+        for (pc, r) in records.iter() {
+            println!(
+                "    {:08x}  {:8} executed  {:8} taken  {:8} mispredicted  {:6.2}%",
+                pc,
+                r.executed,
+                r.taken,
+                r.mispredicted,
+                100.0 * r.accuracy()
+            );
+        }
     }
 }
 
@@ -217,16 +993,57 @@ fn asm() {
     let words = assemble_program_buf(&mut reader).unwrap();
 
     if let Some(path) = &CFG.output {
-        let mut output = fs::File::create(&path).unwrap();
+        let mut output = fs::File::create(path).unwrap();
         for w in words {
             output.write_all(&w.to_le_bytes()).unwrap();
         }
     }
 }
 
+/// Run a directory of tests and summarize the results.
+fn test(dir: &Path, jobs: usize) {
+    let tests = test_runner::discover(dir);
+    let results = test_runner::run_tests(&tests, jobs);
+
+    for result in results.iter() {
+        match &result.outcome {
+            TestOutcome::Passed => println!("{}... ok", result.name),
+            TestOutcome::Failed(_) => println!("{}... FAILED", result.name),
+            TestOutcome::Skipped(why) => println!("{}... skipped: {}", result.name, why),
+        }
+    }
+
+    let failed: Vec<&TestResult> = results
+        .iter()
+        .filter(|r| matches!(r.outcome, TestOutcome::Failed(_)))
+        .collect();
+    for result in failed.iter() {
+        if let TestOutcome::Failed(why) = &result.outcome {
+            println!(
+                "\n---- {} ----\n{}\n\n{}",
+                result.name,
+                why,
+                result.trace.trim_end()
+            );
+        }
+    }
+
+    let passed = results.iter().filter(|r| r.passed()).count();
+    println!(
+        "\n{} passed, {} failed, {} skipped",
+        passed,
+        failed.len(),
+        results.len() - passed - failed.len()
+    );
+    if !failed.is_empty() {
+        process::exit(EXIT_ASSERTIONS_FAILED);
+    }
+}
+
 fn main() {
-    match CFG.mode {
+    match &CFG.mode {
         Mode::Assembler => asm(),
         Mode::Emulator => emu(),
+        Mode::Test { dir, jobs } => test(dir, *jobs),
     }
 }
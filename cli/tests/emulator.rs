@@ -0,0 +1,158 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Command},
+};
+
+use lib_rv32_asm::assemble_program;
+
+/// A scratch directory, removed with its contents when dropped. Tests run
+/// in parallel, so each one uses its own.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("lib-rv32-cli-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Assemble `program` into a raw binary named `name` in `dir`.
+fn binary(dir: &ScratchDir, name: &str, program: &str) -> PathBuf {
+    let path = dir.join(&format!("{}.bin", name));
+    let bytes: Vec<u8> = assemble_program(program)
+        .unwrap()
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+    fs::write(&path, bytes).unwrap();
+    path
+}
+
+/// Run the emulator on `program` and return its exit status.
+fn run(name: &str, program: &str, args: &[&str]) -> i32 {
+    let dir = ScratchDir::new(name);
+    let path = binary(&dir, name, program);
+    Command::new(env!("CARGO_BIN_EXE_lib-rv32-cli"))
+        .arg(&path)
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+const EXIT_7: &str = "addi a0, zero, 7\naddi a7, zero, 93\necall";
+const SELF_LOOP: &str = "addi t0, zero, 1\nloop: jal zero, loop";
+
+#[test]
+fn test_exit_status_passed_through() {
+    assert_eq!(7, run("exit", EXIT_7, &[]));
+}
+
+#[test]
+fn test_stop_pc() {
+    assert_eq!(0, run("stop_pc", SELF_LOOP, &["-s", "4"]));
+}
+
+#[test]
+fn test_self_loop() {
+    // A jump to itself only stops emulation when asked to.
+    assert_eq!(0, run("self_loop", SELF_LOOP, &["--stop-on", "self-loop"]));
+    assert_eq!(
+        124,
+        run("self_loop_off", SELF_LOOP, &["--max-instructions", "100"])
+    );
+}
+
+#[test]
+fn test_ebreak() {
+    assert_eq!(0, run("ebreak", "ebreak", &["--stop-on", "ebreak"]));
+    assert_eq!(125, run("ebreak_off", "ebreak", &[]));
+}
+
+#[test]
+fn test_limits() {
+    assert_eq!(
+        124,
+        run(
+            "instruction_limit",
+            SELF_LOOP,
+            &["--max-instructions", "10"]
+        )
+    );
+    assert_eq!(124, run("timeout", SELF_LOOP, &["--timeout", "0"]));
+}
+
+#[test]
+fn test_fault() {
+    // The word after the program is zero, which is not an instruction.
+    assert_eq!(125, run("fault", "addi t0, zero, 1", &[]));
+}
+
+#[test]
+fn test_assertions() {
+    let dir = ScratchDir::new("assertions");
+    let pass = dir.join("pass.json");
+    fs::write(&pass, r#"{"registers": {"a0": "7"}}"#).unwrap();
+    let fail = dir.join("fail.json");
+    fs::write(&fail, r#"{"registers": {"a0": "8"}}"#).unwrap();
+
+    // With assertions, their result replaces the program's own status.
+    assert_eq!(
+        0,
+        run("assert_pass", EXIT_7, &["-a", pass.to_str().unwrap()])
+    );
+    assert_eq!(
+        1,
+        run("assert_fail", EXIT_7, &["-a", fail.to_str().unwrap()])
+    );
+}
+
+#[test]
+fn test_snapshot_resume() {
+    let dir = ScratchDir::new("snapshot");
+    let snapshot = dir.join("brk.snap");
+    let snapshot = snapshot.to_str().unwrap();
+
+    // Exits with the program break, which starts where the binary ends.
+    let program = "addi a7, zero, 214\n\
+                   addi a0, zero, 0\n\
+                   ecall\n\
+                   addi a7, zero, 93\n\
+                   ecall";
+    assert_eq!(20, run("brk", program, &[]));
+    assert_eq!(
+        124,
+        run(
+            "brk",
+            program,
+            &["--max-instructions", "1", "--save-snapshot", snapshot]
+        )
+    );
+    let status = Command::new(env!("CARGO_BIN_EXE_lib-rv32-cli"))
+        .args(["--load-snapshot", snapshot])
+        .status()
+        .unwrap();
+    assert_eq!(Some(20), status.code());
+
+    // Memory options only apply to a freshly loaded program.
+    let status = Command::new(env!("CARGO_BIN_EXE_lib-rv32-cli"))
+        .args(["--load-snapshot", snapshot, "--misaligned", "split"])
+        .stderr(process::Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(Some(1), status.code());
+}
@@ -7,7 +7,18 @@ pub const OPCODE_LOAD: u8 = 0b0000011;
 pub const OPCODE_STORE: u8 = 0b0100011;
 pub const OPCODE_ARITHMETIC_IMM: u8 = 0b0010011;
 pub const OPCODE_ARITHMETIC: u8 = 0b0110011;
+pub const OPCODE_MISC_MEM: u8 = 0b0001111;
+pub const OPCODE_AMO: u8 = 0b0101111;
+pub const OPCODE_SYSTEM: u8 = 0b1110011;
+pub const OPCODE_LOAD_FP: u8 = 0b0000111;
+pub const OPCODE_STORE_FP: u8 = 0b0100111;
+pub const OPCODE_MADD: u8 = 0b1000011;
+pub const OPCODE_MSUB: u8 = 0b1000111;
+pub const OPCODE_NMSUB: u8 = 0b1001011;
+pub const OPCODE_NMADD: u8 = 0b1001111;
+pub const OPCODE_OP_FP: u8 = 0b1010011;
 
+pub const FUNC3_JALR: u8 = 0b000;
 pub const FUNC3_BEQ: u8 = 0b000;
 pub const FUNC3_BNE: u8 = 0b001;
 pub const FUNC3_BLT: u8 = 0b100;
@@ -30,11 +41,86 @@ pub const FUNC3_XOR: u8 = 0b100;
 pub const FUNC3_SR: u8 = 0b101;
 pub const FUNC3_OR: u8 = 0b110;
 pub const FUNC3_AND: u8 = 0b111;
+pub const FUNC3_PRIV: u8 = 0b000;
+pub const FUNC3_CSRRW: u8 = 0b001;
+pub const FUNC3_CSRRS: u8 = 0b010;
+pub const FUNC3_CSRRC: u8 = 0b011;
+pub const FUNC3_CSRRWI: u8 = 0b101;
+pub const FUNC3_CSRRSI: u8 = 0b110;
+pub const FUNC3_CSRRCI: u8 = 0b111;
 
 pub const FUNC7_ADD: u8 = 0b0000000;
 pub const FUNC7_SUB: u8 = 0b0100000;
-pub const FUNC7_SRA: u8 = 0b0000000;
-pub const FUNC7_SRL: u8 = 0b0100000;
+pub const FUNC7_SRA: u8 = 0b0100000;
+pub const FUNC7_SRL: u8 = 0b0000000;
+pub const FUNC7_MULDIV: u8 = 0b0000001;
+
+/// Width of the RV32A atomics, and their operation in the top five bits.
+/// The two bits below those are the `aq` and `rl` ordering bits.
+pub const FUNC3_AMO_W: u8 = 0b010;
+pub const FUNC5_AMOADD: u8 = 0b00000;
+pub const FUNC5_AMOSWAP: u8 = 0b00001;
+pub const FUNC5_LR: u8 = 0b00010;
+pub const FUNC5_SC: u8 = 0b00011;
+pub const FUNC5_AMOXOR: u8 = 0b00100;
+pub const FUNC5_AMOOR: u8 = 0b01000;
+pub const FUNC5_AMOAND: u8 = 0b01100;
+pub const FUNC5_AMOMIN: u8 = 0b10000;
+pub const FUNC5_AMOMAX: u8 = 0b10100;
+pub const FUNC5_AMOMINU: u8 = 0b11000;
+pub const FUNC5_AMOMAXU: u8 = 0b11100;
+
+/// Widths of floating-point loads and stores.
+pub const FUNC3_FW: u8 = 0b010;
+pub const FUNC3_FD: u8 = 0b011;
+
+/// Operations of `OP-FP` instructions, in the top five bits of FUNC7. The
+/// two bits below those are the format: 0 for single and 1 for double
+/// precision, like the format field of the fused multiply-adds.
+pub const FUNC5_FADD: u8 = 0b00000;
+pub const FUNC5_FSUB: u8 = 0b00001;
+pub const FUNC5_FMUL: u8 = 0b00010;
+pub const FUNC5_FDIV: u8 = 0b00011;
+pub const FUNC5_FSQRT: u8 = 0b01011;
+pub const FUNC5_FSGNJ: u8 = 0b00100;
+pub const FUNC5_FMINMAX: u8 = 0b00101;
+pub const FUNC5_FCVT_FF: u8 = 0b01000;
+pub const FUNC5_FCMP: u8 = 0b10100;
+pub const FUNC5_FCVT_WF: u8 = 0b11000;
+pub const FUNC5_FCVT_FW: u8 = 0b11010;
+pub const FUNC5_FMV_XF: u8 = 0b11100;
+pub const FUNC5_FMV_FX: u8 = 0b11110;
+
+/// Rounding mode field value that selects the mode in `frm`.
+pub const RM_DYNAMIC: u8 = 0b111;
+
+pub const FFLAGS_NX: u32 = 1 << 0;
+pub const FFLAGS_UF: u32 = 1 << 1;
+pub const FFLAGS_OF: u32 = 1 << 2;
+pub const FFLAGS_DZ: u32 = 1 << 3;
+pub const FFLAGS_NV: u32 = 1 << 4;
+
+pub const CSR_FFLAGS: u16 = 0x001;
+pub const CSR_FRM: u16 = 0x002;
+pub const CSR_FCSR: u16 = 0x003;
+pub const CSR_SSTATUS: u16 = 0x100;
+pub const CSR_SEPC: u16 = 0x141;
+pub const CSR_SATP: u16 = 0x180;
+pub const CSR_MSTATUS: u16 = 0x300;
+pub const CSR_MEPC: u16 = 0x341;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+
+/// Top seven bits of `sfence.vma`.
+pub const FUNC7_SFENCE_VMA: u8 = 0b0001001;
 
 /// Array to match register numbers to their common names.
 pub static REG_NAMES: &[&str] = &[
@@ -42,3 +128,10 @@ pub static REG_NAMES: &[&str] = &[
     "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
     "t5", "t6",
 ];
+
+/// Array to match floating-point register numbers to their common names.
+pub static FP_REG_NAMES: &[&str] = &[
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
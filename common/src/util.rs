@@ -13,8 +13,6 @@ macro_rules! parse_int {
 
 #[cfg(test)]
 mod tests {
-    use crate::parse_int;
-
     #[test]
     fn test_parse_int() {
         assert_eq!(17, parse_int!(u32, "17").unwrap());
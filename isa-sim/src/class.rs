@@ -0,0 +1,125 @@
+use lib_rv32_common::{bit_slice, constants::*};
+
+use crate::{
+    decode_func3, decode_func5, decode_func7, decode_opcode, decode_rd, decode_rs1, decode_rs2,
+    float::FpInstruction,
+};
+
+/// Broad class of an instruction, by the functional unit it needs.
+/// Used by timing and pipeline models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstructionClass {
+    /// Integer arithmetic, logic, comparisons, `lui` and `auipc`.
+    Alu,
+    Load,
+    Store,
+    /// Conditional branches.
+    Branch,
+    /// `jal` and `jalr`.
+    Jump,
+    /// Floating-point arithmetic, conversions, comparisons and moves.
+    Float,
+    /// `fdiv` and `fsqrt`.
+    FloatDivide,
+    /// `fence` and `fence.i`.
+    Fence,
+    /// CSR accesses and other `SYSTEM` instructions.
+    System,
+    /// Anything that does not decode, including the M extension, which
+    /// is not implemented.
+    Unknown,
+}
+
+impl InstructionClass {
+    /// Classify a `u32` formatted instruction.
+    pub fn of(ir: u32) -> Self {
+        match decode_opcode!(ir) {
+            OPCODE_LUI | OPCODE_AUIPC | OPCODE_ARITHMETIC_IMM => InstructionClass::Alu,
+            OPCODE_ARITHMETIC => match decode_func7!(ir) {
+                FUNC7_MULDIV => InstructionClass::Unknown,
+                _ => InstructionClass::Alu,
+            },
+            OPCODE_LOAD | OPCODE_LOAD_FP => InstructionClass::Load,
+            OPCODE_STORE | OPCODE_STORE_FP => InstructionClass::Store,
+            OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD => InstructionClass::Float,
+            OPCODE_OP_FP => match decode_func5!(ir) {
+                FUNC5_FDIV | FUNC5_FSQRT => InstructionClass::FloatDivide,
+                _ => InstructionClass::Float,
+            },
+            // Atomics other than `sc.w` load their result from memory.
+            OPCODE_AMO => match decode_func5!(ir) {
+                FUNC5_SC => InstructionClass::Store,
+                _ => InstructionClass::Load,
+            },
+            OPCODE_BRANCH => InstructionClass::Branch,
+            OPCODE_JAL | OPCODE_JALR => InstructionClass::Jump,
+            OPCODE_MISC_MEM => InstructionClass::Fence,
+            OPCODE_SYSTEM => InstructionClass::System,
+            _ => InstructionClass::Unknown,
+        }
+    }
+}
+
+/// Registers an instruction reads and writes. `x0` is only reported by
+/// `fields`, since it carries no dependency. Only integer registers are
+/// reported, so a floating-point instruction may have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegisterUsage {
+    pub rs1: Option<u8>,
+    pub rs2: Option<u8>,
+    pub rd: Option<u8>,
+}
+
+impl RegisterUsage {
+    /// Decode the register operands of a `u32` formatted instruction.
+    pub fn of(ir: u32) -> Self {
+        let nonzero = |r: Option<u8>| r.filter(|r| *r != 0);
+        let fields = RegisterUsage::fields(ir);
+        RegisterUsage {
+            rs1: nonzero(fields.rs1),
+            rs2: nonzero(fields.rs2),
+            rd: nonzero(fields.rd),
+        }
+    }
+
+    /// Decode the register fields a `u32` formatted instruction uses,
+    /// including those naming `x0`.
+    pub fn fields(ir: u32) -> Self {
+        let rd = Some(decode_rd!(ir));
+        let rs1 = Some(decode_rs1!(ir));
+        let rs2 = Some(decode_rs2!(ir));
+
+        let (rs1, rs2, rd) = match decode_opcode!(ir) {
+            OPCODE_LUI | OPCODE_AUIPC | OPCODE_JAL => (None, None, rd),
+            OPCODE_JALR | OPCODE_LOAD | OPCODE_ARITHMETIC_IMM => (rs1, None, rd),
+            OPCODE_LOAD_FP | OPCODE_STORE_FP => (rs1, None, None),
+            OPCODE_OP_FP => match FpInstruction::decode(ir) {
+                Some(inst) => (
+                    rs1.filter(|_| inst.int_rs1()),
+                    None,
+                    rd.filter(|_| inst.int_rd()),
+                ),
+                None => (None, None, None),
+            },
+            OPCODE_ARITHMETIC => (rs1, rs2, rd),
+            OPCODE_BRANCH | OPCODE_STORE => (rs1, rs2, None),
+            OPCODE_AMO => match decode_func5!(ir) {
+                FUNC5_LR => (rs1, None, rd),
+                _ => (rs1, rs2, rd),
+            },
+            OPCODE_SYSTEM => match decode_func3!(ir) {
+                FUNC3_PRIV if ir >> 25 == FUNC7_SFENCE_VMA as u32 => (rs1, rs2, None),
+                FUNC3_PRIV => (None, None, None),
+                FUNC3_CSRRW | FUNC3_CSRRS | FUNC3_CSRRC => (rs1, None, rd),
+                _ => (None, None, rd),
+            },
+            _ => (None, None, None),
+        };
+        RegisterUsage { rs1, rs2, rd }
+    }
+
+    /// Whether the instruction reads register `reg`.
+    pub fn reads(&self, reg: u8) -> bool {
+        self.rs1 == Some(reg) || self.rs2 == Some(reg)
+    }
+}
@@ -59,6 +59,14 @@ macro_rules! decode_s_imm {
     };
 }
 
+/// Decode the CSR address from a `u32` formatted `SYSTEM` instruction.
+#[macro_export]
+macro_rules! decode_csr {
+    ($ir:expr) => {
+        bit_slice!($ir, 31, 20) as u16
+    };
+}
+
 /// Decode the FUNC3 field from a `u32` formatted instruction.
 #[macro_export]
 macro_rules! decode_func3 {
@@ -75,6 +83,33 @@ macro_rules! decode_func7 {
     };
 }
 
+/// Decode the operation of a `u32` formatted atomic or floating-point
+/// instruction, the top five bits of its FUNC7 field.
+#[macro_export]
+macro_rules! decode_func5 {
+    ($ir:expr) => {
+        bit_slice!($ir, 31, 27) as u8
+    };
+}
+
+/// Decode the floating-point format field of a `u32` formatted
+/// floating-point instruction, the low two bits of its FUNC7 field.
+#[macro_export]
+macro_rules! decode_fmt {
+    ($ir:expr) => {
+        bit_slice!($ir, 26, 25) as u8
+    };
+}
+
+/// Decode the third operand register field from a `u32` formatted fused
+/// multiply-add.
+#[macro_export]
+macro_rules! decode_rs3 {
+    ($ir:expr) => {
+        bit_slice!($ir, 31, 27) as u8
+    };
+}
+
 /// Decode the destination register field from a `u32` formatted instruction.
 #[macro_export]
 macro_rules! decode_rd {
@@ -0,0 +1,341 @@
+use lib_rv32_common::{
+    bit_concat, bit_extend, bit_slice, constants::*, sized_bit_extend, sized_bit_slice,
+};
+
+use crate::{
+    b_imm, decode_func3, decode_func5, decode_func7, decode_i_imm, decode_j_imm, decode_opcode,
+    decode_rd, decode_rs1, decode_rs2, decode_rs3, decode_s_imm, decode_u_imm,
+    float::{FpInstruction, FpOp, RoundingMode},
+};
+
+/// Every mnemonic the simulator executes, in encoding order.
+pub const MNEMONICS: [&str; 121] = [
+    "lui",
+    "auipc",
+    "jal",
+    "jalr",
+    "beq",
+    "bne",
+    "blt",
+    "bge",
+    "bltu",
+    "bgeu",
+    "lb",
+    "lh",
+    "lw",
+    "lbu",
+    "lhu",
+    "sb",
+    "sh",
+    "sw",
+    "addi",
+    "slti",
+    "sltiu",
+    "xori",
+    "ori",
+    "andi",
+    "slli",
+    "srli",
+    "srai",
+    "add",
+    "sub",
+    "sll",
+    "slt",
+    "sltu",
+    "xor",
+    "srl",
+    "sra",
+    "or",
+    "and",
+    "fence",
+    "fence.i",
+    "mul",
+    "mulh",
+    "mulhsu",
+    "mulhu",
+    "div",
+    "divu",
+    "rem",
+    "remu",
+    "lr.w",
+    "sc.w",
+    "amoswap.w",
+    "amoadd.w",
+    "amoxor.w",
+    "amoand.w",
+    "amoor.w",
+    "amomin.w",
+    "amomax.w",
+    "amominu.w",
+    "amomaxu.w",
+    "flw",
+    "fsw",
+    "fmadd.s",
+    "fmsub.s",
+    "fnmsub.s",
+    "fnmadd.s",
+    "fadd.s",
+    "fsub.s",
+    "fmul.s",
+    "fdiv.s",
+    "fsqrt.s",
+    "fsgnj.s",
+    "fsgnjn.s",
+    "fsgnjx.s",
+    "fmin.s",
+    "fmax.s",
+    "fcvt.w.s",
+    "fcvt.wu.s",
+    "fmv.x.w",
+    "feq.s",
+    "flt.s",
+    "fle.s",
+    "fclass.s",
+    "fcvt.s.w",
+    "fcvt.s.wu",
+    "fmv.w.x",
+    "fld",
+    "fsd",
+    "fmadd.d",
+    "fmsub.d",
+    "fnmsub.d",
+    "fnmadd.d",
+    "fadd.d",
+    "fsub.d",
+    "fmul.d",
+    "fdiv.d",
+    "fsqrt.d",
+    "fsgnj.d",
+    "fsgnjn.d",
+    "fsgnjx.d",
+    "fmin.d",
+    "fmax.d",
+    "fcvt.s.d",
+    "fcvt.d.s",
+    "feq.d",
+    "flt.d",
+    "fle.d",
+    "fclass.d",
+    "fcvt.w.d",
+    "fcvt.wu.d",
+    "fcvt.d.w",
+    "fcvt.d.wu",
+    "ecall",
+    "ebreak",
+    "sret",
+    "mret",
+    "sfence.vma",
+    "csrrw",
+    "csrrs",
+    "csrrc",
+    "csrrwi",
+    "csrrsi",
+    "csrrci",
+];
+
+/// Mnemonic of a `u32` formatted instruction, or `None` if it does not
+/// decode.
+pub fn mnemonic(ir: u32) -> Option<&'static str> {
+    let func3 = decode_func3!(ir);
+    let func7 = decode_func7!(ir);
+
+    Some(match decode_opcode!(ir) {
+        OPCODE_LUI => "lui",
+        OPCODE_AUIPC => "auipc",
+        OPCODE_JAL => "jal",
+        OPCODE_JALR => "jalr",
+        OPCODE_MISC_MEM => match func3 {
+            0 => "fence",
+            1 => "fence.i",
+            _ => return None,
+        },
+        OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB
+        | OPCODE_NMADD | OPCODE_OP_FP => return FpInstruction::decode(ir).map(|i| i.mnemonic()),
+        OPCODE_AMO if func3 == FUNC3_AMO_W => match decode_func5!(ir) {
+            FUNC5_LR => "lr.w",
+            FUNC5_SC => "sc.w",
+            FUNC5_AMOSWAP => "amoswap.w",
+            FUNC5_AMOADD => "amoadd.w",
+            FUNC5_AMOXOR => "amoxor.w",
+            FUNC5_AMOAND => "amoand.w",
+            FUNC5_AMOOR => "amoor.w",
+            FUNC5_AMOMIN => "amomin.w",
+            FUNC5_AMOMAX => "amomax.w",
+            FUNC5_AMOMINU => "amominu.w",
+            FUNC5_AMOMAXU => "amomaxu.w",
+            _ => return None,
+        },
+        OPCODE_SYSTEM => match func3 {
+            FUNC3_PRIV => match ir >> 7 {
+                0 => "ecall",
+                0x2000 => "ebreak",
+                0x204000 => "sret",
+                0x604000 => "mret",
+                _ if ir >> 25 == FUNC7_SFENCE_VMA as u32 && decode_rd!(ir) == 0 => "sfence.vma",
+                _ => return None,
+            },
+            FUNC3_CSRRW => "csrrw",
+            FUNC3_CSRRS => "csrrs",
+            FUNC3_CSRRC => "csrrc",
+            FUNC3_CSRRWI => "csrrwi",
+            FUNC3_CSRRSI => "csrrsi",
+            FUNC3_CSRRCI => "csrrci",
+            _ => return None,
+        },
+        OPCODE_BRANCH => match func3 {
+            FUNC3_BEQ => "beq",
+            FUNC3_BNE => "bne",
+            FUNC3_BLT => "blt",
+            FUNC3_BGE => "bge",
+            FUNC3_BLTU => "bltu",
+            FUNC3_BGEU => "bgeu",
+            _ => return None,
+        },
+        OPCODE_LOAD => match func3 {
+            FUNC3_LB => "lb",
+            FUNC3_LH => "lh",
+            FUNC3_LW => "lw",
+            FUNC3_LBU => "lbu",
+            FUNC3_LHU => "lhu",
+            _ => return None,
+        },
+        OPCODE_STORE => match func3 {
+            FUNC3_SB => "sb",
+            FUNC3_SH => "sh",
+            FUNC3_SW => "sw",
+            _ => return None,
+        },
+        OPCODE_ARITHMETIC_IMM => match func3 {
+            FUNC3_ADD_SUB => "addi",
+            FUNC3_SLT => "slti",
+            FUNC3_SLTU => "sltiu",
+            FUNC3_XOR => "xori",
+            FUNC3_OR => "ori",
+            FUNC3_AND => "andi",
+            FUNC3_SLL => "slli",
+            FUNC3_SR if func7 == FUNC7_SRA => "srai",
+            FUNC3_SR => "srli",
+            _ => return None,
+        },
+        OPCODE_ARITHMETIC => match (func7, func3) {
+            (FUNC7_ADD, FUNC3_ADD_SUB) => "add",
+            (FUNC7_SUB, FUNC3_ADD_SUB) => "sub",
+            (FUNC7_ADD, FUNC3_SLL) => "sll",
+            (FUNC7_ADD, FUNC3_SLT) => "slt",
+            (FUNC7_ADD, FUNC3_SLTU) => "sltu",
+            (FUNC7_ADD, FUNC3_XOR) => "xor",
+            (FUNC7_SRL, FUNC3_SR) => "srl",
+            (FUNC7_SRA, FUNC3_SR) => "sra",
+            (FUNC7_ADD, FUNC3_OR) => "or",
+            (FUNC7_ADD, FUNC3_AND) => "and",
+            (FUNC7_MULDIV, f3) => [
+                "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+            ][f3 as usize],
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Sign-extended immediate operand of a `u32` formatted instruction, if
+/// it has a signed one. Shift amounts and CSR immediates are unsigned
+/// and not reported. For `lui` and `auipc` this is the value added,
+/// i.e. with the low 12 bits clear.
+pub fn immediate(ir: u32) -> Option<i32> {
+    Some(match decode_opcode!(ir) {
+        OPCODE_LUI | OPCODE_AUIPC => decode_u_imm!(ir) as i32,
+        OPCODE_JAL => decode_j_imm!(ir) as i32,
+        OPCODE_BRANCH => b_imm!(ir) as i32,
+        OPCODE_STORE | OPCODE_STORE_FP => decode_s_imm!(ir) as i32,
+        OPCODE_ARITHMETIC_IMM if matches!(decode_func3!(ir), FUNC3_SLL | FUNC3_SR) => return None,
+        OPCODE_JALR | OPCODE_LOAD | OPCODE_LOAD_FP | OPCODE_ARITHMETIC_IMM => {
+            decode_i_imm!(ir) as i32
+        }
+        _ => return None,
+    })
+}
+
+/// Disassemble a `u32` formatted instruction into assembly text, e.g.
+/// `addi t0, t0, 1` or `lw a0, 8(sp)`. Branch and jump offsets are
+/// relative to the instruction. Anything that does not decode is shown
+/// as `unknown`. Floating-point instructions name their rounding mode
+/// unless it is dynamic, e.g. `fadd.s fa0, fa1, fa2, rtz`.
+pub fn disassemble(ir: u32) -> String {
+    let rd = REG_NAMES[decode_rd!(ir) as usize];
+    let rs1 = REG_NAMES[decode_rs1!(ir) as usize];
+    let rs2 = REG_NAMES[decode_rs2!(ir) as usize];
+    let name = match mnemonic(ir) {
+        Some(name) => name,
+        None => return "unknown".to_string(),
+    };
+    let imm = immediate(ir).unwrap_or(0);
+
+    if let Some(inst) = FpInstruction::decode(ir) {
+        return disassemble_float(ir, inst, imm);
+    }
+
+    match decode_opcode!(ir) {
+        OPCODE_LUI | OPCODE_AUIPC => format!("{} {}, 0x{:x}", name, rd, decode_u_imm!(ir) >> 12),
+        OPCODE_JAL => format!("{} {}, {}", name, rd, imm),
+        OPCODE_JALR | OPCODE_LOAD => format!("{} {}, {}({})", name, rd, imm, rs1),
+        OPCODE_STORE => format!("{} {}, {}({})", name, rs2, imm, rs1),
+        OPCODE_BRANCH => format!("{} {}, {}, {}", name, rs1, rs2, imm),
+        OPCODE_MISC_MEM => name.to_string(),
+        OPCODE_AMO => {
+            let ordering = ["", ".rl", ".aq", ".aqrl"][bit_slice!(ir, 26, 25) as usize];
+            match decode_func5!(ir) {
+                FUNC5_LR => format!("{}{} {}, ({})", name, ordering, rd, rs1),
+                _ => format!("{}{} {}, {}, ({})", name, ordering, rd, rs2, rs1),
+            }
+        }
+        OPCODE_SYSTEM if name == "sfence.vma" => format!("{} {}, {}", name, rs1, rs2),
+        OPCODE_SYSTEM if decode_func3!(ir) == FUNC3_PRIV => name.to_string(),
+        OPCODE_SYSTEM => {
+            let csr = bit_slice!(ir, 31, 20);
+            match decode_func3!(ir) & 0b100 {
+                0 => format!("{} {}, 0x{:03x}, {}", name, rd, csr, rs1),
+                _ => format!("{} {}, 0x{:03x}, {}", name, rd, csr, decode_rs1!(ir)),
+            }
+        }
+        OPCODE_ARITHMETIC_IMM => match immediate(ir) {
+            Some(imm) => format!("{} {}, {}, {}", name, rd, rs1, imm),
+            None => format!("{} {}, {}, {}", name, rd, rs1, decode_i_imm!(ir) & 0b11111),
+        },
+        _ => format!("{} {}, {}, {}", name, rd, rs1, rs2),
+    }
+}
+
+/// Disassemble a floating-point instruction, naming each register
+/// operand as an integer or floating-point register as appropriate.
+fn disassemble_float(ir: u32, inst: FpInstruction, imm: i32) -> String {
+    let name = inst.mnemonic();
+    let fp = |r: u8| FP_REG_NAMES[r as usize];
+    let (rd, rs1, rs2, rs3) = (
+        decode_rd!(ir),
+        decode_rs1!(ir),
+        decode_rs2!(ir),
+        decode_rs3!(ir),
+    );
+    let rd = match inst.int_rd() {
+        true => REG_NAMES[rd as usize],
+        false => fp(rd),
+    };
+    let rs1 = match inst.int_rs1() {
+        true => REG_NAMES[rs1 as usize],
+        false => fp(rs1),
+    };
+    let rm = match (inst.rounds(), decode_func3!(ir)) {
+        (true, rm) if rm != RM_DYNAMIC => format!(", {}", RoundingMode::NAMES[rm as usize]),
+        _ => String::new(),
+    };
+
+    match inst.op {
+        FpOp::Load => format!("{} {}, {}({})", name, rd, imm, rs1),
+        FpOp::Store => format!("{} {}, {}({})", name, fp(rs2), imm, rs1),
+        _ => match inst.sources() {
+            1 => format!("{} {}, {}{}", name, rd, rs1, rm),
+            2 => format!("{} {}, {}, {}{}", name, rd, rs1, fp(rs2), rm),
+            _ => format!("{} {}, {}, {}, {}{}", name, rd, rs1, fp(rs2), fp(rs3), rm),
+        },
+    }
+}
@@ -3,15 +3,27 @@
 ///
 /// Instruction format errors contain `(instruction: u32, bad_field: u8)`.
 ///
-/// Memory errors contain `(address: u32)`.
+/// Memory errors contain `(address: u32)`. Accessing unmapped memory is
+/// `MemoryOutOfBoundsError`, and accessing memory in a way its region does
+/// not permit (e.g. storing to read-only memory, or an access denied by the
+/// PMP) is `MemoryPermissionError`. A virtual address without a valid
+/// mapping that permits the access is a `PageFaultError`.
 ///
-/// Register file errors contain `(reg_num: u8)`.
+/// Register file errors contain `(reg_num: u8)` or `(csr_num: u16)`.
+///
+/// `ecall` and `ebreak` contain `(pc: u32)`; the instruction is not
+/// retired, so the caller can service it and move on.
 #[derive(Debug, PartialEq)]
 pub enum RiscvError {
     InvalidOpcodeError(u32, u8),
     InvalidFunc3Error(u32, u8),
     InvalidFunc7Error(u32, u8),
     RegisterOutOfRangeError(u8),
+    InvalidCsrError(u16),
     MemoryOutOfBoundsError(u32),
     MemoryAlignmentError(u32),
+    MemoryPermissionError(u32),
+    PageFaultError(u32),
+    EnvironmentCallError(u32),
+    BreakpointError(u32),
 }
@@ -3,11 +3,21 @@ use log::info;
 use lib_rv32_common::constants::*;
 
 use crate::{
-    b_imm, decode::*, decode_func3, decode_func7, decode_i_imm, decode_j_imm, decode_opcode,
-    decode_rd, decode_rs1, decode_rs2, decode_s_imm, decode_u_imm, traits::Memory,
-    traits::RegisterFile, RiscvError,
+    b_imm, decode::*, decode_csr, decode_func3, decode_func5, decode_func7, decode_i_imm,
+    decode_j_imm, decode_opcode, decode_rd, decode_rs1, decode_rs2, decode_s_imm, decode_u_imm,
+    float::exec_float, traits::Memory, traits::RegisterFile, RiscvError,
 };
 
+/// Jumps and taken branches to an address that is not word-aligned raise
+/// the misaligned exception themselves, leaving `pc` and `rd` unchanged,
+/// rather than faulting when the target is fetched.
+fn check_target(target: u32) -> Result<(), RiscvError> {
+    match target & 0b11 {
+        0 => Ok(()),
+        _ => Err(RiscvError::MemoryAlignmentError(target)),
+    }
+}
+
 /// Decode and execute instruction. This will use the program counter to
 /// fetch an instruction from memory, decode/evaluate it, and commit the
 /// results to `pc`, `mem`, and `rf`.
@@ -45,16 +55,12 @@ where
     M: Memory,
     R: RegisterFile,
 {
-    let ir = mem.fetch(*pc);
-    if let Err(why) = ir {
-        return Err(why);
-    }
-    let ir = ir.unwrap();
-    let opcode = decode_opcode!(ir) as u8;
+    let ir = mem.fetch(*pc)?;
+    let opcode = decode_opcode!(ir);
 
     info!("[{:04x}]  {:08x}", pc, ir);
 
-    return match opcode {
+    match opcode {
         OPCODE_LUI => {
             let rd = decode_rd!(ir);
             let imm = decode_u_imm!(ir);
@@ -64,10 +70,8 @@ where
                 "lui", REG_NAMES[rd as usize], imm, imm as i32
             );
 
-            if let Err(why) = rf.write(rd, imm) {
-                return Err(why);
-            }
-            *pc += 4;
+            rf.write(rd, imm)?;
+            *pc = pc.wrapping_add(4);
 
             Ok(())
         }
@@ -83,10 +87,8 @@ where
                 (imm >> 12)
             );
 
-            if let Err(why) = rf.write(rd, *pc + imm) {
-                return Err(why);
-            }
-            *pc += 4;
+            rf.write(rd, pc.wrapping_add(imm))?;
+            *pc = pc.wrapping_add(4);
 
             Ok(())
         }
@@ -100,10 +102,10 @@ where
                 "jal", REG_NAMES[rd as usize], imm, imm as i32
             );
 
-            if let Err(why) = rf.write(rd, *pc + 4) {
-                return Err(why);
-            }
-            *pc = pc.wrapping_add(imm);
+            let target = pc.wrapping_add(imm);
+            check_target(target)?;
+            rf.write(rd, pc.wrapping_add(4))?;
+            *pc = target;
             info!("pc <- 0x{:x}", pc);
 
             Ok(())
@@ -119,15 +121,12 @@ where
                 "jalr", REG_NAMES[rd as usize], imm as i32, REG_NAMES[rs1 as usize]
             );
 
-            let rs1_data = match rf.read(rs1) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
-            if let Err(why) = rf.write(rd, *pc + 4) {
-                return Err(why);
-            }
-
-            *pc = rs1_data.wrapping_add(imm);
+            let rs1_data = rf.read(rs1)?;
+            // The target's least-significant bit is always cleared.
+            let target = rs1_data.wrapping_add(imm) & !0b1;
+            check_target(target)?;
+            rf.write(rd, pc.wrapping_add(4))?;
+            *pc = target;
             info!("pc <- 0x{:x}", pc);
 
             Ok(())
@@ -135,23 +134,18 @@ where
 
         OPCODE_BRANCH => {
             let rs1 = decode_rs1!(ir);
-            let rs1_data = match rf.read(rs1) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
+            let rs1_data = rf.read(rs1)?;
             let rs2 = decode_rs2!(ir);
-            let rs2_data = match rf.read(rs2) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
+            let rs2_data = rf.read(rs2)?;
             let func3 = decode_func3!(ir);
             let taken = match func3 {
                 FUNC3_BEQ => rs1_data == rs2_data,
                 FUNC3_BNE => rs1_data != rs2_data,
-                FUNC3_BLT => rs1_data < rs2_data,
-                FUNC3_BGE => rs1_data >= rs2_data,
+                // sign-extension
+                FUNC3_BLT => (rs1_data as i32) < (rs2_data as i32),
+                FUNC3_BGE => (rs1_data as i32) >= (rs2_data as i32),
                 FUNC3_BLTU => rs1_data < rs2_data,
-                FUNC3_BGEU => rs1_data > rs2_data,
+                FUNC3_BGEU => rs1_data >= rs2_data,
                 _ => return Err(RiscvError::InvalidFunc3Error(ir, func3)),
             };
             let imm = b_imm!(ir);
@@ -178,10 +172,12 @@ where
             }
 
             if taken {
-                *pc = pc.wrapping_add(imm);
+                let target = pc.wrapping_add(imm);
+                check_target(target)?;
+                *pc = target;
                 info!("pc <- 0x{:x}", pc);
             } else {
-                *pc += 4;
+                *pc = pc.wrapping_add(4);
             }
 
             Ok(())
@@ -189,10 +185,7 @@ where
 
         OPCODE_LOAD => {
             let rs1 = decode_rs1!(ir);
-            let base = match rf.read(rs1) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
+            let base = rf.read(rs1)?;
             let imm = decode_i_imm!(ir);
             let addr = base.wrapping_add(imm);
             let rd = decode_rd!(ir);
@@ -213,31 +206,16 @@ where
                 REG_NAMES[rs1 as usize]
             );
 
-            if let Err(why) = rf.write(
-                rd,
-                match {
-                    match func3 {
-                        FUNC3_LB => match mem.read_byte(addr) {
-                            Ok(d) => Ok((d as i8) as u32), // sign-extension
-                            Err(why) => return Err(why),
-                        },
-                        FUNC3_LH => match mem.read_half_word(addr) {
-                            Ok(d) => Ok((d as i16) as u32), // sign-extension
-                            Err(why) => return Err(why),
-                        },
-                        FUNC3_LBU => mem.read_byte(addr),
-                        FUNC3_LHU => mem.read_half_word(addr),
-                        FUNC3_LW => mem.read_word(addr),
-                        _ => return Err(RiscvError::InvalidFunc3Error(ir, func3)),
-                    }
-                } {
-                    Ok(d) => d,
-                    Err(why) => return Err(why),
-                },
-            ) {
-                return Err(why);
-            }
-            *pc += 4;
+            let data = match func3 {
+                FUNC3_LB => (mem.read_byte(addr)? as i8) as u32, // sign-extension
+                FUNC3_LH => (mem.read_half_word(addr)? as i16) as u32, // sign-extension
+                FUNC3_LBU => mem.read_byte(addr)?,
+                FUNC3_LHU => mem.read_half_word(addr)?,
+                FUNC3_LW => mem.read_word(addr)?,
+                _ => return Err(RiscvError::InvalidFunc3Error(ir, func3)),
+            };
+            rf.write(rd, data)?;
+            *pc = pc.wrapping_add(4);
 
             Ok(())
         }
@@ -261,24 +239,15 @@ where
                 REG_NAMES[rs1 as usize]
             );
 
-            let addr = match rf.read(rs1) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            }
-            .wrapping_add(imm);
-            let data = match rf.read(rs2) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
-            if let Err(why) = match func3 {
-                FUNC3_SB => mem.write_byte(addr, data),
-                FUNC3_SH => mem.write_half_word(addr, data),
-                FUNC3_SW => mem.write_word(addr, data),
-                _ => Err(RiscvError::InvalidFunc3Error(ir, decode_func3!(ir))),
-            } {
-                return Err(why);
+            let addr = rf.read(rs1)?.wrapping_add(imm);
+            let data = rf.read(rs2)?;
+            match func3 {
+                FUNC3_SB => mem.write_byte(addr, data)?,
+                FUNC3_SH => mem.write_half_word(addr, data)?,
+                FUNC3_SW => mem.write_word(addr, data)?,
+                _ => return Err(RiscvError::InvalidFunc3Error(ir, func3)),
             }
-            *pc += 4;
+            *pc = pc.wrapping_add(4);
 
             Ok(())
         }
@@ -286,15 +255,9 @@ where
         OPCODE_ARITHMETIC | OPCODE_ARITHMETIC_IMM => {
             let rd = decode_rd!(ir);
             let rs1 = decode_rs1!(ir);
-            let lhs = match rf.read(rs1) {
-                Ok(d) => d,
-                Err(why) => return Err(why),
-            };
+            let lhs = rf.read(rs1)?;
             let rhs = match opcode {
-                OPCODE_ARITHMETIC => match rf.read(decode_rs2!(ir)) {
-                    Ok(d) => d,
-                    Err(why) => return Err(why),
-                },
+                OPCODE_ARITHMETIC => rf.read(decode_rs2!(ir))?,
                 OPCODE_ARITHMETIC_IMM => decode_i_imm!(ir),
                 _ => return Err(RiscvError::InvalidOpcodeError(ir, decode_opcode!(ir))),
             };
@@ -303,10 +266,10 @@ where
                 // This func3 is complicated, it depends on whether we're
                 // using immediates or not.
                 FUNC3_ADD_SUB => match opcode {
-                    OPCODE_ARITHMETIC => match decode_func3!(ir) {
+                    OPCODE_ARITHMETIC => match decode_func7!(ir) {
                         FUNC7_SUB => {
                             ir_name = "sub";
-                            |l: u32, r: u32| l.wrapping_add(r)
+                            |l: u32, r: u32| l.wrapping_sub(r)
                         }
                         FUNC7_ADD => {
                             ir_name = "add";
@@ -322,7 +285,7 @@ where
                 },
                 FUNC3_SLL => {
                     ir_name = "sll";
-                    |l: u32, r: u32| l << r
+                    |l: u32, r: u32| l << (r & 0b11111)
                 }
                 FUNC3_SLT => {
                     ir_name = "slt";
@@ -340,13 +303,13 @@ where
                 FUNC3_SR => match decode_func7!(ir) {
                     FUNC7_SRA => {
                         ir_name = "sra";
-                        |l: u32, r: u32| ((l as i32) >> r) as u32 // sign-extension
+                        |l: u32, r: u32| ((l as i32) >> (r & 0b11111)) as u32 // sign-extension
                     }
                     FUNC7_SRL => {
                         ir_name = "srl";
-                        |l: u32, r: u32| l >> r
+                        |l: u32, r: u32| l >> (r & 0b11111)
                     }
-                    _ => return Err(RiscvError::InvalidFunc3Error(ir, decode_func3!(ir))),
+                    _ => return Err(RiscvError::InvalidFunc7Error(ir, decode_func7!(ir))),
                 },
                 FUNC3_OR => {
                     ir_name = "or";
@@ -376,13 +339,211 @@ where
                 }
             );
 
-            if let Err(why) = rf.write(decode_rd!(ir), bi_operator(lhs, rhs)) {
-                return Err(why);
+            rf.write(decode_rd!(ir), bi_operator(lhs, rhs))?;
+            *pc = pc.wrapping_add(4);
+
+            Ok(())
+        }
+        // Atomics take effect within a single step, so the `aq` and `rl`
+        // ordering bits need no handling.
+        OPCODE_AMO => {
+            let rd = decode_rd!(ir);
+            let rs1 = decode_rs1!(ir);
+            let rs2 = decode_rs2!(ir);
+            let func3 = decode_func3!(ir);
+            let func5 = decode_func5!(ir);
+            if func3 != FUNC3_AMO_W {
+                return Err(RiscvError::InvalidFunc3Error(ir, func3));
+            }
+            let op: fn(u32, u32) -> u32 = match func5 {
+                FUNC5_LR | FUNC5_SC => |old, _| old,
+                FUNC5_AMOSWAP => |_, src| src,
+                FUNC5_AMOADD => |old, src| old.wrapping_add(src),
+                FUNC5_AMOXOR => |old, src| old ^ src,
+                FUNC5_AMOAND => |old, src| old & src,
+                FUNC5_AMOOR => |old, src| old | src,
+                FUNC5_AMOMIN => |old, src| (old as i32).min(src as i32) as u32,
+                FUNC5_AMOMAX => |old, src| (old as i32).max(src as i32) as u32,
+                FUNC5_AMOMINU => |old, src| old.min(src),
+                FUNC5_AMOMAXU => |old, src| old.max(src),
+                _ => return Err(RiscvError::InvalidFunc7Error(ir, decode_func7!(ir))),
+            };
+
+            match func5 {
+                FUNC5_LR => info!(
+                    "{:6} {}, ({})",
+                    "lr.w", REG_NAMES[rd as usize], REG_NAMES[rs1 as usize]
+                ),
+                _ => info!(
+                    "{:6} {}, {}, ({})",
+                    crate::mnemonic(ir).unwrap_or(""),
+                    REG_NAMES[rd as usize],
+                    REG_NAMES[rs2 as usize],
+                    REG_NAMES[rs1 as usize]
+                ),
+            }
+
+            // Unlike other accesses, atomics are never split.
+            let addr = rf.read(rs1)?;
+            if addr % 4 != 0 {
+                return Err(RiscvError::MemoryAlignmentError(addr));
+            }
+            let src = rf.read(rs2)?;
+            let data = match func5 {
+                FUNC5_LR => mem.load_reserved(addr)?,
+                FUNC5_SC => match mem.store_conditional(addr, src)? {
+                    true => 0,
+                    false => 1,
+                },
+                _ => {
+                    let old = mem.read_word(addr)?;
+                    mem.write_word(addr, op(old, src))?;
+                    old
+                }
+            };
+            rf.write(rd, data)?;
+            *pc = pc.wrapping_add(4);
+
+            Ok(())
+        }
+
+        OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB
+        | OPCODE_NMADD | OPCODE_OP_FP => exec_float(ir, pc, mem, rf),
+
+        // There is a single hart and no caches, so memory is always
+        // coherent and `fence`/`fence.i` have nothing to do.
+        OPCODE_MISC_MEM => {
+            info!("{:6}", "fence");
+            *pc = pc.wrapping_add(4);
+
+            Ok(())
+        }
+
+        OPCODE_SYSTEM => {
+            let rd = decode_rd!(ir);
+            let rs1 = decode_rs1!(ir);
+            let csr = decode_csr!(ir);
+            let func3 = decode_func3!(ir);
+
+            // There is no trap handling here: `ecall` and `ebreak` are
+            // left for the caller, with the pc still pointing at them.
+            // `mret` and `sret` only need the status and exception pc CSRs
+            // from the register file, which also decides who may use them.
+            if func3 == FUNC3_PRIV {
+                return match (csr, rs1, rd) {
+                    (0, 0, 0) => {
+                        info!("{:6}", "ecall");
+                        Err(RiscvError::EnvironmentCallError(*pc))
+                    }
+                    (1, 0, 0) => {
+                        info!("{:6}", "ebreak");
+                        Err(RiscvError::BreakpointError(*pc))
+                    }
+                    (0x102, 0, 0) => {
+                        info!("{:6}", "sret");
+                        // Restore the supervisor interrupt enable, return
+                        // to the mode in SPP (which becomes user mode) and
+                        // resume at `sepc`. In machine mode this goes
+                        // through `mstatus`, so that MPRV is cleared in the
+                        // same write.
+                        let (csr, status) = match rf.read_csr(CSR_MSTATUS) {
+                            Ok(mstatus) => (CSR_MSTATUS, mstatus),
+                            Err(_) => (CSR_SSTATUS, rf.read_csr(CSR_SSTATUS)?),
+                        };
+                        let sie = match status & MSTATUS_SPIE {
+                            0 => 0,
+                            _ => MSTATUS_SIE,
+                        };
+                        rf.write_csr(
+                            csr,
+                            (status & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV))
+                                | sie
+                                | MSTATUS_SPIE,
+                        )?;
+                        *pc = rf.read_csr(CSR_SEPC)?;
+                        Ok(())
+                    }
+                    (csr, _, 0) if csr >> 5 == FUNC7_SFENCE_VMA as u16 => {
+                        info!(
+                            "{:6} {}, {}",
+                            "sfence.vma",
+                            REG_NAMES[rs1 as usize],
+                            REG_NAMES[(csr & 0x1f) as usize]
+                        );
+                        // The TLB is the caller's; only check that the
+                        // mode may manage address translation.
+                        rf.read_csr(CSR_SATP)?;
+                        *pc = pc.wrapping_add(4);
+                        Ok(())
+                    }
+                    (0x302, 0, 0) => {
+                        info!("{:6}", "mret");
+                        // Re-enable interrupts if they were enabled when
+                        // the trap was taken, return to the mode in MPP
+                        // (which becomes user mode) and resume at `mepc`.
+                        let mstatus = rf.read_csr(CSR_MSTATUS)?;
+                        let mie = match mstatus & MSTATUS_MPIE {
+                            0 => 0,
+                            _ => MSTATUS_MIE,
+                        };
+                        let mprv = match mstatus & MSTATUS_MPP {
+                            MSTATUS_MPP => mstatus & MSTATUS_MPRV,
+                            _ => 0,
+                        };
+                        rf.write_csr(
+                            CSR_MSTATUS,
+                            (mstatus & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV))
+                                | mie
+                                | MSTATUS_MPIE
+                                | mprv,
+                        )?;
+                        *pc = rf.read_csr(CSR_MEPC)?;
+                        Ok(())
+                    }
+                    _ => Err(RiscvError::InvalidFunc3Error(ir, func3)),
+                };
+            }
+
+            // The immediate forms use the rs1 field as a 5-bit immediate.
+            let (name, operand) = match func3 {
+                FUNC3_CSRRW => ("csrrw", rf.read(rs1)?),
+                FUNC3_CSRRS => ("csrrs", rf.read(rs1)?),
+                FUNC3_CSRRC => ("csrrc", rf.read(rs1)?),
+                FUNC3_CSRRWI => ("csrrwi", rs1 as u32),
+                FUNC3_CSRRSI => ("csrrsi", rs1 as u32),
+                FUNC3_CSRRCI => ("csrrci", rs1 as u32),
+                _ => return Err(RiscvError::InvalidFunc3Error(ir, func3)),
+            };
+
+            info!(
+                "{:6} {}, 0x{:03x}, {}",
+                name,
+                REG_NAMES[rd as usize],
+                csr,
+                match func3 {
+                    FUNC3_CSRRWI | FUNC3_CSRRSI | FUNC3_CSRRCI => rs1.to_string(),
+                    _ => String::from(REG_NAMES[rs1 as usize]),
+                }
+            );
+
+            // csrrw does not read the CSR when rd is x0, and csrrs/csrrc
+            // do not write it when the operand is x0 (or zero).
+            let old = match (func3, rd) {
+                (FUNC3_CSRRW | FUNC3_CSRRWI, 0) => 0,
+                _ => rf.read_csr(csr)?,
+            };
+            match func3 {
+                FUNC3_CSRRW | FUNC3_CSRRWI => rf.write_csr(csr, operand)?,
+                _ if rs1 == 0 => (),
+                FUNC3_CSRRS | FUNC3_CSRRSI => rf.write_csr(csr, old | operand)?,
+                _ => rf.write_csr(csr, old & !operand)?,
             }
-            *pc += 4;
+            rf.write(rd, old)?;
+            *pc = pc.wrapping_add(4);
 
             Ok(())
         }
+
         _ => Err(RiscvError::InvalidOpcodeError(ir, decode_opcode!(ir))),
-    };
+    }
 }
@@ -0,0 +1,840 @@
+use std::cmp::Ordering;
+
+use log::info;
+
+use lib_rv32_common::{
+    bit_concat, bit_extend, bit_slice, constants::*, sized_bit_extend, sized_bit_slice,
+};
+
+use crate::{
+    decode_fmt, decode_func3, decode_func5, decode_func7, decode_i_imm, decode_opcode, decode_rd,
+    decode_rs1, decode_rs2, decode_rs3, decode_s_imm, traits::Memory, traits::RegisterFile,
+    RiscvError,
+};
+
+const CANONICAL_NAN_S: u32 = 0x7fc0_0000;
+const CANONICAL_NAN_D: u64 = 0x7ff8_0000_0000_0000;
+
+/// Operation of a floating-point instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FpOp {
+    Load,
+    Store,
+    Madd,
+    Msub,
+    Nmsub,
+    Nmadd,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sqrt,
+    Sgnj,
+    Sgnjn,
+    Sgnjx,
+    Min,
+    Max,
+    /// `fcvt.s.d` or `fcvt.d.s`.
+    Convert,
+    /// `fcvt.w.s` or `fcvt.w.d`, and the unsigned `fcvt.wu.*`.
+    ToInt {
+        unsigned: bool,
+    },
+    /// `fcvt.s.w` or `fcvt.d.w`, and the unsigned `fcvt.*.wu`.
+    FromInt {
+        unsigned: bool,
+    },
+    /// `fmv.x.w`
+    MoveToInt,
+    /// `fmv.w.x`
+    MoveFromInt,
+    Eq,
+    Lt,
+    Le,
+    Class,
+}
+
+/// A decoded floating-point instruction. `double` is its format, which
+/// for conversions between formats is the destination's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FpInstruction {
+    pub op: FpOp,
+    pub double: bool,
+}
+
+impl FpInstruction {
+    /// Decode a `u32` formatted floating-point instruction, or return
+    /// `None` if it is not one. Reserved rounding modes do not decode.
+    pub fn decode(ir: u32) -> Option<Self> {
+        let func3 = decode_func3!(ir);
+        let rs2 = decode_rs2!(ir);
+        let double = match decode_opcode!(ir) {
+            OPCODE_LOAD_FP | OPCODE_STORE_FP => match func3 {
+                FUNC3_FW => false,
+                FUNC3_FD => true,
+                _ => return None,
+            },
+            _ => match decode_fmt!(ir) {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+        };
+        let op = match decode_opcode!(ir) {
+            OPCODE_LOAD_FP => FpOp::Load,
+            OPCODE_STORE_FP => FpOp::Store,
+            OPCODE_MADD => FpOp::Madd,
+            OPCODE_MSUB => FpOp::Msub,
+            OPCODE_NMSUB => FpOp::Nmsub,
+            OPCODE_NMADD => FpOp::Nmadd,
+            OPCODE_OP_FP => match (decode_func5!(ir), func3, rs2) {
+                (FUNC5_FADD, _, _) => FpOp::Add,
+                (FUNC5_FSUB, _, _) => FpOp::Sub,
+                (FUNC5_FMUL, _, _) => FpOp::Mul,
+                (FUNC5_FDIV, _, _) => FpOp::Div,
+                (FUNC5_FSQRT, _, 0) => FpOp::Sqrt,
+                (FUNC5_FSGNJ, 0, _) => FpOp::Sgnj,
+                (FUNC5_FSGNJ, 1, _) => FpOp::Sgnjn,
+                (FUNC5_FSGNJ, 2, _) => FpOp::Sgnjx,
+                (FUNC5_FMINMAX, 0, _) => FpOp::Min,
+                (FUNC5_FMINMAX, 1, _) => FpOp::Max,
+                // The source is the other format.
+                (FUNC5_FCVT_FF, _, 0) if double => FpOp::Convert,
+                (FUNC5_FCVT_FF, _, 1) if !double => FpOp::Convert,
+                (FUNC5_FCMP, 2, _) => FpOp::Eq,
+                (FUNC5_FCMP, 1, _) => FpOp::Lt,
+                (FUNC5_FCMP, 0, _) => FpOp::Le,
+                (FUNC5_FCVT_WF, _, 0) => FpOp::ToInt { unsigned: false },
+                (FUNC5_FCVT_WF, _, 1) => FpOp::ToInt { unsigned: true },
+                (FUNC5_FCVT_FW, _, 0) => FpOp::FromInt { unsigned: false },
+                (FUNC5_FCVT_FW, _, 1) => FpOp::FromInt { unsigned: true },
+                // Moves of doubles to and from integer registers are RV64
+                // only.
+                (FUNC5_FMV_XF, 0, 0) if !double => FpOp::MoveToInt,
+                (FUNC5_FMV_XF, 1, 0) => FpOp::Class,
+                (FUNC5_FMV_FX, 0, 0) if !double => FpOp::MoveFromInt,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let inst = FpInstruction { op, double };
+        if inst.has_rounding_mode()
+            && RoundingMode::from_bits(func3).is_none()
+            && func3 != RM_DYNAMIC
+        {
+            return None;
+        }
+        Some(inst)
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        let (single, double) = match self.op {
+            FpOp::Load => ("flw", "fld"),
+            FpOp::Store => ("fsw", "fsd"),
+            FpOp::Madd => ("fmadd.s", "fmadd.d"),
+            FpOp::Msub => ("fmsub.s", "fmsub.d"),
+            FpOp::Nmsub => ("fnmsub.s", "fnmsub.d"),
+            FpOp::Nmadd => ("fnmadd.s", "fnmadd.d"),
+            FpOp::Add => ("fadd.s", "fadd.d"),
+            FpOp::Sub => ("fsub.s", "fsub.d"),
+            FpOp::Mul => ("fmul.s", "fmul.d"),
+            FpOp::Div => ("fdiv.s", "fdiv.d"),
+            FpOp::Sqrt => ("fsqrt.s", "fsqrt.d"),
+            FpOp::Sgnj => ("fsgnj.s", "fsgnj.d"),
+            FpOp::Sgnjn => ("fsgnjn.s", "fsgnjn.d"),
+            FpOp::Sgnjx => ("fsgnjx.s", "fsgnjx.d"),
+            FpOp::Min => ("fmin.s", "fmin.d"),
+            FpOp::Max => ("fmax.s", "fmax.d"),
+            FpOp::Convert => ("fcvt.s.d", "fcvt.d.s"),
+            FpOp::ToInt { unsigned: false } => ("fcvt.w.s", "fcvt.w.d"),
+            FpOp::ToInt { unsigned: true } => ("fcvt.wu.s", "fcvt.wu.d"),
+            FpOp::FromInt { unsigned: false } => ("fcvt.s.w", "fcvt.d.w"),
+            FpOp::FromInt { unsigned: true } => ("fcvt.s.wu", "fcvt.d.wu"),
+            FpOp::MoveToInt => ("fmv.x.w", "fmv.x.w"),
+            FpOp::MoveFromInt => ("fmv.w.x", "fmv.w.x"),
+            FpOp::Eq => ("feq.s", "feq.d"),
+            FpOp::Lt => ("flt.s", "flt.d"),
+            FpOp::Le => ("fle.s", "fle.d"),
+            FpOp::Class => ("fclass.s", "fclass.d"),
+        };
+        match self.double {
+            true => double,
+            false => single,
+        }
+    }
+
+    /// Whether FUNC3 holds a rounding mode rather than selecting the
+    /// operation.
+    fn has_rounding_mode(&self) -> bool {
+        matches!(
+            self.op,
+            FpOp::Madd
+                | FpOp::Msub
+                | FpOp::Nmsub
+                | FpOp::Nmadd
+                | FpOp::Add
+                | FpOp::Sub
+                | FpOp::Mul
+                | FpOp::Div
+                | FpOp::Sqrt
+                | FpOp::Convert
+                | FpOp::ToInt { .. }
+                | FpOp::FromInt { .. }
+        )
+    }
+
+    /// Whether the rounding mode can make a difference. Widening
+    /// conversions are exact.
+    pub fn rounds(&self) -> bool {
+        self.has_rounding_mode()
+            && !matches!(
+                (self.op, self.double),
+                (FpOp::Convert, true) | (FpOp::FromInt { .. }, true)
+            )
+    }
+
+    /// Whether rs1 is an integer register.
+    pub fn int_rs1(&self) -> bool {
+        matches!(
+            self.op,
+            FpOp::Load | FpOp::Store | FpOp::FromInt { .. } | FpOp::MoveFromInt
+        )
+    }
+
+    /// Whether rd is an integer register.
+    pub fn int_rd(&self) -> bool {
+        matches!(
+            self.op,
+            FpOp::ToInt { .. } | FpOp::MoveToInt | FpOp::Eq | FpOp::Lt | FpOp::Le | FpOp::Class
+        )
+    }
+
+    /// Number of register operands, rs1 to rs3, that are read.
+    pub fn sources(&self) -> usize {
+        match self.op {
+            FpOp::Load | FpOp::FromInt { .. } | FpOp::MoveFromInt => 1,
+            FpOp::Sqrt | FpOp::Convert | FpOp::ToInt { .. } | FpOp::MoveToInt | FpOp::Class => 1,
+            FpOp::Madd | FpOp::Msub | FpOp::Nmsub | FpOp::Nmadd => 3,
+            _ => 2,
+        }
+    }
+}
+
+/// Rounding modes, as encoded in `frm` and the rounding mode field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RoundingMode {
+    /// To nearest, ties to even.
+    Rne = 0,
+    /// Towards zero.
+    Rtz = 1,
+    /// Down, towards negative infinity.
+    Rdn = 2,
+    /// Up, towards positive infinity.
+    Rup = 3,
+    /// To nearest, ties away from zero.
+    Rmm = 4,
+}
+
+impl RoundingMode {
+    pub const NAMES: [&'static str; 5] = ["rne", "rtz", "rdn", "rup", "rmm"];
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        Some(match bits {
+            0 => RoundingMode::Rne,
+            1 => RoundingMode::Rtz,
+            2 => RoundingMode::Rdn,
+            3 => RoundingMode::Rup,
+            4 => RoundingMode::Rmm,
+            _ => return None,
+        })
+    }
+}
+
+/// Execute a floating-point instruction. It is invalid if `rf` has no
+/// floating-point registers, or if it rounds dynamically and `frm` holds
+/// a reserved mode.
+pub(crate) fn exec_float<M, R>(
+    ir: u32,
+    pc: &mut u32,
+    mem: &mut M,
+    rf: &mut R,
+) -> Result<(), RiscvError>
+where
+    M: Memory,
+    R: RegisterFile,
+{
+    let opcode = decode_opcode!(ir);
+    let inst = match FpInstruction::decode(ir) {
+        Some(inst) => inst,
+        None if matches!(opcode, OPCODE_LOAD_FP | OPCODE_STORE_FP) => {
+            return Err(RiscvError::InvalidFunc3Error(ir, decode_func3!(ir)))
+        }
+        None => return Err(RiscvError::InvalidFunc7Error(ir, decode_func7!(ir))),
+    };
+    if rf.fp().is_none() {
+        return Err(RiscvError::InvalidOpcodeError(ir, opcode));
+    }
+    info!("{}", crate::disassemble(ir));
+
+    let rd = decode_rd!(ir);
+    let rs1 = decode_rs1!(ir);
+    let rs2 = decode_rs2!(ir);
+    let double = inst.double;
+
+    match inst.op {
+        FpOp::Load => {
+            let addr = rf.read(rs1)?.wrapping_add(decode_i_imm!(ir));
+            let data = match double {
+                true => {
+                    let low = mem.read_word(addr)? as u64;
+                    low | (mem.read_word(addr.wrapping_add(4))? as u64) << 32
+                }
+                false => boxed(mem.read_word(addr)?),
+            };
+            write_fp(rf, rd, data)?;
+        }
+        FpOp::Store => {
+            let addr = rf.read(rs1)?.wrapping_add(decode_s_imm!(ir));
+            let data = read_fp(rf, rs2)?;
+            mem.write_word(addr, data as u32)?;
+            if double {
+                mem.write_word(addr.wrapping_add(4), (data >> 32) as u32)?;
+            }
+        }
+        _ => {
+            let rm = match inst.has_rounding_mode() {
+                true => rounding_mode(ir, rf)?,
+                false => RoundingMode::Rne,
+            };
+            let rs1_data = match inst.int_rs1() {
+                true => rf.read(rs1)? as u64,
+                false => read_fp(rf, rs1)?,
+            };
+            let rs2_data = match inst.sources() {
+                2 | 3 => read_fp(rf, rs2)?,
+                _ => 0,
+            };
+            let rs3_data = match inst.sources() {
+                3 => read_fp(rf, decode_rs3!(ir))?,
+                _ => 0,
+            };
+
+            let (result, flags) = execute(inst, [rs1_data, rs2_data, rs3_data], rm);
+            match inst.int_rd() {
+                true => rf.write(rd, result as u32)?,
+                false => write_fp(rf, rd, result)?,
+            }
+            if flags != 0 {
+                let fflags = rf.read_csr(CSR_FFLAGS)?;
+                if fflags | flags != fflags {
+                    rf.write_csr(CSR_FFLAGS, fflags | flags)?;
+                }
+            }
+        }
+    }
+    *pc = pc.wrapping_add(4);
+
+    Ok(())
+}
+
+fn read_fp<R: RegisterFile>(rf: &mut R, num: u8) -> Result<u64, RiscvError> {
+    rf.fp().unwrap().read(num)
+}
+
+fn write_fp<R: RegisterFile>(rf: &mut R, num: u8, data: u64) -> Result<(), RiscvError> {
+    rf.fp().unwrap().write(num, data)
+}
+
+/// Rounding mode of `ir`, looked up in `frm` if it is dynamic.
+fn rounding_mode<R: RegisterFile>(ir: u32, rf: &R) -> Result<RoundingMode, RiscvError> {
+    let rm = match decode_func3!(ir) {
+        RM_DYNAMIC => rf.read_csr(CSR_FRM)? as u8,
+        rm => rm,
+    };
+    RoundingMode::from_bits(rm).ok_or(RiscvError::InvalidFunc3Error(ir, decode_func3!(ir)))
+}
+
+/// NaN-box a single-precision value.
+fn boxed(bits: u32) -> u64 {
+    0xffff_ffff_0000_0000 | bits as u64
+}
+
+/// The single-precision value in a register, or the canonical NaN if it
+/// is not properly NaN-boxed.
+fn unboxed(bits: u64) -> u32 {
+    match bits >> 32 {
+        0xffff_ffff => bits as u32,
+        _ => CANONICAL_NAN_S,
+    }
+}
+
+/// A register operand of the instruction's format: its value, exactly
+/// widened to `f64`, and whether it is a NaN, signaling or not.
+#[derive(Clone, Copy)]
+struct Operand {
+    value: f64,
+    nan: bool,
+    signaling: bool,
+}
+
+impl Operand {
+    fn new(bits: u64, double: bool) -> Self {
+        // Widening a signaling NaN may quiet it, so look at the bits.
+        let (value, nan, quiet) = match double {
+            true => {
+                let value = f64::from_bits(bits);
+                (value, value.is_nan(), bits & 1 << 51 != 0)
+            }
+            false => {
+                let bits = unboxed(bits);
+                let value = f32::from_bits(bits);
+                (value as f64, value.is_nan(), bits & 1 << 22 != 0)
+            }
+        };
+        Operand {
+            value,
+            nan,
+            signaling: nan && !quiet,
+        }
+    }
+}
+
+/// Encode `value`, which must be representable in the format, as register
+/// contents. NaNs become the canonical NaN.
+fn encode(value: f64, double: bool) -> u64 {
+    match (value.is_nan(), double) {
+        (true, true) => CANONICAL_NAN_D,
+        (true, false) => boxed(CANONICAL_NAN_S),
+        (false, true) => value.to_bits(),
+        (false, false) => boxed((value as f32).to_bits()),
+    }
+}
+
+/// A finite, nonzero exact result, `mant * 2^exp`. Bits of a result
+/// below those of `mant` are folded into its lowest bit, which is enough
+/// to round correctly while it has a few more bits than the format.
+#[derive(Debug, Clone, Copy)]
+struct Exact {
+    neg: bool,
+    mant: u128,
+    exp: i32,
+}
+
+impl Exact {
+    /// A finite, nonzero `value`, with a 53-bit significand.
+    fn new(value: f64) -> Self {
+        let bits = value.to_bits();
+        let (frac, biased) = (bits & ((1 << 52) - 1), (bits >> 52 & 0x7ff) as i32);
+        let (mant, exp) = match biased {
+            0 => (frac, -1074),
+            _ => (frac | 1 << 52, biased - 1075),
+        };
+        Exact {
+            neg: value < 0.0,
+            mant: mant as u128,
+            exp,
+        }
+        .normalized(52)
+    }
+
+    /// Shift the significand up so that its top bit is bit `top`.
+    fn normalized(self, top: u32) -> Self {
+        let shift = top - (127 - self.mant.leading_zeros());
+        Exact {
+            mant: self.mant << shift,
+            exp: self.exp - shift as i32,
+            ..self
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Exact {
+            neg: self.neg != other.neg,
+            mant: self.mant * other.mant,
+            exp: self.exp + other.exp,
+        }
+    }
+
+    fn div(self, other: Self) -> Self {
+        // Both significands have 53 bits, so the quotient has at least 74.
+        let dividend = self.mant << 74;
+        let remainder = dividend % other.mant;
+        Exact {
+            neg: self.neg != other.neg,
+            mant: (dividend / other.mant) | (remainder != 0) as u128,
+            exp: self.exp - other.exp - 74,
+        }
+    }
+
+    fn sqrt(self) -> Self {
+        // Make the exponent even, and the root at least 62 bits.
+        let shift = 72 + (self.exp & 1) as u32;
+        let radicand = self.mant << shift;
+        let root = radicand.isqrt();
+        Exact {
+            neg: false,
+            mant: root | (root * root != radicand) as u128,
+            exp: (self.exp - shift as i32) / 2,
+        }
+    }
+
+    /// The sum, or `None` if it is exactly zero.
+    fn add(self, other: Self) -> Option<Self> {
+        // With both significands at the top, only bits far below those of
+        // the larger can be lost.
+        let (a, b) = (self.normalized(125), other.normalized(125));
+        let (a, b) = match a.exp >= b.exp {
+            true => (a, b),
+            false => (b, a),
+        };
+        let b_mant = match a.exp - b.exp {
+            0 => b.mant,
+            shift @ 1..=126 => b.mant >> shift | (b.mant & ((1 << shift) - 1) != 0) as u128,
+            _ => 1,
+        };
+        let (neg, mant) = match (a.neg == b.neg, a.mant.cmp(&b_mant)) {
+            (true, _) => (a.neg, a.mant + b_mant),
+            (false, Ordering::Equal) => return None,
+            (false, Ordering::Greater) => (a.neg, a.mant - b_mant),
+            (false, Ordering::Less) => (b.neg, b_mant - a.mant),
+        };
+        Some(Exact {
+            neg,
+            mant,
+            exp: a.exp,
+        })
+    }
+
+    /// The significand rounded in mode `rm` to a multiple of `2^lsb`, in
+    /// units of it, and whether that was inexact.
+    fn round_at(&self, lsb: i32, rm: RoundingMode) -> (u128, bool) {
+        let shift = lsb - self.exp;
+        if shift <= 0 {
+            return (self.mant << -shift, false);
+        }
+        // The mantissa is never zero, so dropping all of it is inexact.
+        let (kept, dropped) = match shift {
+            1..=127 => (self.mant >> shift, self.mant & ((1 << shift) - 1)),
+            _ => (0, self.mant),
+        };
+        let rest = match shift {
+            1..=127 => dropped.cmp(&(1 << (shift - 1))),
+            _ => Ordering::Less,
+        };
+        let inexact = dropped != 0;
+        let up = match rm {
+            RoundingMode::Rne => {
+                rest == Ordering::Greater || rest == Ordering::Equal && kept & 1 == 1
+            }
+            RoundingMode::Rtz => false,
+            RoundingMode::Rdn => inexact && self.neg,
+            RoundingMode::Rup => inexact && !self.neg,
+            RoundingMode::Rmm => rest != Ordering::Less,
+        };
+        (kept + up as u128, inexact)
+    }
+
+    /// Round to the format in mode `rm`, and return the result as register
+    /// contents with the exception flags raised.
+    fn round(self, rm: RoundingMode, double: bool) -> (u64, u32) {
+        let (precision, emin, emax) = match double {
+            true => (53, -1022, 1023),
+            false => (24, -126, 127),
+        };
+        // Exponent of the top bit.
+        let top = self.exp + (127 - self.mant.leading_zeros()) as i32;
+        let lsb = top.max(emin) - (precision - 1);
+        let (mant, inexact) = self.round_at(lsb, rm);
+
+        let mut flags = 0;
+        if inexact {
+            flags |= FFLAGS_NX;
+            // Tininess is detected after rounding, as if the exponent range
+            // were unbounded.
+            let (unbounded, _) = self.round_at(top - (precision - 1), rm);
+            if top + ((unbounded >> precision) as i32) < emin {
+                flags |= FFLAGS_UF;
+            }
+        }
+        let sign = match self.neg {
+            true => -1.0,
+            false => 1.0,
+        };
+        if mant == 0 {
+            return (encode(sign * 0.0, double), flags);
+        }
+        let msb = lsb + (127 - mant.leading_zeros()) as i32;
+        if msb > emax {
+            let away = match rm {
+                RoundingMode::Rne | RoundingMode::Rmm => true,
+                RoundingMode::Rtz => false,
+                RoundingMode::Rdn => self.neg,
+                RoundingMode::Rup => !self.neg,
+            };
+            let max = match (away, double) {
+                (true, _) => f64::INFINITY,
+                (false, true) => f64::MAX,
+                (false, false) => f32::MAX as f64,
+            };
+            return (encode(sign * max, double), flags | FFLAGS_OF | FFLAGS_NX);
+        }
+
+        // Rounding up may have carried into a new top bit.
+        let (biased, mant) = match msb < emin {
+            true => (0, mant),
+            false if msb - lsb == precision => ((msb - emin + 1) as u64, mant >> 1),
+            false => ((msb - emin + 1) as u64, mant),
+        };
+        let frac = mant as u64 & ((1 << (precision - 1)) - 1);
+        let bits =
+            (self.neg as u64) << (if double { 63 } else { 31 }) | biased << (precision - 1) | frac;
+        match double {
+            true => (bits, flags),
+            false => (boxed(bits as u32), flags),
+        }
+    }
+}
+
+/// Exact zero sum of `a` and `b`. It is negative if both are, or if their
+/// signs differ and the rounding mode is down.
+fn zero(a: f64, b: f64, rm: RoundingMode) -> f64 {
+    let (a, b) = (a.is_sign_negative(), b.is_sign_negative());
+    match (a && b) || (a != b && rm == RoundingMode::Rdn) {
+        true => -0.0,
+        false => 0.0,
+    }
+}
+
+/// Execute an instruction that neither loads nor stores, and return its
+/// result, as register contents, and the exception flags it raised.
+fn execute(inst: FpInstruction, sources: [u64; 3], rm: RoundingMode) -> (u64, u32) {
+    let double = inst.double;
+    let [a, b, _] = sources;
+    match inst.op {
+        FpOp::Load | FpOp::Store => unreachable!(),
+        FpOp::Sgnj | FpOp::Sgnjn | FpOp::Sgnjx => {
+            let (a, b, sign_bit) = match double {
+                true => (a, b, 1 << 63),
+                false => (unboxed(a) as u64, unboxed(b) as u64, 1 << 31),
+            };
+            let sign = match inst.op {
+                FpOp::Sgnj => b & sign_bit,
+                FpOp::Sgnjn => !b & sign_bit,
+                _ => (a ^ b) & sign_bit,
+            };
+            let result = (a & !sign_bit) | sign;
+            match double {
+                true => (result, 0),
+                false => (boxed(result as u32), 0),
+            }
+        }
+        FpOp::MoveToInt => (a as u32 as u64, 0),
+        FpOp::MoveFromInt => (boxed(a as u32), 0),
+        FpOp::Class => (classify(Operand::new(a, double), double) as u64, 0),
+        FpOp::Eq | FpOp::Lt | FpOp::Le => {
+            let (a, b) = (Operand::new(a, double), Operand::new(b, double));
+            let invalid = match inst.op {
+                FpOp::Eq => a.signaling || b.signaling,
+                _ => a.nan || b.nan,
+            };
+            let result = match inst.op {
+                FpOp::Eq => a.value == b.value,
+                FpOp::Lt => a.value < b.value,
+                _ => a.value <= b.value,
+            };
+            (result as u64, if invalid { FFLAGS_NV } else { 0 })
+        }
+        FpOp::Min | FpOp::Max => {
+            let (a, b) = (Operand::new(a, double), Operand::new(b, double));
+            let flags = match a.signaling || b.signaling {
+                true => FFLAGS_NV,
+                false => 0,
+            };
+            let value = match (a.nan, b.nan) {
+                (true, true) => f64::NAN,
+                (true, false) => b.value,
+                (false, true) => a.value,
+                // Negative zero is below positive zero.
+                _ if a.value == b.value => match (inst.op, a.value.is_sign_negative()) {
+                    (FpOp::Min, true) | (FpOp::Max, false) => a.value,
+                    _ => b.value,
+                },
+                _ if (a.value < b.value) == (inst.op == FpOp::Min) => a.value,
+                _ => b.value,
+            };
+            (encode(value, double), flags)
+        }
+        FpOp::ToInt { unsigned } => {
+            let a = Operand::new(a, double);
+            let rounded = match rm {
+                RoundingMode::Rne => a.value.round_ties_even(),
+                RoundingMode::Rtz => a.value.trunc(),
+                RoundingMode::Rdn => a.value.floor(),
+                RoundingMode::Rup => a.value.ceil(),
+                RoundingMode::Rmm => a.value.round(),
+            };
+            let (min, max) = match unsigned {
+                true => (0.0, u32::MAX as f64),
+                false => (i32::MIN as f64, i32::MAX as f64),
+            };
+            let result = match () {
+                _ if a.nan || rounded > max => max,
+                _ if rounded < min => min,
+                _ => rounded,
+            };
+            let flags = match () {
+                _ if a.nan || rounded > max || rounded < min => FFLAGS_NV,
+                _ if rounded != a.value => FFLAGS_NX,
+                _ => 0,
+            };
+            let result = match unsigned {
+                true => result as u32,
+                false => result as i32 as u32,
+            };
+            (result as u64, flags)
+        }
+        FpOp::FromInt { unsigned } => {
+            let value = match unsigned {
+                true => a as u32 as f64,
+                false => a as u32 as i32 as f64,
+            };
+            match value == 0.0 {
+                true => (encode(value, double), 0),
+                false => Exact::new(value).round(rm, double),
+            }
+        }
+        FpOp::Convert => {
+            let a = Operand::new(a, !double);
+            let flags = match a.signaling {
+                true => FFLAGS_NV,
+                false => 0,
+            };
+            match a.nan || a.value.is_infinite() || a.value == 0.0 {
+                true => (encode(a.value, double), flags),
+                false => Exact::new(a.value).round(rm, double),
+            }
+        }
+        _ => arithmetic(inst, sources, rm),
+    }
+}
+
+/// Execute an arithmetic instruction, from `fadd` to `fsqrt` and the fused
+/// multiply-adds.
+fn arithmetic(inst: FpInstruction, sources: [u64; 3], rm: RoundingMode) -> (u64, u32) {
+    let double = inst.double;
+    let operands = &[
+        Operand::new(sources[0], double),
+        Operand::new(sources[1], double),
+        Operand::new(sources[2], double),
+    ][..match inst.op {
+        FpOp::Sqrt => 1,
+        FpOp::Madd | FpOp::Msub | FpOp::Nmsub | FpOp::Nmadd => 3,
+        _ => 2,
+    }];
+    let (a, b) = (operands[0].value, operands.get(1).map_or(0.0, |b| b.value));
+    let c = operands.get(2).map_or(0.0, |c| c.value);
+    let invalid = (encode(f64::NAN, double), FFLAGS_NV);
+
+    let fused = operands.len() == 3;
+    // Multiplying infinity by zero is invalid even if the addend is a quiet
+    // NaN.
+    let infinite_product = fused && (a.is_infinite() && b == 0.0 || a == 0.0 && b.is_infinite());
+    if operands.iter().any(|operand| operand.nan) {
+        let flags = match operands.iter().any(|operand| operand.signaling) || infinite_product {
+            true => FFLAGS_NV,
+            false => 0,
+        };
+        return (encode(f64::NAN, double), flags);
+    }
+
+    let exact = |value: f64| (encode(value, double), 0);
+    match inst.op {
+        FpOp::Add | FpOp::Sub => {
+            let b = match inst.op {
+                FpOp::Sub => -b,
+                _ => b,
+            };
+            match () {
+                _ if (a + b).is_nan() => invalid,
+                _ if a.is_infinite() || b.is_infinite() => exact(a + b),
+                _ if a == 0.0 && b == 0.0 => exact(zero(a, b, rm)),
+                _ if a == 0.0 || b == 0.0 => exact(a + b),
+                _ => match Exact::new(a).add(Exact::new(b)) {
+                    Some(sum) => sum.round(rm, double),
+                    None => exact(zero(a, b, rm)),
+                },
+            }
+        }
+        FpOp::Mul => match () {
+            _ if (a * b).is_nan() => invalid,
+            _ if !a.is_finite() || !b.is_finite() || a == 0.0 || b == 0.0 => exact(a * b),
+            _ => Exact::new(a).mul(Exact::new(b)).round(rm, double),
+        },
+        FpOp::Div => match () {
+            _ if (a / b).is_nan() => invalid,
+            _ if b == 0.0 && a.is_finite() => (encode(a / b, double), FFLAGS_DZ),
+            _ if !a.is_finite() || !b.is_finite() || a == 0.0 => exact(a / b),
+            _ => Exact::new(a).div(Exact::new(b)).round(rm, double),
+        },
+        FpOp::Sqrt => match () {
+            _ if a.sqrt().is_nan() => invalid,
+            _ if a.is_infinite() || a == 0.0 => exact(a.sqrt()),
+            _ => Exact::new(a).sqrt().round(rm, double),
+        },
+        _ => {
+            let a = match inst.op {
+                FpOp::Nmsub | FpOp::Nmadd => -a,
+                _ => a,
+            };
+            let c = match inst.op {
+                FpOp::Msub | FpOp::Nmadd => -c,
+                _ => c,
+            };
+            // The product on the host is only used when it is exactly
+            // infinite or zero, as it may otherwise overflow or underflow.
+            let product = a * b;
+            let infinite = a.is_infinite() || b.is_infinite();
+            match () {
+                _ if infinite_product || infinite && (product + c).is_nan() => invalid,
+                _ if infinite => exact(product + c),
+                _ if c.is_infinite() => exact(c),
+                // The product is an exact zero.
+                _ if (a == 0.0 || b == 0.0) && c == 0.0 => exact(zero(product, c, rm)),
+                _ if a == 0.0 || b == 0.0 => exact(c),
+                _ if c == 0.0 => Exact::new(a).mul(Exact::new(b)).round(rm, double),
+                _ => match Exact::new(a).mul(Exact::new(b)).add(Exact::new(c)) {
+                    Some(sum) => sum.round(rm, double),
+                    None => exact(zero(1.0, -1.0, rm)),
+                },
+            }
+        }
+    }
+}
+
+/// The `fclass` mask of an operand, with one of its ten bits set.
+fn classify(operand: Operand, double: bool) -> u32 {
+    let value = operand.value;
+    let subnormal = match double {
+        true => value != 0.0 && value.abs() < f64::MIN_POSITIVE,
+        false => value != 0.0 && value.abs() < f32::MIN_POSITIVE as f64,
+    };
+    let index = match () {
+        _ if operand.signaling => 8,
+        _ if operand.nan => 9,
+        _ if value == f64::NEG_INFINITY => 0,
+        _ if value == f64::INFINITY => 7,
+        _ if value == 0.0 => match value.is_sign_negative() {
+            true => 3,
+            false => 4,
+        },
+        _ if subnormal => match value < 0.0 {
+            true => 2,
+            false => 5,
+        },
+        _ => match value < 0.0 {
+            true => 1,
+            false => 6,
+        },
+    };
+    1 << index
+}
@@ -1,9 +1,15 @@
+/// Classification of instructions for performance models.
+mod class;
 /// Decoding macros.
 pub mod decode;
+/// Disassembly of single instructions.
+mod disasm;
 /// Enumeration for errors thrown by an MCU.
 mod error;
 /// Execution and decoding logic.
 mod exec;
+/// Execution of the F and D extensions.
+mod float;
 
 /// Traits to be implementation by other implementations of
 /// an MCU.
@@ -15,5 +21,7 @@ mod test;
 /// Re-export common library.
 pub use lib_rv32_common as common;
 
+pub use class::{InstructionClass, RegisterUsage};
+pub use disasm::{disassemble, immediate, mnemonic, MNEMONICS};
 pub use error::RiscvError;
 pub use exec::exec_one;
@@ -104,3 +104,219 @@ fn test_decode_rd() {
         );
     }
 }
+
+#[test]
+fn test_instruction_class() {
+    std::assert_eq!(
+        InstructionClass::Alu,
+        InstructionClass::of(instructions::LUI_X5_4)
+    );
+    std::assert_eq!(
+        InstructionClass::Alu,
+        InstructionClass::of(instructions::SUB_X5_X5_X5)
+    );
+    std::assert_eq!(
+        InstructionClass::Load,
+        InstructionClass::of(instructions::LW_X5_0_X5)
+    );
+    std::assert_eq!(
+        InstructionClass::Store,
+        InstructionClass::of(instructions::SB_X5_0_X5)
+    );
+    std::assert_eq!(
+        InstructionClass::Branch,
+        InstructionClass::of(instructions::BEQ_X5_X5_12)
+    );
+    std::assert_eq!(
+        InstructionClass::Jump,
+        InstructionClass::of(instructions::JALR_X5_X5_4)
+    );
+    // mul x5, x6, x7 is not implemented.
+    std::assert_eq!(InstructionClass::Unknown, InstructionClass::of(0x027302b3));
+    // lr.w a0, (a1) and sc.w a0, a2, (a1)
+    std::assert_eq!(InstructionClass::Load, InstructionClass::of(0x1005a52f));
+    std::assert_eq!(InstructionClass::Store, InstructionClass::of(0x18c5a52f));
+    std::assert_eq!(InstructionClass::Unknown, InstructionClass::of(0));
+}
+
+#[test]
+fn test_register_usage() {
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: Some(6),
+            rs2: Some(7),
+            rd: Some(5)
+        },
+        RegisterUsage::of(0x027302b3)
+    );
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: Some(5),
+            rs2: Some(5),
+            rd: None
+        },
+        RegisterUsage::of(instructions::SW_X5_0_X5)
+    );
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: None,
+            rs2: None,
+            rd: Some(5)
+        },
+        RegisterUsage::of(instructions::LUI_X5_4)
+    );
+    // addi x0, x0, 17 carries no dependencies.
+    std::assert_eq!(
+        RegisterUsage::default(),
+        RegisterUsage::of(instructions::ADDI_X0_X0_17)
+    );
+    assert!(RegisterUsage::of(instructions::LW_X5_0_X5).reads(5));
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: Some(11),
+            rs2: None,
+            rd: Some(10)
+        },
+        RegisterUsage::of(0x1005a52f)
+    );
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: Some(11),
+            rs2: Some(12),
+            rd: Some(10)
+        },
+        RegisterUsage::of(0x06c5a52f)
+    );
+}
+
+#[test]
+fn test_disassemble() {
+    std::assert_eq!("addi t0, t0, 1", disassemble(instructions::ADDI_X5_X5_1));
+    std::assert_eq!(
+        "addi t0, t1, -1",
+        disassemble(instructions::ADDI_X5_X6_NEG_1)
+    );
+    std::assert_eq!("sub t0, t0, t0", disassemble(instructions::SUB_X5_X5_X5));
+    std::assert_eq!("srai t0, t0, 1", disassemble(instructions::SRAI_X5_X5_1));
+    std::assert_eq!("lui t0, 0x4", disassemble(instructions::LUI_X5_4));
+    std::assert_eq!("lw t0, 0(t0)", disassemble(instructions::LW_X5_0_X5));
+    std::assert_eq!("sw t0, 0(t0)", disassemble(instructions::SW_X5_0_X5));
+    std::assert_eq!("beq t0, t0, 12", disassemble(instructions::BEQ_X5_X5_12));
+    std::assert_eq!("jal zero, -4", disassemble(instructions::JAL_X0_NEG_4));
+    std::assert_eq!("jalr t0, 4(t0)", disassemble(instructions::JALR_X5_X5_4));
+    std::assert_eq!("mul t0, t1, t2", disassemble(0x027302b3));
+    std::assert_eq!("lr.w a0, (a1)", disassemble(0x1005a52f));
+    std::assert_eq!("sc.w a0, a2, (a1)", disassemble(0x18c5a52f));
+    std::assert_eq!("amoadd.w.aqrl a0, a2, (a1)", disassemble(0x06c5a52f));
+    std::assert_eq!("amoswap.w.aq t0, t1, (t2)", disassemble(0x0c63a2af));
+    std::assert_eq!("unknown", disassemble(0));
+}
+
+#[test]
+fn test_decode_csr() {
+    // csrrs t0, mcycle, zero
+    assert_eq!(0xb00, decode_csr!(0xb00022f3u32));
+    std::assert_eq!(InstructionClass::System, InstructionClass::of(0xb00022f3));
+    std::assert_eq!("csrrs t0, 0xb00, zero", disassemble(0xb00022f3));
+    // csrrwi zero, 0x320, 5
+    std::assert_eq!("csrrwi zero, 0x320, 5", disassemble(0x3202d073));
+}
+
+#[test]
+fn test_mnemonic() {
+    for (name, ir) in [
+        ("addi", instructions::ADDI_X5_X5_1),
+        ("srai", instructions::SRAI_X5_X5_1),
+        ("sub", instructions::SUB_X5_X5_X5),
+        ("bne", instructions::BNE_X5_X5_76),
+        ("ecall", 0x00000073),
+        ("ebreak", 0x00100073),
+        ("sret", 0x10200073),
+        ("mret", 0x30200073),
+        ("sfence.vma", 0x12050073),
+        ("lr.w", 0x1005a52f),
+        ("sc.w", 0x18c5a52f),
+        ("amoadd.w", 0x06c5a52f),
+        ("amoswap.w", 0x0c63a2af),
+    ] {
+        std::assert_eq!(Some(name), mnemonic(ir));
+        assert!(MNEMONICS.contains(&name));
+    }
+    std::assert_eq!(None, mnemonic(0));
+    // amo with a doubleword width.
+    std::assert_eq!(None, mnemonic(0x06c5b52f));
+    std::assert_eq!("ebreak", disassemble(0x00100073));
+    std::assert_eq!("sfence.vma a0, zero", disassemble(0x12050073));
+    assert!(RegisterUsage::of(0x12050073).reads(10));
+}
+
+#[test]
+fn test_immediate() {
+    std::assert_eq!(Some(-1), immediate(instructions::ADDI_X5_X6_NEG_1));
+    std::assert_eq!(Some(76), immediate(instructions::BNE_X5_X5_76));
+    std::assert_eq!(None, immediate(instructions::SRAI_X5_X5_1));
+    std::assert_eq!(None, immediate(instructions::SUB_X5_X5_X5));
+
+    // x0 is only reported as a field.
+    let fields = RegisterUsage::fields(instructions::ADDI_X0_X0_17);
+    std::assert_eq!(
+        (Some(0), Some(0), None),
+        (fields.rd, fields.rs1, fields.rs2)
+    );
+    std::assert_eq!(
+        RegisterUsage::default(),
+        RegisterUsage::of(instructions::ADDI_X0_X0_17)
+    );
+}
+
+#[test]
+fn test_float() {
+    for (text, ir) in [
+        ("fadd.s fa0, fa1, fa2", 0x00c5f553),
+        ("fadd.s fa0, fa1, fa2, rtz", 0x00c59553),
+        ("flw fa0, 8(sp)", 0x00812507),
+        ("fsd fs0, -8(sp)", 0xfe813c27),
+        ("fmadd.d fa0, fa1, fa2, fa3", 0x6ac5f543),
+        ("fcvt.w.s a0, fa0, rtz", 0xc0051553),
+        // Widening is exact, so the rounding mode is not shown.
+        ("fcvt.d.w fa0, a0", 0xd2050553),
+        ("fmv.x.w a0, fa0", 0xe0050553),
+        ("feq.d a0, fa0, fa1", 0xa2b52553),
+        ("fsqrt.d fa0, fa1", 0x5a05f553),
+        ("fclass.s a0, fa0", 0xe0051553),
+    ] {
+        std::assert_eq!(text, disassemble(ir));
+        assert!(MNEMONICS.contains(&mnemonic(ir).unwrap()));
+    }
+    // A reserved rounding mode, and fmv.x.d which is RV64 only.
+    std::assert_eq!(None, mnemonic(0x00c5d553));
+    std::assert_eq!(None, mnemonic(0xe2050553));
+    std::assert_eq!(Some(-8), immediate(0xfe813c27));
+
+    std::assert_eq!(InstructionClass::Load, InstructionClass::of(0x00812507));
+    std::assert_eq!(InstructionClass::Store, InstructionClass::of(0xfe813c27));
+    std::assert_eq!(InstructionClass::Float, InstructionClass::of(0x6ac5f543));
+    std::assert_eq!(
+        InstructionClass::FloatDivide,
+        InstructionClass::of(0x18c5f553)
+    );
+
+    // Only integer registers are reported.
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: Some(2),
+            rs2: None,
+            rd: None
+        },
+        RegisterUsage::of(0xfe813c27)
+    );
+    std::assert_eq!(RegisterUsage::default(), RegisterUsage::of(0x00c5f553));
+    std::assert_eq!(
+        RegisterUsage {
+            rs1: None,
+            rs2: None,
+            rd: Some(10)
+        },
+        RegisterUsage::of(0xa2b52553)
+    );
+}
@@ -10,6 +10,38 @@ pub trait RegisterFile {
     /// Write a value `data` to the register numbered `num`. Returns a `Result` containing
     /// an error if one occured, otherwise returns an empty `Result`.
     fn write(&mut self, num: u8, data: u32) -> Result<(), RiscvError>;
+
+    /// Read the control and status register numbered `csr`. Register files
+    /// without CSRs need not implement this; every access is then invalid.
+    fn read_csr(&self, csr: u16) -> Result<u32, RiscvError> {
+        Err(RiscvError::InvalidCsrError(csr))
+    }
+
+    /// Write `data` to the control and status register numbered `csr`.
+    /// Writes to read-only registers should return an error.
+    fn write_csr(&mut self, csr: u16, _data: u32) -> Result<(), RiscvError> {
+        Err(RiscvError::InvalidCsrError(csr))
+    }
+
+    /// The floating-point registers of the F and D extensions. Register
+    /// files without them need not implement this; floating-point
+    /// instructions are then invalid.
+    fn fp(&mut self) -> Option<&mut dyn FpRegisterFile> {
+        None
+    }
+}
+
+/// Trait to be implemented by a floating-point register file: 32 registers
+/// of 64 bits. Single-precision values are NaN-boxed, i.e. held in the low
+/// half with the upper half set. `fcsr`, with the rounding mode and
+/// exception flags, is read and written through `RegisterFile::read_csr`
+/// and `RegisterFile::write_csr`.
+pub trait FpRegisterFile {
+    /// Read the register numbered `num`.
+    fn read(&self, num: u8) -> Result<u64, RiscvError>;
+
+    /// Write `data` to the register numbered `num`.
+    fn write(&mut self, num: u8, data: u64) -> Result<(), RiscvError>;
 }
 
 pub trait Memory {
@@ -50,4 +82,18 @@ pub trait Memory {
     /// This makes no guarantees about endianness, only that `read_byte` returns the same
     /// data after a `write_byte`.
     fn write_byte(&mut self, addr: u32, data: u32) -> Result<(), RiscvError>;
+
+    /// Read the word at `addr` for `lr.w` and reserve it for a following
+    /// `sc.w`. Memories that do not track reservations need not implement
+    /// this; it is then a plain `read_word`.
+    fn load_reserved(&mut self, addr: u32) -> Result<u32, RiscvError> {
+        self.read_word(addr)
+    }
+
+    /// Write `data` to the word at `addr` for `sc.w` if it is still
+    /// reserved, and return whether it was. The reservation is dropped
+    /// either way. Without reservation tracking every `sc.w` succeeds.
+    fn store_conditional(&mut self, addr: u32, data: u32) -> Result<bool, RiscvError> {
+        self.write_word(addr, data).map(|_| true)
+    }
 }
@@ -4,8 +4,21 @@ use std::{
     process,
 };
 
+/// Check whether a RISC-V cross-compiler is available on the `PATH`.
+#[cfg(debug_assertions)]
+fn have_cross_compiler() -> bool {
+    ["riscv64-unknown-elf-gcc", "riscv32-unknown-elf-gcc"]
+        .iter()
+        .any(|cc| process::Command::new(cc).arg("--version").output().is_ok())
+}
+
 #[cfg(debug_assertions)]
 fn build_tests() {
+    if !have_cross_compiler() {
+        println!("cargo:warning=No RISC-V cross-compiler found, skipping GCC-built test programs.");
+        return;
+    }
+
     let output = process::Command::new("./build-tests.sh")
         .output()
         .expect("Failed to execute test build script.");
@@ -0,0 +1,27 @@
+# Compute the tenth Fibonacci number with a call, store it and stop.
+
+.section .text.init
+
+start:
+    li   sp, 0x10000
+    li   a0, 10
+    call fib
+
+    li   t0, 0x0F00
+    sw   a0, 0(t0)
+    ebreak
+
+# a0 <- fib(a0)
+fib:
+    li   t0, 0
+    li   t1, 1
+    beqz a0, done
+loop:
+    add  t2, t0, t1
+    mv   t0, t1
+    mv   t1, t2
+    addi a0, a0, -1
+    bnez a0, loop
+done:
+    mv   a0, t0
+    ret
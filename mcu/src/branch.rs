@@ -0,0 +1,475 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use lib_rv32_isa::{
+    b_imm,
+    common::{bit_concat, bit_extend, bit_slice, sized_bit_extend, sized_bit_slice},
+    InstructionClass,
+};
+
+use crate::Step;
+
+/// Predicts the direction of conditional branches.
+pub trait BranchPredictor {
+    /// Guess whether the branch at `pc` jumping to `target` is taken.
+    fn predict(&mut self, pc: u32, target: u32) -> bool;
+
+    /// Learn the actual outcome of the branch just predicted.
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+}
+
+/// Always predicts not taken.
+#[derive(Debug, Clone, Default)]
+pub struct NotTaken;
+
+impl BranchPredictor for NotTaken {
+    fn predict(&mut self, _pc: u32, _target: u32) -> bool {
+        false
+    }
+
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+}
+
+/// Backward taken, forward not taken: loops are predicted to iterate.
+#[derive(Debug, Clone, Default)]
+pub struct Btfn;
+
+impl BranchPredictor for Btfn {
+    fn predict(&mut self, pc: u32, target: u32) -> bool {
+        target < pc
+    }
+
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+}
+
+/// Table of saturating counters indexed by the branch address. With 1-bit
+/// counters it predicts the last outcome; with 2-bit counters it takes two
+/// mispredictions in a row to change its mind.
+#[derive(Debug, Clone)]
+pub struct Bimodal {
+    max: u8,
+    counters: Vec<u8>,
+}
+
+impl Bimodal {
+    /// `bits` is 1 or 2; `entries` must be a power of two.
+    pub fn new(bits: u8, entries: usize) -> Self {
+        assert!(bits == 1 || bits == 2);
+        assert!(entries.is_power_of_two());
+
+        // Start weakly not taken.
+        let max = (1 << bits) - 1;
+        Bimodal {
+            max,
+            counters: vec![max / 2; entries],
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        (pc >> 2) as usize & (self.counters.len() - 1)
+    }
+}
+
+impl BranchPredictor for Bimodal {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters[self.index(pc)] > self.max / 2
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let i = self.index(pc);
+        let c = &mut self.counters[i];
+        *c = if taken {
+            (*c + 1).min(self.max)
+        } else {
+            c.saturating_sub(1)
+        };
+    }
+}
+
+/// Two-bit counters indexed by the branch address XOR the global history
+/// of recent outcomes, so correlated branches can be told apart.
+#[derive(Debug, Clone)]
+pub struct Gshare {
+    history_bits: u32,
+    history: u32,
+    counters: Vec<u8>,
+}
+
+impl Gshare {
+    /// `entries` must be a power of two.
+    pub fn new(history_bits: u32, entries: usize) -> Self {
+        assert!(entries.is_power_of_two());
+        assert!(history_bits <= 32);
+
+        Gshare {
+            history_bits,
+            history: 0,
+            counters: vec![1; entries],
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        ((pc >> 2) ^ self.history) as usize & (self.counters.len() - 1)
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn predict(&mut self, pc: u32, _target: u32) -> bool {
+        self.counters[self.index(pc)] > 1
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let i = self.index(pc);
+        let c = &mut self.counters[i];
+        *c = if taken {
+            (*c + 1).min(3)
+        } else {
+            c.saturating_sub(1)
+        };
+
+        let mask = match self.history_bits {
+            32 => u32::MAX,
+            n => (1 << n) - 1,
+        };
+        self.history = ((self.history << 1) | taken as u32) & mask;
+    }
+}
+
+/// Selection of a direction predictor, e.g. from the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PredictorKind {
+    NotTaken,
+    Btfn,
+    OneBit(usize),
+    TwoBit(usize),
+    Gshare(usize),
+}
+
+impl PredictorKind {
+    /// Parse `not-taken`, `btfn`, `1bit`, `2bit` or `gshare`, optionally
+    /// followed by `:ENTRIES` for the table-based predictors (default 1024).
+    pub fn parse(s: &str) -> Option<Self> {
+        let (name, entries) = match s.split_once(':') {
+            Some((name, n)) => (
+                name,
+                n.parse().ok().filter(|n: &usize| n.is_power_of_two())?,
+            ),
+            None => (s, 1024),
+        };
+        match name {
+            "not-taken" => Some(PredictorKind::NotTaken),
+            "btfn" => Some(PredictorKind::Btfn),
+            "1bit" => Some(PredictorKind::OneBit(entries)),
+            "2bit" => Some(PredictorKind::TwoBit(entries)),
+            "gshare" => Some(PredictorKind::Gshare(entries)),
+            _ => None,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn BranchPredictor> {
+        match *self {
+            PredictorKind::NotTaken => Box::new(NotTaken),
+            PredictorKind::Btfn => Box::new(Btfn),
+            PredictorKind::OneBit(n) => Box::new(Bimodal::new(1, n)),
+            PredictorKind::TwoBit(n) => Box::new(Bimodal::new(2, n)),
+            PredictorKind::Gshare(n) => Box::new(Gshare::new(n.trailing_zeros(), n)),
+        }
+    }
+}
+
+impl std::fmt::Display for PredictorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PredictorKind::NotTaken => write!(f, "not-taken"),
+            PredictorKind::Btfn => write!(f, "btfn"),
+            PredictorKind::OneBit(n) => write!(f, "1bit:{}", n),
+            PredictorKind::TwoBit(n) => write!(f, "2bit:{}", n),
+            PredictorKind::Gshare(n) => write!(f, "gshare:{}", n),
+        }
+    }
+}
+
+/// Direct-mapped branch target buffer for `jal` and `jalr`.
+#[derive(Debug, Clone)]
+pub struct Btb {
+    entries: Vec<Option<(u32, u32)>>,
+}
+
+impl Btb {
+    /// `entries` must be a power of two.
+    pub fn new(entries: usize) -> Self {
+        assert!(entries.is_power_of_two());
+        Btb {
+            entries: vec![None; entries],
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        (pc >> 2) as usize & (self.entries.len() - 1)
+    }
+
+    pub fn predict(&self, pc: u32) -> Option<u32> {
+        match self.entries[self.index(pc)] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, pc: u32, target: u32) {
+        let i = self.index(pc);
+        self.entries[i] = Some((pc, target));
+    }
+}
+
+/// Return address stack. When full, the oldest entry is dropped.
+#[derive(Debug, Clone)]
+pub struct ReturnStack {
+    depth: usize,
+    stack: Vec<u32>,
+}
+
+impl ReturnStack {
+    pub fn new(depth: usize) -> Self {
+        ReturnStack {
+            depth,
+            stack: Vec::with_capacity(depth),
+        }
+    }
+
+    pub fn push(&mut self, addr: u32) {
+        if self.stack.len() == self.depth {
+            self.stack.remove(0);
+        }
+        self.stack.push(addr);
+    }
+
+    pub fn pop(&mut self) -> Option<u32> {
+        self.stack.pop()
+    }
+}
+
+/// Outcomes of one static branch or jump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct BranchRecord {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+impl BranchRecord {
+    /// Fraction of executions predicted correctly, or 1 if never executed.
+    pub fn accuracy(&self) -> f64 {
+        match self.executed {
+            0 => 1.0,
+            n => (n - self.mispredicted) as f64 / n as f64,
+        }
+    }
+
+    fn record(&mut self, taken: bool, correct: bool) {
+        self.executed += 1;
+        self.taken += taken as u64;
+        self.mispredicted += !correct as u64;
+    }
+}
+
+/// Runs a direction predictor, a BTB and a return address stack over the
+/// executed instructions and keeps statistics per branch address.
+///
+/// Conditional branches are scored on direction only, since their target
+/// is known at decode. Jumps are scored on target: returns (`jalr` through
+/// `ra` or `t0`) use the return address stack, everything else the BTB.
+pub struct BranchSimulator {
+    kind: PredictorKind,
+    predictor: Box<dyn BranchPredictor>,
+    btb: Btb,
+    ras: ReturnStack,
+    branches: BTreeMap<u32, BranchRecord>,
+    jumps: BTreeMap<u32, BranchRecord>,
+}
+
+impl BranchSimulator {
+    /// Use the given predictor with a 64-entry BTB and an 8-deep return
+    /// address stack.
+    pub fn new(kind: PredictorKind) -> Self {
+        BranchSimulator::with_target_predictors(kind, Btb::new(64), ReturnStack::new(8))
+    }
+
+    pub fn with_target_predictors(kind: PredictorKind, btb: Btb, ras: ReturnStack) -> Self {
+        BranchSimulator {
+            kind,
+            predictor: kind.build(),
+            btb,
+            ras,
+            branches: BTreeMap::new(),
+            jumps: BTreeMap::new(),
+        }
+    }
+
+    pub fn kind(&self) -> PredictorKind {
+        self.kind
+    }
+
+    /// Statistics per conditional branch, by address.
+    pub fn branches(&self) -> &BTreeMap<u32, BranchRecord> {
+        &self.branches
+    }
+
+    /// Statistics per jump, by address.
+    pub fn jumps(&self) -> &BTreeMap<u32, BranchRecord> {
+        &self.jumps
+    }
+
+    /// All conditional branches together.
+    pub fn branch_total(&self) -> BranchRecord {
+        total(&self.branches)
+    }
+
+    /// All jumps together.
+    pub fn jump_total(&self) -> BranchRecord {
+        total(&self.jumps)
+    }
+
+    /// Predict and learn from an executed instruction. Returns whether it
+    /// was a branch or jump that was mispredicted.
+    pub fn retire(&mut self, step: &Step) -> bool {
+        let ir = step.ir;
+        match InstructionClass::of(ir) {
+            InstructionClass::Branch => {
+                let target = step.pc.wrapping_add(b_imm!(ir));
+                let taken = step.next_pc != step.pc.wrapping_add(4);
+                let correct = self.predictor.predict(step.pc, target) == taken;
+                self.predictor.update(step.pc, target, taken);
+                self.branches
+                    .entry(step.pc)
+                    .or_default()
+                    .record(taken, correct);
+                !correct
+            }
+            InstructionClass::Jump => {
+                let is_return = step.is_return();
+                let predicted = if is_return {
+                    self.ras.pop()
+                } else {
+                    self.btb.predict(step.pc)
+                };
+                if !is_return {
+                    self.btb.update(step.pc, step.next_pc);
+                }
+                if step.is_call() {
+                    self.ras.push(step.pc.wrapping_add(4));
+                }
+
+                let correct = predicted == Some(step.next_pc);
+                self.jumps.entry(step.pc).or_default().record(true, correct);
+                !correct
+            }
+            _ => false,
+        }
+    }
+}
+
+fn total(records: &BTreeMap<u32, BranchRecord>) -> BranchRecord {
+    records
+        .values()
+        .fold(BranchRecord::default(), |a, r| BranchRecord {
+            executed: a.executed + r.executed,
+            taken: a.taken + r.taken,
+            mispredicted: a.mispredicted + r.mispredicted,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::Mcu;
+
+    /// A loop of 10 iterations around an inner branch that alternates
+    /// taken and not taken.
+    const PROGRAM: &str = "addi t0, zero, 10\n\
+                           loop: andi t1, t0, 1\n\
+                           beq t1, zero, 8\n\
+                           addi t2, t2, 1\n\
+                           addi t0, t0, -1\n\
+                           bne t0, zero, loop\n\
+                           end: jal zero, end";
+
+    fn simulate(kind: &str) -> BranchSimulator {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program(PROGRAM).unwrap())
+            .unwrap();
+        let mut sim = BranchSimulator::new(PredictorKind::parse(kind).unwrap());
+        while mcu.pc != 24 {
+            sim.retire(&mcu.step().unwrap());
+        }
+        sim
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(PredictorKind::Btfn), PredictorKind::parse("btfn"));
+        assert_eq!(
+            Some(PredictorKind::Gshare(256)),
+            PredictorKind::parse("gshare:256")
+        );
+        assert_eq!(
+            Some(PredictorKind::TwoBit(1024)),
+            PredictorKind::parse("2bit")
+        );
+        assert_eq!(None, PredictorKind::parse("2bit:100"));
+        assert_eq!(None, PredictorKind::parse("perceptron"));
+    }
+
+    #[test]
+    fn test_static_predictors() {
+        let sim = simulate("not-taken");
+        // The loop branch is taken 9 times and the inner one 5 times.
+        assert_eq!(9, sim.branches()[&20].mispredicted);
+        assert_eq!(5, sim.branches()[&8].mispredicted);
+        assert_eq!(20, sim.branch_total().executed);
+
+        let sim = simulate("btfn");
+        assert_eq!(1, sim.branches()[&20].mispredicted);
+        assert_eq!(5, sim.branches()[&8].mispredicted);
+    }
+
+    #[test]
+    fn test_bimodal() {
+        // The last-outcome predictor is always wrong on an alternating
+        // branch, and so are 2-bit counters bouncing between the two weak
+        // states. Both miss the loop branch on entry and exit.
+        for kind in ["1bit", "2bit"] {
+            let sim = simulate(kind);
+            assert_eq!(10, sim.branches()[&8].mispredicted);
+            assert_eq!(2, sim.branches()[&20].mispredicted);
+            assert_eq!(0.4, sim.branch_total().accuracy());
+        }
+    }
+
+    #[test]
+    fn test_gshare_learns_pattern() {
+        let sim = simulate("gshare:256");
+        assert!(sim.branches()[&8].mispredicted <= 4);
+    }
+
+    #[test]
+    fn test_return_stack() {
+        let mut mcu = Mcu::new(0x1000);
+        // jal ra, 12; jal ra, 8; jal zero, 0; jalr zero, 0(ra)
+        mcu.mem
+            .program_words(&[0x00c000ef, 0x008000ef, 0x0000006f, 0x00008067])
+            .unwrap();
+        let mut sim = BranchSimulator::new(PredictorKind::NotTaken);
+        for _ in 0..4 {
+            sim.retire(&mcu.step().unwrap());
+        }
+
+        // Both calls miss in the cold BTB, both returns hit in the RAS.
+        assert_eq!(2, sim.jumps()[&12].executed);
+        assert_eq!(0, sim.jumps()[&12].mispredicted);
+        assert_eq!(2, sim.jump_total().mispredicted);
+    }
+}
@@ -0,0 +1,498 @@
+use std::cell::{Ref, RefCell};
+
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::{traits::Memory as MemoryTrait, RiscvError};
+
+use crate::{trace::AccessKind, Step};
+
+/// Which line to evict from a full set.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Replacement {
+    /// Least recently used.
+    Lru,
+    /// First in, first out.
+    Fifo,
+    /// Pseudo-random, with a fixed seed so runs are reproducible.
+    Random,
+}
+
+/// What happens on a store.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WritePolicy {
+    /// Stores only update the cache; dirty lines are written to memory
+    /// when evicted. Store misses allocate a line.
+    WriteBack,
+    /// Stores are always written to memory. Store misses do not allocate.
+    WriteThrough,
+}
+
+/// Geometry and policies of a single cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Total capacity in bytes.
+    pub size: usize,
+    /// Number of ways per set. Use `size / line_size` for a fully
+    /// associative cache.
+    pub associativity: usize,
+    /// Line size in bytes.
+    pub line_size: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    /// Cycles to go to memory on a miss or to write back a dirty line.
+    pub miss_penalty: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            size: 4096,
+            associativity: 2,
+            line_size: 16,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            miss_penalty: 10,
+        }
+    }
+}
+
+/// Kind of access seen by a cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheAccess {
+    Fetch,
+    Read,
+    Write,
+}
+
+/// Hits and misses for one kind of access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct HitMiss {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl HitMiss {
+    /// Fraction of accesses that hit, or 0 with no accesses.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+/// Counters kept by a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub fetch: HitMiss,
+    pub read: HitMiss,
+    pub write: HitMiss,
+    /// Dirty lines written back on eviction.
+    pub writebacks: u64,
+    /// Stores written straight to memory by a write-through cache.
+    pub memory_writes: u64,
+}
+
+impl CacheStats {
+    /// All accesses together.
+    pub fn total(&self) -> HitMiss {
+        HitMiss {
+            hits: self.fetch.hits + self.read.hits + self.write.hits,
+            misses: self.fetch.misses + self.read.misses + self.write.misses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    /// Last use for LRU, insertion for FIFO.
+    stamp: u64,
+}
+
+/// Model of a set-associative cache. Only tags are kept: the data always
+/// lives in the backing memory, so the cache never changes what a program
+/// computes, only how long it takes.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    stats: CacheStats,
+    clock: u64,
+    seed: u32,
+}
+
+impl Cache {
+    /// Build a cache. Sizes must be powers of two and describe at least
+    /// one set.
+    pub fn new(config: CacheConfig) -> Self {
+        assert!(config.line_size.is_power_of_two());
+        assert!(config.associativity > 0);
+        assert!(config
+            .size
+            .is_multiple_of(config.line_size * config.associativity));
+        let num_sets = config.size / (config.line_size * config.associativity);
+        assert!(num_sets.is_power_of_two());
+
+        Cache {
+            sets: vec![vec![Line::default(); config.associativity]; num_sets],
+            config,
+            stats: CacheStats::default(),
+            clock: 0,
+            seed: 0x2545f491,
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Look up an address, update the cache, and return the stall cycles
+    /// spent going to memory (0 on a hit).
+    pub fn access(&mut self, addr: u32, access: CacheAccess) -> u64 {
+        self.clock += 1;
+        let line_addr = addr / self.config.line_size as u32;
+        let index = line_addr as usize % self.sets.len();
+        let tag = line_addr / self.sets.len() as u32;
+        let write = access == CacheAccess::Write;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+
+        let counter = match access {
+            CacheAccess::Fetch => &mut self.stats.fetch,
+            CacheAccess::Read => &mut self.stats.read,
+            CacheAccess::Write => &mut self.stats.write,
+        };
+        if write && !write_back {
+            self.stats.memory_writes += 1;
+        }
+
+        let set = &mut self.sets[index];
+        if let Some(line) = set.iter_mut().find(|l| l.valid && l.tag == tag) {
+            counter.hits += 1;
+            if self.config.replacement == Replacement::Lru {
+                line.stamp = self.clock;
+            }
+            line.dirty |= write && write_back;
+            return 0;
+        }
+
+        counter.misses += 1;
+        if write && !write_back {
+            // No write-allocate; the store went to memory through a buffer.
+            return 0;
+        }
+        let mut stall = self.config.miss_penalty;
+
+        let way = match set.iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None => match self.config.replacement {
+                Replacement::Lru | Replacement::Fifo => set
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, l)| l.stamp)
+                    .map(|(i, _)| i)
+                    .unwrap(),
+                Replacement::Random => {
+                    // xorshift32
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 17;
+                    self.seed ^= self.seed << 5;
+                    self.seed as usize % set.len()
+                }
+            },
+        };
+        if set[way].valid && set[way].dirty {
+            self.stats.writebacks += 1;
+            stall += self.config.miss_penalty;
+        }
+        set[way] = Line {
+            valid: true,
+            dirty: write,
+            tag,
+            stamp: self.clock,
+        };
+        stall
+    }
+}
+
+/// Configuration of the first-level caches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HierarchyConfig {
+    /// Data cache, also used for fetches if there is no instruction cache.
+    pub data: CacheConfig,
+    /// Instruction cache, for a split (Harvard) L1.
+    pub instruction: Option<CacheConfig>,
+}
+
+/// First-level caches: either split instruction and data caches, or a
+/// single unified one.
+#[derive(Debug, Clone)]
+pub struct CacheHierarchy {
+    icache: Option<Cache>,
+    dcache: Cache,
+    stall_cycles: u64,
+}
+
+impl CacheHierarchy {
+    pub fn new(config: HierarchyConfig) -> Self {
+        CacheHierarchy {
+            icache: config.instruction.map(Cache::new),
+            dcache: Cache::new(config.data),
+            stall_cycles: 0,
+        }
+    }
+
+    /// The instruction cache, if the hierarchy is split.
+    pub fn icache(&self) -> Option<&Cache> {
+        self.icache.as_ref()
+    }
+
+    /// The data cache, or the unified cache.
+    pub fn dcache(&self) -> &Cache {
+        &self.dcache
+    }
+
+    /// Total cycles spent waiting for memory.
+    pub fn stall_cycles(&self) -> u64 {
+        self.stall_cycles
+    }
+
+    /// Route an access to the right cache and return its stall cycles.
+    pub fn access(&mut self, addr: u32, access: CacheAccess) -> u64 {
+        let stall = match (access, self.icache.as_mut()) {
+            (CacheAccess::Fetch, Some(icache)) => icache.access(addr, access),
+            _ => self.dcache.access(addr, access),
+        };
+        self.stall_cycles += stall;
+        stall
+    }
+
+    /// Replay the fetch and data accesses of an executed instruction and
+    /// return their stall cycles.
+    pub fn retire(&mut self, step: &Step) -> u64 {
+        let mut stall = self.access(step.pc, CacheAccess::Fetch);
+        for a in step.accesses.iter() {
+            stall += self.access(
+                a.addr,
+                match a.kind {
+                    AccessKind::Load => CacheAccess::Read,
+                    AccessKind::Store => CacheAccess::Write,
+                },
+            );
+        }
+        stall
+    }
+}
+
+/// Wrapper that runs every access to a memory through a cache hierarchy.
+/// This works with any `Memory`, e.g. when calling `exec_one` directly.
+pub struct CachedMemory<M: MemoryTrait> {
+    inner: M,
+    caches: RefCell<CacheHierarchy>,
+}
+
+impl<M: MemoryTrait> CachedMemory<M> {
+    pub fn new(inner: M, caches: CacheHierarchy) -> Self {
+        CachedMemory {
+            inner,
+            caches: RefCell::new(caches),
+        }
+    }
+
+    pub fn caches(&self) -> Ref<'_, CacheHierarchy> {
+        self.caches.borrow()
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (M, CacheHierarchy) {
+        (self.inner, self.caches.into_inner())
+    }
+
+    fn read(
+        &self,
+        addr: u32,
+        access: CacheAccess,
+        r: Result<u32, RiscvError>,
+    ) -> Result<u32, RiscvError> {
+        if r.is_ok() {
+            self.caches.borrow_mut().access(addr, access);
+        }
+        r
+    }
+
+    fn write(&mut self, addr: u32, r: Result<(), RiscvError>) -> Result<(), RiscvError> {
+        if r.is_ok() {
+            self.caches.get_mut().access(addr, CacheAccess::Write);
+        }
+        r
+    }
+}
+
+impl<M: MemoryTrait> MemoryTrait for CachedMemory<M> {
+    fn fetch(&self, pc: u32) -> Result<u32, RiscvError> {
+        self.read(pc, CacheAccess::Fetch, self.inner.fetch(pc))
+    }
+
+    fn read_word(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.read(addr, CacheAccess::Read, self.inner.read_word(addr))
+    }
+
+    fn read_half_word(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.read(addr, CacheAccess::Read, self.inner.read_half_word(addr))
+    }
+
+    fn read_byte(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.read(addr, CacheAccess::Read, self.inner.read_byte(addr))
+    }
+
+    fn write_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        let r = self.inner.write_word(addr, data);
+        self.write(addr, r)
+    }
+
+    fn write_half_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        let r = self.inner.write_half_word(addr, data);
+        self.write(addr, r)
+    }
+
+    fn write_byte(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        let r = self.inner.write_byte(addr, data);
+        self.write(addr, r)
+    }
+
+    fn load_reserved(&mut self, addr: u32) -> Result<u32, RiscvError> {
+        let r = self.inner.load_reserved(addr);
+        self.read(addr, CacheAccess::Read, r)
+    }
+
+    // A failed `sc.w` does not write, so it does not reach the cache.
+    fn store_conditional(&mut self, addr: u32, data: u32) -> Result<bool, RiscvError> {
+        let r = self.inner.store_conditional(addr, data);
+        if let Ok(true) = r {
+            self.caches.get_mut().access(addr, CacheAccess::Write);
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+    use lib_rv32_isa::exec_one;
+
+    use super::*;
+    use crate::{Memory, RegisterFile};
+
+    fn config(size: usize, associativity: usize, replacement: Replacement) -> CacheConfig {
+        CacheConfig {
+            size,
+            associativity,
+            line_size: 16,
+            replacement,
+            ..CacheConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_spatial_locality() {
+        let mut cache = Cache::new(CacheConfig::default());
+        assert_eq!(10, cache.access(0x100, CacheAccess::Read));
+        for addr in (0x104..0x110).step_by(4) {
+            assert_eq!(0, cache.access(addr, CacheAccess::Read));
+        }
+        assert_eq!(10, cache.access(0x110, CacheAccess::Read));
+        assert_eq!(HitMiss { hits: 3, misses: 2 }, cache.stats().read);
+    }
+
+    #[test]
+    fn test_lru_vs_fifo() {
+        // One set of two ways; 0x00, 0x20 and 0x40 all map to it.
+        for (replacement, hit) in [(Replacement::Lru, true), (Replacement::Fifo, false)] {
+            let mut cache = Cache::new(config(32, 2, replacement));
+            cache.access(0x00, CacheAccess::Read);
+            cache.access(0x20, CacheAccess::Read);
+            cache.access(0x00, CacheAccess::Read);
+            // LRU evicts 0x20, FIFO evicts 0x00.
+            cache.access(0x40, CacheAccess::Read);
+            assert_eq!(hit, cache.access(0x00, CacheAccess::Read) == 0);
+        }
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut cache = Cache::new(config(16, 1, Replacement::Lru));
+        cache.access(0x00, CacheAccess::Write);
+        assert_eq!(0, cache.stats().memory_writes);
+        // Evicting the dirty line costs a write-back on top of the miss.
+        assert_eq!(20, cache.access(0x10, CacheAccess::Read));
+        assert_eq!(1, cache.stats().writebacks);
+    }
+
+    #[test]
+    fn test_write_through() {
+        let mut cache = Cache::new(CacheConfig {
+            write_policy: WritePolicy::WriteThrough,
+            ..config(16, 1, Replacement::Lru)
+        });
+        // No write-allocate.
+        assert_eq!(0, cache.access(0x00, CacheAccess::Write));
+        assert_eq!(10, cache.access(0x00, CacheAccess::Read));
+        cache.access(0x00, CacheAccess::Write);
+        assert_eq!(10, cache.access(0x10, CacheAccess::Read));
+        assert_eq!(2, cache.stats().memory_writes);
+        assert_eq!(0, cache.stats().writebacks);
+    }
+
+    #[test]
+    fn test_random_replacement() {
+        let mut cache = Cache::new(config(64, 4, Replacement::Random));
+        for addr in (0..0x400).step_by(16) {
+            cache.access(addr, CacheAccess::Read);
+        }
+        assert_eq!(64, cache.stats().read.misses);
+    }
+
+    #[test]
+    fn test_split_cached_memory() {
+        let words = assemble_program(
+            "addi t0, zero, 0x100\n\
+             loop: lw t1, 0(t0)\n\
+             sw t1, 4(t0)\n\
+             jal zero, loop",
+        )
+        .unwrap();
+        let mut mem = Memory::new(0x1000);
+        mem.program_words(&words).unwrap();
+
+        let caches = CacheHierarchy::new(HierarchyConfig {
+            data: CacheConfig::default(),
+            instruction: Some(CacheConfig::default()),
+        });
+        let mut mem = CachedMemory::new(mem, caches);
+        let mut rf = RegisterFile::new();
+        let mut pc = 0;
+        for _ in 0..10 {
+            exec_one(&mut pc, &mut mem, &mut rf).unwrap();
+        }
+
+        let caches = mem.caches();
+        let icache = caches.icache().unwrap().stats();
+        assert_eq!(HitMiss { hits: 9, misses: 1 }, icache.fetch);
+        let dcache = caches.dcache().stats();
+        assert_eq!(HitMiss { hits: 2, misses: 1 }, dcache.read);
+        assert_eq!(HitMiss { hits: 3, misses: 0 }, dcache.write);
+        assert_eq!(20, caches.stall_cycles());
+    }
+}
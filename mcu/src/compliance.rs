@@ -0,0 +1,458 @@
+use log::info;
+
+use lib_rv32_isa::{common::parse_int, RiscvError};
+
+use crate::{
+    elf::{Elf, ElfError},
+    Host, Mcu, Memory, MemoryTrait, PAGE_SIZE,
+};
+
+/// Default number of instructions a compliance test may execute before it
+/// is considered hung.
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+/// Enumeration of possible errors when running a compliance test.
+#[derive(Debug, PartialEq)]
+pub enum ComplianceError {
+    ElfError(ElfError),
+    MissingSymbolError(String),
+    ExecutionError(u32, RiscvError),
+    InstructionLimitError(u64),
+    ReferenceFormatError(usize),
+}
+
+impl From<ElfError> for ComplianceError {
+    fn from(why: ElfError) -> Self {
+        ComplianceError::ElfError(why)
+    }
+}
+
+/// Outcome of running a compliance test to completion.
+#[derive(Debug)]
+pub struct ComplianceRun {
+    /// Words between `begin_signature` and `end_signature`, if the test
+    /// has a signature.
+    pub signature: Option<Vec<u32>>,
+    /// Value written to `tohost`, if the test halted that way.
+    pub tohost: Option<u32>,
+    /// Number of instructions executed.
+    pub instructions: u64,
+}
+
+impl ComplianceRun {
+    /// Exit code encoded in the `tohost` write (riscv-tests convention:
+    /// `(code << 1) | 1`). `Some(0)` means the test reported success.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.tohost.map(|v| v >> 1)
+    }
+}
+
+/// A word that differs between the produced and the reference signature.
+/// `None` means the signature ended before this index.
+#[derive(Debug, PartialEq)]
+pub struct SignatureMismatch {
+    pub index: usize,
+    pub expected: Option<u32>,
+    pub actual: Option<u32>,
+}
+
+/// Run a compliance test ELF until it halts and dump its signature.
+///
+/// The test halts when it exits through HTIF, by writing `(code << 1) | 1`
+/// to the `tohost` symbol, or through a syscall, or, if it has no
+/// `tohost`, when it jumps to itself (`j .`). Once the test installs a
+/// trap vector, as riscv-tests do, its `ecall`s go there rather than to
+/// the host, so the vector can report the result through `tohost`. The signature
+/// region is delimited by the `begin_signature` and `end_signature` symbols.
+/// Tests without them, such as riscv-tests, only report their result
+/// through `tohost`, which they must then have.
+///
+/// `mem_size` bytes are mapped from the start of the page the ELF is
+/// loaded at, so tests linked at `0x80000000` run as they are.
+pub fn run_compliance_test(
+    elf: &Elf,
+    mem_size: usize,
+    max_instructions: u64,
+) -> Result<ComplianceRun, ComplianceError> {
+    let region = match (elf.symbol("begin_signature"), elf.symbol("end_signature")) {
+        (Some(begin), Some(end)) => Some((begin, end)),
+        (Some(_), None) => {
+            return Err(ComplianceError::MissingSymbolError(
+                "end_signature".to_string(),
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(ComplianceError::MissingSymbolError(
+                "begin_signature".to_string(),
+            ))
+        }
+        (None, None) => None,
+    };
+    let tohost_addr = elf.symbol("tohost");
+    if region.is_none() && tohost_addr.is_none() {
+        return Err(ComplianceError::MissingSymbolError("tohost".to_string()));
+    }
+
+    let base = elf.load_base() & !(PAGE_SIZE as u32 - 1);
+    let size = (mem_size as u64).min((1 << 32) - base as u64) as u32;
+    let mut mcu = Mcu::with_memory(Memory::sparse().with_region(base, size));
+    mcu.program_elf(elf)
+        .map_err(|why| ComplianceError::ExecutionError(elf.entry, why))?;
+    let mut host = Host::new().capture_output();
+    if let Some(addr) = tohost_addr {
+        host = host.with_htif(addr, elf.symbol("fromhost"));
+    }
+
+    let mut tohost = None;
+
+    loop {
+        if mcu.instructions >= max_instructions {
+            return Err(ComplianceError::InstructionLimitError(mcu.instructions));
+        }
+
+        let pc = mcu.pc;
+        mcu.step_with_host(&mut host)
+            .map_err(|why| ComplianceError::ExecutionError(pc, why))?;
+
+        if let Some(code) = host.exit_code() {
+            info!("\nStopping because the test exited with {}.\n", code);
+            if let Some(addr) = tohost_addr {
+                tohost = mcu.mem.fetch(addr).ok().filter(|v| *v != 0);
+            }
+            break;
+        } else if tohost_addr.is_none() && mcu.pc == pc {
+            info!("\nStopping because of a self-loop at 0x{:x}.\n", pc);
+            break;
+        }
+    }
+
+    let signature = match region {
+        Some((begin, end)) => Some(
+            (begin..end)
+                .step_by(4)
+                .map(|addr| mcu.mem.fetch(addr))
+                .collect::<Result<Vec<u32>, RiscvError>>()
+                .map_err(|why| ComplianceError::ExecutionError(mcu.pc, why))?,
+        ),
+        None => None,
+    };
+
+    Ok(ComplianceRun {
+        signature,
+        tohost,
+        instructions: mcu.instructions,
+    })
+}
+
+/// Parse a reference signature file. Each line contains one or more
+/// 32-bit words in hex, with the lowest-addressed word right-most (the
+/// format written by Spike and Sail with any signature granularity).
+pub fn parse_signature(text: &str) -> Result<Vec<u32>, ComplianceError> {
+    let mut words = Vec::new();
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let line = line
+            .strip_prefix("0x")
+            .or_else(|| line.strip_prefix("0X"))
+            .unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() % 8 != 0 {
+            return Err(ComplianceError::ReferenceFormatError(line_num + 1));
+        }
+        for chunk in line.as_bytes().rchunks(8) {
+            let hex = std::str::from_utf8(chunk).unwrap();
+            match parse_int!(u32, format!("0x{}", hex)) {
+                Ok(w) => words.push(w),
+                Err(_) => return Err(ComplianceError::ReferenceFormatError(line_num + 1)),
+            }
+        }
+    }
+    Ok(words)
+}
+
+/// Format a signature in the reference format, one word per line.
+pub fn format_signature(signature: &[u32]) -> String {
+    signature.iter().map(|w| format!("{:08x}\n", w)).collect()
+}
+
+/// Compare a produced signature with a reference signature.
+pub fn compare_signatures(actual: &[u32], expected: &[u32]) -> Vec<SignatureMismatch> {
+    (0..actual.len().max(expected.len()))
+        .filter_map(|index| {
+            let a = actual.get(index).copied();
+            let e = expected.get(index).copied();
+            if a == e {
+                None
+            } else {
+                Some(SignatureMismatch {
+                    index,
+                    expected: e,
+                    actual: a,
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use glob::glob;
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::elf::test_util::build_elf;
+
+    const MEM_SIZE: usize = 0x1000;
+
+    fn assemble_elf(program: &str, symbols: &[(&str, u32)]) -> Elf {
+        assemble_elf_at(0, program, symbols)
+    }
+
+    fn assemble_elf_at(base: u32, program: &str, symbols: &[(&str, u32)]) -> Elf {
+        words_elf(base, &assemble_program(program).unwrap(), symbols)
+    }
+
+    fn words_elf(base: u32, words: &[u32], symbols: &[(&str, u32)]) -> Elf {
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect();
+        Elf::parse(&build_elf(base, &bytes, symbols)).unwrap()
+    }
+
+    #[test]
+    fn test_tohost_halt() {
+        let elf = assemble_elf(
+            "addi t0, zero, 0x40\n\
+             addi t1, zero, 17\n\
+             sw t1, 0(t0)\n\
+             addi t1, t1, -20\n\
+             sw t1, 4(t0)\n\
+             addi t2, zero, 1\n\
+             sw t2, 64(t0)\n\
+             loop: jal zero, loop",
+            &[
+                ("begin_signature", 0x40),
+                ("end_signature", 0x48),
+                ("tohost", 0x80),
+            ],
+        );
+
+        let run = run_compliance_test(&elf, MEM_SIZE, 100).unwrap();
+        assert_eq!(Some(vec![17, -3i32 as u32]), run.signature);
+        assert_eq!(Some(0), run.exit_code());
+        // The write is serviced after the instruction following it.
+        assert_eq!(8, run.instructions);
+    }
+
+    #[test]
+    fn test_ram_base() {
+        let elf = assemble_elf_at(
+            0x8000_0000,
+            "auipc t0, 0\n\
+             addi t1, zero, 17\n\
+             sw t1, 0x40(t0)\n\
+             addi t2, zero, 1\n\
+             sw t2, 0x80(t0)\n\
+             loop: jal zero, loop",
+            &[
+                ("begin_signature", 0x8000_0040),
+                ("end_signature", 0x8000_0044),
+                ("tohost", 0x8000_0080),
+            ],
+        );
+
+        let run = run_compliance_test(&elf, MEM_SIZE, 100).unwrap();
+        assert_eq!(Some(vec![17]), run.signature);
+        assert_eq!(Some(0), run.exit_code());
+    }
+
+    #[test]
+    fn test_self_loop_halt() {
+        let elf = assemble_elf(
+            "addi t1, zero, 5\n\
+             sw t1, 0x40(zero)\n\
+             loop: jal zero, loop",
+            &[("begin_signature", 0x40), ("end_signature", 0x44)],
+        );
+
+        let run = run_compliance_test(&elf, MEM_SIZE, 100).unwrap();
+        assert_eq!(Some(vec![5]), run.signature);
+        assert_eq!(None, run.tohost);
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let elf = assemble_elf(
+            "loop: addi t0, t0, 1\n\
+             jal zero, loop",
+            &[("begin_signature", 0x40), ("end_signature", 0x44)],
+        );
+
+        assert_eq!(
+            ComplianceError::InstructionLimitError(10),
+            run_compliance_test(&elf, MEM_SIZE, 10).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_tohost_only() {
+        // A failing test writes its test number, shifted, to tohost.
+        for (code, exit_code) in [(1, 0), (7, 3)] {
+            let elf = assemble_elf(
+                &format!(
+                    "addi t1, zero, {}\n\
+                     sw t1, 0x80(zero)\n\
+                     loop: jal zero, loop",
+                    code
+                ),
+                &[("tohost", 0x80)],
+            );
+
+            let run = run_compliance_test(&elf, MEM_SIZE, 100).unwrap();
+            assert_eq!(None, run.signature);
+            assert_eq!(Some(exit_code), run.exit_code());
+        }
+    }
+
+    #[test]
+    fn test_riscv_tests_handler() {
+        // RVTEST_PASS and RVTEST_FAIL make an exit ecall, which the trap
+        // vector turns into a tohost write.
+        for (gp, exit_code) in [(1, 0), (7, 3)] {
+            let mut words = assemble_program("addi t0, zero, 0x40").unwrap();
+            // csrrw zero, mtvec, t0
+            words.push(0x30529073);
+            words.extend(
+                assemble_program(&format!(
+                    "addi gp, zero, {}\n\
+                     addi a7, zero, 93\n\
+                     addi a0, zero, 0\n\
+                     ecall",
+                    gp
+                ))
+                .unwrap(),
+            );
+            words.resize(0x10, 0);
+            words.extend(assemble_program("sw gp, 0x80(zero)\nloop: jal zero, loop").unwrap());
+            let elf = words_elf(0, &words, &[("tohost", 0x80)]);
+
+            let run = run_compliance_test(&elf, MEM_SIZE, 100).unwrap();
+            assert_eq!(Some(gp), run.tohost);
+            assert_eq!(Some(exit_code), run.exit_code());
+        }
+    }
+
+    #[test]
+    fn test_missing_symbols() {
+        let elf = assemble_elf("addi t0, t0, 1", &[]);
+        assert_eq!(
+            ComplianceError::MissingSymbolError("tohost".to_string()),
+            run_compliance_test(&elf, MEM_SIZE, 10).unwrap_err()
+        );
+        let elf = assemble_elf("addi t0, t0, 1", &[("begin_signature", 0x40)]);
+        assert_eq!(
+            ComplianceError::MissingSymbolError("end_signature".to_string()),
+            run_compliance_test(&elf, MEM_SIZE, 10).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_signature() {
+        assert_eq!(
+            vec![0xdeadbeef, 0x00000001],
+            parse_signature("deadbeef\n00000001\n").unwrap()
+        );
+        assert_eq!(
+            vec![0x00000002, 0x00000001],
+            parse_signature("0000000100000002\n").unwrap()
+        );
+        assert_eq!(
+            ComplianceError::ReferenceFormatError(2),
+            parse_signature("00000000\n123\n").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_compare_signatures() {
+        assert!(compare_signatures(&[1, 2], &[1, 2]).is_empty());
+        assert_eq!(
+            vec![
+                SignatureMismatch {
+                    index: 1,
+                    expected: Some(3),
+                    actual: Some(2)
+                },
+                SignatureMismatch {
+                    index: 2,
+                    expected: Some(4),
+                    actual: None
+                }
+            ],
+            compare_signatures(&[1, 2], &[1, 3, 4])
+        );
+    }
+
+    /// Run every vendored `compliance/**/*.elf` against the
+    /// `.reference_output` next to it, or, for tests without a signature,
+    /// check that they pass through `tohost`. The suite's ELFs are not part
+    /// of the repository, so this only runs with `--ignored`, and fails if
+    /// none were vendored.
+    #[test]
+    #[ignore = "needs the suite's ELFs vendored into mcu/compliance/"]
+    fn test_compliance_suite() {
+        let mut pass = true;
+        let mut count = 0;
+        for elf_path in glob("./compliance/**/*.elf").unwrap().map(|p| p.unwrap()) {
+            count += 1;
+            let result = Elf::from_file(&elf_path)
+                .map_err(ComplianceError::from)
+                .and_then(|elf| run_compliance_test(&elf, 0x100000, DEFAULT_MAX_INSTRUCTIONS));
+
+            match result {
+                Ok(run) if run.signature.is_none() => {
+                    if run.exit_code() == Some(0) {
+                        eprintln!("{}... ok", elf_path.display());
+                    } else {
+                        pass = false;
+                        eprintln!(
+                            "{}... failed with tohost {:x?}",
+                            elf_path.display(),
+                            run.tohost
+                        );
+                    }
+                }
+                Ok(run) => {
+                    let reference_path = elf_path.with_extension("reference_output");
+                    if !Path::new(&reference_path).exists() {
+                        pass = false;
+                        eprintln!("{}... no reference signature", elf_path.display());
+                        continue;
+                    }
+                    let expected =
+                        parse_signature(&fs::read_to_string(&reference_path).unwrap()).unwrap();
+                    let mismatches = compare_signatures(&run.signature.unwrap(), &expected);
+                    if mismatches.is_empty() {
+                        eprintln!("{}... ok", elf_path.display());
+                    } else {
+                        pass = false;
+                        eprintln!("{}... signature mismatch:", elf_path.display());
+                        for m in mismatches {
+                            eprintln!("    [{}] {:x?} != {:x?}", m.index, m.actual, m.expected);
+                        }
+                    }
+                }
+                Err(why) => {
+                    pass = false;
+                    eprintln!("{}... {:?}", elf_path.display(), why);
+                }
+            }
+        }
+        assert!(count > 0, "No compliance ELFs found in mcu/compliance/.");
+        assert!(pass);
+    }
+}
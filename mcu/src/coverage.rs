@@ -0,0 +1,260 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use lib_rv32_isa::{disassemble, InstructionClass};
+
+use crate::{lines::LineTable, Memory, Step};
+
+/// Directions taken by a conditional branch.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCoverage {
+    /// Whether both directions were exercised.
+    pub fn is_full(&self) -> bool {
+        self.taken > 0 && self.not_taken > 0
+    }
+}
+
+/// Coverage of a source line: the count of its most executed instruction
+/// and its conditional branches (`None` if never executed).
+#[derive(Default)]
+struct LineCoverage {
+    count: u64,
+    branches: Vec<Option<BranchCoverage>>,
+}
+
+/// Executed instructions and branch directions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    hits: BTreeMap<u32, u64>,
+    branches: BTreeMap<u32, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Record an executed instruction.
+    pub fn retire(&mut self, step: &Step) {
+        *self.hits.entry(step.pc).or_default() += 1;
+        if InstructionClass::of(step.ir) == InstructionClass::Branch {
+            let branch = self.branches.entry(step.pc).or_default();
+            match step.next_pc == step.pc.wrapping_add(4) {
+                true => branch.not_taken += 1,
+                false => branch.taken += 1,
+            }
+        }
+    }
+
+    /// Times each executed address was executed.
+    pub fn hits(&self) -> &BTreeMap<u32, u64> {
+        &self.hits
+    }
+
+    /// Directions of each executed branch.
+    pub fn branches(&self) -> &BTreeMap<u32, BranchCoverage> {
+        &self.branches
+    }
+
+    /// Times the instruction at `addr` was executed.
+    pub fn count(&self, addr: u32) -> u64 {
+        self.hits.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Coverage in lcov's tracefile format. Every line in `lines` is
+    /// reported, with the count of its most executed instruction, and
+    /// every conditional branch gets a taken and a not-taken entry.
+    /// `mem` is used to find the branches that never executed.
+    pub fn lcov(&self, lines: &LineTable, mem: &Memory) -> String {
+        let mut files: BTreeMap<usize, BTreeMap<u32, LineCoverage>> = BTreeMap::new();
+        for (start, end, row) in lines.ranges() {
+            let entry = files
+                .entry(row.file)
+                .or_default()
+                .entry(row.line)
+                .or_default();
+            entry.count = entry.count.max(
+                self.hits
+                    .range(start..end)
+                    .map(|(_, n)| *n)
+                    .max()
+                    .unwrap_or(0),
+            );
+            for addr in (start..end).step_by(4) {
+                match mem.peek(addr, 4) {
+                    Ok(ir) if InstructionClass::of(ir) == InstructionClass::Branch => {
+                        entry.branches.push(self.branches.get(&addr).copied())
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut out = String::new();
+        for (file, lines_of_file) in files.iter() {
+            writeln!(out, "TN:").unwrap();
+            writeln!(out, "SF:{}", lines.files()[*file]).unwrap();
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (line, l) in lines_of_file.iter() {
+                for (block, branch) in l.branches.iter().enumerate() {
+                    for (i, count) in [branch.map(|b| b.taken), branch.map(|b| b.not_taken)]
+                        .iter()
+                        .enumerate()
+                    {
+                        branches_found += 1;
+                        match count {
+                            Some(n) => {
+                                branches_hit += (*n > 0) as u32;
+                                writeln!(out, "BRDA:{},{},{},{}", line, block, i, n).unwrap();
+                            }
+                            None => writeln!(out, "BRDA:{},{},{},-", line, block, i).unwrap(),
+                        }
+                    }
+                }
+            }
+            writeln!(out, "BRF:{}", branches_found).unwrap();
+            writeln!(out, "BRH:{}", branches_hit).unwrap();
+            for (line, l) in lines_of_file.iter() {
+                writeln!(out, "DA:{},{}", line, l.count).unwrap();
+            }
+            writeln!(out, "LF:{}", lines_of_file.len()).unwrap();
+            writeln!(
+                out,
+                "LH:{}",
+                lines_of_file.values().filter(|l| l.count > 0).count()
+            )
+            .unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    /// Disassembly of `start..end` with the execution count of every
+    /// instruction (`#####` if it never ran), the directions taken by
+    /// branches, and source lines if a line table is given.
+    pub fn annotate(
+        &self,
+        mem: &Memory,
+        start: u32,
+        end: u32,
+        lines: Option<&LineTable>,
+    ) -> String {
+        let mut out = String::new();
+        let mut last_line = None;
+        for addr in (start..end).step_by(4) {
+            let ir = match mem.peek(addr, 4) {
+                Ok(ir) => ir,
+                Err(_) => break,
+            };
+            if let Some(line) = lines.and_then(|l| l.lookup(addr)) {
+                if last_line != Some(line) {
+                    writeln!(out, "{}:{}", line.0, line.1).unwrap();
+                    last_line = Some(line);
+                }
+            }
+            let count = match self.count(addr) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            let mut text = format!(
+                "{:>9}  {:08x}  {:08x}  {:24}",
+                count,
+                addr,
+                ir,
+                disassemble(ir)
+            );
+            if let Some(b) = self.branches.get(&addr) {
+                write!(text, "  taken {}, not taken {}", b.taken, b.not_taken).unwrap();
+            }
+            writeln!(out, "{}", text.trim_end()).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program_with_lines;
+
+    use super::*;
+    use crate::Mcu;
+
+    const PROGRAM: &str = "addi t0, zero, 3\n\
+                           addi t0, t0, -1\n\
+                           \n\
+                           bne t0, zero, -4\n\
+                           beq t0, zero, 8\n\
+                           addi t1, zero, 1\n\
+                           jal zero, 0";
+
+    fn run() -> (Mcu, Coverage, LineTable) {
+        let (words, lines) = assemble_program_with_lines(PROGRAM).unwrap();
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_words(&words).unwrap();
+        let mut coverage = Coverage::new();
+        for _ in 0..10 {
+            coverage.retire(&mcu.step().unwrap());
+        }
+        (mcu, coverage, LineTable::from_listing("loop.s", &lines))
+    }
+
+    #[test]
+    fn test_counts() {
+        let (_, coverage, _) = run();
+        assert_eq!(3, coverage.count(4));
+        assert_eq!(0, coverage.count(16));
+        assert_eq!(
+            BranchCoverage {
+                taken: 2,
+                not_taken: 1
+            },
+            coverage.branches()[&8]
+        );
+        assert!(coverage.branches()[&8].is_full());
+        assert!(!coverage.branches()[&12].is_full());
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (mcu, coverage, lines) = run();
+        assert_eq!(
+            "TN:\n\
+             SF:loop.s\n\
+             BRDA:4,0,0,2\n\
+             BRDA:4,0,1,1\n\
+             BRDA:5,0,0,1\n\
+             BRDA:5,0,1,0\n\
+             BRF:4\n\
+             BRH:3\n\
+             DA:1,1\n\
+             DA:2,3\n\
+             DA:4,3\n\
+             DA:5,1\n\
+             DA:6,0\n\
+             DA:7,2\n\
+             LF:6\n\
+             LH:5\n\
+             end_of_record\n",
+            coverage.lcov(&lines, &mcu.mem)
+        );
+    }
+
+    #[test]
+    fn test_annotate() {
+        let (mcu, coverage, lines) = run();
+        let text = coverage.annotate(&mcu.mem, 8, 20, Some(&lines));
+        let expected = [
+            "loop.s:4",
+            "        3  00000008  fe029ee3  bne t0, zero, -4          taken 2, not taken 1",
+            "loop.s:5",
+            "        1  0000000c  00028463  beq t0, zero, 8           taken 1, not taken 0",
+            "loop.s:6",
+            "    #####  00000010  00100313  addi t1, zero, 1",
+        ];
+        assert_eq!(expected.join("\n") + "\n", text);
+    }
+}
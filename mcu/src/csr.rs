@@ -0,0 +1,435 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::{InstructionClass, RiscvError};
+
+use crate::{trace::AccessKind, Step};
+
+pub const CSR_MCOUNTINHIBIT: u16 = 0x320;
+pub const CSR_MHPMEVENT3: u16 = 0x323;
+pub const CSR_MCYCLE: u16 = 0xb00;
+pub const CSR_MINSTRET: u16 = 0xb02;
+pub const CSR_MHPMCOUNTER3: u16 = 0xb03;
+pub const CSR_MCYCLEH: u16 = 0xb80;
+pub const CSR_CYCLE: u16 = 0xc00;
+pub const CSR_TIME: u16 = 0xc01;
+pub const CSR_INSTRET: u16 = 0xc02;
+pub const CSR_HPMCOUNTER3: u16 = 0xc03;
+pub const CSR_CYCLEH: u16 = 0xc80;
+
+/// Number of programmable counters, `mhpmcounter3` to `mhpmcounter31`.
+pub const HPM_COUNTERS: usize = 29;
+
+/// Event counted by a programmable counter. The discriminant is the value
+/// written to `mhpmeventN` to select it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HpmEvent {
+    #[default]
+    None = 0,
+    Loads = 1,
+    Stores = 2,
+    Branches = 3,
+    TakenBranches = 4,
+    Jumps = 5,
+    CacheMisses = 6,
+    Traps = 7,
+}
+
+impl HpmEvent {
+    pub const ALL: [HpmEvent; 8] = [
+        HpmEvent::None,
+        HpmEvent::Loads,
+        HpmEvent::Stores,
+        HpmEvent::Branches,
+        HpmEvent::TakenBranches,
+        HpmEvent::Jumps,
+        HpmEvent::CacheMisses,
+        HpmEvent::Traps,
+    ];
+
+    /// Event selected by an `mhpmeventN` value. Unknown selectors count
+    /// nothing, and read back as zero.
+    pub fn from_code(code: u32) -> Self {
+        HpmEvent::ALL
+            .get(code as usize)
+            .copied()
+            .unwrap_or(HpmEvent::None)
+    }
+
+    /// Parse an event name such as `taken-branches`.
+    pub fn parse(name: &str) -> Option<Self> {
+        HpmEvent::ALL
+            .iter()
+            .copied()
+            .find(|e| e.to_string() == name)
+    }
+}
+
+impl fmt::Display for HpmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HpmEvent::None => "none",
+            HpmEvent::Loads => "loads",
+            HpmEvent::Stores => "stores",
+            HpmEvent::Branches => "branches",
+            HpmEvent::TakenBranches => "taken-branches",
+            HpmEvent::Jumps => "jumps",
+            HpmEvent::CacheMisses => "cache-misses",
+            HpmEvent::Traps => "traps",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Machine counter CSRs: `mcycle`, `minstret`, `mhpmcounter3..31`, their
+/// event selectors, `mcountinhibit`, and the read-only user views
+/// `cycle`, `time`, `instret` and `hpmcounterN`.
+///
+/// Every retired instruction advances `mcycle` by one. Hosts with a timing
+/// model account for the remaining cycles with `add_cycles`, and report
+/// events the MCU cannot see (cache misses, exceptions the host handled)
+/// with `record`. `time` ticks with `mcycle`. An instruction that traps
+/// counts a cycle and a trap, but does not advance `minstret`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CsrFile {
+    cycle: u64,
+    instret: u64,
+    counters: [u64; HPM_COUNTERS],
+    events: [HpmEvent; HPM_COUNTERS],
+    inhibit: u32,
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        CsrFile::default()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+
+    /// Value of `mhpmcounterN`, for `n` in `3..=31`.
+    pub fn counter(&self, n: usize) -> u64 {
+        self.counters[n - 3]
+    }
+
+    /// Event counted by `mhpmcounterN`, for `n` in `3..=31`.
+    pub fn event(&self, n: usize) -> HpmEvent {
+        self.events[n - 3]
+    }
+
+    /// Select the event counted by `mhpmcounterN`, for `n` in `3..=31`.
+    pub fn set_event(&mut self, n: usize, event: HpmEvent) {
+        self.events[n - 3] = event;
+    }
+
+    /// Programmable counters with an event selected, as
+    /// `(n, event, count)`.
+    pub fn active_counters(&self) -> impl Iterator<Item = (usize, HpmEvent, u64)> + '_ {
+        (3..3 + HPM_COUNTERS)
+            .map(move |n| (n, self.event(n), self.counter(n)))
+            .filter(|(_, e, _)| *e != HpmEvent::None)
+    }
+
+    /// Add cycles spent beyond the one per instruction that is counted
+    /// on retirement, e.g. stalls estimated by a timing model.
+    pub fn add_cycles(&mut self, n: u64) {
+        if self.inhibit & 1 == 0 {
+            self.cycle = self.cycle.wrapping_add(n);
+        }
+    }
+
+    /// Count `n` occurrences of `event` on every counter selecting it.
+    pub fn record(&mut self, event: HpmEvent, n: u64) {
+        self.count(event, n as i64);
+    }
+
+    fn count(&mut self, event: HpmEvent, n: i64) {
+        if event == HpmEvent::None || n == 0 {
+            return;
+        }
+        for i in 0..HPM_COUNTERS {
+            if self.events[i] == event && self.inhibit & (1 << (i + 3)) == 0 {
+                self.counters[i] = self.counters[i].wrapping_add(n as u64);
+            }
+        }
+    }
+
+    /// Account for an instruction executed by `Mcu::step`. A counter the
+    /// instruction wrote itself is not advanced, so the written value is
+    /// what the next instruction reads.
+    pub(crate) fn retire(&mut self, step: &Step) {
+        self.advance(step, 1);
+    }
+
+    /// Reverse `retire` when stepping back over `step`.
+    pub(crate) fn unretire(&mut self, step: &Step) {
+        self.advance(step, -1);
+    }
+
+    fn advance(&mut self, step: &Step, n: i64) {
+        let written = step.csr_write.map(|w| w.csr & 0xff7f);
+
+        if self.inhibit & 1 == 0 && written != Some(CSR_MCYCLE) {
+            self.cycle = self.cycle.wrapping_add(n as u64);
+        }
+        // An instruction that trapped did not retire.
+        if self.inhibit & 0b100 == 0 && written != Some(CSR_MINSTRET) && step.trap.is_none() {
+            self.instret = self.instret.wrapping_add(n as u64);
+        }
+        if step.trap.is_some() {
+            self.count(HpmEvent::Traps, n);
+        }
+
+        // Reverting the counter a CSR instruction wrote is left to the
+        // restored write, so keep it out of the event counts too.
+        let saved = written
+            .filter(|csr| (CSR_MHPMCOUNTER3..CSR_MHPMCOUNTER3 + HPM_COUNTERS as u16).contains(csr))
+            .map(|csr| (csr - CSR_MHPMCOUNTER3) as usize)
+            .map(|i| (i, self.counters[i]));
+
+        let loads = step
+            .accesses
+            .iter()
+            .filter(|a| a.kind == AccessKind::Load)
+            .count() as i64;
+        let stores = step.accesses.len() as i64 - loads;
+        self.count(HpmEvent::Loads, loads * n);
+        self.count(HpmEvent::Stores, stores * n);
+        match InstructionClass::of(step.ir) {
+            InstructionClass::Branch => {
+                self.count(HpmEvent::Branches, n);
+                if step.next_pc != step.pc.wrapping_add(4) {
+                    self.count(HpmEvent::TakenBranches, n);
+                }
+            }
+            InstructionClass::Jump => self.count(HpmEvent::Jumps, n),
+            _ => (),
+        }
+
+        if let Some((i, value)) = saved {
+            self.counters[i] = value;
+        }
+    }
+
+    /// Read a counter CSR.
+    pub fn read(&self, csr: u16) -> Result<u32, RiscvError> {
+        let value = match csr {
+            CSR_MCOUNTINHIBIT => return Ok(self.inhibit),
+            _ if (CSR_MHPMEVENT3..CSR_MHPMEVENT3 + HPM_COUNTERS as u16).contains(&csr) => {
+                return Ok(self.events[(csr - CSR_MHPMEVENT3) as usize] as u32)
+            }
+            _ => self.counter_csr(csr)?,
+        };
+        // The upper halves are at +0x80.
+        Ok(match csr & 0x80 {
+            0 => value as u32,
+            _ => (value >> 32) as u32,
+        })
+    }
+
+    /// Write a counter CSR. The user views are read-only.
+    pub fn write(&mut self, csr: u16, data: u32) -> Result<(), RiscvError> {
+        let reg = match csr {
+            CSR_MCOUNTINHIBIT => {
+                // Bit 1 (time) is hardwired to zero.
+                self.inhibit = data & !0b10;
+                return Ok(());
+            }
+            _ if (CSR_MHPMEVENT3..CSR_MHPMEVENT3 + HPM_COUNTERS as u16).contains(&csr) => {
+                self.events[(csr - CSR_MHPMEVENT3) as usize] = HpmEvent::from_code(data);
+                return Ok(());
+            }
+            _ if csr & 0xff00 != 0xb00 => return Err(RiscvError::InvalidCsrError(csr)),
+            _ => {
+                self.counter_csr(csr)?;
+                match csr & 0xff7f {
+                    CSR_MCYCLE => &mut self.cycle,
+                    CSR_MINSTRET => &mut self.instret,
+                    c => &mut self.counters[(c - CSR_MHPMCOUNTER3) as usize],
+                }
+            }
+        };
+        *reg = match csr & 0x80 {
+            0 => (*reg & !0xffff_ffff) | data as u64,
+            _ => (*reg & 0xffff_ffff) | (data as u64) << 32,
+        };
+        Ok(())
+    }
+
+    /// Full value of a counter CSR or its upper half.
+    fn counter_csr(&self, csr: u16) -> Result<u64, RiscvError> {
+        match csr {
+            0xb00..=0xb1f | 0xb80..=0xb9f | 0xc00..=0xc1f | 0xc80..=0xc9f => (),
+            _ => return Err(RiscvError::InvalidCsrError(csr)),
+        }
+        match csr & 0x1f {
+            0 => Ok(self.cycle),
+            1 if csr & 0xff00 == 0xc00 => Ok(self.cycle),
+            1 => Err(RiscvError::InvalidCsrError(csr)),
+            2 => Ok(self.instret),
+            n => Ok(self.counters[n as usize - 3]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{Mcu, RegisterFileTrait};
+
+    /// Encode a CSR instruction, which the assembler does not support.
+    fn csr_op(func3: u32, rd: u32, csr: u16, rs1: u32) -> u32 {
+        (csr as u32) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | 0b1110011
+    }
+
+    fn mcu_with_words(words: &[u32]) -> Mcu {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_words(words).unwrap();
+        mcu
+    }
+
+    #[test]
+    fn test_read_counters() {
+        let mut words = assemble_program("addi t0, zero, 1\naddi t0, t0, 1").unwrap();
+        // csrrs a0, cycle, zero; csrrs a1, instret, zero
+        words.push(csr_op(0b010, 10, CSR_CYCLE, 0));
+        words.push(csr_op(0b010, 11, CSR_INSTRET, 0));
+        let mut mcu = mcu_with_words(&words);
+        mcu.rf.csrs.add_cycles(10);
+        for _ in 0..4 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(12, mcu.rf.read(10).unwrap());
+        assert_eq!(3, mcu.rf.read(11).unwrap());
+        assert_eq!(14, mcu.rf.csrs.cycles());
+        assert_eq!(4, mcu.rf.csrs.instret());
+    }
+
+    #[test]
+    fn test_events() {
+        let mut words = vec![
+            // csrrwi zero, mhpmevent3, loads; csrrwi zero, mhpmevent4, taken-branches
+            csr_op(0b101, 0, CSR_MHPMEVENT3, HpmEvent::Loads as u32),
+            csr_op(0b101, 0, CSR_MHPMEVENT3 + 1, HpmEvent::TakenBranches as u32),
+        ];
+        words.extend(
+            assemble_program(
+                "lw t0, 0x100(zero)\n\
+                 sw t0, 0x104(zero)\n\
+                 beq zero, zero, 8\n\
+                 lw t0, 0x100(zero)\n\
+                 lw t0, 0x100(zero)",
+            )
+            .unwrap(),
+        );
+        let mut mcu = mcu_with_words(&words);
+        for _ in 0..6 {
+            mcu.step().unwrap();
+        }
+        mcu.rf.csrs.record(HpmEvent::Loads, 5);
+        assert_eq!(HpmEvent::Loads, mcu.rf.csrs.event(3));
+        assert_eq!(7, mcu.rf.csrs.counter(3));
+        assert_eq!(1, mcu.rf.csrs.counter(4));
+        assert_eq!(Ok(7), mcu.rf.read_csr(CSR_HPMCOUNTER3));
+        assert_eq!(
+            vec![(3, HpmEvent::Loads, 7), (4, HpmEvent::TakenBranches, 1)],
+            mcu.rf.csrs.active_counters().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_write_counter() {
+        let mut mcu = mcu_with_words(&[
+            // csrrw zero, mcycleh, t0; csrrw zero, mcycle, zero; csrrs a0, cycleh, zero
+            csr_op(0b001, 0, CSR_MCYCLEH, 5),
+            csr_op(0b001, 0, CSR_MCYCLE, 0),
+            csr_op(0b010, 10, CSR_CYCLEH, 0),
+        ]);
+        mcu.rf.write(5, 2).unwrap();
+        for _ in 0..3 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(2, mcu.rf.read(10).unwrap());
+        // The write to mcycle is not followed by an increment.
+        assert_eq!(2 << 32 | 1, mcu.rf.csrs.cycles());
+    }
+
+    #[test]
+    fn test_inhibit() {
+        let mut mcu = mcu_with_words(&[
+            // csrrwi zero, mcountinhibit, 0b111; addi zero, zero, 0
+            csr_op(0b101, 0, CSR_MCOUNTINHIBIT, 0b111),
+            0x00000013,
+        ]);
+        mcu.step().unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0, mcu.rf.csrs.cycles());
+        assert_eq!(0, mcu.rf.csrs.instret());
+        assert_eq!(Ok(0b101), mcu.rf.csrs.read(CSR_MCOUNTINHIBIT));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(
+            Err(RiscvError::InvalidCsrError(CSR_CYCLE)),
+            csrs.write(CSR_CYCLE, 0)
+        );
+        assert_eq!(Err(RiscvError::InvalidCsrError(0xb01)), csrs.read(0xb01));
+        assert_eq!(Err(RiscvError::InvalidCsrError(0x7c0)), csrs.read(0x7c0));
+        assert_eq!(Ok(0), csrs.read(CSR_TIME));
+
+        let mut mcu = mcu_with_words(&[csr_op(0b001, 0, CSR_INSTRET, 0)]);
+        assert_eq!(
+            Err(RiscvError::InvalidCsrError(CSR_INSTRET)),
+            mcu.step().map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut words = vec![
+            csr_op(0b101, 0, CSR_MHPMEVENT3, HpmEvent::Stores as u32),
+            csr_op(0b101, 0, CSR_MHPMCOUNTER3, 9),
+        ];
+        words.extend(assemble_program("sw zero, 0x100(zero)").unwrap());
+        let mut mcu = mcu_with_words(&words);
+        mcu.enable_undo(8);
+        for _ in 0..3 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(10, mcu.rf.csrs.counter(3));
+
+        mcu.step_back().unwrap();
+        assert_eq!(9, mcu.rf.csrs.counter(3));
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.rf.csrs.counter(3));
+        assert_eq!(1, mcu.rf.csrs.cycles());
+        mcu.step_back().unwrap();
+        assert_eq!(CsrFile::new(), mcu.rf.csrs);
+
+        for _ in 0..3 {
+            mcu.replay().unwrap();
+        }
+        assert_eq!(10, mcu.rf.csrs.counter(3));
+        assert_eq!(3, mcu.rf.csrs.instret());
+    }
+
+    #[test]
+    fn test_event_names() {
+        for event in HpmEvent::ALL.iter() {
+            assert_eq!(Some(*event), HpmEvent::parse(&event.to_string()));
+            assert_eq!(*event, HpmEvent::from_code(*event as u32));
+        }
+        assert_eq!(HpmEvent::None, HpmEvent::from_code(100));
+    }
+}
@@ -0,0 +1,391 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+/// Segment flag: executable.
+pub const PF_X: u32 = 1;
+/// Segment flag: writable.
+pub const PF_W: u32 = 2;
+/// Segment flag: readable.
+pub const PF_R: u32 = 4;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// Enumeration of possible errors when parsing an ELF file.
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    BadMagicError,
+    UnsupportedClassError,
+    UnsupportedEndiannessError,
+    UnsupportedMachineError(u16),
+    UnsupportedDwarfVersionError(u16),
+    TruncatedError,
+    IOError,
+}
+
+/// The kind of entity a symbol refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+/// An entry of the ELF symbol table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+/// A loadable segment. Bytes between `data.len()` and `mem_size` are
+/// zero-filled when loaded (e.g. `.bss`).
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    /// `PF_R`, `PF_W` and `PF_X` permissions.
+    pub flags: u32,
+}
+
+/// A section header, kept so tools can find debug information.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Minimal parser for 32-bit, little-endian RISC-V ELF executables.
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    bytes: Vec<u8>,
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> Result<u16, ElfError> {
+    match bytes.get(off..off + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(ElfError::TruncatedError),
+    }
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> Result<u32, ElfError> {
+    match bytes.get(off..off + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(ElfError::TruncatedError),
+    }
+}
+
+/// Read a NUL-terminated string from a string table.
+fn read_str(bytes: &[u8], off: usize) -> Result<String, ElfError> {
+    let tail = bytes.get(off..).ok_or(ElfError::TruncatedError)?;
+    let len = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+}
+
+fn slice(bytes: &[u8], off: u32, size: u32) -> Result<&[u8], ElfError> {
+    bytes
+        .get(off as usize..(off as usize).saturating_add(size as usize))
+        .ok_or(ElfError::TruncatedError)
+}
+
+impl Elf {
+    /// Check whether a buffer starts with the ELF magic number.
+    pub fn is_elf(bytes: &[u8]) -> bool {
+        bytes.starts_with(ELF_MAGIC)
+    }
+
+    /// Parse an ELF file from a byte buffer.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        if !Elf::is_elf(bytes) {
+            return Err(ElfError::BadMagicError);
+        }
+        match bytes.get(4) {
+            Some(&ELFCLASS32) => (),
+            Some(_) => return Err(ElfError::UnsupportedClassError),
+            None => return Err(ElfError::TruncatedError),
+        }
+        match bytes.get(5) {
+            Some(&ELFDATA2LSB) => (),
+            Some(_) => return Err(ElfError::UnsupportedEndiannessError),
+            None => return Err(ElfError::TruncatedError),
+        }
+        let machine = read_u16(bytes, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::UnsupportedMachineError(machine));
+        }
+
+        let entry = read_u32(bytes, 24)?;
+        let phoff = read_u32(bytes, 28)? as usize;
+        let shoff = read_u32(bytes, 32)? as usize;
+        let phentsize = read_u16(bytes, 42)? as usize;
+        let phnum = read_u16(bytes, 44)? as usize;
+        let shentsize = read_u16(bytes, 46)? as usize;
+        let shnum = read_u16(bytes, 48)? as usize;
+        let shstrndx = read_u16(bytes, 50)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if read_u32(bytes, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(bytes, ph + 4)?;
+            let paddr = read_u32(bytes, ph + 12)?;
+            let file_size = read_u32(bytes, ph + 16)?;
+            let mem_size = read_u32(bytes, ph + 20)?;
+            let flags = read_u32(bytes, ph + 24)?;
+            segments.push(Segment {
+                addr: paddr,
+                data: slice(bytes, offset, file_size)?.to_vec(),
+                mem_size,
+                flags,
+            });
+        }
+
+        // (name offset, type, addr, offset, size, link)
+        let mut headers = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            headers.push((
+                read_u32(bytes, sh)?,
+                read_u32(bytes, sh + 4)?,
+                read_u32(bytes, sh + 12)?,
+                read_u32(bytes, sh + 16)?,
+                read_u32(bytes, sh + 20)?,
+                read_u32(bytes, sh + 24)?,
+            ));
+        }
+
+        let mut sections = Vec::new();
+        if let Some(shstrtab) = headers.get(shstrndx) {
+            let names = slice(bytes, shstrtab.3, shstrtab.4)?;
+            for h in headers.iter() {
+                sections.push(Section {
+                    name: read_str(names, h.0 as usize)?,
+                    addr: h.2,
+                    offset: h.3,
+                    size: h.4,
+                });
+            }
+        }
+
+        let mut symbols = Vec::new();
+        for h in headers.iter().filter(|h| h.1 == SHT_SYMTAB) {
+            let strtab = headers.get(h.5 as usize).ok_or(ElfError::TruncatedError)?;
+            let names = slice(bytes, strtab.3, strtab.4)?;
+            let table = slice(bytes, h.3, h.4)?;
+            for entry in table.chunks_exact(16) {
+                let name = read_str(names, read_u32(entry, 0)? as usize)?;
+                if name.is_empty() {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name,
+                    addr: read_u32(entry, 4)?,
+                    size: read_u32(entry, 8)?,
+                    kind: match entry[12] & 0xf {
+                        STT_FUNC => SymbolKind::Function,
+                        STT_OBJECT => SymbolKind::Object,
+                        _ => SymbolKind::Other,
+                    },
+                });
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            sections,
+            symbols,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// Read and parse an ELF file.
+    pub fn from_file(path: &Path) -> Result<Self, ElfError> {
+        match fs::read(path) {
+            Ok(bytes) => Elf::parse(&bytes),
+            Err(_) => Err(ElfError::IOError),
+        }
+    }
+
+    /// Look up the address of a symbol by name.
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Lowest address of any loadable segment, or 0 if there are none.
+    pub fn load_base(&self) -> u32 {
+        self.segments.iter().map(|s| s.addr).min().unwrap_or(0)
+    }
+
+    /// Find a section by name and return its contents.
+    pub fn section_data(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.name == name)
+            .and_then(|s| slice(&self.bytes, s.offset, s.size).ok())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    /// Build a minimal ELF executable with a single loadable segment at `base`
+    /// and a symbol table. Only meant for exercising the loader in tests.
+    pub fn build_elf(base: u32, text: &[u8], symbols: &[(&str, u32)]) -> Vec<u8> {
+        build_elf_with_flags(base, text, symbols, 7)
+    }
+
+    /// Like `build_elf`, with the segment's `PF_*` flags.
+    pub fn build_elf_with_flags(
+        base: u32,
+        text: &[u8],
+        symbols: &[(&str, u32)],
+        flags: u32,
+    ) -> Vec<u8> {
+        const EHDR: usize = 52;
+        const PHDR: usize = 32;
+        const SHDR: usize = 40;
+
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 16];
+        for (name, addr) in symbols {
+            let name_off = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+            symtab.extend_from_slice(&name_off.to_le_bytes());
+            symtab.extend_from_slice(&addr.to_le_bytes());
+            symtab.extend_from_slice(&0u32.to_le_bytes());
+            symtab.extend_from_slice(&[0x12, 0, 1, 0]);
+        }
+        let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+        let text_off = EHDR + PHDR;
+        let symtab_off = text_off + text.len();
+        let strtab_off = symtab_off + symtab.len();
+        let shstrtab_off = strtab_off + strtab.len();
+        let shoff = shstrtab_off + shstrtab.len();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+        for half in [2u16, 243] {
+            out.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1u32, base, EHDR as u32, shoff as u32, 0] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [EHDR as u16, PHDR as u16, 1, SHDR as u16, 5, 4] {
+            out.extend_from_slice(&half.to_le_bytes());
+        }
+
+        let text_len = text.len() as u32;
+        for word in [1, text_off as u32, base, base, text_len, text_len, flags, 4] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        out.extend_from_slice(text);
+        out.extend_from_slice(&symtab);
+        out.extend_from_slice(&strtab);
+        out.extend_from_slice(&shstrtab);
+
+        let sections: [[u32; 10]; 5] = [
+            [0; 10],
+            [1, 1, 6, base, text_off as u32, text_len, 0, 0, 4, 0],
+            [
+                7,
+                2,
+                0,
+                0,
+                symtab_off as u32,
+                symtab.len() as u32,
+                3,
+                1,
+                4,
+                16,
+            ],
+            [
+                15,
+                3,
+                0,
+                0,
+                strtab_off as u32,
+                strtab.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+            [
+                23,
+                3,
+                0,
+                0,
+                shstrtab_off as u32,
+                shstrtab.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+        ];
+        for sh in sections.iter() {
+            for word in sh.iter() {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let bytes = test_util::build_elf(0x100, &[0x13, 0, 0, 0], &[("_start", 0x100)]);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(0x100, elf.entry);
+        assert_eq!(1, elf.segments.len());
+        assert_eq!(0x100, elf.segments[0].addr);
+        assert_eq!(vec![0x13, 0, 0, 0], elf.segments[0].data);
+        assert_eq!(Some(0x100), elf.symbol("_start"));
+        assert_eq!(SymbolKind::Function, elf.symbols[0].kind);
+        assert_eq!(Some(&[0x13, 0, 0, 0][..]), elf.section_data(".text"));
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        assert_eq!(
+            ElfError::BadMagicError,
+            Elf::parse(&[0x13, 0, 0, 0]).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = test_util::build_elf(0, &[0x13, 0, 0, 0], &[]);
+        assert_eq!(
+            ElfError::TruncatedError,
+            Elf::parse(&bytes[..40]).unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::{collections::HashMap, fmt::Write};
+
+use serde::Serialize;
+
+use lib_rv32_isa::{
+    common::constants::*, immediate, mnemonic, InstructionClass, RegisterUsage, MNEMONICS,
+};
+
+use crate::Step;
+
+/// Groups of coverage bins, in report order.
+pub const GROUPS: [&str; 7] = [
+    "mnemonic",
+    "rd",
+    "rs1",
+    "rs2",
+    "immediate",
+    "branch",
+    "alignment",
+];
+
+/// Mnemonics with a signed immediate operand.
+const IMMEDIATE_MNEMONICS: [&str; 24] = [
+    "lui", "auipc", "jal", "jalr", "beq", "bne", "blt", "bge", "bltu", "bgeu", "lb", "lh", "lw",
+    "lbu", "lhu", "sb", "sh", "sw", "addi", "slti", "sltiu", "xori", "ori", "andi",
+];
+
+/// Aligned offsets within a word at which loads and stores can access.
+const ALIGNMENTS: [(&[&str], &[u32]); 3] = [
+    (&["lb", "lbu", "sb"], &[0, 1, 2, 3]),
+    (&["lh", "lhu", "sh"], &[0, 2]),
+    (&["lw", "sw"], &[0]),
+];
+
+/// A functional coverage point: something a thorough test suite should
+/// make the core do at least once.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bin {
+    pub group: &'static str,
+    pub name: String,
+    pub hits: u64,
+}
+
+/// Functional coverage of the instruction space, for judging how thorough
+/// a test suite is. There are bins for every mnemonic, every register as
+/// `rd`, `rs1` and `rs2`, negative, zero and positive immediates, both
+/// directions of every branch, and every aligned offset of loads and
+/// stores.
+#[derive(Debug, Clone)]
+pub struct IsaCoverage {
+    bins: Vec<Bin>,
+    index: HashMap<(&'static str, String), usize>,
+}
+
+impl Default for IsaCoverage {
+    fn default() -> Self {
+        IsaCoverage::new()
+    }
+}
+
+impl IsaCoverage {
+    pub fn new() -> Self {
+        let mut coverage = IsaCoverage {
+            bins: Vec::new(),
+            index: HashMap::new(),
+        };
+        for name in MNEMONICS.iter() {
+            coverage.add("mnemonic", name.to_string());
+        }
+        for group in ["rd", "rs1", "rs2"] {
+            for reg in REG_NAMES.iter() {
+                coverage.add(group, reg.to_string());
+            }
+        }
+        for name in IMMEDIATE_MNEMONICS.iter() {
+            for sign in ["negative", "zero", "positive"] {
+                coverage.add("immediate", format!("{} {}", name, sign));
+            }
+        }
+        for name in ["beq", "bne", "blt", "bge", "bltu", "bgeu"] {
+            coverage.add("branch", format!("{} taken", name));
+            coverage.add("branch", format!("{} not taken", name));
+        }
+        for (names, offsets) in ALIGNMENTS.iter() {
+            for name in names.iter() {
+                for offset in offsets.iter() {
+                    coverage.add("alignment", format!("{} +{}", name, offset));
+                }
+            }
+        }
+        coverage
+    }
+
+    fn add(&mut self, group: &'static str, name: String) {
+        self.index.insert((group, name.clone()), self.bins.len());
+        self.bins.push(Bin {
+            group,
+            name,
+            hits: 0,
+        });
+    }
+
+    fn hit(&mut self, group: &'static str, name: String) {
+        if let Some(&i) = self.index.get(&(group, name)) {
+            self.bins[i].hits += 1;
+        }
+    }
+
+    /// Record an executed instruction.
+    pub fn retire(&mut self, step: &Step) {
+        let name = match mnemonic(step.ir) {
+            Some(name) => name,
+            None => return,
+        };
+        self.hit("mnemonic", name.to_string());
+
+        let fields = RegisterUsage::fields(step.ir);
+        for (group, reg) in [("rd", fields.rd), ("rs1", fields.rs1), ("rs2", fields.rs2)] {
+            if let Some(reg) = reg {
+                self.hit(group, REG_NAMES[reg as usize].to_string());
+            }
+        }
+
+        if let Some(imm) = immediate(step.ir) {
+            let sign = match imm {
+                i32::MIN..=-1 => "negative",
+                0 => "zero",
+                _ => "positive",
+            };
+            self.hit("immediate", format!("{} {}", name, sign));
+        }
+
+        if InstructionClass::of(step.ir) == InstructionClass::Branch {
+            let direction = match step.next_pc == step.pc.wrapping_add(4) {
+                true => "not taken",
+                false => "taken",
+            };
+            self.hit("branch", format!("{} {}", name, direction));
+        }
+
+        if let Some(access) = step.accesses.first() {
+            self.hit("alignment", format!("{} +{}", name, access.addr & 0b11));
+        }
+    }
+
+    /// Every bin with its hit count, grouped in the order of `GROUPS`.
+    pub fn bins(&self) -> &[Bin] {
+        &self.bins
+    }
+
+    /// Bins hit at least once and the total number of bins, optionally
+    /// only in `group`.
+    pub fn covered(&self, group: Option<&str>) -> (usize, usize) {
+        self.bins
+            .iter()
+            .filter(|b| group.is_none_or(|g| b.group == g))
+            .fold((0, 0), |(hit, total), b| {
+                (hit + (b.hits > 0) as usize, total + 1)
+            })
+    }
+
+    /// Covered bins per group and overall, followed by the bins that were
+    /// never hit.
+    pub fn report(&self) -> String {
+        let percent = |(hit, total): (usize, usize)| match total {
+            0 => 0.0,
+            n => 100.0 * hit as f64 / n as f64,
+        };
+        let mut out = String::new();
+        for group in GROUPS.iter() {
+            let covered = self.covered(Some(group));
+            writeln!(
+                out,
+                "{:10} {:4}/{:<4} {:6.2}%",
+                group,
+                covered.0,
+                covered.1,
+                percent(covered)
+            )
+            .unwrap();
+        }
+        let covered = self.covered(None);
+        writeln!(
+            out,
+            "{:10} {:4}/{:<4} {:6.2}%",
+            "total",
+            covered.0,
+            covered.1,
+            percent(covered)
+        )
+        .unwrap();
+
+        for group in GROUPS.iter() {
+            let missing: Vec<&str> = self
+                .bins
+                .iter()
+                .filter(|b| b.group == *group && b.hits == 0)
+                .map(|b| b.name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                writeln!(out, "\nmissing {}: {}", group, missing.join(", ")).unwrap();
+            }
+        }
+        out
+    }
+
+    /// All bins as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.bins).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::Mcu;
+
+    fn run(program: &str, n: usize) -> IsaCoverage {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        let mut coverage = IsaCoverage::new();
+        for _ in 0..n {
+            coverage.retire(&mcu.step().unwrap());
+        }
+        coverage
+    }
+
+    fn hits(coverage: &IsaCoverage, group: &str, name: &str) -> u64 {
+        coverage
+            .bins()
+            .iter()
+            .find(|b| b.group == group && b.name == name)
+            .unwrap()
+            .hits
+    }
+
+    #[test]
+    fn test_bins() {
+        let coverage = IsaCoverage::new();
+        assert_eq!((0, 121), coverage.covered(Some("mnemonic")));
+        assert_eq!((0, 32), coverage.covered(Some("rs2")));
+        assert_eq!((0, 72), coverage.covered(Some("immediate")));
+        assert_eq!((0, 12), coverage.covered(Some("branch")));
+        assert_eq!((0, 20), coverage.covered(Some("alignment")));
+    }
+
+    #[test]
+    fn test_retire() {
+        let coverage = run(
+            "addi t0, zero, -1\n\
+             sb t0, 0x101(zero)\n\
+             lh t1, 0x102(zero)\n\
+             beq t0, t1, 8\n\
+             add t2, t0, t1",
+            4,
+        );
+        assert_eq!(1, hits(&coverage, "mnemonic", "addi"));
+        assert_eq!(0, hits(&coverage, "mnemonic", "add"));
+        assert_eq!(1, hits(&coverage, "rd", "t0"));
+        assert_eq!(3, hits(&coverage, "rs1", "zero"));
+        assert_eq!(1, hits(&coverage, "immediate", "addi negative"));
+        assert_eq!(1, hits(&coverage, "immediate", "beq positive"));
+        assert_eq!(1, hits(&coverage, "alignment", "sb +1"));
+        assert_eq!(1, hits(&coverage, "alignment", "lh +2"));
+        assert_eq!(1, hits(&coverage, "branch", "beq not taken"));
+        assert_eq!((4, 121), coverage.covered(Some("mnemonic")));
+    }
+
+    #[test]
+    fn test_report() {
+        let coverage = run("addi t0, zero, 1\nbne t0, zero, -4", 2);
+        let report = coverage.report();
+        assert!(report.starts_with("mnemonic      2/121    1.65%\n"));
+        assert!(report.contains("\nmissing branch: beq taken, beq not taken, bne not taken, "));
+    }
+}
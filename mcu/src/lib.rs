@@ -1,13 +1,74 @@
 use serde::{Deserialize, Serialize};
 
+use lib_rv32_isa::{exec_one, RiscvError};
+
+use trace::{AccessKind, RecordingMemory, RecordingRegisterFile};
+
 /// Contains reference `Memory` struct.
 mod memory;
 
 /// Contains referende `RegisterFile` struct.
 mod register_file;
 
-#[cfg(test)]
-mod test_runner;
+/// Branch predictor models and their statistics.
+pub mod branch;
+
+/// Cache models that estimate hit rates and memory stalls.
+pub mod cache;
+
+/// Performance counter CSRs.
+pub mod csr;
+
+/// Physical memory protection CSRs.
+pub mod pmp;
+
+/// Sv32 address translation and TLB model.
+pub mod mmu;
+
+/// Coverage of executed instructions and branches.
+pub mod coverage;
+
+/// Loader for ELF executables.
+pub mod elf;
+
+/// Runner for the RISC-V architectural compliance suite.
+pub mod compliance;
+
+/// Five-stage pipeline model for visualising hazards.
+pub mod pipeline;
+
+/// Functional coverage of the instruction set.
+pub mod isa_coverage;
+
+/// Source line tables for mapping addresses to code.
+pub mod lines;
+
+/// Per-function and per-block execution profiles.
+pub mod profile;
+
+/// Newlib syscalls, semihosting and HTIF serviced by the host.
+pub mod syscall;
+
+/// Multi-hart systems sharing a memory and CLINT.
+pub mod soc;
+
+/// Saving, restoring and checkpointing the MCU state.
+pub mod snapshot;
+
+/// Runner for directory-per-test program suites.
+pub mod test_runner;
+
+/// Cycle estimates for executed instructions.
+pub mod timing;
+
+/// Records of executed instructions.
+pub mod trace;
+
+/// Machine-mode trap CSRs and exception causes.
+pub mod trap;
+
+/// Undo log for running the MCU backwards.
+pub mod undo;
 
 /// Re-export ISA simulator.
 pub use lib_rv32_isa as isa;
@@ -15,8 +76,26 @@ pub use lib_rv32_isa as isa;
 /// Re-export common library.
 pub use lib_rv32_isa::common;
 
+pub use branch::{BranchPredictor, BranchSimulator, PredictorKind};
+pub use cache::{CacheConfig, CacheHierarchy, CachedMemory, HierarchyConfig};
+pub use coverage::Coverage;
+pub use csr::{CsrFile, HpmEvent};
+pub use elf::Elf;
+pub use isa_coverage::IsaCoverage;
+pub use lines::LineTable;
 pub use memory::*;
+pub use mmu::{Tlb, TlbStats};
+pub use pipeline::{Pipeline, PipelineConfig};
+pub use pmp::Pmp;
+pub use profile::Profiler;
 pub use register_file::*;
+pub use snapshot::{Checkpoints, ProgramInfo, SnapshotError, SnapshotFormat};
+pub use soc::{Clint, Schedule, Soc};
+pub use syscall::Host;
+pub use timing::{Latencies, LatencyModel, TimingModel};
+pub use trace::Step;
+pub use trap::{Exception, Interrupt, Privilege, TakenInterrupt, Trap, TrapCsrs};
+pub use undo::UndoLog;
 
 /// Reference implementation of an MCU. Contains a PC,
 /// register file, and memory.
@@ -25,23 +104,245 @@ pub struct Mcu {
     pub pc: u32,
     pub mem: Memory,
     pub rf: RegisterFile,
+    /// Number of instructions executed with `step`.
+    pub instructions: u64,
+    /// What the loader knows about the program, for resuming snapshots.
+    pub program: ProgramInfo,
+    /// History for stepping backwards. Not part of snapshots.
+    #[serde(skip)]
+    undo: Option<UndoLog>,
+    /// Cached translations. Not part of snapshots.
+    #[serde(skip)]
+    tlb: Option<Tlb>,
 }
 
 impl Mcu {
     /// Construct an MCU with the provided memory size.
     pub fn new(size: usize) -> Self {
+        Mcu::with_memory(Memory::new(size))
+    }
+
+    /// Construct an MCU around an existing memory, such as one with
+    /// regions mapped away from address 0.
+    pub fn with_memory(mem: Memory) -> Self {
         Mcu {
             pc: 0,
-            mem: Memory::new(size),
+            mem,
             rf: RegisterFile::new(),
+            instructions: 0,
+            program: ProgramInfo::default(),
+            undo: None,
+            tlb: None,
         }
     }
+
+    /// Execute a single instruction, count it, and return a record of
+    /// its effects. The record is added to the undo log if enabled.
+    pub fn step(&mut self) -> Result<Step, RiscvError> {
+        self.step_inner(None)
+    }
+
+    /// Like `step`, but `ecall`s and semihosting `ebreak`s are serviced
+    /// by `host` and retire like any other instruction, and HTIF requests
+    /// are serviced once written. Once the program installs a trap
+    /// handler in `mtvec`, its `ecall`s go to that handler instead. What
+    /// the host writes to registers and memory is part of the record, but
+    /// effects outside the MCU, such as file writes, cannot be undone.
+    pub fn step_with_host(&mut self, host: &mut Host) -> Result<Step, RiscvError> {
+        self.step_inner(Some(host))
+    }
+
+    fn step_inner(&mut self, mut host: Option<&mut Host>) -> Result<Step, RiscvError> {
+        let pc = self.pc;
+        let privilege = self.rf.trap.privilege();
+        let reservation = self.mem.reservation();
+        let mut pte_updates = Vec::new();
+        let fetched = mmu::translate(
+            &mut self.mem,
+            &self.rf.pmp,
+            self.tlb.as_mut(),
+            &self.rf.trap.translation(false),
+            pc,
+            Access::Execute,
+            &mut pte_updates,
+        )
+        .and_then(|ppc| {
+            self.mem
+                .fetch_instruction(ppc)
+                .and_then(|ir| {
+                    self.rf
+                        .pmp
+                        .check(ppc, 4, Access::Execute, privilege)
+                        .map(|_| ir)
+                })
+                .map_err(mmu::at(ppc, pc))
+        });
+        let ir = match fetched {
+            Ok(ir) => ir,
+            Err(err) => {
+                let trap = self.trap(pc, Exception::of_fetch(&err, pc), err)?;
+                return Ok(self.retire(Step {
+                    pc,
+                    ir: 0,
+                    next_pc: self.pc,
+                    privilege,
+                    next_privilege: self.rf.trap.privilege(),
+                    reservation,
+                    next_reservation: reservation,
+                    reg_write: None,
+                    fp_write: None,
+                    csr_write: None,
+                    accesses: Vec::new(),
+                    pte_updates,
+                    trap: Some(trap),
+                    interrupts: Vec::new(),
+                }));
+            }
+        };
+
+        // Instructions that access memory do not write CSRs, so the PMP
+        // and translation they are checked against cannot change
+        // underneath them.
+        let pmp = self.rf.pmp.clone();
+        let returns_to = self.rf.trap.returns_to(ir);
+        // The host stands in for machine-mode software, unless the program
+        // brings its own; calls from lower modes are for the kernel running
+        // on the MCU.
+        let machine = privilege == Privilege::Machine;
+        let serves_ecall = machine && !self.rf.trap.handler_installed();
+        let mut mem = RecordingMemory::new(
+            &mut self.mem,
+            &pmp,
+            self.rf.trap.translation(true),
+            self.tlb.as_mut(),
+            (pc, ir),
+        );
+        let mut rf = RecordingRegisterFile::new(&mut self.rf);
+        let result = match (
+            exec_one(&mut self.pc, &mut mem, &mut rf),
+            host.as_deref_mut(),
+        ) {
+            (Err(RiscvError::EnvironmentCallError(_)), Some(host)) if serves_ecall => {
+                host.ecall(&mut mem, &mut rf)?;
+                self.pc = self.pc.wrapping_add(4);
+                Ok(())
+            }
+            (Err(RiscvError::BreakpointError(_)), Some(host))
+                if machine && Host::is_semihosting(&mem, pc) =>
+            {
+                host.semihost(&mut mem, &mut rf)?;
+                self.pc = self.pc.wrapping_add(4);
+                Ok(())
+            }
+            (result, _) => result,
+        };
+        if let (Ok(()), Some(host)) = (&result, host) {
+            let written = host.tohost().is_some_and(|tohost| {
+                mem.accesses
+                    .borrow()
+                    .iter()
+                    .any(|a| a.kind == AccessKind::Store && (tohost..tohost + 8).contains(&a.addr))
+            });
+            host.htif(&mut mem, written)?;
+        }
+
+        let (reg_write, fp_write, csr_write) = (rf.write, rf.fp_write, rf.csr_write);
+        let accesses = mem.accesses.into_inner();
+        pte_updates.extend(mem.pte_updates.into_inner());
+        let trap = match result {
+            Ok(()) => {
+                if let Some(target) = returns_to {
+                    self.rf.trap.set_privilege(target);
+                }
+                if mmu::is_sfence(ir) {
+                    self.sfence(ir);
+                }
+                None
+            }
+            Err(err) => Some(self.trap(pc, Exception::of_error(&err, ir, privilege), err)?),
+        };
+        Ok(self.retire(Step {
+            pc,
+            ir,
+            next_pc: self.pc,
+            privilege,
+            next_privilege: self.rf.trap.privilege(),
+            reservation,
+            next_reservation: self.mem.reservation(),
+            reg_write,
+            fp_write,
+            csr_write,
+            accesses,
+            pte_updates,
+            trap,
+            interrupts: Vec::new(),
+        }))
+    }
+
+    /// Enter the trap handler for the exception `err` raised by the
+    /// instruction at `pc`, or return `err` if there is no handler or it
+    /// is not an exception.
+    fn trap(
+        &mut self,
+        pc: u32,
+        cause: Option<(Exception, u32)>,
+        err: RiscvError,
+    ) -> Result<Trap, RiscvError> {
+        match cause {
+            Some((cause, tval)) if self.rf.trap.takes(cause) => {
+                let old = self.rf.trap.clone();
+                self.pc = self.rf.trap.enter(pc, cause, tval);
+                Ok(Trap { cause, tval, old })
+            }
+            _ => Err(err),
+        }
+    }
+
+    /// Enter the handler of the pending interrupt, if there is one that
+    /// is enabled, instead of executing the next instruction. Taking an
+    /// interrupt is not a step; the undo log records it with the
+    /// instruction before it, so stepping back over that instruction
+    /// undoes the interrupt too.
+    pub fn take_interrupt(&mut self) -> Option<Interrupt> {
+        let interrupt = self.rf.trap.pending_interrupt()?;
+        let taken = TakenInterrupt {
+            interrupt,
+            pc: self.pc,
+            old: self.rf.trap.clone(),
+        };
+        self.pc = self.rf.trap.interrupt(self.pc, interrupt);
+        if let Some(undo) = self.undo.as_mut() {
+            undo.push_interrupt(taken);
+        }
+        Some(interrupt)
+    }
+
+    /// Count an executed instruction and add it to the undo log.
+    fn retire(&mut self, step: Step) -> Step {
+        self.instructions += 1;
+        self.rf.csrs.retire(&step);
+        if let Some(undo) = self.undo.as_mut() {
+            undo.push(step.clone());
+        }
+        step
+    }
+
+    /// Program the MCU with the segments of an ELF executable and
+    /// set the program counter to its entry point.
+    pub fn program_elf(&mut self, elf: &Elf) -> Result<(), RiscvError> {
+        self.mem.program_elf(elf)?;
+        self.pc = elf.entry;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lib_rv32_isa::{common::instructions, exec_one};
+    use lib_rv32_isa::{
+        common::{constants::*, instructions},
+        exec_one,
+    };
 
     const MEM_SIZE: u32 = 0x10000;
 
@@ -92,4 +393,356 @@ mod tests {
 
         assert_eq!(4, mcu.pc);
     }
+
+    #[test]
+    fn test_sub_x5_x5_x5() {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        mcu.mem
+            .program_words(&[instructions::SUB_X5_X5_X5])
+            .unwrap();
+        mcu.rf.write(5, 7).unwrap();
+        exec_one(&mut mcu.pc, &mut mcu.mem, &mut mcu.rf).unwrap();
+
+        assert_eq!(0, mcu.rf.read(5).unwrap());
+    }
+
+    #[test]
+    fn test_srai_x5_x5_1() {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        mcu.mem
+            .program_words(&[instructions::SRAI_X5_X5_1])
+            .unwrap();
+        mcu.rf.write(5, -8i32 as u32).unwrap();
+        exec_one(&mut mcu.pc, &mut mcu.mem, &mut mcu.rf).unwrap();
+
+        assert_eq!(-4, mcu.rf.read(5).unwrap() as i32);
+    }
+
+    #[test]
+    fn test_top_of_address_space() {
+        let mut mcu = Mcu::with_memory(Memory::sparse().with_region(0xffff_f000, 0x1000));
+        let program = lib_rv32_asm::assemble_program("auipc t0, 0x80000\njal ra, -4").unwrap();
+        mcu.mem.poke(0xffff_fff8, program[0], 4).unwrap();
+        mcu.mem.poke(0xffff_fffc, program[1], 4).unwrap();
+        mcu.pc = 0xffff_fff8;
+
+        mcu.step().unwrap();
+        assert_eq!(0x7fff_fff8, mcu.rf.read(5).unwrap());
+        assert_eq!(0xffff_fffc, mcu.pc);
+        // The link wraps around to the bottom of the address space.
+        mcu.step().unwrap();
+        assert_eq!(0, mcu.rf.read(1).unwrap());
+        assert_eq!(0xffff_fff8, mcu.pc);
+    }
+
+    #[test]
+    fn test_atomics() {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        let program = "addi t0, zero, 0x100\n\
+                       addi t1, zero, -5\n\
+                       amoadd.w a0, t1, (t0)\n\
+                       amomin.w a1, t1, (t0)\n\
+                       amomaxu.w.aqrl a2, t1, (t0)\n\
+                       amoswap.w a3, t1, (t0)\n\
+                       amoxor.w a4, t1, (t0)";
+        mcu.mem
+            .program_words(&lib_rv32_asm::assemble_program(program).unwrap())
+            .unwrap();
+        mcu.mem.poke(0x100, 3, 4).unwrap();
+        for _ in 0..7 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(
+            vec![3, -2i32 as u32, -5i32 as u32, -5i32 as u32, -5i32 as u32],
+            (10..15)
+                .map(|r| mcu.rf.read(r).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, mcu.mem.peek(0x100, 4).unwrap());
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        let program = "addi t0, zero, 0x100\n\
+                       addi t1, zero, 7\n\
+                       lr.w a0, (t0)\n\
+                       sc.w a1, t1, (t0)\n\
+                       sc.w a2, t1, (t0)\n\
+                       lr.w a0, (t0)\n\
+                       sb zero, 3(t0)\n\
+                       sc.w a3, zero, (t0)\n\
+                       lr.w a0, (t0)\n\
+                       sw zero, 4(t0)\n\
+                       sc.w a4, zero, (t0)";
+        mcu.mem
+            .program_words(&lib_rv32_asm::assemble_program(program).unwrap())
+            .unwrap();
+        let steps: Vec<Step> = (0..11).map(|_| mcu.step().unwrap()).collect();
+        // The first `sc.w` succeeds; the second has no reservation, and the
+        // third lost it to a store.
+        assert_eq!(
+            vec![0, 1, 1, 0],
+            (11..15)
+                .map(|r| mcu.rf.read(r).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, mcu.mem.peek(0x100, 4).unwrap());
+        assert_eq!(None, mcu.mem.reservation());
+        // Only successful `sc.w`s store.
+        assert_eq!(1, steps[3].accesses.len());
+        assert!(steps[4].accesses.is_empty());
+    }
+
+    #[test]
+    fn test_misaligned_atomic() {
+        let mut mcu = Mcu::with_memory(
+            Memory::new(MEM_SIZE as usize).with_misaligned_policy(MisalignedPolicy::Split),
+        );
+        mcu.mem
+            .program_words(
+                &lib_rv32_asm::assemble_program("addi t0, zero, 0x102\namoadd.w a0, a0, (t0)")
+                    .unwrap(),
+            )
+            .unwrap();
+        mcu.step().unwrap();
+        assert_eq!(
+            Err(RiscvError::MemoryAlignmentError(0x102)),
+            mcu.step().map(|_| ())
+        );
+    }
+
+    /// NaN-box a single-precision value.
+    fn single(x: f32) -> u64 {
+        0xffff_ffff_0000_0000 | x.to_bits() as u64
+    }
+
+    /// Load `program`, with `fp` in the floating-point registers.
+    fn float_mcu(program: &str, fp: &[(u8, u64)]) -> Mcu {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        mcu.mem
+            .program_words(&lib_rv32_asm::assemble_program(program).unwrap())
+            .unwrap();
+        for (reg, data) in fp {
+            mcu.rf.fp.write(*reg, *data).unwrap();
+        }
+        mcu
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let program = "addi a0, zero, 3\n\
+                       addi a1, zero, -2\n\
+                       fcvt.s.w fa0, a0\n\
+                       fcvt.s.w fa1, a1\n\
+                       fadd.s fa2, fa0, fa1\n\
+                       fmul.s fa3, fa0, fa1\n\
+                       fdiv.s fa4, fa0, fa1\n\
+                       fmadd.s fa5, fa0, fa1, fa0\n\
+                       fcvt.w.s a2, fa4\n\
+                       fcvt.w.s a3, fa4, rtz\n\
+                       flt.s a4, fa1, fa0\n\
+                       fcvt.d.w ft0, a0\n\
+                       fsqrt.d ft1, ft0\n\
+                       fcvt.d.s ft2, fa4";
+        let mut mcu = float_mcu(program, &[]);
+        for _ in 0..14 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(
+            vec![single(1.0), single(-6.0), single(-1.5), single(-3.0)],
+            (12..16)
+                .map(|r| mcu.rf.fp.read(r).unwrap())
+                .collect::<Vec<_>>()
+        );
+        // -1.5 rounds to even, or towards zero.
+        assert_eq!(-2, mcu.rf.read(12).unwrap() as i32);
+        assert_eq!(-1, mcu.rf.read(13).unwrap() as i32);
+        assert_eq!(1, mcu.rf.read(14).unwrap());
+        assert_eq!(3f64.sqrt().to_bits(), mcu.rf.fp.read(1).unwrap());
+        assert_eq!((-1.5f64).to_bits(), mcu.rf.fp.read(2).unwrap());
+        assert_eq!(FFLAGS_NX, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+    }
+
+    #[test]
+    fn test_float_rounding() {
+        let program = "fdiv.s ft0, fa0, fa1, rne\n\
+                       fdiv.s ft1, fa0, fa1, rtz\n\
+                       fdiv.s ft2, fa0, fa1, rdn\n\
+                       fdiv.s ft3, fa0, fa1, rup\n\
+                       fdiv.s ft4, fa0, fa1, rmm\n\
+                       fdiv.s ft5, fa2, fa1, rdn\n\
+                       fdiv.s ft6, fa2, fa1, rtz\n\
+                       li a0, 0x1000001\n\
+                       fcvt.s.w ft7, a0\n\
+                       fcvt.s.w ft8, a0, rmm\n\
+                       fcvt.s.w ft9, a0, rdn\n\
+                       fdiv.d ft10, fa3, fa4\n\
+                       fdiv.d ft11, fa3, fa4, rup\n\
+                       fdiv.s fs0, fa0, fa1";
+        let mut mcu = float_mcu(
+            program,
+            &[
+                (10, single(1.0)),
+                (11, single(3.0)),
+                (12, single(-1.0)),
+                (13, 1f64.to_bits()),
+                (14, 3f64.to_bits()),
+            ],
+        );
+        for _ in 0..14 {
+            mcu.step().unwrap();
+        }
+        // The last `fdiv.s` rounds dynamically.
+        mcu.rf.write_csr(CSR_FRM, 1).unwrap();
+        mcu.step().unwrap();
+        assert_eq!(
+            vec![
+                0x3eaaaaab, 0x3eaaaaaa, 0x3eaaaaaa, 0x3eaaaaab, 0x3eaaaaab, 0xbeaaaaab, 0xbeaaaaaa
+            ],
+            (0..7)
+                .map(|r| mcu.rf.fp.read(r).unwrap() as u32)
+                .collect::<Vec<_>>()
+        );
+        // 2^24 + 1 is halfway between two singles.
+        assert_eq!(single(16777216.0), mcu.rf.fp.read(7).unwrap());
+        assert_eq!(single(16777218.0), mcu.rf.fp.read(28).unwrap());
+        assert_eq!(single(16777216.0), mcu.rf.fp.read(29).unwrap());
+        assert_eq!(0x3fd5555555555555, mcu.rf.fp.read(30).unwrap());
+        assert_eq!(0x3fd5555555555556, mcu.rf.fp.read(31).unwrap());
+        assert_eq!(0xffffffff3eaaaaaa, mcu.rf.fp.read(8).unwrap());
+        assert_eq!(FFLAGS_NX, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+
+        // Reserved rounding modes are illegal.
+        mcu.rf.write_csr(CSR_FRM, 5).unwrap();
+        mcu.pc -= 4;
+        assert!(matches!(
+            mcu.step(),
+            Err(RiscvError::InvalidFunc3Error(_, 7))
+        ));
+    }
+
+    #[test]
+    fn test_float_exceptions() {
+        let program = "fdiv.s ft0, fa0, fa0\n\
+                       fdiv.s ft1, fa1, fa0\n\
+                       fsqrt.s ft2, fa2\n\
+                       fadd.s ft3, fa3, fa1\n\
+                       feq.s a0, fa4, fa1\n\
+                       flt.s a1, fa4, fa1\n\
+                       fcvt.w.s a2, fa4\n\
+                       fcvt.wu.s a3, fa2\n\
+                       fmin.s ft4, fa4, fa1\n\
+                       fadd.s ft5, fa5, fa5\n\
+                       fadd.s ft6, fa5, fa5, rtz\n\
+                       fmadd.s ft7, fa0, ft1, fa4\n\
+                       fmul.s ft8, fa6, fa7";
+        let mut mcu = float_mcu(
+            program,
+            &[
+                (10, single(0.0)),
+                (11, single(1.0)),
+                (12, single(-1.0)),
+                // A signaling and a quiet NaN.
+                (13, 0xffffffff7f800001),
+                (14, single(f32::NAN)),
+                (15, single(f32::MAX)),
+                (16, single(f32::from_bits(1))),
+                (17, single(0.5)),
+            ],
+        );
+        let nan = 0xffffffff7fc00000;
+        for (reg, value, flags) in [
+            (Some(0), nan, FFLAGS_NV),
+            (Some(1), single(f32::INFINITY), FFLAGS_DZ),
+            (Some(2), nan, FFLAGS_NV),
+            (Some(3), nan, FFLAGS_NV),
+            (None, 0, 0),
+            (None, 0, FFLAGS_NV),
+            (None, i32::MAX as u64, FFLAGS_NV),
+            (None, 0, FFLAGS_NV),
+            (Some(4), single(1.0), 0),
+            (Some(5), single(f32::INFINITY), FFLAGS_OF | FFLAGS_NX),
+            (Some(6), single(f32::MAX), FFLAGS_OF | FFLAGS_NX),
+            // Infinity times zero is invalid even with a quiet NaN addend.
+            (Some(7), nan, FFLAGS_NV),
+            (Some(28), single(0.0), FFLAGS_UF | FFLAGS_NX),
+        ] {
+            let step = mcu.step().unwrap();
+            match reg {
+                Some(reg) => assert_eq!(value, mcu.rf.fp.read(reg).unwrap()),
+                None => assert_eq!(value as u32, step.reg_write.map_or(0, |w| w.new)),
+            }
+            assert_eq!(flags, mcu.rf.read_csr(CSR_FFLAGS).unwrap(), "{:x}", step.ir);
+            mcu.rf.write_csr(CSR_FFLAGS, 0).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_nan_boxing() {
+        let program = "fadd.s ft0, fa0, fa0\n\
+                       fmv.x.w a0, fa0\n\
+                       fcvt.s.d ft1, fa0\n\
+                       fcvt.d.s ft2, ft1\n\
+                       addi t0, zero, 0x100\n\
+                       fsd fa0, 0(t0)\n\
+                       flw ft3, 4(t0)\n\
+                       fsw ft1, 8(t0)\n\
+                       fld ft4, 0(t0)\n\
+                       fmv.w.x ft5, t0";
+        // A double, so not a properly boxed single.
+        let mut mcu = float_mcu(program, &[(10, 0x3ff8000000000000)]);
+        for _ in 0..10 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(0xffffffff7fc00000, mcu.rf.fp.read(0).unwrap());
+        assert_eq!(0, mcu.rf.read(10).unwrap());
+        assert_eq!(single(1.5), mcu.rf.fp.read(1).unwrap());
+        assert_eq!(0x3ff8000000000000, mcu.rf.fp.read(2).unwrap());
+        assert_eq!(0xffffffff3ff80000, mcu.rf.fp.read(3).unwrap());
+        assert_eq!(0x3fc00000, mcu.mem.peek(0x108, 4).unwrap());
+        assert_eq!(0x3ff8000000000000, mcu.rf.fp.read(4).unwrap());
+        assert_eq!(0xffffffff00000100, mcu.rf.fp.read(5).unwrap());
+        // No exceptions: the canonical NaN is quiet.
+        assert_eq!(0, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+    }
+
+    #[test]
+    fn test_float_undo() {
+        let mut mcu = float_mcu(
+            "fadd.s fa1, fa0, fa0\nfdiv.s fa2, fa0, fa3",
+            &[(10, single(1.0)), (13, single(3.0))],
+        );
+        mcu.enable_undo(8);
+        let step = mcu.step().unwrap();
+        let write = step.fp_write.unwrap();
+        assert_eq!((11, 0, single(2.0)), (write.reg, write.old, write.new));
+        assert_eq!(None, step.reg_write);
+        mcu.step().unwrap();
+        assert_eq!(FFLAGS_NX, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+
+        mcu.step_back().unwrap();
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.rf.fp.read(11).unwrap());
+        assert_eq!(0, mcu.rf.fp.read(12).unwrap());
+        assert_eq!(0, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+
+        mcu.replay().unwrap();
+        mcu.replay().unwrap();
+        assert_eq!(single(2.0), mcu.rf.fp.read(11).unwrap());
+        assert_eq!(single(1.0 / 3.0), mcu.rf.fp.read(12).unwrap());
+        assert_eq!(FFLAGS_NX, mcu.rf.read_csr(CSR_FFLAGS).unwrap());
+    }
+
+    #[test]
+    fn test_blt_is_signed() {
+        let mut mcu = Mcu::new(MEM_SIZE as usize);
+        // blt x5, x6, 12
+        mcu.mem.program_words(&[0x0062c663]).unwrap();
+        mcu.rf.write(5, -1i32 as u32).unwrap();
+        mcu.rf.write(6, 1).unwrap();
+        exec_one(&mut mcu.pc, &mut mcu.mem, &mut mcu.rf).unwrap();
+
+        assert_eq!(12, mcu.pc);
+    }
 }
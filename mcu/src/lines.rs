@@ -0,0 +1,531 @@
+use crate::elf::{Elf, ElfError};
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+/// Start of the instructions generated for a source line. The line
+/// extends to the address of the next row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRow {
+    pub addr: u32,
+    /// Index into `LineTable::files`.
+    pub file: usize,
+    pub line: u32,
+    /// Marks the first address after a sequence of rows; it has no line.
+    pub end_sequence: bool,
+}
+
+/// Map from instruction addresses to source lines, read from DWARF
+/// `.debug_line` or built from an assembler listing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<LineRow>,
+}
+
+/// Cursor over a DWARF section.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, ElfError> {
+        let b = *self.bytes.get(self.off).ok_or(ElfError::TruncatedError)?;
+        self.off += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ElfError> {
+        let b = self
+            .bytes
+            .get(self.off..self.off.saturating_add(n))
+            .ok_or(ElfError::TruncatedError)?;
+        self.off += n;
+        Ok(b)
+    }
+
+    /// Little-endian unsigned integer of `n` bytes.
+    fn uint(&mut self, n: usize) -> Result<u64, ElfError> {
+        Ok(self
+            .bytes(n)?
+            .iter()
+            .rev()
+            .fold(0, |v, b| v << 8 | *b as u64))
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                value |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfError> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                value |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    /// NUL-terminated string.
+    fn str(&mut self) -> Result<String, ElfError> {
+        let tail = self.bytes.get(self.off..).ok_or(ElfError::TruncatedError)?;
+        let len = tail
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(ElfError::TruncatedError)?;
+        self.off += len + 1;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+/// String at `off` in a string section.
+fn str_at(section: &[u8], off: u64) -> Result<String, ElfError> {
+    Reader {
+        bytes: section,
+        off: off as usize,
+    }
+    .str()
+}
+
+/// An attribute of a DWARF 5 directory or file entry: a string or a number.
+enum Attr {
+    Str(String),
+    Num(u64),
+}
+
+impl LineTable {
+    pub fn new() -> Self {
+        LineTable::default()
+    }
+
+    /// Line table of a program assembled from `file`, where instruction
+    /// `i` is at address `4 * i` and came from line `lines[i]`.
+    pub fn from_listing(file: &str, lines: &[usize]) -> Self {
+        let mut rows: Vec<LineRow> = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| LineRow {
+                addr: 4 * i as u32,
+                file: 0,
+                line: *line as u32,
+                end_sequence: false,
+            })
+            .collect();
+        rows.push(LineRow {
+            addr: 4 * lines.len() as u32,
+            file: 0,
+            line: 0,
+            end_sequence: true,
+        });
+        LineTable {
+            files: vec![file.to_string()],
+            rows,
+        }
+    }
+
+    /// Read the line table from the DWARF debug information of an ELF
+    /// executable. Executables without debug information give an empty
+    /// table.
+    pub fn from_elf(elf: &Elf) -> Result<Self, ElfError> {
+        match elf.section_data(".debug_line") {
+            Some(debug_line) => LineTable::parse(
+                debug_line,
+                elf.section_data(".debug_str").unwrap_or_default(),
+                elf.section_data(".debug_line_str").unwrap_or_default(),
+            ),
+            None => Ok(LineTable::new()),
+        }
+    }
+
+    /// Parse the contents of a `.debug_line` section (DWARF 2 to 5). The
+    /// string sections are only needed for DWARF 5.
+    pub fn parse(
+        debug_line: &[u8],
+        debug_str: &[u8],
+        debug_line_str: &[u8],
+    ) -> Result<Self, ElfError> {
+        let mut table = LineTable::new();
+        let mut r = Reader {
+            bytes: debug_line,
+            off: 0,
+        };
+        while r.off < debug_line.len() {
+            let (unit_length, offset_size) = match r.uint(4)? {
+                0xffff_ffff => (r.uint(8)?, 8),
+                n => (n, 4),
+            };
+            let end = r.off.saturating_add(unit_length as usize);
+            table.parse_unit(
+                &mut Reader {
+                    bytes: debug_line.get(..end).ok_or(ElfError::TruncatedError)?,
+                    off: r.off,
+                },
+                offset_size,
+                debug_str,
+                debug_line_str,
+            )?;
+            r.off = end;
+        }
+        // End markers go before rows that start another sequence at the
+        // same address.
+        table.rows.sort_by_key(|row| (row.addr, !row.end_sequence));
+        Ok(table)
+    }
+
+    fn parse_unit(
+        &mut self,
+        r: &mut Reader,
+        offset_size: usize,
+        debug_str: &[u8],
+        debug_line_str: &[u8],
+    ) -> Result<(), ElfError> {
+        let version = r.uint(2)? as u16;
+        if !(2..=5).contains(&version) {
+            return Err(ElfError::UnsupportedDwarfVersionError(version));
+        }
+        if version >= 5 {
+            // Address and segment selector sizes.
+            r.bytes(2)?;
+        }
+        let header_length = r.uint(offset_size)? as usize;
+        let program = r.off + header_length;
+        let min_length = r.u8()? as u64;
+        if version >= 4 {
+            // Maximum operations per instruction, only used for VLIW.
+            r.u8()?;
+        }
+        // default_is_stmt: every row is reported, statement or not.
+        r.u8()?;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()? as u64;
+        let opcode_base = r.u8()?;
+        let opcode_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?;
+        if line_range == 0 {
+            return Err(ElfError::TruncatedError);
+        }
+
+        // Files of this unit as indices into `self.files`.
+        let mut files: Vec<usize> = Vec::new();
+        if version >= 5 {
+            let read_entries = |r: &mut Reader| -> Result<Vec<(Option<String>, u64)>, ElfError> {
+                let format_count = r.u8()?;
+                let mut format = Vec::new();
+                for _ in 0..format_count {
+                    format.push((r.uleb()?, r.uleb()?));
+                }
+                let count = r.uleb()?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    let (mut path, mut dir) = (None, 0);
+                    for (content, form) in format.iter() {
+                        let attr = match *form {
+                            DW_FORM_STRING => Attr::Str(r.str()?),
+                            DW_FORM_LINE_STRP => {
+                                Attr::Str(str_at(debug_line_str, r.uint(offset_size)?)?)
+                            }
+                            DW_FORM_STRP => Attr::Str(str_at(debug_str, r.uint(offset_size)?)?),
+                            DW_FORM_UDATA => Attr::Num(r.uleb()?),
+                            DW_FORM_DATA1 => Attr::Num(r.uint(1)?),
+                            DW_FORM_DATA2 => Attr::Num(r.uint(2)?),
+                            DW_FORM_DATA4 => Attr::Num(r.uint(4)?),
+                            DW_FORM_DATA8 => Attr::Num(r.uint(8)?),
+                            DW_FORM_DATA16 => {
+                                r.bytes(16)?;
+                                Attr::Num(0)
+                            }
+                            DW_FORM_BLOCK => {
+                                let n = r.uleb()? as usize;
+                                r.bytes(n)?;
+                                Attr::Num(0)
+                            }
+                            _ => return Err(ElfError::TruncatedError),
+                        };
+                        match (*content, attr) {
+                            (DW_LNCT_PATH, Attr::Str(s)) => path = Some(s),
+                            (DW_LNCT_DIRECTORY_INDEX, Attr::Num(n)) => dir = n,
+                            _ => (),
+                        }
+                    }
+                    entries.push((path, dir));
+                }
+                Ok(entries)
+            };
+            let dirs: Vec<String> = read_entries(r)?
+                .into_iter()
+                .map(|(path, _)| path.unwrap_or_default())
+                .collect();
+            for (path, dir) in read_entries(r)? {
+                let path = join(dirs.get(dir as usize), &path.unwrap_or_default());
+                files.push(self.intern(path));
+            }
+        } else {
+            let mut dirs = Vec::new();
+            loop {
+                let dir = r.str()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            // File 0 is unused before DWARF 5.
+            files.push(usize::MAX);
+            loop {
+                let name = r.str()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                r.uleb()?;
+                r.uleb()?;
+                let path = join(dir.checked_sub(1).and_then(|d| dirs.get(d)), &name);
+                files.push(self.intern(path));
+            }
+        }
+        r.off = program;
+
+        let file_of =
+            |files: &[usize], n: u64| files.get(n as usize).copied().unwrap_or(usize::MAX);
+        let mut addr = 0u64;
+        let mut file = file_of(&files, 1);
+        let mut line = 1i64;
+        let emit = |rows: &mut Vec<LineRow>, addr: u64, file: usize, line: i64, end: bool| {
+            if file != usize::MAX || end {
+                rows.push(LineRow {
+                    addr: addr as u32,
+                    file: if end { 0 } else { file },
+                    line: if end { 0 } else { line as u32 },
+                    end_sequence: end,
+                });
+            }
+        };
+
+        while r.off < r.bytes.len() {
+            let op = r.u8()?;
+            if op >= opcode_base {
+                let adjusted = (op - opcode_base) as u64;
+                addr += (adjusted / line_range) * min_length;
+                line += line_base + (adjusted % line_range) as i64;
+                emit(&mut self.rows, addr, file, line, false);
+                continue;
+            }
+            match op {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.off.saturating_add(len);
+                    match r.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            emit(&mut self.rows, addr, file, line, true);
+                            addr = 0;
+                            file = file_of(&files, 1);
+                            line = 1;
+                        }
+                        DW_LNE_SET_ADDRESS => addr = r.uint(len - 1)?,
+                        DW_LNE_DEFINE_FILE => {
+                            let name = r.str()?;
+                            files.push(self.intern(name));
+                        }
+                        _ => (),
+                    }
+                    r.off = next;
+                }
+                DW_LNS_COPY => emit(&mut self.rows, addr, file, line, false),
+                DW_LNS_ADVANCE_PC => addr += r.uleb()? * min_length,
+                DW_LNS_ADVANCE_LINE => line += r.sleb()?,
+                DW_LNS_SET_FILE => file = file_of(&files, r.uleb()?),
+                DW_LNS_CONST_ADD_PC => {
+                    addr += ((255 - opcode_base as u64) / line_range) * min_length
+                }
+                DW_LNS_FIXED_ADVANCE_PC => addr += r.uint(2)?,
+                _ => {
+                    // Skip the operands of opcodes we do not need.
+                    for _ in 0..opcode_lengths[op as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn intern(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Source files, indexed by `LineRow::file`.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Rows sorted by address.
+    pub fn rows(&self) -> &[LineRow] {
+        &self.rows
+    }
+
+    /// Address ranges of source lines, as `(start, end, row)`.
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32, &LineRow)> {
+        self.rows
+            .windows(2)
+            .filter(|w| !w[0].end_sequence)
+            .map(|w| (w[0].addr, w[1].addr, &w[0]))
+    }
+
+    /// File and line of the instruction at `addr`.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self
+            .rows
+            .partition_point(|r| r.addr <= addr)
+            .checked_sub(1)?;
+        let row = &self.rows[i];
+        match row.end_sequence || i + 1 == self.rows.len() {
+            true => None,
+            false => Some((&self.files[row.file], row.line)),
+        }
+    }
+}
+
+fn join(dir: Option<&String>, name: &str) -> String {
+    match dir {
+        Some(dir) if !dir.is_empty() && !name.starts_with('/') => format!("{}/{}", dir, name),
+        _ => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DWARF 3 line program for `a.c` in `src`: line 3 at 0x100, line 4
+    /// at 0x108, line 3 again at 0x10c, ending at 0x110.
+    fn debug_line_v3() -> Vec<u8> {
+        let mut header = vec![
+            4,    // minimum_instruction_length
+            1,    // default_is_stmt
+            0xfb, // line_base -5
+            14,   // line_range
+            13,   // opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, // standard_opcode_lengths
+        ];
+        header.extend_from_slice(b"src\0\0a.c\0\x01\0\0\0");
+        let program = [
+            // DW_LNE_set_address 0x100
+            0,
+            5,
+            DW_LNE_SET_ADDRESS,
+            0x00,
+            0x01,
+            0,
+            0,
+            // advance_line 2; copy
+            DW_LNS_ADVANCE_LINE,
+            2,
+            DW_LNS_COPY,
+            // special: address += 2 (8 bytes), line += 1
+            13 + 2 * 14 + 6,
+            // special: address += 1, line -= 1
+            13 + 14 + 4,
+            // advance_pc 1; end_sequence
+            DW_LNS_ADVANCE_PC,
+            1,
+            0,
+            1,
+            DW_LNE_END_SEQUENCE,
+        ];
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend_from_slice(&program);
+        let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(unit);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_v3() {
+        let table = LineTable::parse(&debug_line_v3(), &[], &[]).unwrap();
+        assert_eq!(&["src/a.c".to_string()], table.files());
+        assert_eq!(None, table.lookup(0xfc));
+        assert_eq!(Some(("src/a.c", 3)), table.lookup(0x104));
+        assert_eq!(Some(("src/a.c", 4)), table.lookup(0x108));
+        assert_eq!(Some(("src/a.c", 3)), table.lookup(0x10c));
+        assert_eq!(None, table.lookup(0x110));
+        assert_eq!(
+            vec![(0x100, 0x108, 3), (0x108, 0x10c, 4), (0x10c, 0x110, 3)],
+            table
+                .ranges()
+                .map(|(s, e, r)| (s, e, r.line))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut bytes = debug_line_v3();
+        bytes[4] = 9;
+        assert_eq!(
+            Err(ElfError::UnsupportedDwarfVersionError(9)),
+            LineTable::parse(&bytes, &[], &[])
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let table = LineTable::from_listing("prog.s", &[1, 2, 4]);
+        assert_eq!(Some(("prog.s", 4)), table.lookup(8));
+        assert_eq!(None, table.lookup(12));
+        assert_eq!(3, table.ranges().count());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs, path::Path};
+use std::{fmt, fs, path::Path, str::FromStr, sync::Arc};
 
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -6,39 +6,469 @@ use serde::{Deserialize, Serialize};
 pub use lib_rv32_isa::traits::Memory as MemoryTrait;
 use lib_rv32_isa::{common::bit_slice, RiscvError};
 
+use crate::elf::{Elf, PF_R, PF_W, PF_X};
+
+/// Size of the pages that memory is allocated in.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Number of entries in each level of the page table.
+const TABLE_ENTRIES: usize = 1024;
+
+/// Kinds of access a region may allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RWX: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+    pub const RW: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const RX: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    pub const R: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: false,
+    };
+
+    /// Permissions from an ELF segment's `PF_*` flags.
+    pub fn from_elf_flags(flags: u32) -> Self {
+        Permissions {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            execute: flags & PF_X != 0,
+        }
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+/// Parse permissions like `rwx`, `r-x` or `rw`.
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut perms = Permissions {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        for c in s.chars() {
+            match c {
+                'r' => perms.read = true,
+                'w' => perms.write = true,
+                'x' => perms.execute = true,
+                '-' => (),
+                _ => return Err(format!("{} is not a valid permission", c)),
+            }
+        }
+        Ok(perms)
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// A mapped range of addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    pub perms: Permissions,
+}
+
+impl Region {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < self.size
+    }
+}
+
+/// What to do with loads and stores that are not naturally aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MisalignedPolicy {
+    /// Fail with `MemoryAlignmentError`.
+    #[default]
+    Trap,
+    /// Split the access into byte accesses, in order. A store that faults
+    /// part way has written the bytes before the fault.
+    Split,
+    /// Perform the access as a whole, as a trap handler emulating it
+    /// would: every byte is checked before any is written.
+    Emulate,
+}
+
+impl FromStr for MisalignedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(MisalignedPolicy::Trap),
+            "split" => Ok(MisalignedPolicy::Split),
+            "emulate" => Ok(MisalignedPolicy::Emulate),
+            _ => Err(format!("{} is not a valid misaligned access policy", s)),
+        }
+    }
+}
+
+/// Kind of access being checked against a region's permissions or the
+/// PMP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+type Page = Arc<[u8; PAGE_SIZE]>;
+
+/// Two-level table of pages, like Sv32's, that are allocated when first
+/// written. Pages that were never written read as zero.
+///
+/// Pages and second-level tables are shared between clones and copied
+/// when first written, so cloning a memory, e.g. for a checkpoint, only
+/// copies what changes afterwards.
+#[derive(Clone, Default)]
+struct PageTable {
+    root: Vec<Option<Arc<Vec<Option<Page>>>>>,
+}
+
+impl PageTable {
+    fn index(addr: u32) -> (usize, usize, usize) {
+        let addr = addr as usize;
+        (
+            addr / (PAGE_SIZE * TABLE_ENTRIES),
+            (addr / PAGE_SIZE) % TABLE_ENTRIES,
+            addr % PAGE_SIZE,
+        )
+    }
+
+    fn page(&self, addr: u32) -> Option<&Page> {
+        let (vpn1, vpn0, _) = PageTable::index(addr);
+        self.root.get(vpn1)?.as_ref()?[vpn0].as_ref()
+    }
+
+    fn page_mut(&mut self, addr: u32) -> &mut [u8; PAGE_SIZE] {
+        let (vpn1, vpn0, _) = PageTable::index(addr);
+        if self.root.is_empty() {
+            self.root.resize(TABLE_ENTRIES, None);
+        }
+        let table = self.root[vpn1].get_or_insert_with(|| Arc::new(vec![None; TABLE_ENTRIES]));
+        let page = Arc::make_mut(table)[vpn0].get_or_insert_with(|| Arc::new([0; PAGE_SIZE]));
+        Arc::make_mut(page)
+    }
+
+    fn byte(&self, addr: u32) -> u8 {
+        match self.page(addr) {
+            Some(page) => page[addr as usize % PAGE_SIZE],
+            None => 0,
+        }
+    }
+
+    fn set_byte(&mut self, addr: u32, byte: u8) {
+        // Don't allocate a page just to clear it.
+        if byte != 0 || self.page(addr).is_some() {
+            self.page_mut(addr)[addr as usize % PAGE_SIZE] = byte;
+        }
+    }
+
+    /// Allocated pages with their base addresses, in address order.
+    fn pages(&self) -> impl Iterator<Item = (u32, &Page)> {
+        self.root.iter().enumerate().flat_map(|(vpn1, table)| {
+            table
+                .iter()
+                .flat_map(|table| table.iter())
+                .enumerate()
+                .filter_map(move |(vpn0, page)| {
+                    page.as_ref()
+                        .map(|page| (((vpn1 * TABLE_ENTRIES + vpn0) * PAGE_SIZE) as u32, page))
+                })
+        })
+    }
+}
+
 /// Heap allocated, little-endian implementation of memory.
+///
+/// Memory is made of mapped regions anywhere in the 32-bit address space,
+/// backed by pages that are only allocated once written. Accessing an
+/// address outside of every region is an access fault, reported as
+/// `MemoryOutOfBoundsError`, and so is accessing a region in a way its
+/// permissions don't allow, reported as `MemoryPermissionError`.
+/// Inspecting and programming the memory ignores permissions.
+///
+/// `lr.w` reserves the word it loads, and any write that touches the word
+/// afterwards, including the host's, drops the reservation, so that the
+/// next `sc.w` fails. A `Soc` keeps a reservation for each hart.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Memory {
+    /// Total size of the mapped regions.
     pub size: usize,
-    mem: Vec<u8>,
+    regions: Vec<Region>,
+    misaligned: MisalignedPolicy,
+    #[serde(with = "nonzero_blocks")]
+    pages: PageTable,
+    /// Word reserved by `lr.w`. Not part of snapshots.
+    #[serde(skip)]
+    reservation: Option<u32>,
+}
+
+/// Serialize the pages as their non-zero blocks, which keeps snapshots
+/// of mostly-empty memories small.
+mod nonzero_blocks {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::PageTable;
+
+    const BLOCK_SIZE: usize = 64;
+
+    #[derive(Serialize, Deserialize)]
+    struct Blocks {
+        runs: Vec<(u32, Vec<u8>)>,
+    }
+
+    pub fn serialize<S: Serializer>(pages: &PageTable, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (page_base, page) in pages.pages() {
+            for (i, block) in page.chunks(BLOCK_SIZE).enumerate() {
+                if block.iter().all(|b| *b == 0) {
+                    continue;
+                }
+                let base = page_base + (i * BLOCK_SIZE) as u32;
+                match runs.last_mut() {
+                    Some((start, data)) if start.wrapping_add(data.len() as u32) == base => {
+                        data.extend_from_slice(block)
+                    }
+                    _ => runs.push((base, block.to_vec())),
+                }
+            }
+        }
+        Blocks { runs }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PageTable, D::Error> {
+        let blocks = Blocks::deserialize(deserializer)?;
+        let mut pages = PageTable::default();
+        for (start, data) in blocks.runs {
+            if start as u64 + data.len() as u64 > 1 << 32 {
+                return Err(serde::de::Error::custom("block out of bounds"));
+            }
+            for (i, byte) in data.iter().enumerate() {
+                pages.set_byte(start + i as u32, *byte);
+            }
+        }
+        Ok(pages)
+    }
 }
 
 impl Memory {
-    /// Allocate a memory with the given size.
+    /// Allocate a memory with the given size, mapped at address 0. The size
+    /// must fit in the 32-bit address space.
     pub fn new(size: usize) -> Self {
-        assert!(size % 4 == 0);
+        assert!(size.is_multiple_of(4));
         assert!(size > 0);
+        assert!(size as u64 <= u32::MAX as u64, "Memory size exceeds 4 GiB.");
 
+        let mut mem = Memory::sparse();
+        mem.map(0, size as u32);
+        mem
+    }
+
+    /// Create a memory with nothing mapped.
+    pub fn sparse() -> Self {
         Memory {
-            size,
-            mem: vec![0; size],
+            size: 0,
+            regions: Vec::new(),
+            misaligned: MisalignedPolicy::Trap,
+            pages: PageTable::default(),
+            reservation: None,
+        }
+    }
+
+    /// Map `size` bytes starting at `base` for any access.
+    pub fn map(&mut self, base: u32, size: u32) {
+        self.map_with_permissions(base, size, Permissions::RWX);
+    }
+
+    /// Map `size` bytes starting at `base` with `perms`. Both must be
+    /// word-aligned, and the region may not wrap around the address space.
+    /// Where it overlaps regions mapped before, its permissions win.
+    pub fn map_with_permissions(&mut self, base: u32, size: u32, perms: Permissions) {
+        assert!(base.is_multiple_of(4) && size.is_multiple_of(4));
+        assert!(size > 0);
+        assert!(base as u64 + size as u64 <= 1 << 32);
+
+        self.regions.push(Region { base, size, perms });
+
+        // Count overlapping bytes once.
+        let mut spans: Vec<(u64, u64)> = self
+            .regions
+            .iter()
+            .map(|r| (r.base as u64, r.base as u64 + r.size as u64))
+            .collect();
+        spans.sort_unstable();
+        let mut end = 0;
+        self.size = 0;
+        for (start, stop) in spans {
+            let start = start.max(end);
+            if stop > start {
+                self.size += (stop - start) as usize;
+            }
+            end = end.max(stop);
         }
     }
 
-    /// Read a little-endian number from a byte-vector of arbitrary size.
-    fn read(&self, base: usize, size: usize, log: bool) -> Result<u32, RiscvError> {
-        // Check if read falls on a word, half-word, or byte boundary.
-        if base % size != 0 {
-            return Err(RiscvError::MemoryAlignmentError(base as u32));
-        // Check that the read is within bounds.
-        } else if base >= self.size as usize {
-            return Err(RiscvError::MemoryOutOfBoundsError(base as u32));
+    /// Builder form of `map`.
+    pub fn with_region(mut self, base: u32, size: u32) -> Self {
+        self.map(base, size);
+        self
+    }
+
+    /// Builder form of `map_with_permissions`.
+    pub fn with_region_permissions(mut self, base: u32, size: u32, perms: Permissions) -> Self {
+        self.map_with_permissions(base, size, perms);
+        self
+    }
+
+    /// Apply the permissions of an ELF's segments to the memory they are
+    /// loaded in, so that, for example, stores into `.text` fault.
+    pub fn protect_elf(&mut self, elf: &Elf) {
+        for segment in elf.segments.iter().filter(|s| s.mem_size > 0) {
+            let base = segment.addr & !3;
+            let end = (segment.addr as u64 + segment.mem_size as u64 + 3) & !3;
+            self.map_with_permissions(
+                base,
+                (end.min(1 << 32) - base as u64) as u32,
+                Permissions::from_elf_flags(segment.flags),
+            );
         }
+    }
+
+    /// The mapped regions, in the order they were mapped.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
 
-        let data = self.mem[base..base + size]
+    /// The region that `addr` falls in, if it is mapped. Where regions
+    /// overlap, this is the one mapped last.
+    pub fn region(&self, addr: u32) -> Option<Region> {
+        self.regions
             .iter()
-            .enumerate()
-            .map(|(i, b)| ((*b as u32) << (i * 8)) as u32)
+            .rev()
+            .find(|r| r.contains(addr))
+            .copied()
+    }
+
+    /// Set what happens to misaligned loads and stores.
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned = policy;
+    }
+
+    /// Builder form of `set_misaligned_policy`.
+    pub fn with_misaligned_policy(mut self, policy: MisalignedPolicy) -> Self {
+        self.misaligned = policy;
+        self
+    }
+
+    pub fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.misaligned
+    }
+
+    /// Fetch an instruction, which must be aligned and executable.
+    pub fn fetch_instruction(&self, pc: u32) -> Result<u32, RiscvError> {
+        if !pc.is_multiple_of(4) {
+            return Err(RiscvError::MemoryAlignmentError(pc));
+        }
+        self.check(pc, Some(Access::Execute))?;
+        self.read(pc, 4, None, false)
+    }
+
+    /// Address of the word reserved by `lr.w`, if the reservation is
+    /// still valid.
+    pub fn reservation(&self) -> Option<u32> {
+        self.reservation
+    }
+
+    pub(crate) fn set_reservation(&mut self, reservation: Option<u32>) {
+        self.reservation = reservation;
+    }
+
+    /// Number of pages that have been allocated.
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.pages().count()
+    }
+
+    /// Check that `addr` is mapped and, unless `access` is `None`, that
+    /// its region allows the access.
+    fn check(&self, addr: u32, access: Option<Access>) -> Result<(), RiscvError> {
+        match self.region(addr) {
+            None => Err(RiscvError::MemoryOutOfBoundsError(addr)),
+            Some(region) if access.is_some_and(|a| !region.perms.allows(a)) => {
+                Err(RiscvError::MemoryPermissionError(addr))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Check every byte an access touches. Aligned accesses fall in a
+    /// single region, and misaligned ones depend on the policy.
+    fn check_access(
+        &self,
+        base: u32,
+        size: usize,
+        access: Option<Access>,
+    ) -> Result<(), RiscvError> {
+        // Check if the access falls on a word, half-word, or byte boundary.
+        if base.is_multiple_of(size as u32) {
+            return self.check(base, access);
+        } else if self.misaligned == MisalignedPolicy::Trap {
+            return Err(RiscvError::MemoryAlignmentError(base));
+        }
+        for i in 0..size {
+            self.check(base.wrapping_add(i as u32), access)?;
+        }
+        Ok(())
+    }
+
+    /// Read a little-endian number of arbitrary size.
+    fn read(
+        &self,
+        base: u32,
+        size: usize,
+        access: Option<Access>,
+        log: bool,
+    ) -> Result<u32, RiscvError> {
+        self.check_access(base, size, access)?;
+
+        let data = (0..size)
+            .map(|i| (self.pages.byte(base.wrapping_add(i as u32)) as u32) << (i * 8))
             .sum();
 
         if log {
@@ -56,8 +486,25 @@ impl Memory {
         Ok(data)
     }
 
+    /// Read without logging, for inspecting state outside of execution.
+    pub fn peek(&self, addr: u32, size: usize) -> Result<u32, RiscvError> {
+        self.read(addr, size, None, false)
+    }
+
+    /// Write without logging, for restoring state outside of execution.
+    pub(crate) fn poke(&mut self, addr: u32, data: u32, size: usize) -> Result<(), RiscvError> {
+        self.write(addr, data, size, None, false)
+    }
+
     /// Write a little-endian number of arbitrary size.
-    fn write(&mut self, base: usize, data: u32, size: usize, log: bool) -> Result<(), RiscvError> {
+    fn write(
+        &mut self,
+        base: u32,
+        data: u32,
+        size: usize,
+        access: Option<Access>,
+        log: bool,
+    ) -> Result<(), RiscvError> {
         if log {
             match size {
                 1 => info!("(byte *)0x{:08x} <- 0x{:x} ({})", base, data, data as i32),
@@ -70,29 +517,71 @@ impl Memory {
             }
         }
 
-        // Check if read falls on a word, half-word, or byte boundary.
-        if base % size != 0 {
-            return Err(RiscvError::MemoryAlignmentError(base as u32));
-        // Check that the read is within bounds.
-        } else if base >= self.size as usize {
-            return Err(RiscvError::MemoryOutOfBoundsError(base as u32));
+        let split = !base.is_multiple_of(size as u32) && self.misaligned == MisalignedPolicy::Split;
+        if !split {
+            self.check_access(base, size, access)?;
         }
 
-        for (i, b) in self.mem[base..base + size].iter_mut().enumerate() {
-            *b = bit_slice!(data, 8 * (i + 1), 8 * i) as u8;
+        // Only the bytes actually written drop the reservation, so a store
+        // that faults keeps it.
+        let mut result = Ok(());
+        let mut written = 0;
+        for i in 0..size {
+            let addr = base.wrapping_add(i as u32);
+            if split {
+                if let Err(err) = self.check(addr, access) {
+                    result = Err(err);
+                    break;
+                }
+            }
+            self.pages
+                .set_byte(addr, bit_slice!(data, 8 * (i + 1), 8 * i) as u8);
+            written += 1;
         }
 
-        Ok(())
+        if let Some(reserved) = self.reservation {
+            let (start, end) = (base as u64, base as u64 + written as u64);
+            if start < reserved as u64 + 4 && (reserved as u64) < end {
+                self.reservation = None;
+            }
+        }
+
+        result
     }
 
     /// Program the memory from a vector of little-endian bytes.
     pub fn program_le_bytes(&mut self, bytes: &[u8]) -> Result<(), RiscvError> {
-        for (word_addr, chunk) in bytes.chunks(4).enumerate() {
-            for (byte_offset, byte) in chunk.iter().enumerate() {
-                if let Err(why) = self.write(word_addr * 4 + byte_offset, *byte as u32, 1, false) {
-                    return Err(why);
-                }
-            }
+        self.program_le_bytes_at(0, bytes)
+    }
+
+    /// Program the memory from a vector of little-endian bytes, starting at `base`.
+    pub fn program_le_bytes_at(&mut self, base: u32, bytes: &[u8]) -> Result<(), RiscvError> {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.write(
+                base.wrapping_add(offset as u32),
+                *byte as u32,
+                1,
+                None,
+                false,
+            )?
+        }
+        Ok(())
+    }
+
+    /// Program the memory with the loadable segments of an ELF file.
+    pub fn program_elf(&mut self, elf: &Elf) -> Result<(), RiscvError> {
+        for segment in elf.segments.iter() {
+            // Segments may not wrap around the address space.
+            let bss = segment.mem_size.saturating_sub(segment.data.len() as u32);
+            let bss_base = segment
+                .addr
+                .checked_add(segment.data.len() as u32)
+                .ok_or(RiscvError::MemoryOutOfBoundsError(segment.addr))?;
+            bss_base
+                .checked_add(bss)
+                .ok_or(RiscvError::MemoryOutOfBoundsError(bss_base))?;
+            self.program_le_bytes_at(segment.addr, &segment.data)?;
+            self.program_le_bytes_at(bss_base, &vec![0; bss as usize])?;
         }
         Ok(())
     }
@@ -100,16 +589,14 @@ impl Memory {
     /// Program the memory from a vector of words.
     pub fn program_words(&mut self, words: &[u32]) -> Result<(), RiscvError> {
         for (addr, word) in words.iter().enumerate() {
-            if let Err(why) = self.write(addr * 4, *word as u32, 4, false) {
-                return Err(why);
-            }
+            self.write(addr as u32 * 4, *word, 4, None, false)?
         }
         Ok(())
     }
 
     /// Program the memory from a binary file generally created by gcc or clang.
     pub fn program_from_file(&mut self, path: &Path) -> Result<u32, RiscvError> {
-        let prog_bytes = fs::read(&path).expect("Could not read binary.");
+        let prog_bytes = fs::read(path).expect("Could not read binary.");
         match self.program_le_bytes(&prog_bytes) {
             Err(why) => Err(why),
             Ok(_) => Ok(prog_bytes.len() as u32),
@@ -120,42 +607,59 @@ impl Memory {
 // Implement the trait that allows us to execute instructions on this memory.
 impl MemoryTrait for Memory {
     fn fetch(&self, pc: u32) -> Result<u32, RiscvError> {
-        self.read(pc as usize, 4, false)
+        if !pc.is_multiple_of(4) {
+            return Err(RiscvError::MemoryAlignmentError(pc));
+        }
+        self.read(pc, 4, None, false)
     }
 
     fn read_word(&self, addr: u32) -> Result<u32, RiscvError> {
-        self.read(addr as usize, 4, true)
+        self.read(addr, 4, Some(Access::Read), true)
     }
 
     fn read_half_word(&self, addr: u32) -> Result<u32, RiscvError> {
-        match self.read(addr as usize, 2, true) {
+        match self.read(addr, 2, Some(Access::Read), true) {
             Ok(d) => Ok(d),
             Err(why) => Err(why),
         }
     }
     fn read_byte(&self, addr: u32) -> Result<u32, RiscvError> {
-        match self.read(addr as usize, 1, true) {
+        match self.read(addr, 1, Some(Access::Read), true) {
             Ok(d) => Ok(d),
             Err(why) => Err(why),
         }
     }
 
     fn write_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
-        self.write(addr as usize, data, 4, true)
+        self.write(addr, data, 4, Some(Access::Write), true)
     }
 
     fn write_half_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
-        self.write(addr as usize, data as u32, 2, true)
+        self.write(addr, data, 2, Some(Access::Write), true)
     }
 
     fn write_byte(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
-        self.write(addr as usize, data as u32, 1, true)
+        self.write(addr, data, 1, Some(Access::Write), true)
+    }
+
+    fn load_reserved(&mut self, addr: u32) -> Result<u32, RiscvError> {
+        let data = self.read_word(addr)?;
+        self.reservation = Some(addr);
+        Ok(data)
+    }
+
+    fn store_conditional(&mut self, addr: u32, data: u32) -> Result<bool, RiscvError> {
+        match self.reservation.take() {
+            Some(reserved) if reserved == addr => self.write_word(addr, data).map(|_| true),
+            _ => Ok(false),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::elf::test_util::build_elf_with_flags;
 
     #[test]
     #[should_panic]
@@ -169,6 +673,13 @@ mod test {
         let _ = Memory::new(0);
     }
 
+    #[test]
+    #[should_panic]
+    #[cfg(target_pointer_width = "64")]
+    fn test_create_too_large() {
+        let _ = Memory::new(1 << 32);
+    }
+
     #[test]
     fn test_out_of_bounds() {
         let mem = Memory::new(1024);
@@ -178,6 +689,49 @@ mod test {
         };
     }
 
+    #[test]
+    fn test_reservation() {
+        let mut mem = Memory::new(1024);
+        mem.load_reserved(0x100).unwrap();
+        // Writes to other words keep it.
+        mem.write_word(0x104, 1).unwrap();
+        mem.write_byte(0xff, 1).unwrap();
+        assert_eq!(Some(0x100), mem.reservation());
+        assert_eq!(Ok(true), mem.store_conditional(0x100, 2));
+        assert_eq!(None, mem.reservation());
+
+        mem.load_reserved(0x100).unwrap();
+        assert_eq!(Ok(false), mem.store_conditional(0x104, 3));
+        assert_eq!(0, mem.peek(0x104, 4).unwrap() & !1);
+
+        mem.load_reserved(0x100).unwrap();
+        mem.poke(0x102, 0, 2).unwrap();
+        assert_eq!(Ok(false), mem.store_conditional(0x100, 3));
+        assert_eq!(2, mem.peek(0x100, 4).unwrap());
+    }
+
+    #[test]
+    fn test_reservation_faulting_store() {
+        let mut mem = Memory::new(0x1000)
+            .with_region_permissions(0x800, 0x800, Permissions::R)
+            .with_misaligned_policy(MisalignedPolicy::Split);
+        // A store that faults does not touch the reserved word.
+        mem.load_reserved(0x800).unwrap();
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x800)),
+            mem.write_word(0x800, 1)
+        );
+        assert_eq!(Some(0x800), mem.reservation());
+
+        // Split stores that fault drop it only if their written bytes
+        // overlap it.
+        assert!(mem.write_word(0x7fe, 1).is_err());
+        assert_eq!(Some(0x800), mem.reservation());
+        mem.load_reserved(0x7fc).unwrap();
+        assert!(mem.write_word(0x7fe, 1).is_err());
+        assert_eq!(None, mem.reservation());
+    }
+
     #[test]
     fn test_misaligned() {
         let mut mem = Memory::new(1024);
@@ -226,8 +780,8 @@ mod test {
         mem.write_half_word(ADDR, 0x1712).unwrap();
 
         // Is it little-endian?
-        assert_eq!(mem.mem[ADDR as usize], 0x12);
-        assert_eq!(mem.mem[(ADDR + 1) as usize], 0x17);
+        assert_eq!(mem.peek(ADDR, 1).unwrap(), 0x12);
+        assert_eq!(mem.peek(ADDR + 1, 1).unwrap(), 0x17);
     }
 
     #[test]
@@ -236,8 +790,8 @@ mod test {
         let mut mem = Memory::new(1024);
 
         // mem[ADDR] = 0x1712;
-        mem.mem[ADDR as usize] = 0x12;
-        mem.mem[(ADDR + 1) as usize] = 0x17;
+        mem.poke(ADDR, 0x12, 1).unwrap();
+        mem.poke(ADDR + 1, 0x17, 1).unwrap();
 
         assert_eq!(0x1712, mem.read_half_word(ADDR).unwrap());
     }
@@ -260,10 +814,10 @@ mod test {
         mem.write_word(ADDR, 0x76821712).unwrap();
 
         // Is it little-endian?
-        assert_eq!(mem.mem[ADDR as usize], 0x12);
-        assert_eq!(mem.mem[(ADDR + 1) as usize], 0x17);
-        assert_eq!(mem.mem[(ADDR + 2) as usize], 0x82);
-        assert_eq!(mem.mem[(ADDR + 3) as usize], 0x76);
+        assert_eq!(mem.peek(ADDR, 1).unwrap(), 0x12);
+        assert_eq!(mem.peek(ADDR + 1, 1).unwrap(), 0x17);
+        assert_eq!(mem.peek(ADDR + 2, 1).unwrap(), 0x82);
+        assert_eq!(mem.peek(ADDR + 3, 1).unwrap(), 0x76);
     }
 
     #[test]
@@ -272,10 +826,10 @@ mod test {
         let mut mem = Memory::new(1024);
 
         // mem[ADDR] = 0x1712;
-        mem.mem[ADDR as usize] = 0x12;
-        mem.mem[(ADDR + 1) as usize] = 0x17;
-        mem.mem[(ADDR + 2) as usize] = 0x82;
-        mem.mem[(ADDR + 3) as usize] = 0x76;
+        mem.poke(ADDR, 0x12, 1).unwrap();
+        mem.poke(ADDR + 1, 0x17, 1).unwrap();
+        mem.poke(ADDR + 2, 0x82, 1).unwrap();
+        mem.poke(ADDR + 3, 0x76, 1).unwrap();
 
         assert_eq!(0x76821712, mem.read_word(ADDR).unwrap());
     }
@@ -290,6 +844,196 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_sparse() {
+        let mut mem = Memory::sparse()
+            .with_region(0x8000_0000, 0x10000)
+            .with_region(0xffff_f000, 0x1000);
+        assert_eq!(0x11000, mem.size);
+        assert_eq!(0, mem.allocated_pages());
+
+        // Mapped memory reads as zero until written, and is allocated a
+        // page at a time.
+        assert_eq!(0, mem.read_word(0x8000_fffc).unwrap());
+        mem.write_word(0x8000_1000, 0x12345678).unwrap();
+        mem.write_byte(0x8000_1fff, 0x9a).unwrap();
+        mem.write_word(0xffff_fffc, 0xdeadbeef).unwrap();
+        assert_eq!(2, mem.allocated_pages());
+        assert_eq!(0x12345678, mem.read_word(0x8000_1000).unwrap());
+        assert_eq!(0x9a, mem.read_byte(0x8000_1fff).unwrap());
+        assert_eq!(0xdeadbeef, mem.read_word(0xffff_fffc).unwrap());
+
+        // Everything else is unmapped.
+        for addr in [0, 0x7fff_fffc, 0x8001_0000, 0xffff_effc] {
+            assert_eq!(
+                Err(RiscvError::MemoryOutOfBoundsError(addr)),
+                mem.read_word(addr)
+            );
+            assert_eq!(
+                Err(RiscvError::MemoryOutOfBoundsError(addr)),
+                mem.write_word(addr, 1)
+            );
+        }
+        assert_eq!(2, mem.allocated_pages());
+        assert_eq!(
+            Some(Region {
+                base: 0xffff_f000,
+                size: 0x1000,
+                perms: Permissions::RWX
+            }),
+            mem.region(0xffff_f123)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_map_wrapping() {
+        let _ = Memory::sparse().with_region(0xffff_f000, 0x2000);
+    }
+
+    #[test]
+    fn test_clone_shares_pages() {
+        let mut mem = Memory::new(0x2000);
+        mem.write_word(0x10, 1).unwrap();
+        mem.write_word(0x1010, 2).unwrap();
+        let mut copy = mem.clone();
+        assert!(Arc::ptr_eq(
+            mem.pages.page(0).unwrap(),
+            copy.pages.page(0).unwrap()
+        ));
+
+        // Writing copies only the page written.
+        copy.write_word(0x10, 3).unwrap();
+        assert!(!Arc::ptr_eq(
+            mem.pages.page(0).unwrap(),
+            copy.pages.page(0).unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            mem.pages.page(0x1000).unwrap(),
+            copy.pages.page(0x1000).unwrap()
+        ));
+        assert_eq!(1, mem.read_word(0x10).unwrap());
+        assert_eq!(3, copy.read_word(0x10).unwrap());
+    }
+
+    #[test]
+    fn test_serialize_pages() {
+        let mut mem = Memory::sparse().with_region(0x8000_0000, 0x400000);
+        mem.write_word(0x8000_0000, 1).unwrap();
+        mem.write_word(0x803f_fffc, 2).unwrap();
+        mem.write_word(0x8000_0004, 0).unwrap();
+
+        let mem: Memory = serde_json::from_str(&serde_json::to_string(&mem).unwrap()).unwrap();
+        assert_eq!(2, mem.allocated_pages());
+        assert_eq!(1, mem.read_word(0x8000_0000).unwrap());
+        assert_eq!(2, mem.read_word(0x803f_fffc).unwrap());
+        assert_eq!(
+            mem.regions(),
+            [Region {
+                base: 0x8000_0000,
+                size: 0x400000,
+                perms: Permissions::RWX
+            }]
+        );
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut mem = Memory::new(0x2000)
+            .with_region_permissions(0, 0x1000, Permissions::RX)
+            .with_region_permissions(0x4000, 0x1000, "-w-".parse().unwrap());
+        assert_eq!(0x3000, mem.size);
+        mem.program_words(&[0x13]).unwrap();
+
+        // Text can be read and executed, but not written.
+        assert_eq!(0x13, mem.read_word(0).unwrap());
+        assert_eq!(0x13, mem.fetch_instruction(0).unwrap());
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x10)),
+            mem.write_byte(0x10, 1)
+        );
+        assert_eq!(
+            Err(RiscvError::MemoryAlignmentError(2)),
+            mem.fetch_instruction(2)
+        );
+
+        // The rest of the first region keeps its permissions.
+        mem.write_word(0x1000, 0x13).unwrap();
+        assert_eq!(0x13, mem.fetch_instruction(0x1000).unwrap());
+
+        // Write-only memory can't be read, except to inspect it.
+        mem.write_word(0x4000, 7).unwrap();
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x4000)),
+            mem.read_word(0x4000)
+        );
+        assert_eq!(7, mem.peek(0x4000, 4).unwrap());
+        assert_eq!("-w-", mem.region(0x4000).unwrap().perms.to_string());
+    }
+
+    #[test]
+    fn test_protect_elf() {
+        let text = [0x13, 0, 0, 0, 0x13, 0, 0, 0];
+        let elf = Elf::parse(&build_elf_with_flags(0x100, &text, &[], PF_R | PF_X)).unwrap();
+        let mut mem = Memory::new(0x1000);
+        mem.program_elf(&elf).unwrap();
+        mem.protect_elf(&elf);
+
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x104)),
+            mem.write_word(0x104, 0)
+        );
+        mem.write_word(0x108, 0).unwrap();
+        assert_eq!(0x13, mem.fetch_instruction(0x104).unwrap());
+    }
+
+    #[test]
+    fn test_program_elf_wrapping() {
+        // The segment's data runs past the top of the address space.
+        let text = [0x13, 0, 0, 0, 0x13, 0, 0, 0];
+        let elf = Elf::parse(&build_elf_with_flags(0xffff_fffc, &text, &[], PF_R | PF_X)).unwrap();
+        let mut mem = Memory::new(0x1000);
+        assert_eq!(
+            Err(RiscvError::MemoryOutOfBoundsError(0xffff_fffc)),
+            mem.program_elf(&elf)
+        );
+        // Nothing wrapped around to address 0.
+        assert_eq!(0, mem.read_word(0).unwrap());
+    }
+
+    #[test]
+    fn test_misaligned_policies() {
+        let mem = || Memory::new(0x1000).with_region_permissions(0x800, 0x800, Permissions::R);
+
+        let trap = mem();
+        assert_eq!(Err(RiscvError::MemoryAlignmentError(1)), trap.read_word(1));
+
+        // Splitting writes the bytes before the fault.
+        let mut split = mem().with_misaligned_policy(MisalignedPolicy::Split);
+        split.write_word(0x101, 0x44332211).unwrap();
+        assert_eq!(0x44332211, split.read_word(0x101).unwrap());
+        assert_eq!(0x332211, split.peek(0x100, 4).unwrap() >> 8);
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x800)),
+            split.write_word(0x7fe, 0x44332211)
+        );
+        assert_eq!(0x2211, split.peek(0x7fe, 2).unwrap());
+
+        // Emulating checks every byte first.
+        let mut emulate = mem().with_misaligned_policy(MisalignedPolicy::Emulate);
+        emulate.write_half_word(0x103, 0xbeef).unwrap();
+        assert_eq!(0xbeef, emulate.read_half_word(0x103).unwrap());
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x800)),
+            emulate.write_word(0x7fe, 0x44332211)
+        );
+        assert_eq!(0, emulate.peek(0x7fe, 2).unwrap());
+        assert_eq!(
+            Err(RiscvError::MemoryOutOfBoundsError(0x1000)),
+            emulate.read_word(0xffe)
+        );
+    }
+
     #[test]
     fn test_program_little_endian() {
         const NUM: u32 = 0x12345678;
@@ -0,0 +1,743 @@
+use serde::Serialize;
+
+use lib_rv32_isa::{
+    common::{bit_slice, constants::*},
+    decode_func3, decode_opcode, decode_rd, decode_rs1, decode_rs2,
+    traits::RegisterFile as RegisterFileTrait,
+    RiscvError,
+};
+
+pub use lib_rv32_isa::common::constants::CSR_SATP;
+
+use crate::{
+    pmp::Pmp,
+    trace::{AccessKind, MemoryAccess},
+    trap::Privilege,
+    Access, Mcu, Memory, PAGE_SIZE,
+};
+
+/// Fields of `satp`: the translation mode (bare or Sv32), the address
+/// space identifier and the physical page number of the root page table.
+pub const SATP_MODE: u32 = 1 << 31;
+pub const SATP_ASID: u32 = 0x1ff << 22;
+pub const SATP_PPN: u32 = 0x3f_ffff;
+
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+/// What an access is translated with: `satp`, the mode the access is
+/// made in, and the SUM and MXR bits of `mstatus`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Translation {
+    pub satp: u32,
+    pub privilege: Privilege,
+    /// Supervisor mode may load and store to user pages.
+    pub sum: bool,
+    /// Loads may read executable pages that are not readable.
+    pub mxr: bool,
+}
+
+impl Translation {
+    /// Whether addresses are translated, i.e. `satp` selects Sv32 and the
+    /// access is not made in machine mode.
+    pub fn enabled(&self) -> bool {
+        self.satp & SATP_MODE != 0 && self.privilege != Privilege::Machine
+    }
+
+    /// Address space identifier in `satp`.
+    pub fn asid(&self) -> u16 {
+        ((self.satp & SATP_ASID) >> 22) as u16
+    }
+
+    /// Whether a leaf `pte` allows `access` in this mode.
+    fn allows(&self, pte: u32, access: Access) -> bool {
+        let user = pte & PTE_U != 0;
+        let mode = match self.privilege {
+            Privilege::User => user,
+            Privilege::Supervisor => !user || (self.sum && access != Access::Execute),
+            Privilege::Machine => true,
+        };
+        let kind = match access {
+            Access::Read => pte & PTE_R != 0 || (self.mxr && pte & PTE_X != 0),
+            Access::Write => pte & PTE_W != 0,
+            Access::Execute => pte & PTE_X != 0,
+        };
+        mode && kind
+    }
+}
+
+/// Lookups and flushes counted by a `Tlb`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// `sfence.vma`s executed.
+    pub flushes: u64,
+}
+
+impl TlbStats {
+    /// Fraction of lookups that hit, or 0 with no lookups.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+/// A cached leaf page table entry.
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    /// Virtual page number, or bits 31-22 of the address for a megapage.
+    tag: u32,
+    megapage: bool,
+    asid: u16,
+    global: bool,
+    pte: u32,
+    /// Last use, for LRU replacement.
+    stamp: u64,
+}
+
+impl TlbEntry {
+    fn matches(&self, vaddr: u32, asid: u16) -> bool {
+        let tag = match self.megapage {
+            true => vaddr >> 22,
+            false => vaddr >> 12,
+        };
+        self.tag == tag && (self.global || self.asid == asid)
+    }
+
+    fn paddr(&self, vaddr: u32) -> u64 {
+        physical(self.pte, self.megapage, vaddr)
+    }
+}
+
+/// Model of a fully associative TLB with LRU replacement. Entries are
+/// tagged with the address space identifier, except for global pages.
+///
+/// Like a hardware TLB, it may keep using a translation after the page
+/// table changes until `sfence.vma` flushes it, so it can expose missing
+/// fences. A store to a page cached before it was dirty walks the page
+/// table again to set the dirty bit.
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    capacity: usize,
+    entries: Vec<TlbEntry>,
+    stats: TlbStats,
+    clock: u64,
+}
+
+impl Tlb {
+    /// Build a TLB holding up to `capacity` translations.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+
+        Tlb {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            stats: TlbStats::default(),
+            clock: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of translations held.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> TlbStats {
+        self.stats
+    }
+
+    /// Drop the translations of `vaddr`, or of all addresses, in the
+    /// address space `asid`, or in all of them, like `sfence.vma`. Global
+    /// pages are only dropped when no address space is given.
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u16>) {
+        self.stats.flushes += 1;
+        self.entries.retain(|e| {
+            let page = vaddr.is_none_or(|vaddr| e.matches(vaddr, e.asid));
+            let space = asid.is_none_or(|asid| !e.global && e.asid == asid);
+            !(page && space)
+        });
+    }
+
+    /// Drop every translation without counting a flush, for when the
+    /// state it was built from is rewound.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn lookup(&mut self, vaddr: u32, asid: u16) -> Option<TlbEntry> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.iter_mut().find(|e| e.matches(vaddr, asid)) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                entry.stamp = clock;
+                Some(*entry)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        self.entries.retain(|e| {
+            !(e.tag == entry.tag && e.megapage == entry.megapage && e.asid == entry.asid)
+        });
+        if self.entries.len() == self.capacity {
+            let lru = (0..self.entries.len())
+                .min_by_key(|i| self.entries[*i].stamp)
+                .unwrap();
+            self.entries.swap_remove(lru);
+        }
+        self.entries.push(entry);
+    }
+}
+
+/// Physical address of `vaddr` in the page mapped by the leaf `pte`.
+fn physical(pte: u32, megapage: bool, vaddr: u32) -> u64 {
+    let ppn = (pte >> 10) as u64;
+    match megapage {
+        true => (ppn >> 10) << 22 | bit_slice!(vaddr, 21, 0) as u64,
+        false => ppn << 12 | bit_slice!(vaddr, 11, 0) as u64,
+    }
+}
+
+/// Report a memory error at or after the physical address `paddr` as one
+/// at the same offset from the virtual address `vaddr` it was translated
+/// from.
+pub(crate) fn at(paddr: u32, vaddr: u32) -> impl Fn(RiscvError) -> RiscvError {
+    let virt = move |addr: u32| vaddr.wrapping_add(addr.wrapping_sub(paddr));
+    move |err| match err {
+        RiscvError::MemoryAlignmentError(addr) => RiscvError::MemoryAlignmentError(virt(addr)),
+        RiscvError::MemoryOutOfBoundsError(addr) => RiscvError::MemoryOutOfBoundsError(virt(addr)),
+        RiscvError::MemoryPermissionError(addr) => RiscvError::MemoryPermissionError(virt(addr)),
+        err => err,
+    }
+}
+
+/// Translate `vaddr` for `access` and return the physical address,
+/// walking the Sv32 page table on a TLB miss or without a TLB.
+///
+/// Page table entries are read as supervisor accesses, so they are checked
+/// against the PMP. The walk sets the accessed bit, and the dirty bit for
+/// stores, in the leaf entry; those writes are added to `updates`. A
+/// mapping that is invalid or does not allow the access is a
+/// `PageFaultError`, and a page table or page outside of the physical
+/// address space is an access fault.
+pub(crate) fn translate(
+    mem: &mut Memory,
+    pmp: &Pmp,
+    mut tlb: Option<&mut Tlb>,
+    translation: &Translation,
+    vaddr: u32,
+    access: Access,
+    updates: &mut Vec<MemoryAccess>,
+) -> Result<u32, RiscvError> {
+    if !translation.enabled() {
+        return Ok(vaddr);
+    }
+    let fault = RiscvError::PageFaultError(vaddr);
+    let asid = translation.asid();
+    if let Some(entry) = tlb.as_deref_mut().and_then(|tlb| tlb.lookup(vaddr, asid)) {
+        if access != Access::Write || entry.pte & PTE_D != 0 {
+            return match translation.allows(entry.pte, access) {
+                true => Ok(entry.paddr(vaddr) as u32),
+                false => Err(fault),
+            };
+        }
+    }
+
+    let mut table = (translation.satp & SATP_PPN) as u64 * PAGE_SIZE as u64;
+    let mut global = false;
+    let mut level = 1;
+    let (pte_addr, pte) = loop {
+        let vpn = (vaddr >> (12 + 10 * level)) & 0x3ff;
+        let pte_addr = table + vpn as u64 * 4;
+        if pte_addr > u32::MAX as u64 {
+            return Err(RiscvError::MemoryPermissionError(vaddr));
+        }
+        let pte_addr = pte_addr as u32;
+        pmp.check(pte_addr, 4, Access::Read, Privilege::Supervisor)
+            .map_err(at(pte_addr, vaddr))?;
+        let pte = mem.peek(pte_addr, 4).map_err(at(pte_addr, vaddr))?;
+        if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+            return Err(fault);
+        }
+        global |= pte & PTE_G != 0;
+        if pte & (PTE_R | PTE_X) != 0 {
+            break (pte_addr, pte);
+        }
+        if level == 0 {
+            return Err(fault);
+        }
+        level -= 1;
+        table = (pte >> 10) as u64 * PAGE_SIZE as u64;
+    };
+
+    let megapage = level == 1;
+    if megapage && bit_slice!(pte, 19, 10) != 0 {
+        return Err(fault);
+    }
+    if !translation.allows(pte, access) {
+        return Err(fault);
+    }
+    let mut new = pte | PTE_A;
+    if access == Access::Write {
+        new |= PTE_D;
+    }
+    if new != pte {
+        pmp.check(pte_addr, 4, Access::Write, Privilege::Supervisor)
+            .map_err(at(pte_addr, vaddr))?;
+        mem.poke(pte_addr, new, 4).map_err(at(pte_addr, vaddr))?;
+        updates.push(MemoryAccess {
+            kind: AccessKind::Store,
+            addr: pte_addr,
+            size: 4,
+            data: new,
+            old: pte,
+        });
+    }
+    let paddr = physical(new, megapage, vaddr);
+    if paddr > u32::MAX as u64 {
+        return Err(RiscvError::MemoryPermissionError(vaddr));
+    }
+
+    if let Some(tlb) = tlb {
+        tlb.insert(TlbEntry {
+            tag: match megapage {
+                true => vaddr >> 22,
+                false => vaddr >> 12,
+            },
+            megapage,
+            asid,
+            global,
+            pte: new,
+            stamp: tlb.clock,
+        });
+    }
+    Ok(paddr as u32)
+}
+
+impl Mcu {
+    /// Model a TLB of `capacity` translations. Any previous one and its
+    /// statistics are discarded.
+    pub fn enable_tlb(&mut self, capacity: usize) {
+        self.tlb = Some(Tlb::new(capacity));
+    }
+
+    /// Walk the page table on every access again.
+    pub fn disable_tlb(&mut self) {
+        self.tlb = None;
+    }
+
+    /// The TLB, if one is modelled.
+    pub fn tlb(&self) -> Option<&Tlb> {
+        self.tlb.as_ref()
+    }
+
+    /// Flush the TLB for the `sfence.vma` `ir` that just executed. `x0`
+    /// as either operand selects all addresses or address spaces.
+    pub(crate) fn sfence(&mut self, ir: u32) {
+        let (rs1, rs2) = (decode_rs1!(ir), decode_rs2!(ir));
+        let vaddr = (rs1 != 0).then(|| self.rf.read(rs1).unwrap());
+        let asid = (rs2 != 0).then(|| (self.rf.read(rs2).unwrap() & 0x1ff) as u16);
+        if let Some(tlb) = self.tlb.as_mut() {
+            tlb.flush(vaddr, asid);
+        }
+    }
+}
+
+/// Whether `ir` is an `sfence.vma`.
+pub(crate) fn is_sfence(ir: u32) -> bool {
+    decode_opcode!(ir) == OPCODE_SYSTEM
+        && decode_func3!(ir) == FUNC3_PRIV
+        && decode_rd!(ir) == 0
+        && ir >> 25 == FUNC7_SFENCE_VMA as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{
+        pmp::{CSR_PMPADDR0, CSR_PMPCFG0, PMP_R, PMP_W, PMP_X},
+        trap::{Exception, CSR_MCAUSE, CSR_MTVAL, CSR_MTVEC},
+    };
+
+    const ROOT: u32 = 0x8000;
+    const TABLE: u32 = 0x9000;
+    /// Virtual page of user data, backed by 0x4000.
+    const USER: u32 = 0x40_0000;
+    /// Virtual page that is only readable, backed by 0x5000.
+    const READ_ONLY: u32 = 0x40_1000;
+    const SATP: u32 = SATP_MODE | (ROOT >> 12);
+
+    /// Map the first megapage to itself for supervisor code, and two
+    /// pages of the second one through a level-0 table.
+    fn memory_with_tables() -> Memory {
+        let mut mem = Memory::new(0x10000);
+        mem.poke(ROOT, PTE_V | PTE_R | PTE_W | PTE_X, 4).unwrap();
+        mem.poke(ROOT + 4, (TABLE >> 12) << 10 | PTE_V, 4).unwrap();
+        mem.poke(
+            TABLE,
+            (0x4000 >> 12) << 10 | PTE_V | PTE_R | PTE_W | PTE_U,
+            4,
+        )
+        .unwrap();
+        mem.poke(TABLE + 4, (0x5000 >> 12) << 10 | PTE_V | PTE_R, 4)
+            .unwrap();
+        mem
+    }
+
+    fn translation(privilege: Privilege) -> Translation {
+        Translation {
+            satp: SATP,
+            privilege,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    /// A PMP that lets supervisor mode access everything, including the
+    /// page tables.
+    fn allow_all() -> Pmp {
+        let mut pmp = Pmp::new();
+        pmp.write(CSR_PMPADDR0, u32::MAX).unwrap();
+        pmp.write(CSR_PMPCFG0, (PMP_R | PMP_W | PMP_X | 0x18) as u32)
+            .unwrap();
+        pmp
+    }
+
+    fn walk(
+        mem: &mut Memory,
+        t: &Translation,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, RiscvError> {
+        translate(mem, &allow_all(), None, t, vaddr, access, &mut Vec::new())
+    }
+
+    /// An MCU in supervisor mode running `program` under translation.
+    fn supervisor_mcu(program: &str) -> Mcu {
+        let mut mcu = Mcu::with_memory(memory_with_tables());
+        mcu.mem
+            .program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        mcu.rf.pmp = allow_all();
+        mcu.rf.write_csr(CSR_SATP, SATP).unwrap();
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        mcu
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut mem = memory_with_tables();
+        let s = translation(Privilege::Supervisor);
+        let u = translation(Privilege::User);
+        assert_eq!(Ok(0x1234), walk(&mut mem, &s, 0x1234, Access::Execute));
+        assert_eq!(Ok(0x4010), walk(&mut mem, &u, USER + 0x10, Access::Read));
+        assert_eq!(
+            Ok(0x5ffc),
+            walk(&mut mem, &s, READ_ONLY + 0xffc, Access::Read)
+        );
+        // Machine mode and bare mode are not translated.
+        let m = translation(Privilege::Machine);
+        assert_eq!(Ok(USER), walk(&mut mem, &m, USER, Access::Read));
+        let bare = Translation { satp: 0, ..s };
+        assert_eq!(Ok(USER), walk(&mut mem, &bare, USER, Access::Read));
+
+        for (t, vaddr, access) in [
+            // Unmapped.
+            (s, USER + 0x2000, Access::Read),
+            (s, 0x80_0000, Access::Read),
+            // Not writable.
+            (s, READ_ONLY, Access::Write),
+            // User pages from supervisor mode need SUM, and are never
+            // executable there.
+            (s, USER, Access::Read),
+            (Translation { sum: true, ..s }, USER, Access::Execute),
+            // Supervisor pages are not accessible from user mode.
+            (u, 0, Access::Execute),
+            (u, READ_ONLY, Access::Read),
+        ] {
+            assert_eq!(
+                Err(RiscvError::PageFaultError(vaddr)),
+                walk(&mut mem, &t, vaddr, access),
+                "{:?} 0x{:x} {:?}",
+                t.privilege,
+                vaddr,
+                access
+            );
+        }
+        let sum = Translation { sum: true, ..s };
+        assert_eq!(Ok(0x4000), walk(&mut mem, &sum, USER, Access::Write));
+
+        // MXR makes executable pages readable.
+        mem.poke(TABLE + 8, (0x6000 >> 12) << 10 | PTE_V | PTE_X, 4)
+            .unwrap();
+        let page = USER + 0x2000;
+        assert!(walk(&mut mem, &s, page, Access::Read).is_err());
+        let mxr = Translation { mxr: true, ..s };
+        assert_eq!(Ok(0x6000), walk(&mut mem, &mxr, page, Access::Read));
+
+        // A megapage must be aligned to 4 MiB.
+        mem.poke(ROOT + 8, 1 << 10 | PTE_V | PTE_R, 4).unwrap();
+        assert_eq!(
+            Err(RiscvError::PageFaultError(0x80_0000)),
+            walk(&mut mem, &s, 0x80_0000, Access::Read)
+        );
+    }
+
+    #[test]
+    fn test_accessed_dirty() {
+        let mut mem = memory_with_tables();
+        let u = translation(Privilege::User);
+        let pte = mem.peek(TABLE, 4).unwrap();
+        let mut updates = Vec::new();
+        translate(
+            &mut mem,
+            &allow_all(),
+            None,
+            &u,
+            USER,
+            Access::Read,
+            &mut updates,
+        )
+        .unwrap();
+        translate(
+            &mut mem,
+            &allow_all(),
+            None,
+            &u,
+            USER,
+            Access::Write,
+            &mut updates,
+        )
+        .unwrap();
+        // A second store finds the bits already set.
+        translate(
+            &mut mem,
+            &allow_all(),
+            None,
+            &u,
+            USER,
+            Access::Write,
+            &mut updates,
+        )
+        .unwrap();
+        assert_eq!(pte | PTE_A | PTE_D, mem.peek(TABLE, 4).unwrap());
+        assert_eq!(2, updates.len());
+        assert_eq!(
+            (TABLE, pte, pte | PTE_A),
+            (updates[0].addr, updates[0].old, updates[0].data)
+        );
+        assert_eq!(pte | PTE_A | PTE_D, updates[1].data);
+    }
+
+    #[test]
+    fn test_page_table_access_fault() {
+        let mut mem = memory_with_tables();
+        // The level-0 table is outside of memory.
+        mem.poke(ROOT + 4, (0x80000 >> 12) << 10 | PTE_V, 4)
+            .unwrap();
+        let s = translation(Privilege::Supervisor);
+        assert_eq!(
+            Err(RiscvError::MemoryOutOfBoundsError(USER)),
+            walk(&mut mem, &s, USER, Access::Read)
+        );
+        // Supervisor mode cannot read page tables the PMP does not allow.
+        let mut pmp = Pmp::new();
+        pmp.write(CSR_PMPADDR0, 0x8000 >> 2).unwrap();
+        pmp.write(CSR_PMPCFG0, (PMP_R | PMP_W | PMP_X | 0x08) as u32)
+            .unwrap();
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x1000)),
+            translate(
+                &mut mem,
+                &pmp,
+                None,
+                &s,
+                0x1000,
+                Access::Read,
+                &mut Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn test_tlb() {
+        let mut mem = memory_with_tables();
+        let mut tlb = Tlb::new(2);
+        let s = Translation {
+            sum: true,
+            ..translation(Privilege::Supervisor)
+        };
+        let lookup = |mem: &mut Memory, tlb: &mut Tlb, t: &Translation, vaddr| {
+            translate(
+                mem,
+                &allow_all(),
+                Some(tlb),
+                t,
+                vaddr,
+                Access::Read,
+                &mut Vec::new(),
+            )
+        };
+        lookup(&mut mem, &mut tlb, &s, USER).unwrap();
+        lookup(&mut mem, &mut tlb, &s, USER + 4).unwrap();
+        assert_eq!((1, 1), (tlb.stats().hits, tlb.stats().misses));
+
+        // The cached translation is used until it is flushed.
+        mem.poke(TABLE, 0, 4).unwrap();
+        assert_eq!(Ok(0x4000), lookup(&mut mem, &mut tlb, &s, USER));
+        tlb.flush(Some(USER), None);
+        assert_eq!(
+            Err(RiscvError::PageFaultError(USER)),
+            lookup(&mut mem, &mut tlb, &s, USER)
+        );
+
+        // Other address spaces miss, and the least recently used entry
+        // is evicted.
+        lookup(&mut mem, &mut tlb, &s, 0).unwrap();
+        lookup(&mut mem, &mut tlb, &s, READ_ONLY).unwrap();
+        let other = Translation {
+            satp: SATP | 1 << 22,
+            ..s
+        };
+        lookup(&mut mem, &mut tlb, &other, READ_ONLY).unwrap();
+        assert_eq!(2, tlb.len());
+        let misses = tlb.stats().misses;
+        lookup(&mut mem, &mut tlb, &s, 0).unwrap();
+        assert_eq!(misses + 1, tlb.stats().misses);
+        assert_eq!(2, tlb.len());
+
+        // Flushing one address space keeps global pages.
+        mem.poke(ROOT, PTE_V | PTE_R | PTE_W | PTE_X | PTE_G, 4)
+            .unwrap();
+        tlb.flush(None, None);
+        assert!(tlb.is_empty());
+        lookup(&mut mem, &mut tlb, &s, 0).unwrap();
+        lookup(&mut mem, &mut tlb, &s, READ_ONLY).unwrap();
+        tlb.flush(None, Some(0));
+        assert_eq!(1, tlb.len());
+        let hits = tlb.stats().hits;
+        lookup(&mut mem, &mut tlb, &other, 0).unwrap();
+        assert_eq!(hits + 1, tlb.stats().hits);
+        assert_eq!(3, tlb.stats().flushes);
+    }
+
+    #[test]
+    fn test_supervisor_program() {
+        let mut mcu = supervisor_mcu(
+            "lui t0, 0x400\n\
+             lw a0, 0(t0)\n\
+             lw a1, 0(t0)\n\
+             sfence.vma\n\
+             lw a2, 0(t0)",
+        );
+        mcu.mem.poke(0x4000, 42, 4).unwrap();
+        mcu.rf.write_csr(CSR_SSTATUS, MSTATUS_SUM).unwrap();
+        mcu.enable_tlb(8);
+        for _ in 0..5 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(42, mcu.rf.read(12).unwrap());
+        let stats = mcu.tlb().unwrap().stats();
+        assert_eq!((4, 4, 1), (stats.hits, stats.misses, stats.flushes));
+    }
+
+    #[test]
+    fn test_page_fault() {
+        let mut mcu = supervisor_mcu(
+            "lui t0, 0x401\n\
+             lw a0, 0(t0)\n\
+             sw a0, 0(t0)",
+        );
+        mcu.mem.poke(0x5000, 7, 4).unwrap();
+        mcu.step().unwrap();
+        let step = mcu.step().unwrap();
+        assert_eq!(0x5000, step.accesses[0].addr);
+        assert_eq!(7, mcu.rf.read(10).unwrap());
+        assert_eq!(
+            Err(RiscvError::PageFaultError(READ_ONLY)),
+            mcu.step().map(|_| ())
+        );
+
+        mcu.rf.trap.set_privilege(Privilege::Machine);
+        mcu.rf.write_csr(CSR_MTVEC, 0x100).unwrap();
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        let trap = mcu.step().unwrap().trap.unwrap();
+        assert_eq!(
+            (Exception::StorePageFault, READ_ONLY),
+            (trap.cause, trap.tval)
+        );
+        assert_eq!(Ok(15), mcu.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(Ok(READ_ONLY), mcu.rf.read_csr(CSR_MTVAL));
+
+        // Fetching from an unmapped page.
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        mcu.pc = 0x80_0000;
+        let trap = mcu.step().unwrap().trap.unwrap();
+        assert_eq!(
+            (Exception::InstructionPageFault, 0x80_0000),
+            (trap.cause, trap.tval)
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut mcu = supervisor_mcu("lui t0, 0x400\nsw t0, 0(t0)");
+        mcu.rf.write_csr(CSR_SSTATUS, MSTATUS_SUM).unwrap();
+        mcu.enable_undo(4);
+        mcu.enable_tlb(4);
+        let root = mcu.mem.peek(ROOT, 4).unwrap();
+        let pte = mcu.mem.peek(TABLE, 4).unwrap();
+        mcu.step().unwrap();
+        let step = mcu.step().unwrap();
+        assert_eq!(2, mcu.tlb().unwrap().len());
+        assert_eq!(1, step.pte_updates.len());
+        assert_eq!(pte | PTE_A | PTE_D, mcu.mem.peek(TABLE, 4).unwrap());
+
+        mcu.step_back().unwrap();
+        mcu.step_back().unwrap();
+        assert_eq!(pte, mcu.mem.peek(TABLE, 4).unwrap());
+        assert_eq!(root, mcu.mem.peek(ROOT, 4).unwrap());
+        assert_eq!(0, mcu.mem.peek(0x4000, 4).unwrap());
+        assert!(mcu.tlb().unwrap().is_empty());
+
+        mcu.replay().unwrap();
+        mcu.replay().unwrap();
+        assert_eq!(pte | PTE_A | PTE_D, mcu.mem.peek(TABLE, 4).unwrap());
+        assert_eq!(USER, mcu.mem.peek(0x4000, 4).unwrap());
+    }
+
+    #[test]
+    fn test_sfence_privilege() {
+        let mut mcu = supervisor_mcu("sfence.vma");
+        mcu.rf.trap.write(CSR_SATP, 0).unwrap();
+        mcu.rf.trap.set_privilege(Privilege::User);
+        assert_eq!(
+            Err(RiscvError::InvalidCsrError(CSR_SATP)),
+            mcu.step().map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use lib_rv32_isa::{common::constants::REG_NAMES, disassemble, InstructionClass, RegisterUsage};
+
+use crate::Step;
+
+/// Names of the pipeline stages, in order.
+pub const STAGE_NAMES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+/// Options for the pipeline model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineConfig {
+    /// Forward results from EX and MEM to the next instructions. Without
+    /// it, dependent instructions stall until the producer reaches WB.
+    pub forwarding: bool,
+    /// Number of instructions (and hazards) kept for display.
+    pub history: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            forwarding: true,
+            history: 64,
+        }
+    }
+}
+
+/// An instruction as it went through the pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipelineEntry {
+    pub pc: u32,
+    pub ir: u32,
+    pub text: String,
+    /// Cycle at which the instruction entered each stage, IF to WB.
+    pub stages: [u64; 5],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum HazardKind {
+    /// The consumer reads a register the producer has not written back yet.
+    Data,
+    /// The producer redirected fetch, so the instructions fetched after it
+    /// were flushed. The consumer is the instruction fetched instead.
+    Control,
+}
+
+/// A hazard and the pair of instructions responsible for it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Hazard {
+    pub kind: HazardKind,
+    /// Register carrying the dependency, for data hazards.
+    pub reg: Option<u8>,
+    pub producer_pc: u32,
+    pub producer_ir: u32,
+    pub consumer_pc: u32,
+    /// Cycles lost: stalls for data hazards, flushed slots for control.
+    pub penalty: u64,
+    /// Whether the value was forwarded rather than read from the
+    /// register file.
+    pub forwarded: bool,
+}
+
+/// Result of an instruction still in flight.
+#[derive(Debug, Clone, Copy)]
+struct Producer {
+    pc: u32,
+    ir: u32,
+    load: bool,
+    stages: [u64; 5],
+}
+
+/// Model of the classic in-order IF/ID/EX/MEM/WB pipeline.
+///
+/// It is fed the `Step` records from `Mcu::step` and works out the cycle
+/// at which each instruction enters each stage. Data hazards stall the
+/// consumer in ID; branches and jumps are resolved in EX and flush the
+/// two instructions fetched after them when they redirect fetch.
+#[derive(Debug, Clone, Serialize)]
+pub struct Pipeline {
+    #[serde(skip)]
+    config: PipelineConfig,
+    forwarding: bool,
+    cycles: u64,
+    instructions: u64,
+    entries: VecDeque<PipelineEntry>,
+    hazards: VecDeque<Hazard>,
+    #[serde(skip)]
+    producers: [Option<Producer>; 32],
+    #[serde(skip)]
+    last: Option<([u64; 5], bool)>,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Pipeline {
+            config,
+            forwarding: config.forwarding,
+            cycles: 0,
+            instructions: 0,
+            entries: VecDeque::new(),
+            hazards: VecDeque::new(),
+            producers: [None; 32],
+            last: None,
+        }
+    }
+
+    /// Total cycles until the last instruction left WB.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Number of instructions retired.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The most recent instructions, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &PipelineEntry> {
+        self.entries.iter()
+    }
+
+    /// The most recent hazards, oldest first.
+    pub fn hazards(&self) -> impl Iterator<Item = &Hazard> {
+        self.hazards.iter()
+    }
+
+    /// Account for the next instruction in program order.
+    pub fn retire(&mut self, step: &Step) {
+        let class = InstructionClass::of(step.ir);
+        let usage = RegisterUsage::of(step.ir);
+
+        // IF is free once the previous instruction moved to ID, unless it
+        // redirected fetch, in which case fetch restarts after its EX.
+        // The instruction may then wait in IF until ID is free.
+        let (fetch, decode, ex_free) = match self.last {
+            None => (0, 1, 0),
+            Some((prev, redirect)) => {
+                let fetch = if redirect { prev[2] + 1 } else { prev[1] };
+                (fetch, (fetch + 1).max(prev[2]), prev[2] + 1)
+            }
+        };
+        let earliest_ex = (decode + 1).max(ex_free);
+
+        let mut execute = earliest_ex;
+        let mut hazards = Vec::new();
+        for reg in [usage.rs1, usage.rs2].iter().flatten() {
+            if let Some(p) = self.producers[*reg as usize] {
+                // The register file is written in the first half of WB and
+                // read in the second half of ID.
+                if decode >= p.stages[4] {
+                    continue;
+                }
+                let ready = match (self.config.forwarding, p.load) {
+                    (true, false) => p.stages[2] + 1,
+                    (true, true) => p.stages[3] + 1,
+                    (false, _) => p.stages[4] + 1,
+                };
+                execute = execute.max(ready);
+                hazards.push(Hazard {
+                    kind: HazardKind::Data,
+                    reg: Some(*reg),
+                    producer_pc: p.pc,
+                    producer_ir: p.ir,
+                    consumer_pc: step.pc,
+                    penalty: ready.saturating_sub(earliest_ex),
+                    forwarded: self.config.forwarding,
+                });
+            }
+        }
+        // Both sources may come from the same producer.
+        hazards.dedup_by(|a, b| a.reg == b.reg);
+
+        let stages = [fetch, decode, execute, execute + 1, execute + 2];
+
+        let redirect = match class {
+            InstructionClass::Branch => step.next_pc != step.pc.wrapping_add(4),
+            InstructionClass::Jump => true,
+            _ => false,
+        };
+        if redirect {
+            hazards.push(Hazard {
+                kind: HazardKind::Control,
+                reg: None,
+                producer_pc: step.pc,
+                producer_ir: step.ir,
+                consumer_pc: step.next_pc,
+                // The instructions in IF and ID are squashed.
+                penalty: 2,
+                forwarded: false,
+            });
+        }
+
+        if let Some(rd) = usage.rd {
+            self.producers[rd as usize] = Some(Producer {
+                pc: step.pc,
+                ir: step.ir,
+                load: class == InstructionClass::Load,
+                stages,
+            });
+        }
+        self.last = Some((stages, redirect));
+        self.cycles = stages[4] + 1;
+        self.instructions += 1;
+
+        self.hazards.extend(hazards);
+        while self.hazards.len() > self.config.history {
+            self.hazards.pop_front();
+        }
+        self.entries.push_back(PipelineEntry {
+            pc: step.pc,
+            ir: step.ir,
+            text: disassemble(step.ir),
+            stages,
+        });
+        while self.entries.len() > self.config.history {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Render the recent history as a table with one row per instruction
+    /// and one column per cycle, followed by the hazards. Cycles in which
+    /// an instruction is held in a stage are shown as `--`.
+    pub fn diagram(&self) -> String {
+        let first = match self.entries.front() {
+            Some(e) => e.stages[0],
+            None => return String::new(),
+        };
+        let last = self.entries.back().unwrap().stages[4];
+
+        let mut out = format!("{:8}  {:24}", "pc", "instruction");
+        for cycle in first..=last {
+            out += &format!("{:<4}", cycle);
+        }
+        out = out.trim_end().to_string() + "\n";
+
+        for entry in self.entries.iter() {
+            out += &format!("{:08x}  {:24}", entry.pc, entry.text);
+            for cycle in first..=last {
+                let cell = match entry.stages.iter().rposition(|s| *s <= cycle) {
+                    Some(i) if entry.stages[i] == cycle => STAGE_NAMES[i],
+                    Some(i) if i < 4 => "--",
+                    _ => "",
+                };
+                out += &format!("{:4}", cell);
+            }
+            out = out.trim_end().to_string() + "\n";
+        }
+
+        if !self.hazards.is_empty() {
+            out += "\nhazards:\n";
+        }
+        for h in self.hazards.iter() {
+            out += &match h.kind {
+                HazardKind::Data => format!(
+                    "  data on {}: {:08x} {} -> {:08x} ({}, {} stall cycles)\n",
+                    REG_NAMES[h.reg.unwrap() as usize],
+                    h.producer_pc,
+                    disassemble(h.producer_ir),
+                    h.consumer_pc,
+                    if h.forwarded {
+                        "forwarded"
+                    } else {
+                        "not forwarded"
+                    },
+                    h.penalty
+                ),
+                HazardKind::Control => format!(
+                    "  control: {:08x} {} -> {:08x} ({} cycles flushed)\n",
+                    h.producer_pc,
+                    disassemble(h.producer_ir),
+                    h.consumer_pc,
+                    h.penalty
+                ),
+            };
+        }
+
+        out += &format!(
+            "\ncycles: {}, instructions: {}\n",
+            self.cycles, self.instructions
+        );
+        out
+    }
+
+    /// The recent history, hazards and totals as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new(PipelineConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::Mcu;
+
+    fn run(program: &str, n: usize, forwarding: bool) -> Pipeline {
+        let config = PipelineConfig {
+            forwarding,
+            ..PipelineConfig::default()
+        };
+        run_with(program, n, config)
+    }
+
+    fn run_with(program: &str, n: usize, config: PipelineConfig) -> Pipeline {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        let mut pipeline = Pipeline::new(config);
+        for _ in 0..n {
+            pipeline.retire(&mcu.step().unwrap());
+        }
+        pipeline
+    }
+
+    fn stages(pipeline: &Pipeline) -> Vec<[u64; 5]> {
+        pipeline.entries().map(|e| e.stages).collect()
+    }
+
+    #[test]
+    fn test_no_hazards() {
+        let pipeline = run(
+            "addi t0, zero, 1\naddi t1, zero, 2\naddi t2, zero, 3",
+            3,
+            true,
+        );
+        assert_eq!(
+            vec![[0, 1, 2, 3, 4], [1, 2, 3, 4, 5], [2, 3, 4, 5, 6]],
+            stages(&pipeline)
+        );
+        assert_eq!(7, pipeline.cycles());
+        assert_eq!(0, pipeline.hazards().count());
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let pipeline = run("addi t0, zero, 1\naddi t1, t0, 2", 2, true);
+        assert_eq!(vec![[0, 1, 2, 3, 4], [1, 2, 3, 4, 5]], stages(&pipeline));
+
+        let hazard = pipeline.hazards().next().unwrap();
+        assert_eq!(HazardKind::Data, hazard.kind);
+        assert_eq!(Some(5), hazard.reg);
+        assert_eq!((0, 4), (hazard.producer_pc, hazard.consumer_pc));
+        assert_eq!(0, hazard.penalty);
+    }
+
+    #[test]
+    fn test_stalling() {
+        let pipeline = run(
+            "addi t0, zero, 1\naddi t1, t0, 2\naddi t2, zero, 3",
+            3,
+            false,
+        );
+        // The consumer waits in ID until the producer's WB, and the next
+        // instruction waits in IF behind it.
+        assert_eq!(
+            vec![[0, 1, 2, 3, 4], [1, 2, 5, 6, 7], [2, 5, 6, 7, 8]],
+            stages(&pipeline)
+        );
+        assert_eq!(2, pipeline.hazards().next().unwrap().penalty);
+    }
+
+    #[test]
+    fn test_load_use() {
+        let pipeline = run("lw t0, 0x100(zero)\naddi t1, t0, 2", 2, true);
+        assert_eq!(vec![[0, 1, 2, 3, 4], [1, 2, 4, 5, 6]], stages(&pipeline));
+        assert_eq!(1, pipeline.hazards().next().unwrap().penalty);
+    }
+
+    #[test]
+    fn test_control() {
+        let pipeline = run("jal zero, 8\naddi t0, zero, 1\naddi t1, zero, 1", 2, true);
+        assert_eq!(vec![[0, 1, 2, 3, 4], [3, 4, 5, 6, 7]], stages(&pipeline));
+
+        let hazard = pipeline.hazards().next().unwrap();
+        assert_eq!(HazardKind::Control, hazard.kind);
+        assert_eq!((0, 8), (hazard.producer_pc, hazard.consumer_pc));
+        assert_eq!(2, hazard.penalty);
+    }
+
+    #[test]
+    fn test_history() {
+        let program = "addi t0, zero, 1\naddi t1, t0, 1\naddi t2, t1, 1";
+        let config = PipelineConfig {
+            forwarding: true,
+            history: 1,
+        };
+        let pipeline = run_with(program, 3, config);
+        assert_eq!(vec![[2, 3, 4, 5, 6]], stages(&pipeline));
+        assert_eq!(1, pipeline.hazards().count());
+
+        let pipeline = run_with(
+            program,
+            3,
+            PipelineConfig {
+                history: 0,
+                ..config
+            },
+        );
+        assert_eq!(0, pipeline.entries().count());
+        assert_eq!(0, pipeline.hazards().count());
+        assert_eq!(7, pipeline.cycles());
+    }
+
+    #[test]
+    fn test_diagram() {
+        let pipeline = run("lw t0, 0x100(zero)\naddi t1, t0, 2", 2, true);
+        let diagram = pipeline.diagram();
+        let lines: Vec<&str> = diagram.lines().collect();
+        assert!(lines[1].ends_with("IF  ID  EX  MEM WB"));
+        assert!(lines[2].ends_with("IF  ID  --  EX  MEM WB"));
+        assert!(diagram.contains("data on t0: 00000000 lw t0, 256(zero) -> 00000004"));
+
+        let json: serde_json::Value = serde_json::from_str(&pipeline.to_json()).unwrap();
+        assert_eq!(7, json["cycles"]);
+        assert_eq!("addi t1, t0, 2", json["entries"][1]["text"]);
+    }
+}
@@ -0,0 +1,354 @@
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::RiscvError;
+
+use crate::{trap::Privilege, Access};
+
+pub const CSR_PMPCFG0: u16 = 0x3a0;
+pub const CSR_PMPADDR0: u16 = 0x3b0;
+
+/// Number of PMP entries, configured by `pmpcfg0-3` and `pmpaddr0-15`.
+pub const PMP_ENTRIES: usize = 16;
+
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_L: u8 = 1 << 7;
+
+/// Address matching mode of a PMP entry, bits 3-4 of its configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmpMatch {
+    /// The entry is disabled.
+    Off = 0,
+    /// From the previous entry's address up to this one's.
+    Tor = 1,
+    /// A naturally aligned four-byte region.
+    Na4 = 2,
+    /// A naturally aligned power-of-two region of at least eight bytes.
+    Napot = 3,
+}
+
+impl PmpMatch {
+    fn of(cfg: u8) -> Self {
+        match (cfg >> 3) & 0b11 {
+            0 => PmpMatch::Off,
+            1 => PmpMatch::Tor,
+            2 => PmpMatch::Na4,
+            _ => PmpMatch::Napot,
+        }
+    }
+}
+
+/// Physical memory protection: sixteen entries, each an address range
+/// and the loads, stores and fetches allowed in it.
+///
+/// The lowest numbered entry that matches any byte of an access decides
+/// it, and an access that is only partly inside that entry fails. In
+/// machine mode an access no entry matches is allowed, and an entry only
+/// restricts accesses once it is locked. In user and supervisor mode every
+/// entry applies, and an access no entry matches fails. Locked entries
+/// ignore writes to their configuration and address, and a locked TOR
+/// entry also locks the address below it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u32; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Pmp::default()
+    }
+
+    /// Whether `csr` is one of the PMP registers.
+    pub fn contains(csr: u16) -> bool {
+        (CSR_PMPCFG0..CSR_PMPCFG0 + 4).contains(&csr)
+            || (CSR_PMPADDR0..CSR_PMPADDR0 + PMP_ENTRIES as u16).contains(&csr)
+    }
+
+    /// Configuration byte of entry `i`.
+    pub fn cfg(&self, i: usize) -> u8 {
+        self.cfg[i]
+    }
+
+    /// Value of `pmpaddrI`, bits 33 to 2 of an address.
+    pub fn addr(&self, i: usize) -> u32 {
+        self.addr[i]
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        self.cfg[i] & PMP_L != 0
+    }
+
+    /// Addresses matched by entry `i`, as a half-open range, or `None` if
+    /// it is off or empty.
+    pub fn range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = (self.addr[i] as u64) << 2;
+        let (start, end) = match PmpMatch::of(self.cfg[i]) {
+            PmpMatch::Off => return None,
+            PmpMatch::Tor => match i {
+                0 => (0, addr),
+                _ => ((self.addr[i - 1] as u64) << 2, addr),
+            },
+            PmpMatch::Na4 => (addr, addr + 4),
+            PmpMatch::Napot => {
+                // The number of trailing ones encodes the size.
+                let ones = self.addr[i].trailing_ones();
+                let size = 1u64 << (ones + 3);
+                let start = addr & !(size - 1);
+                (start, start + size)
+            }
+        };
+        match start < end {
+            true => Some((start, end)),
+            false => None,
+        }
+    }
+
+    /// Check an access of `size` bytes at `addr` made in `privilege` mode.
+    /// A denied access is a `MemoryPermissionError`.
+    pub fn check(
+        &self,
+        addr: u32,
+        size: u32,
+        access: Access,
+        privilege: Privilege,
+    ) -> Result<(), RiscvError> {
+        let machine = privilege == Privilege::Machine;
+        let (start, end) = (addr as u64, addr as u64 + size as u64);
+        for i in 0..PMP_ENTRIES {
+            let (lo, hi) = match self.range(i) {
+                Some(range) => range,
+                None => continue,
+            };
+            if end <= lo || hi <= start {
+                continue;
+            }
+            let allowed = match access {
+                _ if start < lo || hi < end => false,
+                _ if machine && !self.is_locked(i) => true,
+                Access::Read => self.cfg[i] & PMP_R != 0,
+                Access::Write => self.cfg[i] & PMP_W != 0,
+                Access::Execute => self.cfg[i] & PMP_X != 0,
+            };
+            return match allowed {
+                true => Ok(()),
+                false => Err(RiscvError::MemoryPermissionError(addr)),
+            };
+        }
+        match machine {
+            true => Ok(()),
+            false => Err(RiscvError::MemoryPermissionError(addr)),
+        }
+    }
+
+    /// Read `pmpcfgN` or `pmpaddrN`.
+    pub fn read(&self, csr: u16) -> Result<u32, RiscvError> {
+        if !Pmp::contains(csr) {
+            return Err(RiscvError::InvalidCsrError(csr));
+        }
+        Ok(match csr {
+            _ if csr >= CSR_PMPADDR0 => self.addr[(csr - CSR_PMPADDR0) as usize],
+            _ => {
+                let first = (csr - CSR_PMPCFG0) as usize * 4;
+                u32::from_le_bytes([
+                    self.cfg[first],
+                    self.cfg[first + 1],
+                    self.cfg[first + 2],
+                    self.cfg[first + 3],
+                ])
+            }
+        })
+    }
+
+    /// Write `pmpcfgN` or `pmpaddrN`. Writes to locked entries are
+    /// ignored, as are the reserved bits and the reserved write-only
+    /// permission.
+    pub fn write(&mut self, csr: u16, data: u32) -> Result<(), RiscvError> {
+        self.write_inner(csr, data, true)
+    }
+
+    /// Write a PMP register regardless of locks, for restoring state
+    /// outside of execution.
+    pub(crate) fn restore(&mut self, csr: u16, data: u32) {
+        self.write_inner(csr, data, false).unwrap();
+    }
+
+    fn write_inner(&mut self, csr: u16, data: u32, locks: bool) -> Result<(), RiscvError> {
+        if !Pmp::contains(csr) {
+            return Err(RiscvError::InvalidCsrError(csr));
+        }
+        if csr >= CSR_PMPADDR0 {
+            let i = (csr - CSR_PMPADDR0) as usize;
+            let tor_above = i + 1 < PMP_ENTRIES
+                && self.is_locked(i + 1)
+                && PmpMatch::of(self.cfg[i + 1]) == PmpMatch::Tor;
+            if !locks || !(self.is_locked(i) || tor_above) {
+                self.addr[i] = data;
+            }
+            return Ok(());
+        }
+
+        let first = (csr - CSR_PMPCFG0) as usize * 4;
+        for (i, byte) in data.to_le_bytes().iter().enumerate() {
+            if locks && self.is_locked(first + i) {
+                continue;
+            }
+            let mut cfg = byte & !0b0110_0000;
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            self.cfg[first + i] = cfg;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Mcu, RegisterFileTrait};
+
+    /// Encode `csrrw`-style instructions.
+    fn csr_op(func3: u32, rd: u32, csr: u16, rs1: u32) -> u32 {
+        (csr as u32) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | 0b1110011
+    }
+
+    fn cfg(i: usize, cfg: u8) -> (u16, u32) {
+        (CSR_PMPCFG0 + i as u16 / 4, (cfg as u32) << (8 * (i % 4)))
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut pmp = Pmp::new();
+        // TOR from 0 to 0x100, NA4 at 0x200, NAPOT 0x400..0x800.
+        pmp.write(CSR_PMPADDR0, 0x100 >> 2).unwrap();
+        pmp.write(CSR_PMPADDR0 + 1, 0x200 >> 2).unwrap();
+        pmp.write(CSR_PMPADDR0 + 2, (0x400 >> 2) | 0x7f).unwrap();
+        pmp.write(CSR_PMPCFG0, 0x00_18_10_08).unwrap();
+        assert_eq!(Some((0, 0x100)), pmp.range(0));
+        assert_eq!(Some((0x200, 0x204)), pmp.range(1));
+        assert_eq!(Some((0x400, 0x800)), pmp.range(2));
+        assert_eq!(None, pmp.range(3));
+        assert_eq!(Ok(0x00_18_10_08), pmp.read(CSR_PMPCFG0));
+
+        // TOR with the previous address above is empty.
+        pmp.write(CSR_PMPCFG0, 0x08_00_00_00).unwrap();
+        assert_eq!(None, pmp.range(3));
+    }
+
+    #[test]
+    fn test_machine_mode() {
+        let mut pmp = Pmp::new();
+        pmp.write(CSR_PMPADDR0, 0x100 >> 2).unwrap();
+        // An unlocked entry without permissions does not restrict M-mode.
+        pmp.write(CSR_PMPCFG0, 0x08).unwrap();
+        assert_eq!(
+            Ok(()),
+            pmp.check(0x10, 4, Access::Write, Privilege::Machine)
+        );
+
+        // Once locked, it does, and accesses straddling its end fail.
+        pmp.write(CSR_PMPCFG0, (PMP_L | PMP_R | 0x08) as u32)
+            .unwrap();
+        assert_eq!(Ok(()), pmp.check(0x10, 4, Access::Read, Privilege::Machine));
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x10)),
+            pmp.check(0x10, 4, Access::Write, Privilege::Machine)
+        );
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0xfe)),
+            pmp.check(0xfe, 4, Access::Read, Privilege::Machine)
+        );
+        assert_eq!(
+            Ok(()),
+            pmp.check(0x100, 4, Access::Execute, Privilege::Machine)
+        );
+    }
+
+    #[test]
+    fn test_lower_modes() {
+        let mut pmp = Pmp::new();
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0)),
+            pmp.check(0, 4, Access::Execute, Privilege::User)
+        );
+        // Unlocked entries apply below machine mode.
+        pmp.write(CSR_PMPADDR0, 0x100 >> 2).unwrap();
+        pmp.write(CSR_PMPCFG0, (PMP_R | PMP_X | 0x08) as u32)
+            .unwrap();
+        assert_eq!(Ok(()), pmp.check(0, 4, Access::Execute, Privilege::User));
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x10)),
+            pmp.check(0x10, 4, Access::Write, Privilege::Supervisor)
+        );
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0x100)),
+            pmp.check(0x100, 4, Access::Read, Privilege::User)
+        );
+        assert_eq!(
+            Ok(()),
+            pmp.check(0x10, 4, Access::Write, Privilege::Machine)
+        );
+    }
+
+    #[test]
+    fn test_locks() {
+        let mut pmp = Pmp::new();
+        pmp.write(CSR_PMPADDR0, 0x40).unwrap();
+        pmp.write(CSR_PMPADDR0 + 1, 0x80).unwrap();
+        pmp.write(CSR_PMPCFG0, ((PMP_L | 0x08) as u32) << 8)
+            .unwrap();
+
+        // Locked TOR entry 1 locks its own address and the one below.
+        pmp.write(CSR_PMPADDR0, 0).unwrap();
+        pmp.write(CSR_PMPADDR0 + 1, 0).unwrap();
+        pmp.write(CSR_PMPCFG0, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(0x40), pmp.read(CSR_PMPADDR0));
+        assert_eq!(Ok(0x80), pmp.read(CSR_PMPADDR0 + 1));
+        // Entries 0, 2 and 3 took the write, minus the reserved bits.
+        assert_eq!(Ok(0x9f_9f_88_9f), pmp.read(CSR_PMPCFG0));
+
+        pmp.restore(CSR_PMPCFG0, 0);
+        assert_eq!(Pmp::new().cfg, pmp.cfg);
+    }
+
+    #[test]
+    fn test_write_only_reserved() {
+        let mut pmp = Pmp::new();
+        pmp.write(CSR_PMPCFG0 + 1, (PMP_W | PMP_X) as u32).unwrap();
+        assert_eq!(PMP_X, pmp.cfg(4));
+        assert_eq!(Err(RiscvError::InvalidCsrError(0x3a4)), pmp.read(0x3a4));
+    }
+
+    #[test]
+    fn test_enforced() {
+        let (csr, value) = cfg(0, PMP_L | PMP_X | 0x18);
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&[
+                // csrrw zero, pmpaddr0, t0; csrrw zero, pmpcfg0, t1
+                csr_op(0b001, 0, CSR_PMPADDR0, 5),
+                csr_op(0b001, 0, csr, 6),
+                // lw t2, 0(zero); sw zero, 0x100(zero)
+                0x00002383,
+                0x10002023,
+            ])
+            .unwrap();
+        // NAPOT over 0..0x100, execute only.
+        mcu.rf.write(5, 0x1f).unwrap();
+        mcu.rf.write(6, value).unwrap();
+        for _ in 0..2 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(
+            Err(RiscvError::MemoryPermissionError(0)),
+            mcu.step().map(|_| ())
+        );
+        assert_eq!(8, mcu.pc);
+
+        // Outside the entry, M-mode accesses are unrestricted.
+        mcu.pc = 12;
+        mcu.step().unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use lib_rv32_isa::InstructionClass;
+
+use crate::{
+    elf::{Symbol, SymbolKind},
+    Step,
+};
+
+/// Time spent in a function.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub addr: u32,
+    /// Times it was entered with a call.
+    pub calls: u64,
+    /// Instructions and cycles executed in the function itself.
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Instructions and cycles including the functions it called.
+    pub inclusive_instructions: u64,
+    pub inclusive_cycles: u64,
+}
+
+/// A straight run of instructions ending in a branch or jump.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlockProfile {
+    pub start: u32,
+    /// Address of the last instruction.
+    pub end: u32,
+    pub executions: u64,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// Instruction-level profiler.
+///
+/// Instructions are attributed to the function whose symbol covers their
+/// address, and calls and returns are followed with a shadow stack to
+/// build inclusive times and folded stacks. Code without a symbol, e.g.
+/// in a raw binary, belongs to the function that was called to reach it,
+/// named after its address.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    /// Symbol ranges as `(start, end, function)`, sorted by address.
+    ranges: Vec<(u32, u32, usize)>,
+    functions: Vec<FunctionProfile>,
+    by_addr: HashMap<u32, usize>,
+    stack: Vec<usize>,
+    /// Cycles per call stack.
+    stacks: HashMap<Vec<usize>, u64>,
+    blocks: BTreeMap<(u32, u32), BlockProfile>,
+    block: Option<BlockProfile>,
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Profiler {
+    /// Profile using the function symbols of an ELF symbol table. Tables
+    /// without any (e.g. from hand-written assembly) use every label.
+    pub fn new(symbols: &[Symbol]) -> Self {
+        let kind = match symbols.iter().any(|s| s.kind == SymbolKind::Function) {
+            true => SymbolKind::Function,
+            false => SymbolKind::Other,
+        };
+        let mut symbols: Vec<&Symbol> = symbols.iter().filter(|s| s.kind == kind).collect();
+        symbols.sort_by_key(|s| s.addr);
+
+        let mut profiler = Profiler::default();
+        for (i, s) in symbols.iter().enumerate() {
+            if profiler.by_addr.contains_key(&s.addr) {
+                continue;
+            }
+            // Symbols without a size extend to the next one.
+            let end = match (s.size, symbols.get(i + 1)) {
+                (0, Some(next)) => next.addr,
+                (0, None) => u32::MAX,
+                (size, _) => s.addr.saturating_add(size),
+            };
+            let f = profiler.function(s.addr, Some(&s.name));
+            profiler.ranges.push((s.addr, end, f));
+        }
+        profiler
+    }
+
+    /// Function starting at `addr`, created if it is not known yet.
+    fn function(&mut self, addr: u32, name: Option<&str>) -> usize {
+        let functions = &mut self.functions;
+        *self.by_addr.entry(addr).or_insert_with(|| {
+            functions.push(FunctionProfile {
+                name: name.map_or_else(|| format!("0x{:08x}", addr), String::from),
+                addr,
+                ..FunctionProfile::default()
+            });
+            functions.len() - 1
+        })
+    }
+
+    /// Function whose symbol covers `addr`.
+    fn symbol_at(&self, addr: u32) -> Option<usize> {
+        let i = self
+            .ranges
+            .partition_point(|r| r.0 <= addr)
+            .checked_sub(1)?;
+        let (start, end, f) = self.ranges[i];
+        (start..end).contains(&addr).then_some(f)
+    }
+
+    /// Account for an executed instruction that took `cycles` cycles,
+    /// e.g. as estimated by a timing model.
+    pub fn retire(&mut self, step: &Step, cycles: u64) {
+        let current = match (self.symbol_at(step.pc), self.stack.last()) {
+            (Some(f), _) => f,
+            (None, Some(&f)) => f,
+            (None, None) => self.function(step.pc, None),
+        };
+        // Jumping into another function without a call (e.g. a tail call)
+        // replaces the current frame.
+        match self.stack.last_mut() {
+            Some(top) => *top = current,
+            None => self.stack.push(current),
+        }
+
+        self.instructions += 1;
+        self.cycles += cycles;
+        let f = &mut self.functions[current];
+        f.instructions += 1;
+        f.cycles += cycles;
+        let mut seen = Vec::with_capacity(self.stack.len());
+        for &f in self.stack.iter() {
+            // Recursive calls only count once.
+            if !seen.contains(&f) {
+                seen.push(f);
+                self.functions[f].inclusive_instructions += 1;
+                self.functions[f].inclusive_cycles += cycles;
+            }
+        }
+        *self.stacks.entry(self.stack.clone()).or_default() += cycles;
+
+        let block = self.block.get_or_insert(BlockProfile {
+            start: step.pc,
+            ..BlockProfile::default()
+        });
+        block.end = step.pc;
+        block.instructions += 1;
+        block.cycles += cycles;
+        if matches!(
+            InstructionClass::of(step.ir),
+            InstructionClass::Branch | InstructionClass::Jump
+        ) {
+            let block = self.block.take().unwrap();
+            let entry = self
+                .blocks
+                .entry((block.start, block.end))
+                .or_insert(BlockProfile {
+                    start: block.start,
+                    end: block.end,
+                    ..BlockProfile::default()
+                });
+            entry.executions += 1;
+            entry.instructions += block.instructions;
+            entry.cycles += block.cycles;
+        }
+
+        if step.is_return() && self.stack.len() > 1 {
+            self.stack.pop();
+        }
+        if step.is_call() {
+            let callee = match self.symbol_at(step.next_pc) {
+                Some(f) => f,
+                None => self.function(step.next_pc, None),
+            };
+            self.functions[callee].calls += 1;
+            self.stack.push(callee);
+        }
+    }
+
+    /// Total instructions profiled.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Total cycles profiled.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Functions that executed at least one instruction, most expensive
+    /// (by exclusive cycles) first.
+    pub fn functions(&self) -> Vec<&FunctionProfile> {
+        let mut functions: Vec<&FunctionProfile> = self
+            .functions
+            .iter()
+            .filter(|f| f.inclusive_instructions > 0)
+            .collect();
+        functions.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.addr.cmp(&b.addr)));
+        functions
+    }
+
+    /// Completed basic blocks, most expensive first.
+    pub fn hot_blocks(&self) -> Vec<&BlockProfile> {
+        let mut blocks: Vec<&BlockProfile> = self.blocks.values().collect();
+        blocks.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)));
+        blocks
+    }
+
+    /// Name and offset of the function containing `addr`, e.g. `main+0x8`.
+    fn location(&self, addr: u32) -> String {
+        match self.symbol_at(addr).map(|f| &self.functions[f]) {
+            Some(f) if f.addr == addr => f.name.clone(),
+            Some(f) => format!("{}+0x{:x}", f.name, addr - f.addr),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    /// Flat profile followed by the call counts, inclusive times and the
+    /// hottest `blocks` basic blocks.
+    pub fn report(&self, blocks: usize) -> String {
+        let percent = |cycles: u64| match self.cycles {
+            0 => 0.0,
+            total => 100.0 * cycles as f64 / total as f64,
+        };
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "{:>7} {:>12} {:>12} {:>7} {:>12} {:>12} {:>8}  function",
+            "self%",
+            "self cycles",
+            "self instrs",
+            "total%",
+            "total cycles",
+            "total instrs",
+            "calls"
+        )
+        .unwrap();
+        for f in self.functions() {
+            writeln!(
+                out,
+                "{:>6.2}% {:>12} {:>12} {:>6.2}% {:>12} {:>12} {:>8}  {}",
+                percent(f.cycles),
+                f.cycles,
+                f.instructions,
+                percent(f.inclusive_cycles),
+                f.inclusive_cycles,
+                f.inclusive_instructions,
+                f.calls,
+                f.name
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nhot blocks:").unwrap();
+        for b in self.hot_blocks().into_iter().take(blocks) {
+            writeln!(
+                out,
+                "{:>6.2}% {:>12} cycles {:>10} executions  {:08x}-{:08x}  {}",
+                percent(b.cycles),
+                b.cycles,
+                b.executions,
+                b.start,
+                b.end,
+                self.location(b.start)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Cycles per call stack in the folded format read by flamegraph
+    /// tools: one `outer;inner cycles` line per stack.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<&str> = stack
+                    .iter()
+                    .map(|&f| self.functions[f].name.as_str())
+                    .collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mcu;
+
+    // main: jal ra, f; jal ra, f; jal zero, 0
+    // f:    addi t0, t0, 1; jalr zero, 0(ra)
+    const PROGRAM: [u32; 5] = [0x00c000ef, 0x008000ef, 0x0000006f, 0x00128293, 0x00008067];
+
+    fn symbol(name: &str, addr: u32, size: u32, kind: SymbolKind) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            kind,
+        }
+    }
+
+    fn profile(symbols: &[Symbol], n: usize) -> Profiler {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_words(&PROGRAM).unwrap();
+        let mut profiler = Profiler::new(symbols);
+        for _ in 0..n {
+            let step = mcu.step().unwrap();
+            let cycles = if step.pc == 12 { 3 } else { 1 };
+            profiler.retire(&step, cycles);
+        }
+        profiler
+    }
+
+    #[test]
+    fn test_functions() {
+        let profiler = profile(
+            &[
+                symbol("main", 0, 12, SymbolKind::Function),
+                symbol("f", 12, 8, SymbolKind::Function),
+                symbol("data", 0x100, 4, SymbolKind::Object),
+            ],
+            7,
+        );
+        let functions = profiler.functions();
+        assert_eq!(2, functions.len());
+
+        let f = functions[0];
+        assert_eq!(
+            ("f", 2, 4, 8),
+            (f.name.as_str(), f.calls, f.instructions, f.cycles)
+        );
+        let main = functions[1];
+        assert_eq!(0, main.calls);
+        assert_eq!(3, main.cycles);
+        assert_eq!(11, main.inclusive_cycles);
+        assert_eq!(7, main.inclusive_instructions);
+        assert_eq!(11, profiler.cycles());
+    }
+
+    #[test]
+    fn test_folded() {
+        let profiler = profile(
+            &[
+                symbol("main", 0, 0, SymbolKind::Other),
+                symbol("f", 12, 0, SymbolKind::Other),
+            ],
+            7,
+        );
+        assert_eq!("main 3\nmain;f 8\n", profiler.folded());
+    }
+
+    #[test]
+    fn test_without_symbols() {
+        let profiler = profile(&[], 7);
+        assert_eq!("0x00000000 3\n0x00000000;0x0000000c 8\n", profiler.folded());
+    }
+
+    #[test]
+    fn test_hot_blocks() {
+        let profiler = profile(&[symbol("f", 12, 8, SymbolKind::Function)], 7);
+        let blocks = profiler.hot_blocks();
+        assert_eq!((12, 16, 2, 8), {
+            let b = blocks[0];
+            (b.start, b.end, b.executions, b.cycles)
+        });
+        assert_eq!(4, blocks.len());
+        assert!(profiler.report(10).contains("0000000c-00000010  f\n"));
+    }
+}
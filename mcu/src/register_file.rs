@@ -1,19 +1,132 @@
 use log::info;
 use serde::{Deserialize, Serialize};
 
+pub use lib_rv32_isa::traits::FpRegisterFile as FpRegisterFileTrait;
 pub use lib_rv32_isa::traits::RegisterFile as RegisterFileTrait;
 use lib_rv32_isa::{common::constants::*, RiscvError};
 
-/// Heap allocated implementation of a register file.
+use crate::{csr::CsrFile, pmp::Pmp, trap::TrapCsrs};
+
+/// Heap allocated implementation of a register file, with the counter,
+/// trap and PMP CSRs, and the floating-point registers.
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct RegisterFile {
     registers: Vec<u32>,
+    pub csrs: CsrFile,
+    pub trap: TrapCsrs,
+    pub pmp: Pmp,
+    pub fp: FpRegisterFile,
+}
+
+/// Heap allocated implementation of the 64-bit floating-point registers
+/// and `fcsr`. There is no `mstatus.FS`, so the registers are always
+/// enabled and considered dirty.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct FpRegisterFile {
+    registers: Vec<u64>,
+    fcsr: u32,
+}
+
+impl FpRegisterFile {
+    pub fn new() -> Self {
+        FpRegisterFile {
+            registers: vec![0; 32],
+            fcsr: 0,
+        }
+    }
+
+    /// Whether `csr` is `fflags`, `frm` or `fcsr`.
+    pub fn contains(csr: u16) -> bool {
+        matches!(csr, CSR_FFLAGS | CSR_FRM | CSR_FCSR)
+    }
+
+    pub fn read_csr(&self, csr: u16) -> u32 {
+        match csr {
+            CSR_FFLAGS => self.fcsr & 0x1f,
+            CSR_FRM => self.fcsr >> 5,
+            _ => self.fcsr,
+        }
+    }
+
+    /// Write `fflags`, `frm` or `fcsr`, ignoring the bits they do not
+    /// have. Reserved rounding modes can be written, but instructions
+    /// using them are illegal.
+    pub fn write_csr(&mut self, csr: u16, data: u32) {
+        self.fcsr = match csr {
+            CSR_FFLAGS => (self.fcsr & !0x1f) | (data & 0x1f),
+            CSR_FRM => (self.fcsr & 0x1f) | (data & 0b111) << 5,
+            _ => data & 0xff,
+        };
+    }
+
+    /// Write without logging, for restoring state outside of execution.
+    pub(crate) fn restore(&mut self, num: u8, data: u64) {
+        if num < 32 {
+            self.registers[num as usize] = data;
+        }
+    }
+}
+
+impl FpRegisterFileTrait for FpRegisterFile {
+    fn write(&mut self, num: u8, data: u64) -> Result<(), RiscvError> {
+        if num > 31 {
+            return Err(RiscvError::RegisterOutOfRangeError(num));
+        }
+        self.registers[num as usize] = data;
+
+        match data >> 32 {
+            0xffff_ffff => info!(
+                "{} <- 0x{:08x} ({})",
+                FP_REG_NAMES[num as usize],
+                data as u32,
+                f32::from_bits(data as u32)
+            ),
+            _ => info!(
+                "{} <- 0x{:016x} ({})",
+                FP_REG_NAMES[num as usize],
+                data,
+                f64::from_bits(data)
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, num: u8) -> Result<u64, RiscvError> {
+        match self.registers.get(num as usize) {
+            Some(data) => Ok(*data),
+            None => Err(RiscvError::RegisterOutOfRangeError(num)),
+        }
+    }
 }
 
 impl RegisterFile {
     pub fn new() -> Self {
         RegisterFile {
             registers: vec![0; 31],
+            csrs: CsrFile::new(),
+            trap: TrapCsrs::new(),
+            pmp: Pmp::new(),
+            fp: FpRegisterFile::new(),
+        }
+    }
+
+    /// Write without logging, for restoring state outside of execution.
+    pub(crate) fn restore(&mut self, num: u8, data: u32) {
+        if (1..32).contains(&num) {
+            self.registers[num as usize - 1] = data;
+        }
+    }
+
+    /// Write a CSR without logging or honouring PMP locks and the current
+    /// privilege mode, for restoring state outside of execution. The CSR
+    /// must be writable.
+    pub(crate) fn restore_csr(&mut self, csr: u16, data: u32) {
+        match csr {
+            _ if Pmp::contains(csr) => self.pmp.restore(csr, data),
+            _ if FpRegisterFile::contains(csr) => self.fp.write_csr(csr, data),
+            _ if TrapCsrs::contains(csr) => self.trap.write(csr, data).unwrap(),
+            _ => self.csrs.write(csr, data).unwrap(),
         }
     }
 }
@@ -43,6 +156,36 @@ impl RegisterFileTrait for RegisterFile {
             Ok(self.registers[num as usize - 1])
         }
     }
+
+    fn read_csr(&self, csr: u16) -> Result<u32, RiscvError> {
+        if !self.trap.allows_csr(csr) {
+            return Err(RiscvError::InvalidCsrError(csr));
+        }
+        match csr {
+            _ if Pmp::contains(csr) => self.pmp.read(csr),
+            _ if FpRegisterFile::contains(csr) => Ok(self.fp.read_csr(csr)),
+            _ if TrapCsrs::contains(csr) => self.trap.read(csr),
+            _ => self.csrs.read(csr),
+        }
+    }
+
+    fn write_csr(&mut self, csr: u16, data: u32) -> Result<(), RiscvError> {
+        if !self.trap.allows_csr(csr) {
+            return Err(RiscvError::InvalidCsrError(csr));
+        }
+        match csr {
+            _ if Pmp::contains(csr) => self.pmp.write(csr, data)?,
+            _ if FpRegisterFile::contains(csr) => self.fp.write_csr(csr, data),
+            _ if TrapCsrs::contains(csr) => self.trap.write(csr, data)?,
+            _ => self.csrs.write(csr, data)?,
+        }
+        info!("csr 0x{:03x} <- 0x{:x}", csr, data);
+        Ok(())
+    }
+
+    fn fp(&mut self) -> Option<&mut dyn FpRegisterFileTrait> {
+        Some(&mut self.fp)
+    }
 }
 
 #[cfg(test)]
@@ -75,4 +218,21 @@ mod tests {
             RegisterFile::new().read(32)
         )
     }
+
+    #[test]
+    fn test_fcsr() {
+        let mut rf = RegisterFile::new();
+        rf.write_csr(CSR_FCSR, 0xfff).unwrap();
+        assert_eq!(0xff, rf.read_csr(CSR_FCSR).unwrap());
+        rf.write_csr(CSR_FRM, 1).unwrap();
+        assert_eq!(0x1f, rf.read_csr(CSR_FFLAGS).unwrap());
+        rf.write_csr(CSR_FFLAGS, FFLAGS_NX).unwrap();
+        assert_eq!(0x21, rf.read_csr(CSR_FCSR).unwrap());
+        assert_eq!(1, rf.read_csr(CSR_FRM).unwrap());
+
+        // f0 is an ordinary register.
+        rf.fp.write(0, 1 << 40).unwrap();
+        assert_eq!(1 << 40, rf.fp.read(0).unwrap());
+        assert_eq!(Err(RiscvError::RegisterOutOfRangeError(32)), rf.fp.read(32));
+    }
 }
@@ -0,0 +1,342 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{elf::Symbol, Mcu};
+
+const SNAPSHOT_MAGIC: &[u8] = b"RV32SNAP";
+const SNAPSHOT_VERSION: u32 = 10;
+
+/// What the loader learned about the program, kept in snapshots so that a
+/// resumed run still has its symbols, its heap and HTIF.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProgramInfo {
+    pub symbols: Vec<Symbol>,
+    /// Start of the heap, i.e. the end of the program.
+    pub heap_start: u32,
+    /// The program break when the snapshot was taken.
+    pub brk: u32,
+    /// Addresses of `tohost` and `fromhost`.
+    pub htif: Option<(u32, Option<u32>)>,
+}
+
+/// Enumeration of possible errors when saving or loading a snapshot.
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    IOError,
+    UnsupportedVersionError(u32),
+    EncodeError,
+    DecodeError,
+}
+
+/// On-disk representation of a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotFormat {
+    /// Magic number and version followed by a `bincode` encoding of the MCU.
+    Binary,
+    /// Human-readable JSON, best kept for small memories.
+    Json,
+}
+
+impl SnapshotFormat {
+    /// Pick a format from a file extension: `.json` is JSON, anything
+    /// else is binary.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("json") => SnapshotFormat::Json,
+            _ => SnapshotFormat::Binary,
+        }
+    }
+}
+
+impl Mcu {
+    /// Encode the complete machine state.
+    pub fn to_snapshot(&self, format: SnapshotFormat) -> Result<Vec<u8>, SnapshotError> {
+        match format {
+            SnapshotFormat::Binary => {
+                let mut bytes = SNAPSHOT_MAGIC.to_vec();
+                bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+                match bincode::serialize(self) {
+                    Ok(body) => bytes.extend(body),
+                    Err(_) => return Err(SnapshotError::EncodeError),
+                }
+                Ok(bytes)
+            }
+            SnapshotFormat::Json => {
+                serde_json::to_vec(self).map_err(|_| SnapshotError::EncodeError)
+            }
+        }
+    }
+
+    /// Decode a machine state produced by `to_snapshot`. The format is
+    /// detected automatically.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        match bytes.strip_prefix(SNAPSHOT_MAGIC) {
+            Some(rest) => {
+                if rest.len() < 4 {
+                    return Err(SnapshotError::DecodeError);
+                }
+                let version = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+                if version != SNAPSHOT_VERSION {
+                    return Err(SnapshotError::UnsupportedVersionError(version));
+                }
+                bincode::deserialize(&rest[4..]).map_err(|_| SnapshotError::DecodeError)
+            }
+            None => serde_json::from_slice(bytes).map_err(|_| SnapshotError::DecodeError),
+        }
+    }
+
+    /// Save the machine state to a file.
+    pub fn save_snapshot(&self, path: &Path, format: SnapshotFormat) -> Result<(), SnapshotError> {
+        let bytes = self.to_snapshot(format)?;
+        fs::write(path, bytes).map_err(|_| SnapshotError::IOError)
+    }
+
+    /// Load a machine state from a file written by `save_snapshot`.
+    pub fn load_snapshot(path: &Path) -> Result<Self, SnapshotError> {
+        match fs::read(path) {
+            Ok(bytes) => Mcu::from_snapshot(&bytes),
+            Err(_) => Err(SnapshotError::IOError),
+        }
+    }
+}
+
+/// In-memory checkpoints taken at regular instruction intervals.
+///
+/// When the store is full, every other checkpoint is dropped and the
+/// interval is doubled, so a run of any length is covered with bounded
+/// memory. Combined with `bisect`, this allows finding the first
+/// instruction at which some condition holds without re-running the
+/// whole program. Checkpoints share memory pages with the running MCU
+/// until either writes them, and leave out its undo log.
+#[derive(Clone)]
+pub struct Checkpoints {
+    interval: u64,
+    capacity: usize,
+    checkpoints: Vec<Mcu>,
+}
+
+impl Checkpoints {
+    /// Take a checkpoint every `interval` instructions, keeping at most
+    /// `capacity` of them.
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        assert!(interval > 0);
+        assert!(capacity >= 2);
+
+        Checkpoints {
+            interval,
+            capacity,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Current spacing between checkpoints, in instructions.
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Number of checkpoints held.
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Whether no checkpoint has been taken yet.
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Offer the current state. A checkpoint is taken if the MCU has
+    /// executed a multiple of the interval since the last one.
+    pub fn record(&mut self, mcu: &Mcu) {
+        let due = match self.checkpoints.last() {
+            None => true,
+            Some(last) => mcu.instructions >= last.instructions + self.interval,
+        };
+        if !due {
+            return;
+        }
+
+        if self.checkpoints.len() == self.capacity {
+            let mut i = 0;
+            self.checkpoints.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.interval *= 2;
+        }
+        self.checkpoints.push(Mcu {
+            pc: mcu.pc,
+            mem: mcu.mem.clone(),
+            rf: mcu.rf.clone(),
+            instructions: mcu.instructions,
+            program: mcu.program.clone(),
+            undo: None,
+            tlb: mcu.tlb.clone(),
+        });
+    }
+
+    /// The latest checkpoint taken at or before `instructions`.
+    pub fn at_or_before(&self, instructions: u64) -> Option<&Mcu> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|c| c.instructions <= instructions)
+    }
+
+    /// Find the latest checkpoint for which `reached` is false, assuming
+    /// `reached` is monotonic over time (false, ..., false, true, ...).
+    /// Resume from the returned state and step to find the exact
+    /// instruction.
+    pub fn bisect<F>(&self, mut reached: F) -> Option<&Mcu>
+    where
+        F: FnMut(&Mcu) -> bool,
+    {
+        let first_reached = self.checkpoints.partition_point(|c| !reached(c));
+        match first_reached {
+            0 => None,
+            n => Some(&self.checkpoints[n - 1]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{elf::SymbolKind, MemoryTrait, RegisterFileTrait};
+    use lib_rv32_isa::common::instructions;
+
+    fn counting_mcu() -> Mcu {
+        let mut mcu = Mcu::new(0x10000);
+        mcu.mem
+            .program_words(&[instructions::ADDI_X5_X5_1, instructions::JAL_X0_NEG_4])
+            .unwrap();
+        mcu
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut mcu = counting_mcu();
+        for _ in 0..7 {
+            mcu.step().unwrap();
+        }
+        mcu.mem.write_word(0x200, 0xdeadbeef).unwrap();
+
+        let bytes = mcu.to_snapshot(SnapshotFormat::Binary).unwrap();
+        assert!(bytes.starts_with(SNAPSHOT_MAGIC));
+        // Mostly-empty memory should not be stored byte-for-byte.
+        assert!(bytes.len() < 2048);
+
+        let restored = Mcu::from_snapshot(&bytes).unwrap();
+        assert_eq!(mcu.pc, restored.pc);
+        assert_eq!(7, restored.instructions);
+        assert_eq!(4, restored.rf.read(5).unwrap());
+        assert_eq!(0xdeadbeef, restored.mem.read_word(0x200).unwrap());
+        assert_eq!(
+            instructions::JAL_X0_NEG_4,
+            restored.mem.read_word(4).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_program_info_round_trip() {
+        let mut mcu = counting_mcu();
+        mcu.program = ProgramInfo {
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                addr: 0,
+                size: 8,
+                kind: SymbolKind::Function,
+            }],
+            heap_start: 0x1000,
+            brk: 0x1400,
+            htif: Some((0x800, None)),
+        };
+
+        for format in [SnapshotFormat::Binary, SnapshotFormat::Json] {
+            let bytes = mcu.to_snapshot(format).unwrap();
+            assert_eq!(mcu.program, Mcu::from_snapshot(&bytes).unwrap().program);
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut mcu = counting_mcu();
+        mcu.step().unwrap();
+
+        let bytes = mcu.to_snapshot(SnapshotFormat::Json).unwrap();
+        let restored = Mcu::from_snapshot(&bytes).unwrap();
+        assert_eq!(4, restored.pc);
+        assert_eq!(1, restored.rf.read(5).unwrap());
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut bytes = Mcu::new(4).to_snapshot(SnapshotFormat::Binary).unwrap();
+        bytes[SNAPSHOT_MAGIC.len()] = 9;
+        assert_eq!(
+            Err(SnapshotError::UnsupportedVersionError(9)),
+            Mcu::from_snapshot(&bytes).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            SnapshotFormat::Json,
+            SnapshotFormat::from_path(Path::new("state.JSON"))
+        );
+        assert_eq!(
+            SnapshotFormat::Binary,
+            SnapshotFormat::from_path(Path::new("state.snap"))
+        );
+    }
+
+    #[test]
+    fn test_checkpoints_thin_out() {
+        let mut mcu = counting_mcu();
+        let mut checkpoints = Checkpoints::new(10, 4);
+        for _ in 0..100 {
+            checkpoints.record(&mcu);
+            mcu.step().unwrap();
+        }
+
+        assert!(checkpoints.len() <= 4);
+        assert_eq!(40, checkpoints.interval());
+        assert_eq!(0, checkpoints.at_or_before(39).unwrap().instructions);
+        assert_eq!(80, checkpoints.at_or_before(99).unwrap().instructions);
+    }
+
+    #[test]
+    fn test_checkpoint_without_undo_log() {
+        let mut mcu = counting_mcu();
+        mcu.enable_undo(8);
+        mcu.step().unwrap();
+        let mut checkpoints = Checkpoints::new(10, 4);
+        checkpoints.record(&mcu);
+
+        let checkpoint = checkpoints.at_or_before(1).unwrap();
+        assert!(checkpoint.undo_log().is_none());
+        assert_eq!(1, mcu.undo_log().unwrap().len());
+        assert_eq!(1, checkpoint.rf.read(5).unwrap());
+    }
+
+    #[test]
+    fn test_bisect() {
+        let mut mcu = counting_mcu();
+        let mut checkpoints = Checkpoints::new(10, 100);
+        for _ in 0..100 {
+            checkpoints.record(&mcu);
+            mcu.step().unwrap();
+        }
+
+        // x5 is incremented every other instruction.
+        let good = checkpoints.bisect(|c| c.rf.read(5).unwrap() >= 23).unwrap();
+        assert_eq!(40, good.instructions);
+
+        let mut mcu = good.clone();
+        while mcu.rf.read(5).unwrap() < 23 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(45, mcu.instructions);
+    }
+}
@@ -0,0 +1,594 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::RiscvError;
+
+use crate::{trap::Interrupt, Elf, Host, Mcu, Memory, Step, TrapCsrs};
+
+/// Where the CLINT is usually mapped.
+pub const CLINT_BASE: u32 = 0x0200_0000;
+/// Size of the CLINT's registers.
+pub const CLINT_SIZE: u32 = 0x10000;
+/// Offset of the `msip` registers, one word per hart.
+pub const CLINT_MSIP: u32 = 0;
+/// Offset of the `mtimecmp` registers, two words per hart.
+pub const CLINT_MTIMECMP: u32 = 0x4000;
+/// Offset of `mtime`.
+pub const CLINT_MTIME: u32 = 0xbff8;
+
+/// Most harts the CLINT has `msip` registers for.
+pub const MAX_HARTS: usize = 4095;
+
+/// Core-local interruptor, laid out like SiFive's. Its registers live in
+/// the shared memory, so harts and the host access them like any other
+/// memory. Bit 0 of a hart's `msip` raises its software interrupt, and its
+/// timer interrupt is pending while `mtime` is not below its `mtimecmp`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Clint {
+    pub base: u32,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Clint { base: CLINT_BASE }
+    }
+}
+
+impl Clint {
+    pub fn new(base: u32) -> Self {
+        Clint { base }
+    }
+
+    /// Map the registers into `mem`.
+    pub fn map(&self, mem: &mut Memory) {
+        mem.map(self.base, CLINT_SIZE);
+    }
+
+    pub fn msip(&self, mem: &Memory, hart: usize) -> bool {
+        let addr = self.base + CLINT_MSIP + 4 * hart as u32;
+        mem.peek(addr, 4).unwrap_or(0) & 1 != 0
+    }
+
+    pub fn mtimecmp(&self, mem: &Memory, hart: usize) -> u64 {
+        peek_u64(mem, self.base + CLINT_MTIMECMP + 8 * hart as u32)
+    }
+
+    pub fn mtime(&self, mem: &Memory) -> u64 {
+        peek_u64(mem, self.base + CLINT_MTIME)
+    }
+
+    pub fn set_mtime(&self, mem: &mut Memory, mtime: u64) {
+        let addr = self.base + CLINT_MTIME;
+        // Both words are mapped by `map`.
+        let _ = mem.poke(addr, mtime as u32, 4);
+        let _ = mem.poke(addr + 4, (mtime >> 32) as u32, 4);
+    }
+
+    /// Set the pending interrupts in `mip` of `hart`.
+    pub fn update(&self, mem: &Memory, hart: usize, trap: &mut TrapCsrs) {
+        trap.set_pending(Interrupt::MachineSoftware, self.msip(mem, hart));
+        trap.set_pending(
+            Interrupt::MachineTimer,
+            self.mtime(mem) >= self.mtimecmp(mem, hart),
+        );
+    }
+}
+
+fn peek_u64(mem: &Memory, addr: u32) -> u64 {
+    let low = mem.peek(addr, 4).unwrap_or(0) as u64;
+    let high = mem.peek(addr + 4, 4).unwrap_or(0) as u64;
+    high << 32 | low
+}
+
+/// Order in which a `Soc` steps its harts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Take turns of `quantum` instructions.
+    RoundRobin { quantum: u32 },
+    /// Pick the hart for each instruction at random, from a generator
+    /// seeded with `seed`.
+    Random { seed: u64 },
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::RoundRobin { quantum: 1 }
+    }
+}
+
+/// Parse a schedule like `round-robin`, `round-robin:QUANTUM` or
+/// `random:SEED`.
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let invalid = || format!("{} is not a valid schedule", s);
+        match (name, arg) {
+            ("round-robin", None) => Ok(Schedule::default()),
+            ("round-robin", Some(quantum)) => match quantum.parse() {
+                Ok(quantum) if quantum > 0 => Ok(Schedule::RoundRobin { quantum }),
+                _ => Err(invalid()),
+            },
+            ("random", Some(seed)) => seed
+                .parse()
+                .map(|seed| Schedule::Random { seed })
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A system of harts sharing one memory and a CLINT. Each hart is an `Mcu`
+/// with `mhartid` set to its index; its own `mem` is empty, as memory is
+/// the system's. Harts are interleaved one instruction at a time in the
+/// order of the `Schedule`, so every run with the same schedule, seed
+/// included, is the same, and races in concurrent code can be reproduced.
+///
+/// `mtime` advances once every round of as many instructions as there are
+/// harts. A hart takes its pending interrupts before its next instruction.
+/// Each hart holds its own `lr.w` reservation, which stores by any hart to
+/// the reserved word drop. A hart stops when its step fails, e.g. on an
+/// exception without a handler. Interrupts are not steps, so stepping a
+/// hart back is not supported.
+pub struct Soc {
+    pub mem: Memory,
+    pub clint: Clint,
+    harts: Vec<Mcu>,
+    reservations: Vec<Option<u32>>,
+    halted: Vec<bool>,
+    schedule: Schedule,
+    rng: u64,
+    current: usize,
+    turn: u32,
+    steps: u64,
+}
+
+impl Soc {
+    /// Construct a system of `harts` harts around `mem`, with the CLINT
+    /// mapped at `CLINT_BASE`. All harts start at address 0.
+    pub fn new(harts: usize, mut mem: Memory, schedule: Schedule) -> Self {
+        assert!((1..=MAX_HARTS).contains(&harts));
+
+        let clint = Clint::default();
+        clint.map(&mut mem);
+        Soc {
+            mem,
+            clint,
+            harts: (0..harts)
+                .map(|i| {
+                    let mut hart = Mcu::with_memory(Memory::sparse());
+                    hart.rf.trap.set_hartid(i as u32);
+                    hart
+                })
+                .collect(),
+            reservations: vec![None; harts],
+            halted: vec![false; harts],
+            schedule,
+            rng: match schedule {
+                Schedule::Random { seed } => seed,
+                _ => 0,
+            },
+            current: 0,
+            turn: 0,
+            steps: 0,
+        }
+    }
+
+    pub fn harts(&self) -> &[Mcu] {
+        &self.harts
+    }
+
+    pub fn hart(&self, hart: usize) -> &Mcu {
+        &self.harts[hart]
+    }
+
+    pub fn hart_mut(&mut self, hart: usize) -> &mut Mcu {
+        &mut self.harts[hart]
+    }
+
+    /// Whether `hart` has stopped.
+    pub fn halted(&self, hart: usize) -> bool {
+        self.halted[hart]
+    }
+
+    /// Address of the word `hart` reserved with `lr.w`, if the
+    /// reservation is still valid.
+    pub fn reservation(&self, hart: usize) -> Option<u32> {
+        self.reservations[hart]
+    }
+
+    /// Number of instructions executed by all harts.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Program the memory with the segments of an ELF executable and
+    /// start every hart at its entry point.
+    pub fn program_elf(&mut self, elf: &Elf) -> Result<(), RiscvError> {
+        self.mem.program_elf(elf)?;
+        for hart in self.harts.iter_mut() {
+            hart.pc = elf.entry;
+        }
+        Ok(())
+    }
+
+    /// Execute a single instruction on the next hart in the schedule, and
+    /// return which hart it was and the record of the instruction, or
+    /// `None` once every hart has stopped.
+    pub fn step(&mut self) -> Option<(usize, Result<Step, RiscvError>)> {
+        self.step_inner(None)
+    }
+
+    /// Like `step`, but with the harts sharing `host` as in
+    /// `Mcu::step_with_host`.
+    pub fn step_with_host(&mut self, host: &mut Host) -> Option<(usize, Result<Step, RiscvError>)> {
+        self.step_inner(Some(host))
+    }
+
+    fn step_inner(&mut self, host: Option<&mut Host>) -> Option<(usize, Result<Step, RiscvError>)> {
+        let i = self.next_hart()?;
+        let hart = &mut self.harts[i];
+        self.clint.update(&self.mem, i, &mut hart.rf.trap);
+
+        std::mem::swap(&mut self.mem, &mut hart.mem);
+        hart.mem.set_reservation(self.reservations[i]);
+        hart.take_interrupt();
+        let result = match host {
+            Some(host) => hart.step_with_host(host),
+            None => hart.step(),
+        };
+        self.reservations[i] = hart.mem.reservation();
+        std::mem::swap(&mut self.mem, &mut hart.mem);
+
+        match &result {
+            Ok(step) => {
+                for (j, reservation) in self.reservations.iter_mut().enumerate() {
+                    let written = reservation.is_some_and(|addr| {
+                        step.writes_memory(addr, 4)
+                            || step.pte_updates.iter().any(|u| u.addr == addr)
+                    });
+                    if j != i && written {
+                        *reservation = None;
+                    }
+                }
+            }
+            Err(_) => self.halted[i] = true,
+        }
+
+        self.steps += 1;
+        if self.steps.is_multiple_of(self.harts.len() as u64) {
+            let mtime = self.clint.mtime(&self.mem);
+            self.clint.set_mtime(&mut self.mem, mtime.wrapping_add(1));
+        }
+        Some((i, result))
+    }
+
+    /// The hart to step next, or `None` if all have stopped.
+    fn next_hart(&mut self) -> Option<usize> {
+        let running: Vec<usize> = (0..self.harts.len()).filter(|&i| !self.halted[i]).collect();
+        if running.is_empty() {
+            return None;
+        }
+        match self.schedule {
+            Schedule::RoundRobin { quantum } => {
+                if self.halted[self.current] || self.turn >= quantum {
+                    self.current = running
+                        .iter()
+                        .copied()
+                        .find(|&i| i > self.current)
+                        .unwrap_or(running[0]);
+                    self.turn = 0;
+                }
+                self.turn += 1;
+                Some(self.current)
+            }
+            Schedule::Random { .. } => {
+                let pick = self.random() % running.len() as u64;
+                Some(running[pick as usize])
+            }
+        }
+    }
+
+    /// Next number from a SplitMix64 generator.
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{
+        pmp::{CSR_PMPADDR0, CSR_PMPCFG0, PMP_R, PMP_W, PMP_X},
+        trap::{
+            Privilege, CSR_MCAUSE, CSR_MHARTID, CSR_MIDELEG, CSR_MIE, CSR_MSTATUS, CSR_MTVEC,
+            CSR_SCAUSE, CSR_SIP, CSR_STVEC, MCAUSE_INTERRUPT,
+        },
+        RegisterFileTrait,
+    };
+    use lib_rv32_isa::common::constants::{MSTATUS_MIE, MSTATUS_SIE};
+
+    /// Encode a CSR instruction, which the assembler does not support.
+    fn csr_op(func3: u32, rd: u32, csr: u16, rs1: u32) -> u32 {
+        (csr as u32) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | 0b1110011
+    }
+
+    fn soc_with_program(harts: usize, program: &str, schedule: Schedule) -> Soc {
+        let mut mem = Memory::new(0x1000);
+        mem.program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        Soc::new(harts, mem, schedule)
+    }
+
+    /// Run until every hart has stopped, and return the order they ran in.
+    fn run(soc: &mut Soc) -> Vec<usize> {
+        let mut order = Vec::new();
+        while let Some((hart, _)) = soc.step() {
+            order.push(hart);
+            assert!(order.len() < 100_000);
+        }
+        order
+    }
+
+    /// Each hart adds one to the word at 0x404 `ITERATIONS` times, under
+    /// the spinlock at 0x400 if `locked`.
+    fn counter_program(locked: bool) -> String {
+        let (acquire, release) = match locked {
+            true => (
+                "acquire: amoswap.w.aq t0, t2, (s0)\nbne t0, zero, acquire\n",
+                "amoswap.w.rl zero, zero, (s0)\n",
+            ),
+            false => ("", ""),
+        };
+        format!(
+            "addi s0, zero, 0x400\n\
+             addi s1, zero, 0x404\n\
+             addi s2, zero, {}\n\
+             addi t2, zero, 1\n\
+             loop:\n\
+             {}\
+             lw t1, 0(s1)\n\
+             addi t1, t1, 1\n\
+             sw t1, 0(s1)\n\
+             {}\
+             addi s2, s2, -1\n\
+             bne s2, zero, loop\n\
+             ebreak",
+            ITERATIONS, acquire, release
+        )
+    }
+
+    const ITERATIONS: u32 = 10;
+
+    #[test]
+    fn test_mhartid() {
+        let mut mem = Memory::new(0x1000);
+        mem.program_words(&[csr_op(0b010, 10, CSR_MHARTID, 0)])
+            .unwrap();
+        let mut soc = Soc::new(3, mem, Schedule::default());
+        for i in 0..3 {
+            assert_eq!(Some(i), soc.step().map(|(hart, _)| hart));
+            assert_eq!(Ok(i as u32), soc.hart(i).rf.read(10));
+        }
+        // Writing it is illegal.
+        assert_eq!(
+            Err(RiscvError::InvalidCsrError(CSR_MHARTID)),
+            soc.hart_mut(0).rf.write_csr(CSR_MHARTID, 1)
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut soc = soc_with_program(
+            3,
+            "addi a0, zero, 1\naddi a0, zero, 2\naddi a0, zero, 3",
+            Schedule::RoundRobin { quantum: 2 },
+        );
+        assert_eq!(vec![0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2], run(&mut soc));
+        assert!((0..3).all(|i| soc.halted(i)));
+        assert_eq!(None, soc.step().map(|(hart, _)| hart));
+    }
+
+    #[test]
+    fn test_random_is_deterministic() {
+        let program = counter_program(false);
+        let orders: Vec<Vec<usize>> = [1, 1, 2]
+            .iter()
+            .map(|&seed| {
+                let mut soc = soc_with_program(4, &program, Schedule::Random { seed });
+                run(&mut soc)
+            })
+            .collect();
+        assert_eq!(orders[0], orders[1]);
+        assert_ne!(orders[0], orders[2]);
+    }
+
+    #[test]
+    fn test_race() {
+        let mut soc = soc_with_program(2, &counter_program(false), Schedule::default());
+        run(&mut soc);
+        // The harts load the same values, so half the increments are lost.
+        assert_eq!(Ok(ITERATIONS), soc.mem.peek(0x404, 4));
+    }
+
+    #[test]
+    fn test_spinlock() {
+        for seed in 0..8 {
+            let mut soc = soc_with_program(4, &counter_program(true), Schedule::Random { seed });
+            run(&mut soc);
+            assert_eq!(Ok(4 * ITERATIONS), soc.mem.peek(0x404, 4));
+            assert_eq!(Ok(0), soc.mem.peek(0x400, 4));
+        }
+    }
+
+    #[test]
+    fn test_reservations() {
+        // Hart 0 runs `lr.w` and `sc.w` around hart 1's store to the word
+        // at `t0`. Only a store to the reserved word breaks the
+        // reservation.
+        for (offset, result) in [(0, 1), (4, 0)] {
+            let mut soc = soc_with_program(
+                2,
+                "lr.w a0, (t0)\nsc.w a1, a0, (t0)\nsw zero, 0(t1)",
+                Schedule::default(),
+            );
+            soc.hart_mut(0).rf.write(5, 0x400).unwrap();
+            soc.hart_mut(1).rf.write(6, 0x400 + offset).unwrap();
+            soc.hart_mut(1).pc = 8;
+            soc.step();
+            assert_eq!(Some(0x400), soc.reservation(0));
+            assert_eq!(None, soc.reservation(1));
+            soc.step();
+            soc.step();
+            assert_eq!(Ok(result), soc.hart(0).rf.read(11));
+            assert_eq!(None, soc.reservation(0));
+        }
+    }
+
+    #[test]
+    fn test_software_interrupt() {
+        // Hart 0 raises hart 1's software interrupt, while hart 1 spins
+        // waiting for it.
+        let mut soc = soc_with_program(
+            2,
+            "lui t0, 0x2000\n\
+             addi t1, zero, 1\n\
+             sw t1, 4(t0)\n\
+             ebreak\n\
+             spin: beq zero, zero, spin",
+            Schedule::default(),
+        );
+        // addi a0, zero, 1
+        soc.mem.poke(0x200, 0x00100513, 4).unwrap();
+        let hart = soc.hart_mut(1);
+        hart.pc = 16;
+        hart.rf.trap.write(CSR_MTVEC, 0x200).unwrap();
+        hart.rf.trap.write(CSR_MIE, 1 << 3).unwrap();
+        hart.rf.trap.write(CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        for _ in 0..6 {
+            soc.step();
+        }
+        assert!(soc.clint.msip(&soc.mem, 1));
+        let hart = soc.hart(1);
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 3), hart.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(0x204, hart.pc);
+        // Interrupts are off in the handler.
+        assert_eq!(
+            Ok(0),
+            hart.rf.read_csr(CSR_MSTATUS).map(|s| s & MSTATUS_MIE)
+        );
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut soc = soc_with_program(1, "spin: beq zero, zero, spin", Schedule::default());
+        let cmp = soc.clint.base + CLINT_MTIMECMP;
+        soc.mem.poke(cmp, 5, 4).unwrap();
+        soc.mem.poke(0x21c, 0x00100513, 4).unwrap();
+        let hart = soc.hart_mut(0);
+        // Vectored, so the timer handler is at 0x200 + 4 * 7.
+        hart.rf.trap.write(CSR_MTVEC, 0x201).unwrap();
+        hart.rf.trap.write(CSR_MIE, 1 << 7).unwrap();
+        hart.rf.trap.write(CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        for _ in 0..5 {
+            soc.step();
+            assert_eq!(0, soc.hart(0).pc);
+        }
+        assert_eq!(5, soc.clint.mtime(&soc.mem));
+        soc.step();
+        assert_eq!(
+            Ok(MCAUSE_INTERRUPT | 7),
+            soc.hart(0).rf.read_csr(CSR_MCAUSE)
+        );
+        assert_eq!(0x220, soc.hart(0).pc);
+    }
+
+    #[test]
+    fn test_forwarded_timer_interrupt() {
+        // As in xv6, machine mode handles the timer and forwards each tick
+        // to the kernel as a supervisor software interrupt.
+        let mut soc = soc_with_program(1, "", Schedule::default());
+        let cmp = soc.clint.base + CLINT_MTIMECMP;
+        soc.mem.poke(cmp, 5, 4).unwrap();
+        let place = |soc: &mut Soc, addr: u32, words: &[u32]| {
+            for (i, word) in words.iter().enumerate() {
+                soc.mem.poke(addr + 4 * i as u32, *word, 4).unwrap();
+            }
+        };
+        place(
+            &mut soc,
+            0x100,
+            &assemble_program("spin: beq zero, zero, spin").unwrap(),
+        );
+        // Raise sip.SSIP and schedule the next tick.
+        let mut timer = assemble_program("addi t1, zero, 2").unwrap();
+        timer.push(csr_op(0b010, 0, CSR_SIP, 6));
+        timer.extend(
+            assemble_program(
+                "lui t0, 0x2004
+                 lw t2, 0(t0)
+                 addi t2, t2, 100
+                 sw t2, 0(t0)
+                 mret",
+            )
+            .unwrap(),
+        );
+        place(&mut soc, 0x200, &timer);
+        // Count the tick and clear sip.SSIP.
+        let mut kernel = assemble_program(
+            "addi s1, s1, 1
+addi t1, zero, 2",
+        )
+        .unwrap();
+        kernel.push(csr_op(0b011, 0, CSR_SIP, 6));
+        kernel.extend(assemble_program("sret").unwrap());
+        place(&mut soc, 0x300, &kernel);
+
+        let hart = soc.hart_mut(0);
+        hart.rf.write_csr(CSR_PMPADDR0, u32::MAX).unwrap();
+        hart.rf
+            .write_csr(CSR_PMPCFG0, (PMP_R | PMP_W | PMP_X | 0x18) as u32)
+            .unwrap();
+        hart.rf.trap.write(CSR_MTVEC, 0x200).unwrap();
+        hart.rf.trap.write(CSR_STVEC, 0x300).unwrap();
+        hart.rf.trap.write(CSR_MIDELEG, 1 << 1).unwrap();
+        hart.rf.trap.write(CSR_MIE, 1 << 7 | 1 << 1).unwrap();
+        hart.rf.trap.write(CSR_MSTATUS, MSTATUS_SIE).unwrap();
+        hart.rf.trap.set_privilege(Privilege::Supervisor);
+        hart.pc = 0x100;
+
+        for _ in 0..150 {
+            soc.step();
+        }
+        let hart = soc.hart(0);
+        assert_eq!(Ok(2), hart.rf.read(9));
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 7), hart.rf.trap.read(CSR_MCAUSE));
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 1), hart.rf.trap.read(CSR_SCAUSE));
+        assert_eq!(Ok(0), hart.rf.trap.read(CSR_SIP));
+        assert_eq!(Privilege::Supervisor, hart.rf.trap.privilege());
+        assert_eq!(0x100, hart.pc);
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(Ok(Schedule::default()), "round-robin".parse());
+        assert_eq!(
+            Ok(Schedule::RoundRobin { quantum: 4 }),
+            "round-robin:4".parse()
+        );
+        assert_eq!(Ok(Schedule::Random { seed: 42 }), "random:42".parse());
+        assert!("round-robin:0".parse::<Schedule>().is_err());
+        assert!("random".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,885 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+
+use lib_rv32_isa::{
+    traits::{Memory as MemoryTrait, RegisterFile as RegisterFileTrait},
+    RiscvError,
+};
+
+// Linux syscall numbers, as used by newlib's libgloss for RISC-V.
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_FSTAT: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_GETTIMEOFDAY: u32 = 169;
+const SYS_BRK: u32 = 214;
+/// Older libgloss uses `open` rather than `openat`.
+const SYS_OPEN: u32 = 1024;
+
+// Semihosting operations, from the Arm specification RISC-V adopted.
+const SEMI_OPEN: u32 = 0x01;
+const SEMI_CLOSE: u32 = 0x02;
+const SEMI_WRITEC: u32 = 0x03;
+const SEMI_WRITE0: u32 = 0x04;
+const SEMI_WRITE: u32 = 0x05;
+const SEMI_READ: u32 = 0x06;
+const SEMI_READC: u32 = 0x07;
+const SEMI_ISERROR: u32 = 0x08;
+const SEMI_ISTTY: u32 = 0x09;
+const SEMI_SEEK: u32 = 0x0a;
+const SEMI_FLEN: u32 = 0x0c;
+const SEMI_REMOVE: u32 = 0x0e;
+const SEMI_CLOCK: u32 = 0x10;
+const SEMI_TIME: u32 = 0x11;
+const SEMI_ERRNO: u32 = 0x13;
+const SEMI_EXIT: u32 = 0x18;
+const SEMI_EXIT_EXTENDED: u32 = 0x20;
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// `slli zero, zero, 0x1f` and `srai zero, zero, 7`, which surround a
+/// semihosting `ebreak`.
+const SEMIHOSTING_ENTRY: u32 = 0x01f01013;
+const SEMIHOSTING_EXIT: u32 = 0x40705013;
+
+// newlib's `open` flags.
+const O_ACCMODE: u32 = 0x3;
+const O_APPEND: u32 = 0x8;
+const O_CREAT: u32 = 0x200;
+const O_TRUNC: u32 = 0x400;
+const O_EXCL: u32 = 0x800;
+
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFREG: u32 = 0o100000;
+
+/// Largest transfer done by a single `read` or `write`. Both may
+/// transfer less than asked for, and newlib retries.
+const MAX_TRANSFER: u32 = 0x10000;
+
+const A0: u8 = 10;
+const A1: u8 = 11;
+const A7: u8 = 17;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Host side of `ecall` syscalls, semihosting `ebreak`s and HTIF, for
+/// running programs built against newlib or for Spike. Descriptors 0 to 2 are the console;
+/// other files can only be opened inside the sandbox directory, and not
+/// at all without one.
+///
+/// Syscalls follow the Linux ABI: the number is in `a7`, arguments are
+/// in `a0` to `a5`, and `a0` gets the result, or `-errno` on failure.
+/// Semihosting calls take the operation in `a0` and a parameter block
+/// pointed to by `a1`.
+///
+/// HTIF requests are 64-bit writes to `tohost`: `(code << 1) | 1` exits,
+/// device 1 is the console, and any other value points to a block of
+/// eight 64-bit words holding a syscall number and its arguments, whose
+/// result replaces the number. Completion is signalled on `fromhost`.
+pub struct Host {
+    sandbox: Option<PathBuf>,
+    handles: Vec<Option<Handle>>,
+    heap_start: u32,
+    heap_end: u32,
+    brk: u32,
+    /// Console input, or `None` to read the process' stdin.
+    stdin: Option<VecDeque<u8>>,
+    /// Console output, or `None` to write to the process' stdout/stderr.
+    output: Option<Vec<u8>>,
+    errno: i32,
+    exit_code: Option<i32>,
+    start: Instant,
+    /// Addresses of `tohost` and `fromhost`.
+    htif: Option<(u32, Option<u32>)>,
+    /// Whether `tohost` was written by the last instruction.
+    htif_pending: bool,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Host::new()
+    }
+}
+
+impl Host {
+    pub fn new() -> Self {
+        Host {
+            sandbox: None,
+            handles: vec![
+                Some(Handle::Stdin),
+                Some(Handle::Stdout),
+                Some(Handle::Stderr),
+            ],
+            heap_start: 0,
+            heap_end: 0,
+            brk: 0,
+            stdin: None,
+            output: None,
+            errno: 0,
+            exit_code: None,
+            start: Instant::now(),
+            htif: None,
+            htif_pending: false,
+        }
+    }
+
+    /// Allow the program to access files under `dir`. Absolute paths are
+    /// taken relative to it, and paths leaving it are refused.
+    pub fn with_sandbox(mut self, dir: &Path) -> Self {
+        self.sandbox = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Let `brk` grow the heap from `start` up to `end`.
+    pub fn with_heap(mut self, start: u32, end: u32) -> Self {
+        self.heap_start = start;
+        self.heap_end = end;
+        self.brk = start;
+        self
+    }
+
+    /// Resume with the program break at `brk`, e.g. from a snapshot. It
+    /// stays at the start of the heap if `brk` is outside of it.
+    pub fn with_brk(mut self, brk: u32) -> Self {
+        self.sys_brk(brk);
+        self
+    }
+
+    /// Watch `tohost` for HTIF requests, and answer them on `fromhost`.
+    pub fn with_htif(mut self, tohost: u32, fromhost: Option<u32>) -> Self {
+        self.htif = Some((tohost, fromhost));
+        self
+    }
+
+    /// Read console input from `bytes` instead of the process' stdin.
+    pub fn with_stdin(mut self, bytes: &[u8]) -> Self {
+        self.stdin = Some(bytes.iter().copied().collect());
+        self
+    }
+
+    /// Keep console output for `output` instead of printing it.
+    pub fn capture_output(mut self) -> Self {
+        self.output = Some(Vec::new());
+        self
+    }
+
+    /// Captured stdout and stderr, interleaved.
+    pub fn output(&self) -> &[u8] {
+        self.output.as_deref().unwrap_or_default()
+    }
+
+    /// The status the program exited with, once it has.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// The current program break.
+    pub fn brk(&self) -> u32 {
+        self.brk
+    }
+
+    /// Service an `ecall`. Unknown syscalls fail with `ENOSYS`.
+    pub fn ecall<M, R>(&mut self, mem: &mut M, rf: &mut R) -> Result<(), RiscvError>
+    where
+        M: MemoryTrait,
+        R: RegisterFileTrait,
+    {
+        let n = rf.read(A7)?;
+        let mut args = [0; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = rf.read(A0 + i as u8)?;
+        }
+        info!("ecall  {} ({:x?})", n, args);
+
+        let result = self.syscall(mem, n, args);
+        match n {
+            SYS_EXIT | SYS_EXIT_GROUP => Ok(()),
+            _ => rf.write(A0, result),
+        }
+    }
+
+    /// Perform syscall `n`, returning its result or `-errno`.
+    fn syscall<M: MemoryTrait>(&mut self, mem: &mut M, n: u32, args: [u32; 6]) -> u32 {
+        let result = match n {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(args[0] as i32);
+                Ok(0)
+            }
+            SYS_OPENAT => self.sys_open(mem, args[1], args[2]),
+            SYS_OPEN => self.sys_open(mem, args[0], args[1]),
+            SYS_CLOSE => self.close(args[0]).map(|_| 0),
+            SYS_READ => self.sys_read(mem, args[0], args[1], args[2]),
+            SYS_WRITE => self.sys_write(mem, args[0], args[1], args[2]),
+            SYS_LSEEK => self.seek(args[0], args[1] as i32, args[2]),
+            SYS_FSTAT => self.sys_fstat(mem, args[0], args[1]),
+            SYS_GETTIMEOFDAY => self.sys_gettimeofday(mem, args[0]),
+            SYS_BRK => Ok(self.sys_brk(args[0])),
+            _ => Err(ENOSYS),
+        };
+        match result {
+            Ok(value) => value,
+            Err(errno) => -errno as u32,
+        }
+    }
+
+    /// Address of `tohost`, if HTIF is enabled.
+    pub fn tohost(&self) -> Option<u32> {
+        self.htif.map(|(tohost, _)| tohost)
+    }
+
+    /// Service a pending HTIF request. `written` tells whether the last
+    /// instruction stored to `tohost`; requests are serviced after the
+    /// instruction that follows, since RV32 writes them in two halves.
+    pub fn htif<M: MemoryTrait>(&mut self, mem: &mut M, written: bool) -> Result<(), RiscvError> {
+        let (tohost, fromhost) = match self.htif {
+            Some(htif) => htif,
+            None => return Ok(()),
+        };
+        if !std::mem::replace(&mut self.htif_pending, written) || self.exit_code.is_some() {
+            return Ok(());
+        }
+        let low = mem.fetch(tohost)?;
+        let high = mem.fetch(tohost + 4)?;
+        if low == 0 && high == 0 {
+            return Ok(());
+        }
+        let (device, command) = (high >> 24, (high >> 16) & 0xff);
+        info!(
+            "htif   device {} command {} (0x{:08x})",
+            device, command, low
+        );
+
+        let (reply_high, reply_low) = match (device, command) {
+            (0, 0) if low & 1 == 1 => {
+                self.exit_code = Some((low >> 1) as i32);
+                return Ok(());
+            }
+            (0, 0) => {
+                let mut words = [0; 8];
+                for (i, word) in words.iter_mut().enumerate() {
+                    *word = mem.fetch(low.wrapping_add(8 * i as u32))?;
+                }
+                let mut args = [0; 6];
+                args.copy_from_slice(&words[1..7]);
+                let result = self.syscall(mem, words[0], args);
+                mem.write_word(low, result)?;
+                mem.write_word(low + 4, ((result as i32) >> 31) as u32)?;
+                (0, 1)
+            }
+            (1, 0) => {
+                let c = self.read(0, 1).ok().and_then(|c| c.first().copied());
+                (high & 0xffff_0000, c.map_or(u32::MAX, |c| c as u32))
+            }
+            (1, 1) => {
+                let _ = self.write(1, &[low as u8]);
+                (high & 0xffff_0000, 0)
+            }
+            _ => (high & 0xffff_0000, 0),
+        };
+
+        mem.write_word(tohost, 0)?;
+        mem.write_word(tohost + 4, 0)?;
+        if let Some(fromhost) = fromhost {
+            mem.write_word(fromhost, reply_low)?;
+            mem.write_word(fromhost + 4, reply_high)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the `ebreak` at `pc` is a semihosting call, i.e. sits
+    /// between `slli zero, zero, 0x1f` and `srai zero, zero, 7`.
+    pub fn is_semihosting<M: MemoryTrait>(mem: &M, pc: u32) -> bool {
+        pc >= 4
+            && mem.fetch(pc - 4) == Ok(SEMIHOSTING_ENTRY)
+            && mem.fetch(pc.wrapping_add(4)) == Ok(SEMIHOSTING_EXIT)
+    }
+
+    /// Service a semihosting call. Unknown operations return -1 and set
+    /// the semihosting errno to `ENOSYS`.
+    pub fn semihost<M, R>(&mut self, mem: &mut M, rf: &mut R) -> Result<(), RiscvError>
+    where
+        M: MemoryTrait,
+        R: RegisterFileTrait,
+    {
+        let op = rf.read(A0)?;
+        let param = rf.read(A1)?;
+        info!("semihosting 0x{:02x} (0x{:08x})", op, param);
+
+        let result = match op {
+            SEMI_EXIT => {
+                self.exit_code = Some((param != ADP_STOPPED_APPLICATION_EXIT) as i32);
+                return Ok(());
+            }
+            SEMI_EXIT_EXTENDED => {
+                self.exit_code = Some(match (arg(mem, param, 0), arg(mem, param, 1)) {
+                    (Ok(ADP_STOPPED_APPLICATION_EXIT), Ok(code)) => code as i32,
+                    _ => 1,
+                });
+                return Ok(());
+            }
+            SEMI_OPEN => (|| {
+                let (name, mode, len) = (
+                    arg(mem, param, 0)?,
+                    arg(mem, param, 1)?,
+                    arg(mem, param, 2)?,
+                );
+                let name = read_bytes(mem, name, len).map_err(|_| EFAULT)?;
+                self.semi_open(&String::from_utf8_lossy(&name), mode)
+            })(),
+            SEMI_CLOSE => arg(mem, param, 0).and_then(|h| self.close(h)).map(|_| 0),
+            SEMI_WRITEC => (|| {
+                let c = mem.read_byte(param).map_err(|_| EFAULT)?;
+                self.write(1, &[c as u8]).map(|_| 0)
+            })(),
+            SEMI_WRITE0 => (|| {
+                let s = read_string(mem, param).map_err(|_| EFAULT)?;
+                self.write(1, &s).map(|_| 0)
+            })(),
+            SEMI_WRITE => (|| {
+                let (h, buf, len) = (
+                    arg(mem, param, 0)?,
+                    arg(mem, param, 1)?,
+                    arg(mem, param, 2)?,
+                );
+                let n = self.sys_write(mem, h, buf, len)?;
+                Ok(len - n)
+            })(),
+            SEMI_READ => (|| {
+                let (h, buf, len) = (
+                    arg(mem, param, 0)?,
+                    arg(mem, param, 1)?,
+                    arg(mem, param, 2)?,
+                );
+                let n = self.sys_read(mem, h, buf, len)?;
+                Ok(len - n)
+            })(),
+            SEMI_READC => self
+                .read(0, 1)
+                .map(|c| c.first().copied().unwrap_or(0) as u32),
+            SEMI_ISERROR => arg(mem, param, 0).map(|status| ((status as i32) < 0) as u32),
+            SEMI_ISTTY => arg(mem, param, 0).and_then(|h| match self.handle(h)? {
+                Handle::File(_) => Ok(0),
+                _ => Ok(1),
+            }),
+            SEMI_SEEK => (|| {
+                let (h, pos) = (arg(mem, param, 0)?, arg(mem, param, 1)?);
+                self.seek(h, pos as i32, 0).map(|_| 0)
+            })(),
+            SEMI_FLEN => arg(mem, param, 0).and_then(|h| match self.handle(h)? {
+                Handle::File(file) => match file.metadata() {
+                    Ok(meta) => Ok(meta.len() as u32),
+                    Err(e) => Err(errno(e)),
+                },
+                _ => Err(EBADF),
+            }),
+            SEMI_REMOVE => (|| {
+                let (name, len) = (arg(mem, param, 0)?, arg(mem, param, 1)?);
+                let name = read_bytes(mem, name, len).map_err(|_| EFAULT)?;
+                let path = self.resolve(&String::from_utf8_lossy(&name))?;
+                fs::remove_file(path).map(|_| 0).map_err(errno)
+            })(),
+            SEMI_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u32),
+            SEMI_TIME => Ok(unix_time().as_secs() as u32),
+            SEMI_ERRNO => Ok(self.errno as u32),
+            _ => Err(ENOSYS),
+        };
+        rf.write(
+            A0,
+            match result {
+                Ok(value) => value,
+                Err(errno) => {
+                    self.errno = errno;
+                    u32::MAX
+                }
+            },
+        )
+    }
+
+    /// Map a path from the program to one inside the sandbox.
+    fn resolve(&self, path: &str) -> Result<PathBuf, i32> {
+        let root = self.sandbox.as_ref().ok_or(EACCES)?;
+        let mut resolved = root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(c) => resolved.push(c),
+                Component::RootDir | Component::CurDir => (),
+                _ => return Err(EACCES),
+            }
+        }
+
+        // Follow symbolic links before checking that the file is inside.
+        let root = root.canonicalize().map_err(errno)?;
+        let parent = match resolved.parent() {
+            Some(parent) => parent.canonicalize().map_err(errno)?,
+            None => return Err(EACCES),
+        };
+        let resolved = match resolved.canonicalize() {
+            Ok(path) => path,
+            Err(_) => parent.join(resolved.file_name().ok_or(EACCES)?),
+        };
+        match resolved.starts_with(&root) && parent.starts_with(&root) {
+            true => Ok(resolved),
+            false => Err(EACCES),
+        }
+    }
+
+    fn handle(&mut self, fd: u32) -> Result<&mut Handle, i32> {
+        match self.handles.get_mut(fd as usize) {
+            Some(Some(handle)) => Ok(handle),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn open(&mut self, path: &str, flags: u32) -> Result<u32, i32> {
+        let path = self.resolve(path)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        match (flags & O_CREAT != 0, flags & O_EXCL != 0) {
+            (true, true) => options.create_new(true),
+            (true, false) => options.create(true),
+            _ => &mut options,
+        };
+        let file = options.open(path).map_err(errno)?;
+
+        let handle = Some(Handle::File(file));
+        match self.handles.iter().position(|h| h.is_none()) {
+            Some(fd) => {
+                self.handles[fd] = handle;
+                Ok(fd as u32)
+            }
+            None => {
+                self.handles.push(handle);
+                Ok(self.handles.len() as u32 - 1)
+            }
+        }
+    }
+
+    fn close(&mut self, fd: u32) -> Result<(), i32> {
+        self.handle(fd)?;
+        self.handles[fd as usize] = None;
+        Ok(())
+    }
+
+    fn read(&mut self, fd: u32, len: u32) -> Result<Vec<u8>, i32> {
+        let mut buf = vec![0; len.min(MAX_TRANSFER) as usize];
+        let n = match self.handle(fd)? {
+            Handle::Stdin => match self.stdin.as_mut() {
+                Some(stdin) => {
+                    let n = buf.len().min(stdin.len());
+                    for (b, c) in buf.iter_mut().zip(stdin.drain(..n)) {
+                        *b = c;
+                    }
+                    n
+                }
+                None => io::stdin().read(&mut buf).map_err(errno)?,
+            },
+            Handle::File(file) => file.read(&mut buf).map_err(errno)?,
+            _ => return Err(EBADF),
+        };
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn write(&mut self, fd: u32, data: &[u8]) -> Result<u32, i32> {
+        let stdout = match self.handle(fd)? {
+            Handle::Stdout => true,
+            Handle::Stderr => false,
+            Handle::File(file) => return file.write(data).map(|n| n as u32).map_err(errno),
+            Handle::Stdin => return Err(EBADF),
+        };
+        match (self.output.as_mut(), stdout) {
+            (Some(output), _) => output.extend_from_slice(data),
+            (None, true) => {
+                let mut out = io::stdout();
+                out.write_all(data)
+                    .and_then(|_| out.flush())
+                    .map_err(errno)?
+            }
+            (None, false) => io::stderr().write_all(data).map_err(errno)?,
+        }
+        Ok(data.len() as u32)
+    }
+
+    fn seek(&mut self, fd: u32, offset: i32, whence: u32) -> Result<u32, i32> {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u32 as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        match self.handle(fd)? {
+            Handle::File(file) => file.seek(pos).map(|p| p as u32).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    fn sys_open<M: MemoryTrait>(&mut self, mem: &M, path: u32, flags: u32) -> Result<u32, i32> {
+        let path = read_string(mem, path).map_err(|_| EFAULT)?;
+        self.open(&String::from_utf8_lossy(&path), flags)
+    }
+
+    fn sys_read<M: MemoryTrait>(
+        &mut self,
+        mem: &mut M,
+        fd: u32,
+        buf: u32,
+        len: u32,
+    ) -> Result<u32, i32> {
+        let data = self.read(fd, len)?;
+        write_bytes(mem, buf, &data).map_err(|_| EFAULT)?;
+        Ok(data.len() as u32)
+    }
+
+    fn sys_write<M: MemoryTrait>(
+        &mut self,
+        mem: &M,
+        fd: u32,
+        buf: u32,
+        len: u32,
+    ) -> Result<u32, i32> {
+        let data = read_bytes(mem, buf, len.min(MAX_TRANSFER)).map_err(|_| EFAULT)?;
+        self.write(fd, &data)
+    }
+
+    /// Fill in the `struct kernel_stat` libgloss converts to a
+    /// `struct stat`. Only the type, size and block size are known.
+    fn sys_fstat<M: MemoryTrait>(&mut self, mem: &mut M, fd: u32, buf: u32) -> Result<u32, i32> {
+        let (mode, size) = match self.handle(fd)? {
+            Handle::File(file) => (S_IFREG | 0o644, file.metadata().map_err(errno)?.len()),
+            _ => (S_IFCHR | 0o620, 0),
+        };
+        let mut stat = [0; 128];
+        stat[16..20].copy_from_slice(&mode.to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        stat[56..60].copy_from_slice(&4096u32.to_le_bytes());
+        write_bytes(mem, buf, &stat).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    /// Fill in a `struct timeval` with 32-bit fields, as libgloss passes
+    /// on RV32.
+    fn sys_gettimeofday<M: MemoryTrait>(&mut self, mem: &mut M, tv: u32) -> Result<u32, i32> {
+        let now = unix_time();
+        let mut timeval = [0; 8];
+        timeval[0..4].copy_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        timeval[4..8].copy_from_slice(&now.subsec_micros().to_le_bytes());
+        write_bytes(mem, tv, &timeval).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    /// Move the break to `addr` if it is within the heap, and return the
+    /// break, which is unchanged on failure.
+    fn sys_brk(&mut self, addr: u32) -> u32 {
+        if (self.heap_start..=self.heap_end).contains(&addr) {
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    /// Open a file with an `fopen` mode numbered as in semihosting:
+    /// `r`, `rb`, `r+`, `r+b`, `w`, `wb`, `w+`, `w+b`, `a` and so on.
+    /// `:tt` is the console.
+    fn semi_open(&mut self, name: &str, mode: u32) -> Result<u32, i32> {
+        if name == ":tt" {
+            return Ok(match mode {
+                0..=3 => 0,
+                4..=7 => 1,
+                _ => 2,
+            });
+        }
+        let access = match mode & 0b10 {
+            0 => (mode >> 2).min(1),
+            _ => 2,
+        };
+        let flags = match mode >> 2 {
+            0 => 0,
+            1 => O_CREAT | O_TRUNC,
+            2 => O_CREAT | O_APPEND,
+            _ => return Err(EINVAL),
+        };
+        self.open(name, access | flags)
+    }
+}
+
+/// Word `i` of a semihosting parameter block.
+fn arg<M: MemoryTrait>(mem: &M, block: u32, i: u32) -> Result<u32, i32> {
+    mem.read_word(block.wrapping_add(4 * i)).map_err(|_| EFAULT)
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn read_bytes<M: MemoryTrait>(mem: &M, addr: u32, len: u32) -> Result<Vec<u8>, RiscvError> {
+    (0..len)
+        .map(|i| mem.read_byte(addr.wrapping_add(i)).map(|b| b as u8))
+        .collect()
+}
+
+/// Read a NUL-terminated string, without the terminator.
+fn read_string<M: MemoryTrait>(mem: &M, addr: u32) -> Result<Vec<u8>, RiscvError> {
+    let mut bytes = Vec::new();
+    loop {
+        match mem.read_byte(addr.wrapping_add(bytes.len() as u32))? as u8 {
+            0 => return Ok(bytes),
+            b => bytes.push(b),
+        }
+    }
+}
+
+fn write_bytes<M: MemoryTrait>(mem: &mut M, addr: u32, bytes: &[u8]) -> Result<(), RiscvError> {
+    for (i, b) in bytes.iter().enumerate() {
+        mem.write_byte(addr.wrapping_add(i as u32), *b as u32)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{
+        trap::{Exception, CSR_MTVEC},
+        Mcu,
+    };
+
+    const A2: u8 = 12;
+    const ECALL: u32 = 0x00000073;
+    const EBREAK: u32 = 0x00100073;
+
+    /// Run a single `ecall` with the given registers set.
+    fn ecall(host: &mut Host, mcu: &mut Mcu, regs: &[(u8, u32)]) -> u32 {
+        mcu.pc = 0;
+        mcu.mem.program_words(&[ECALL]).unwrap();
+        for (reg, value) in regs.iter() {
+            mcu.rf.write(*reg, *value).unwrap();
+        }
+        let step = mcu.step_with_host(host).unwrap();
+        assert_eq!(4, step.next_pc);
+        mcu.rf.read(A0).unwrap()
+    }
+
+    #[test]
+    fn test_console() {
+        let mut mcu = Mcu::new(0x1000);
+        let mut host = Host::new().capture_output().with_stdin(b"abc");
+        mcu.mem.program_le_bytes_at(0x100, b"hi\n").unwrap();
+
+        let args = [(A0, 1), (A1, 0x100), (A2, 3), (A7, SYS_WRITE)];
+        assert_eq!(3, ecall(&mut host, &mut mcu, &args));
+        assert_eq!(b"hi\n", host.output());
+
+        let args = [(A0, 0), (A1, 0x200), (A2, 8), (A7, SYS_READ)];
+        assert_eq!(3, ecall(&mut host, &mut mcu, &args));
+        assert_eq!(0x636261, mcu.mem.peek(0x200, 4).unwrap());
+
+        let args = [(A0, 0), (A1, 0x100), (A2, 1), (A7, SYS_WRITE)];
+        assert_eq!(-EBADF as u32, ecall(&mut host, &mut mcu, &args));
+        assert_eq!(-ENOSYS as u32, ecall(&mut host, &mut mcu, &[(A7, 999)]));
+
+        assert_eq!(None, host.exit_code());
+        ecall(&mut host, &mut mcu, &[(A0, 7), (A7, SYS_EXIT)]);
+        assert_eq!(Some(7), host.exit_code());
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut mcu = Mcu::new(0x1000);
+        let mut host = Host::new().with_heap(0x800, 0xc00);
+        assert_eq!(0x800, ecall(&mut host, &mut mcu, &[(A0, 0), (A7, SYS_BRK)]));
+        assert_eq!(
+            0x900,
+            ecall(&mut host, &mut mcu, &[(A0, 0x900), (A7, SYS_BRK)])
+        );
+        assert_eq!(
+            0x900,
+            ecall(&mut host, &mut mcu, &[(A0, 0xd00), (A7, SYS_BRK)])
+        );
+        assert_eq!(0x900, host.brk());
+
+        assert_eq!(
+            0xa00,
+            Host::new().with_heap(0x800, 0xc00).with_brk(0xa00).brk()
+        );
+        assert_eq!(
+            0x800,
+            Host::new().with_heap(0x800, 0xc00).with_brk(0xd00).brk()
+        );
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let dir = env::temp_dir().join(format!("lib-rv32-sandbox-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_le_bytes_at(0x100, b"/out.txt\0").unwrap();
+        mcu.mem.program_le_bytes_at(0x120, b"../out.txt\0").unwrap();
+        mcu.mem.program_le_bytes_at(0x140, b"data").unwrap();
+        let open = |path| {
+            [
+                (A0, -100i32 as u32),
+                (A1, path),
+                (A2, 1 | O_CREAT | O_TRUNC),
+                (A7, SYS_OPENAT),
+            ]
+        };
+
+        let mut host = Host::new();
+        assert_eq!(-EACCES as u32, ecall(&mut host, &mut mcu, &open(0x100)));
+
+        let mut host = Host::new().with_sandbox(&dir);
+        assert_eq!(-EACCES as u32, ecall(&mut host, &mut mcu, &open(0x120)));
+        let fd = ecall(&mut host, &mut mcu, &open(0x100));
+        assert_eq!(3, fd);
+        let args = [(A0, fd), (A1, 0x140), (A2, 4), (A7, SYS_WRITE)];
+        assert_eq!(4, ecall(&mut host, &mut mcu, &args));
+        let args = [(A0, fd), (A1, 0x200), (A7, SYS_FSTAT)];
+        assert_eq!(0, ecall(&mut host, &mut mcu, &args));
+        assert_eq!(S_IFREG | 0o644, mcu.mem.peek(0x210, 4).unwrap());
+        assert_eq!(4, mcu.mem.peek(0x230, 4).unwrap());
+        assert_eq!(0, ecall(&mut host, &mut mcu, &[(A0, fd), (A7, SYS_CLOSE)]));
+        assert_eq!(
+            -EBADF as u32,
+            ecall(&mut host, &mut mcu, &[(A0, fd), (A7, SYS_CLOSE)])
+        );
+
+        assert_eq!(b"data", fs::read(dir.join("out.txt")).unwrap().as_slice());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_semihosting() {
+        let mut mcu = Mcu::new(0x1000);
+        let mut host = Host::new().capture_output();
+        mcu.mem
+            .program_words(&[SEMIHOSTING_ENTRY, EBREAK, SEMIHOSTING_EXIT, EBREAK])
+            .unwrap();
+        mcu.mem.program_le_bytes_at(0x100, b"ok\n\0").unwrap();
+        mcu.rf.write(A0, SEMI_WRITE0).unwrap();
+        mcu.rf.write(A1, 0x100).unwrap();
+        for _ in 0..3 {
+            mcu.step_with_host(&mut host).unwrap();
+        }
+        assert_eq!(b"ok\n", host.output());
+
+        // A lone ebreak is left to the caller.
+        assert_eq!(
+            Err(RiscvError::BreakpointError(12)),
+            mcu.step_with_host(&mut host)
+        );
+
+        mcu.pc = 4;
+        mcu.rf.write(A0, SEMI_EXIT).unwrap();
+        mcu.rf.write(A1, ADP_STOPPED_APPLICATION_EXIT).unwrap();
+        mcu.step_with_host(&mut host).unwrap();
+        assert_eq!(Some(0), host.exit_code());
+    }
+
+    #[test]
+    fn test_htif() {
+        let program = assemble_program(
+            "addi t0, zero, 0x100\n\
+             lw t1, 0(t0)\n\
+             lw t2, 4(t0)\n\
+             sw t1, 0x40(zero)\n\
+             sw t2, 0x44(zero)\n\
+             addi t0, t0, 8\n\
+             jal zero, -20",
+        )
+        .unwrap();
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_words(&program).unwrap();
+        let requests: [u32; 8] = [
+            // Print "a" on the console.
+            0x61, 0x01010000, // write(1, 0x180, 3)
+            0x200, 0, // Exit with 5.
+            0xb, 0, 0, 0,
+        ];
+        let syscall = [SYS_WRITE, 0, 1, 0, 0x180, 0, 3, 0];
+        for (addr, words) in [(0x100, &requests), (0x200, &syscall)] {
+            let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            mcu.mem.program_le_bytes_at(addr, &bytes).unwrap();
+        }
+        mcu.mem.program_le_bytes_at(0x180, b"bc\n").unwrap();
+
+        let mut host = Host::new().capture_output().with_htif(0x40, Some(0x48));
+        for _ in 0..50 {
+            mcu.step_with_host(&mut host).unwrap();
+            if host.exit_code().is_some() {
+                break;
+            }
+        }
+        assert_eq!(Some(5), host.exit_code());
+        assert_eq!(b"abc\n", host.output());
+        assert_eq!(3, mcu.mem.peek(0x200, 4).unwrap());
+        assert_eq!(1, mcu.mem.peek(0x48, 4).unwrap());
+    }
+
+    #[test]
+    fn test_handler_installed() {
+        let mut mcu = Mcu::new(0x1000);
+        let mut host = Host::new();
+        mcu.rf.write_csr(CSR_MTVEC, 0x100).unwrap();
+        mcu.mem.program_words(&[ECALL]).unwrap();
+        mcu.rf.write(A0, 3).unwrap();
+        mcu.rf.write(A7, SYS_EXIT).unwrap();
+
+        let step = mcu.step_with_host(&mut host).unwrap();
+        assert_eq!(0x100, step.next_pc);
+        assert_eq!(
+            Some(Exception::MachineEnvironmentCall),
+            step.trap.map(|t| t.cause)
+        );
+        assert_eq!(None, host.exit_code());
+    }
+
+    #[test]
+    fn test_without_host() {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem.program_words(&[ECALL]).unwrap();
+        assert_eq!(Err(RiscvError::EnvironmentCallError(0)), mcu.step());
+        assert_eq!(0, mcu.pc);
+        assert_eq!(0, mcu.instructions);
+    }
+}
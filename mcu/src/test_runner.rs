@@ -1,150 +1,521 @@
-use std::{fs, path::Path};
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
 
-use glob::glob;
-use log::{info, Level, LevelFilter, Metadata, Record};
+use serde_json::Value;
 
-use lib_rv32_isa::{common::constants::*, exec_one, RiscvError};
+use lib_rv32_asm::assemble_program;
+use lib_rv32_isa::{
+    common::{constants::*, parse_int},
+    disassemble, RiscvError,
+};
 
-use crate::*;
+use crate::{Elf, Host, Mcu, Memory, Permissions, RegisterFileTrait, Step, PAGE_SIZE};
 
-const MEM_SIZE: u32 = 0x10000;
+/// Name of the file that makes a directory a test.
+pub const TEST_CASE: &str = "test_case.json";
 
-struct Logger;
+/// Memory size of tests that do not set `mem_size`.
+pub const DEFAULT_MEM_SIZE: usize = 0x10000;
 
-impl log::Log for Logger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
-    }
+/// Number of executed instructions kept for the trace of a failure.
+const TRACE_LENGTH: usize = 16;
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            print!("{}", record.args());
-        }
-    }
+/// How a test ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    /// An assertion did not hold or execution faulted.
+    Failed(String),
+    /// The test could not be run, e.g. because its program is not built.
+    Skipped(String),
+}
 
-    fn flush(&self) {}
+/// Result of running one test directory.
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    pub instructions: u64,
+    /// The last instructions executed, disassembled, followed by the
+    /// test's `dump.txt` if it has one. Only kept for failures.
+    pub trace: String,
 }
 
-static LOGGER: Logger = Logger;
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+    }
+}
 
-struct TestResult {
-    name: String,
-    dump: String,
-    state: Box<Mcu>,
-    err: Option<RiscvError>,
-    why: String,
+/// Find the test directories under `dir`, i.e. those with a
+/// `test_case.json`, in sorted order.
+pub fn discover(dir: &Path) -> Vec<PathBuf> {
+    let mut tests = Vec::new();
+    if dir.join(TEST_CASE).is_file() {
+        tests.push(dir.to_path_buf());
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut dirs: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_dir())
+            .collect();
+        dirs.sort();
+        for dir in dirs {
+            tests.extend(discover(&dir));
+        }
+    }
+    tests
 }
 
-fn run_test(dir: &Path) -> Result<(), TestResult> {
-    let test_bin_path_str = format!("{}/prog.bin", dir.display());
-    let test_bin_path = Path::new(&test_bin_path_str);
+/// Run `tests` on up to `jobs` threads. Results are in the order of
+/// `tests`.
+pub fn run_tests(tests: &[PathBuf], jobs: usize) -> Vec<TestResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; tests.len()]);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, tests.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                match tests.get(i) {
+                    Some(dir) => {
+                        let result = run_test(dir);
+                        results.lock().unwrap()[i] = Some(result);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
 
-    let test_dump_path_str = format!("{}/dump.txt", dir.display());
-    let test_dump_path = Path::new(&test_dump_path_str);
+/// Run the test in `dir`, as described by its `test_case.json`:
+///
+/// ```json
+/// {
+///     "program": "prog.bin",
+///     "mem_size": "0x10000",
+///     "regions": [{ "base": "0x80000000", "size": "0x10000", "perms": "rwx" }],
+///     "misaligned": "trap",
+///     "protect": false,
+///     "entry": "0x0",
+///     "stop_pc": 24,
+///     "max_cycles": 80,
+///     "timeout": 1000,
+///     "assertions": {
+///         "registers": { "a0": 20 },
+///         "memory": { "0x1000": "0xff" }
+///     }
+/// }
+/// ```
+///
+/// `max_cycles` is the number of instructions to run before checking the
+/// assertions, and `timeout` a number of instructions whose reaching fails
+/// the test; at least one of them is required. The program defaults to
+/// `prog.bin` and may be an ELF. Alternatively, `sources` lists assembly
+/// files that are assembled together, in order, to give the program, so
+/// the test needs no cross-compiler. Tests also stop at `stop_pc` (whose
+/// digits are read as hex when it is a number), when the program exits
+/// through a syscall or HTIF, at an `ebreak`, or when a raw binary runs
+/// past its end.
+///
+/// `regions`, if given, are the memory that is mapped, and raw binaries
+/// are loaded at the base of the first. Otherwise `mem_size` bytes are
+/// mapped at 0, or from the page an ELF is loaded at. `protect` applies
+/// the permissions of an ELF's segments, and `misaligned` is the policy
+/// for misaligned loads and stores.
+pub fn run_test(dir: &Path) -> TestResult {
+    let mut result = TestResult {
+        name: dir.display().to_string(),
+        outcome: TestOutcome::Passed,
+        instructions: 0,
+        trace: String::new(),
+    };
+    let mut trace = VecDeque::new();
+    result.outcome = match execute(dir, &mut result.instructions, &mut trace) {
+        Ok(outcome) => outcome,
+        Err(why) => TestOutcome::Skipped(why),
+    };
 
-    let test_json_path_str = format!("{}/test_case.json", dir.display());
-    let test_json_path = Path::new(&test_json_path_str);
-    let test_params: serde_json::Value =
-        serde_json::from_str(&fs::read_to_string(test_json_path).unwrap()).unwrap();
+    if let TestOutcome::Failed(_) = result.outcome {
+        for step in trace.iter() {
+            writeln!(
+                result.trace,
+                "[{:04x}]  {:08x}  |  {}",
+                step.pc,
+                step.ir,
+                disassemble(step.ir)
+            )
+            .unwrap();
+        }
+        if let Ok(dump) = fs::read_to_string(dir.join("dump.txt")) {
+            write!(result.trace, "\n{}", dump).unwrap();
+        }
+    }
+    result
+}
 
-    let max_cycles = test_params["max_cycles"].as_u64().unwrap();
-    let stop_pc =
-        u32::from_str_radix(&test_params["stop_pc"].as_u64().unwrap().to_string(), 16).unwrap();
+/// Load and run a test. Problems with the test itself are errors, and
+/// problems with the program under test are failures.
+fn execute(
+    dir: &Path,
+    instructions: &mut u64,
+    trace: &mut VecDeque<Step>,
+) -> Result<TestOutcome, String> {
+    let text = fs::read_to_string(dir.join(TEST_CASE)).map_err(|e| e.to_string())?;
+    let params: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
 
-    let mut register_assertions: Vec<(u8, u32)> = Vec::new();
-    for (i, name) in REG_NAMES.iter().enumerate() {
-        if let Some(d) = test_params["assertions"]["registers"][*name].as_u64() {
-            register_assertions.push((i as u8, d as u32));
+    let max_cycles = params["max_cycles"].as_u64();
+    let timeout = params["timeout"].as_u64();
+    if max_cycles.is_none() && timeout.is_none() {
+        return Err("max_cycles or timeout is required".to_owned());
+    }
+    let stop_pc = match &params["stop_pc"] {
+        Value::Null => None,
+        // Kept for older tests, which give the address's hex digits.
+        Value::Number(n) => {
+            Some(u32::from_str_radix(&n.to_string(), 16).map_err(|_| "bad stop_pc")?)
+        }
+        v => Some(number(v).ok_or("bad stop_pc")?),
+    };
+    let mem_size = match &params["mem_size"] {
+        Value::Null => DEFAULT_MEM_SIZE,
+        v => number(v).ok_or("bad mem_size")? as usize,
+    };
+    let bytes = match &params["sources"] {
+        Value::Null => {
+            let program = params["program"].as_str().unwrap_or("prog.bin");
+            fs::read(dir.join(program)).map_err(|_| format!("{} is not built", program))?
+        }
+        sources => {
+            let sources: Vec<&str> = match sources {
+                Value::String(source) => vec![source],
+                Value::Array(sources) => sources.iter().filter_map(|s| s.as_str()).collect(),
+                _ => return Err("bad sources".to_owned()),
+            };
+            let mut text = String::new();
+            for source in sources.iter() {
+                text += &fs::read_to_string(dir.join(source))
+                    .map_err(|e| format!("{}: {}", source, e))?;
+                text.push('\n');
+            }
+            match assemble_program(&text) {
+                Ok(words) => words.iter().flat_map(|w| w.to_le_bytes()).collect(),
+                Err(why) => {
+                    return Ok(TestOutcome::Failed(format!(
+                        "Could not assemble {}: {:?}",
+                        sources.join(", "),
+                        why
+                    )))
+                }
+            }
+        }
+    };
+    let elf = match Elf::is_elf(&bytes) {
+        true => Some(Elf::parse(&bytes).map_err(|e| format!("{:?}", e))?),
+        false => None,
+    };
+    let mut mem = Memory::sparse();
+    match &params["regions"] {
+        Value::Null => {
+            let base = elf.as_ref().map_or(0, |elf| elf.load_base()) & !(PAGE_SIZE as u32 - 1);
+            if mem_size == 0 || mem_size % 4 != 0 || base as u64 + mem_size as u64 > 1 << 32 {
+                return Err("bad mem_size".to_owned());
+            }
+            mem.map(base, mem_size as u32);
+        }
+        Value::Array(regions) => {
+            for region in regions {
+                let perms = match &region["perms"] {
+                    Value::Null => Some(Permissions::RWX),
+                    v => v.as_str().and_then(|s| s.parse().ok()),
+                };
+                match (number(&region["base"]), number(&region["size"]), perms) {
+                    (Some(base), Some(size), Some(perms))
+                        if base % 4 == 0
+                            && size % 4 == 0
+                            && size > 0
+                            && base as u64 + size as u64 <= 1 << 32 =>
+                    {
+                        mem.map_with_permissions(base, size, perms)
+                    }
+                    _ => return Err(format!("bad region {}", region)),
+                }
+            }
         }
+        _ => return Err("bad regions".to_owned()),
     }
 
-    info!("{}:\n", test_bin_path_str);
-
-    let mut mcu = Mcu::new(MEM_SIZE as usize);
-    let prog_len = mcu.mem.program_from_file(test_bin_path).unwrap();
+    if let Some(policy) = params["misaligned"].as_str() {
+        mem.set_misaligned_policy(policy.parse()?);
+    }
 
-    let mut cycles = 0;
+    let mut mcu = Mcu::with_memory(mem);
+    let mut host = Host::new().capture_output();
+    let mut end = None;
+    if let Some(elf) = elf {
+        mcu.program_elf(&elf).map_err(|e| format!("{:?}", e))?;
+        if params["protect"].as_bool() == Some(true) {
+            mcu.mem.protect_elf(&elf);
+        }
+        if let Some(tohost) = elf.symbol("tohost") {
+            host = host.with_htif(tohost, elf.symbol("fromhost"));
+        }
+    } else {
+        let base = mcu.mem.regions().first().map_or(0, |r| r.base);
+        mcu.mem
+            .program_le_bytes_at(base, &bytes)
+            .map_err(|e| format!("{:?}", e))?;
+        mcu.pc = base;
+        end = Some(base.wrapping_add(bytes.len() as u32));
+    }
+    if !params["entry"].is_null() {
+        mcu.pc = number(&params["entry"]).ok_or("bad entry")?;
+    }
 
     loop {
-        if mcu.pc >= prog_len as u32 {
-            info!("Stopping because the program has exited the text.\n");
+        if Some(mcu.pc) == stop_pc || end.is_some_and(|end| mcu.pc >= end) {
             break;
         }
-
-        if mcu.pc == stop_pc {
-            info!("Stopping because the stop PC 0x{:x} was reached.\n", mcu.pc);
+        if let Some(timeout) = timeout.filter(|timeout| *instructions >= *timeout) {
+            return Ok(TestOutcome::Failed(format!(
+                "Instruction limit of {} reached",
+                timeout
+            )));
+        }
+        if max_cycles.is_some_and(|max_cycles| *instructions >= max_cycles) {
             break;
         }
-
-        if cycles >= max_cycles as u32 {
-            info!("Stopping because the cycle limit was reached.\n");
+        match mcu.step_with_host(&mut host) {
+            Ok(step) => {
+                if trace.len() == TRACE_LENGTH {
+                    trace.pop_front();
+                }
+                trace.push_back(step);
+            }
+            Err(RiscvError::BreakpointError(_)) => break,
+            Err(why) => {
+                return Ok(TestOutcome::Failed(format!(
+                    "Error at 0x{:08x}: {:?}",
+                    mcu.pc, why
+                )))
+            }
+        }
+        *instructions += 1;
+        if host.exit_code().is_some() {
             break;
         }
+    }
 
-        if let Err(e) = exec_one(&mut mcu.pc, &mut mcu.mem, &mut mcu.rf) {
-            return Err(TestResult {
-                name: dir.display().to_string(),
-                dump: fs::read_to_string(test_dump_path).unwrap(),
-                state: Box::new(mcu.clone()),
-                err: Some(e),
-                why: String::from("Error during execution"),
-            });
+    let mut failures = Vec::new();
+    if let Some(code) = host.exit_code().filter(|code| *code != 0) {
+        failures.push(format!("Exited with status {}.", code));
+    }
+    for (i, name) in REG_NAMES.iter().enumerate() {
+        if let Some(v) = params["assertions"]["registers"].get(*name) {
+            let expected = number(v).ok_or(format!("bad assertion on {}", name))?;
+            let actual = mcu.rf.read(i as u8).unwrap();
+            if actual != expected {
+                failures.push(format!(
+                    "Register assertion failed: ({}=0x{:08x}) != 0x{:08x}.",
+                    name, actual, expected
+                ));
+            }
         }
-
-        cycles += 1;
     }
-
-    for assertion in register_assertions {
-        if assertion.1 != mcu.rf.read(assertion.0).unwrap() {
-            return Err(TestResult {
-                name: dir.display().to_string(),
-                dump: fs::read_to_string(test_dump_path).unwrap(),
-                state: Box::new(mcu.clone()),
-                err: None,
-                why: format!(
-                    "Register assertion failed: (x{}=0x{:08x}) != 0x{:08x}.",
-                    assertion.0,
-                    mcu.rf.read(assertion.0).unwrap(),
-                    assertion.1
-                ),
-            });
+    if let Some(memory) = params["assertions"]["memory"].as_object() {
+        for (addr, v) in memory {
+            let addr = parse_int!(u32, addr).map_err(|_| format!("bad address {}", addr))?;
+            let expected = number(v).ok_or(format!("bad assertion on 0x{:08x}", addr))?;
+            match mcu.mem.peek(addr, 4) {
+                Ok(actual) if actual == expected => (),
+                Ok(actual) => failures.push(format!(
+                    "Memory assertion failed: (*0x{:08x}=0x{:08x}) != 0x{:08x}.",
+                    addr, actual, expected
+                )),
+                Err(why) => failures.push(format!("*0x{:08x}: {:?}", addr, why)),
+            }
         }
     }
 
-    Ok(())
+    Ok(match failures.is_empty() {
+        true => TestOutcome::Passed,
+        false => TestOutcome::Failed(failures.join("\n")),
+    })
+}
+
+/// A JSON number, or a string in decimal or hex.
+fn number(v: &Value) -> Option<u32> {
+    match v {
+        Value::Number(n) => n
+            .as_u64()
+            .or(n.as_i64().map(|n| n as u64))
+            .map(|n| n as u32),
+        Value::String(s) => parse_int!(u32, s).ok(),
+        _ => None,
+    }
 }
 
-#[test]
-fn test_program_harness() {
-    log::set_logger(&LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Info))
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Create a test directory with `program` assembled into `prog.bin`.
+    fn make_test(root: &Path, name: &str, program: &str, test_case: &str) -> PathBuf {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let bytes: Vec<u8> = assemble_program(program)
+            .unwrap()
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        fs::write(dir.join("prog.bin"), bytes).unwrap();
+        fs::write(dir.join(TEST_CASE), test_case).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_run_tests() {
+        let root = env::temp_dir().join(format!("lib-rv32-tests-{}", process::id()));
+        make_test(
+            &root,
+            "pass",
+            "addi a0, zero, 20\nsw a0, 0x100(zero)",
+            r#"{ "max_cycles": 10, "assertions": { "registers": { "a0": 20 },
+                 "memory": { "0x100": "0x14" } } }"#,
+        );
+        make_test(
+            &root,
+            "fail",
+            "addi a0, zero, 1\naddi a0, a0, 1",
+            r#"{ "max_cycles": 10, "assertions": { "registers": { "a0": "0x3" } } }"#,
+        );
+        make_test(
+            &root,
+            "nested/limit",
+            "addi t0, t0, 1\njal zero, -4",
+            r#"{ "timeout": 10, "mem_size": "0x1000", "stop_pc": "0x100" }"#,
+        );
+        // Running for max_cycles is not a failure, so a program may spin
+        // once it is done.
+        make_test(
+            &root,
+            "spin",
+            "addi a0, zero, 5\njal zero, 0",
+            r#"{ "max_cycles": 10, "timeout": 20,
+                 "assertions": { "registers": { "a0": 5 } } }"#,
+        );
+        fs::create_dir_all(root.join("source")).unwrap();
+        fs::write(
+            root.join("source").join("start.s"),
+            "    li a0, 0x12345678  # two instructions\n    call inc\n    ebreak\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("source").join("inc.s"),
+            "inc:\n    addi a0, a0, 1\n    ret\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("source").join(TEST_CASE),
+            r#"{ "max_cycles": 10, "sources": ["start.s", "inc.s"],
+                 "assertions": { "registers": { "a0": "0x12345679" } } }"#,
+        )
+        .unwrap();
+        make_test(
+            &root,
+            "rom",
+            "auipc t0, 0\nlw a0, 4(t0)\nlh a1, 5(t0)\nsw a0, 0(t0)",
+            r#"{ "max_cycles": 10, "misaligned": "emulate",
+                 "regions": [{ "base": "0x80000000", "size": "0x1000", "perms": "r-x" }],
+                 "assertions": { "registers": { "a0": "0x0042a503" } } }"#,
+        );
+        fs::create_dir_all(root.join("unbuilt")).unwrap();
+        fs::write(
+            root.join("unbuilt").join(TEST_CASE),
+            r#"{ "max_cycles": 1 }"#,
+        )
         .unwrap();
 
-    let mut pass = true;
-    for dir in match glob("./programs/*") {
-        Err(_) => return,
-        Ok(p) => p,
-    }
-    .map(|p| p.unwrap())
-    {
-        if dir.is_dir() {
-            if let Err(res) = run_test(&dir) {
-                pass = false;
-                eprint!(
-                    "\n\nFailed test: {}@0x{:08x}: {}",
-                    res.name, res.state.pc, res.why
-                );
-                match res.err {
-                    None => eprintln!(),
-                    Some(e) => eprintln!(": {:?}", e),
+        let tests = discover(&root);
+        let names: Vec<&str> = tests
+            .iter()
+            .map(|t| t.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            vec!["fail", "limit", "pass", "rom", "source", "spin", "unbuilt"],
+            names
+        );
+
+        let results = run_tests(&tests, 4);
+        assert_eq!(
+            TestOutcome::Failed(
+                "Register assertion failed: (a0=0x00000002) != 0x00000003.".to_string()
+            ),
+            results[0].outcome
+        );
+        assert!(results[0]
+            .trace
+            .contains("[0004]  00150513  |  addi a0, a0, 1"));
+        assert_eq!(
+            TestOutcome::Failed("Instruction limit of 10 reached".to_string()),
+            results[1].outcome
+        );
+        assert!(results[2].passed());
+        assert_eq!(2, results[2].instructions);
+        assert!(results[2].trace.is_empty());
+        // The misaligned load is emulated, but the store to text faults.
+        assert_eq!(
+            TestOutcome::Failed(
+                "Error at 0x8000000c: MemoryPermissionError(2147483648)".to_string()
+            ),
+            results[3].outcome
+        );
+        assert!(results[4].passed());
+        assert_eq!(6, results[4].instructions);
+        assert!(results[5].passed());
+        assert_eq!(10, results[5].instructions);
+        assert_eq!(
+            TestOutcome::Skipped("prog.bin is not built".to_string()),
+            results[6].outcome
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Run every test under `./programs` whose program has been built.
+    #[test]
+    fn test_program_harness() {
+        let mut pass = true;
+        for result in run_tests(&discover(Path::new("./programs")), 4) {
+            match &result.outcome {
+                TestOutcome::Passed => eprintln!("{}... ok", result.name),
+                TestOutcome::Skipped(why) => eprintln!("{}... skipped: {}", result.name, why),
+                TestOutcome::Failed(why) => {
+                    pass = false;
+                    eprintln!(
+                        "\n\nFailed test: {}: {}\n{}",
+                        result.name, why, result.trace
+                    );
                 }
-                eprintln!("{}", res.dump);
-            } else {
-                eprintln!("{}... ok", dir.display());
             }
-            (0..64).into_iter().for_each(|_| eprint!("="));
-            eprintln!("\n");
         }
+        assert!(pass);
     }
-    assert!(pass);
 }
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::{InstructionClass, RegisterUsage};
+
+use crate::{cache::CacheHierarchy, Step};
+
+/// A model that estimates how many cycles executed instructions take.
+///
+/// Models are fed the `Step` records returned by `Mcu::step`, so the
+/// functional simulation is unaffected by which model (if any) is used.
+pub trait TimingModel {
+    /// Account for an executed instruction and return the cycles it cost.
+    fn retire(&mut self, step: &Step) -> u64;
+
+    /// Total cycles so far.
+    fn cycles(&self) -> u64;
+
+    /// Total instructions so far.
+    fn instructions(&self) -> u64;
+
+    /// Average cycles per instruction.
+    fn cpi(&self) -> f64 {
+        match self.instructions() {
+            0 => 0.0,
+            n => self.cycles() as f64 / n as f64,
+        }
+    }
+}
+
+/// Cycles spent by each class of instruction, and the penalties for
+/// hazards. The defaults describe a classic 5-stage pipeline with
+/// forwarding, branches resolved in EX, and a pipelined FPU whose
+/// divides and square roots are iterative.
+///
+/// When deserialized (e.g. from JSON), missing fields keep their default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Latencies {
+    pub alu: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub float: u64,
+    /// `fdiv` and `fsqrt`.
+    pub float_divide: u64,
+    pub fence: u64,
+    /// Extra cycles when a branch is taken.
+    pub taken_branch_penalty: u64,
+    /// Extra cycles for `jal` and `jalr`.
+    pub jump_penalty: u64,
+    /// Extra cycles when an instruction uses the result of the load
+    /// right before it.
+    pub load_use_penalty: u64,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies {
+            alu: 1,
+            load: 1,
+            store: 1,
+            branch: 1,
+            jump: 1,
+            float: 4,
+            float_divide: 20,
+            fence: 1,
+            taken_branch_penalty: 2,
+            jump_penalty: 2,
+            load_use_penalty: 1,
+        }
+    }
+}
+
+impl Latencies {
+    /// Base cost of an instruction class, without hazards.
+    pub fn of(&self, class: InstructionClass) -> u64 {
+        match class {
+            InstructionClass::Alu | InstructionClass::System | InstructionClass::Unknown => {
+                self.alu
+            }
+            InstructionClass::Load => self.load,
+            InstructionClass::Store => self.store,
+            InstructionClass::Branch => self.branch,
+            InstructionClass::Jump => self.jump,
+            InstructionClass::Float => self.float,
+            InstructionClass::FloatDivide => self.float_divide,
+            InstructionClass::Fence => self.fence,
+        }
+    }
+}
+
+/// Cycles lost to each kind of hazard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct StallCycles {
+    pub load_use: u64,
+    pub taken_branch: u64,
+    pub jump: u64,
+    /// Waiting for memory on cache misses.
+    pub memory: u64,
+}
+
+/// Timing model that charges a fixed latency per instruction class, plus
+/// penalties for load-use hazards and control transfers, and optionally
+/// the stalls of a cache hierarchy.
+#[derive(Debug, Clone)]
+pub struct LatencyModel {
+    latencies: Latencies,
+    cycles: u64,
+    instructions: u64,
+    stalls: StallCycles,
+    /// Destination of the previous instruction, if it was a load.
+    pending_load: Option<u8>,
+    caches: Option<CacheHierarchy>,
+}
+
+impl LatencyModel {
+    pub fn new(latencies: Latencies) -> Self {
+        LatencyModel {
+            latencies,
+            cycles: 0,
+            instructions: 0,
+            stalls: StallCycles::default(),
+            pending_load: None,
+            caches: None,
+        }
+    }
+
+    /// Charge the cache misses of every fetch and data access.
+    pub fn with_caches(mut self, caches: CacheHierarchy) -> Self {
+        self.caches = Some(caches);
+        self
+    }
+
+    pub fn caches(&self) -> Option<&CacheHierarchy> {
+        self.caches.as_ref()
+    }
+
+    pub fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+
+    /// Cycles lost to hazards so far.
+    pub fn stalls(&self) -> StallCycles {
+        self.stalls
+    }
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        LatencyModel::new(Latencies::default())
+    }
+}
+
+impl TimingModel for LatencyModel {
+    fn retire(&mut self, step: &Step) -> u64 {
+        let class = InstructionClass::of(step.ir);
+        let usage = RegisterUsage::of(step.ir);
+        let mut cycles = self.latencies.of(class);
+
+        if matches!(self.pending_load, Some(rd) if usage.reads(rd)) {
+            cycles += self.latencies.load_use_penalty;
+            self.stalls.load_use += self.latencies.load_use_penalty;
+        }
+        match class {
+            InstructionClass::Branch if step.next_pc != step.pc.wrapping_add(4) => {
+                cycles += self.latencies.taken_branch_penalty;
+                self.stalls.taken_branch += self.latencies.taken_branch_penalty;
+            }
+            InstructionClass::Jump => {
+                cycles += self.latencies.jump_penalty;
+                self.stalls.jump += self.latencies.jump_penalty;
+            }
+            _ => (),
+        }
+
+        if let Some(caches) = self.caches.as_mut() {
+            let stall = caches.retire(step);
+            cycles += stall;
+            self.stalls.memory += stall;
+        }
+
+        self.pending_load = match class {
+            InstructionClass::Load => usage.rd,
+            _ => None,
+        };
+        self.cycles += cycles;
+        self.instructions += 1;
+        cycles
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn instructions(&self) -> u64 {
+        self.instructions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::Mcu;
+
+    fn run(program: &str, n: usize, model: &mut dyn TimingModel) {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        for _ in 0..n {
+            let step = mcu.step().unwrap();
+            model.retire(&step);
+        }
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let mut model = LatencyModel::default();
+        run(
+            "addi t0, zero, 1\naddi t1, zero, 2\nadd t2, t0, t1",
+            3,
+            &mut model,
+        );
+        assert_eq!(3, model.cycles());
+        assert_eq!(1.0, model.cpi());
+    }
+
+    #[test]
+    fn test_load_use() {
+        let mut model = LatencyModel::default();
+        run(
+            "lw t0, 0x100(zero)\n\
+             addi t1, t0, 1\n\
+             lw t2, 0x100(zero)\n\
+             addi t1, t1, 1\n\
+             addi t1, t2, 1",
+            5,
+            &mut model,
+        );
+        // Only the first load is used right away.
+        assert_eq!(1, model.stalls().load_use);
+        assert_eq!(6, model.cycles());
+    }
+
+    #[test]
+    fn test_control_penalties() {
+        let latencies = Latencies {
+            taken_branch_penalty: 3,
+            jump_penalty: 1,
+            ..Latencies::default()
+        };
+        let mut model = LatencyModel::new(latencies);
+        run(
+            "beq zero, t0, 8\n\
+             addi t0, t0, 1\n\
+             bne zero, t0, 8\n\
+             jal zero, 0",
+            3,
+            &mut model,
+        );
+        assert_eq!(3, model.stalls().taken_branch);
+        assert_eq!(1, model.stalls().jump);
+        assert_eq!(7, model.cycles());
+    }
+
+    #[test]
+    fn test_cache_stalls() {
+        use crate::cache::{CacheConfig, HierarchyConfig};
+
+        let caches = CacheHierarchy::new(HierarchyConfig {
+            data: CacheConfig::default(),
+            instruction: None,
+        });
+        let mut model = LatencyModel::default().with_caches(caches);
+        run(
+            "lw t0, 0x100(zero)\naddi t1, zero, 1\nlw t2, 0x104(zero)",
+            3,
+            &mut model,
+        );
+        // One miss for the instructions and one for the data.
+        assert_eq!(20, model.stalls().memory);
+        assert_eq!(23, model.cycles());
+    }
+
+    #[test]
+    fn test_partial_latencies() {
+        let latencies: Latencies = serde_json::from_str(r#"{ "float_divide": 8 }"#).unwrap();
+        assert_eq!(8, latencies.float_divide);
+        assert_eq!(4, latencies.float);
+    }
+}
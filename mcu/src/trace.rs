@@ -0,0 +1,442 @@
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::{
+    common::{bit_slice, constants::*},
+    decode_opcode, decode_rd, decode_rs1,
+    traits::{
+        FpRegisterFile as FpRegisterFileTrait, Memory as MemoryTrait,
+        RegisterFile as RegisterFileTrait,
+    },
+    InstructionClass, RiscvError,
+};
+
+use crate::{
+    mmu::{self, Tlb, Translation},
+    pmp::Pmp,
+    trap::{Privilege, TakenInterrupt, Trap},
+    Access, Memory, RegisterFile, PAGE_SIZE,
+};
+
+/// Direction of a data memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AccessKind {
+    Load,
+    Store,
+}
+
+/// A data memory access performed by an instruction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    /// Physical address.
+    pub addr: u32,
+    /// Width of the access in bytes.
+    pub size: u8,
+    /// Value loaded or stored.
+    pub data: u32,
+    /// Previous contents of the location (equal to `data` for loads).
+    pub old: u32,
+}
+
+/// A register written by an instruction. Writes to `x0` are not recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RegisterWrite {
+    pub reg: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A floating-point register written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FpRegisterWrite {
+    pub reg: u8,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// A control and status register written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CsrWrite {
+    pub csr: u16,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Record of an instruction executed by `Mcu::step`. An instruction that
+/// raised an exception has its `trap`, and `next_pc` is the handler. If
+/// the fetch itself faulted, `ir` is zero. `privilege` is the mode the
+/// instruction ran in, and `next_privilege` the one after it. Likewise,
+/// `reservation` is the word reserved by `lr.w` before it and
+/// `next_reservation` the one after it. An instruction writes at most one
+/// integer or floating-point register and at most one CSR. `pte_updates`
+/// are the accessed and dirty bits set in page table entries while
+/// translating its addresses. The undo log adds the `interrupts` taken
+/// after the instruction, before the next one, so they are undone with it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub pc: u32,
+    pub ir: u32,
+    pub next_pc: u32,
+    pub privilege: Privilege,
+    pub next_privilege: Privilege,
+    pub reservation: Option<u32>,
+    pub next_reservation: Option<u32>,
+    pub reg_write: Option<RegisterWrite>,
+    pub fp_write: Option<FpRegisterWrite>,
+    pub csr_write: Option<CsrWrite>,
+    pub accesses: Vec<MemoryAccess>,
+    pub pte_updates: Vec<MemoryAccess>,
+    pub trap: Option<Trap>,
+    pub interrupts: Vec<TakenInterrupt>,
+}
+
+impl Step {
+    /// Whether this instruction wrote any of the bytes in `addr..addr + size`.
+    pub fn writes_memory(&self, addr: u32, size: u32) -> bool {
+        self.accesses.iter().any(|a| {
+            a.kind == AccessKind::Store
+                && a.addr < addr.wrapping_add(size)
+                && addr < a.addr.wrapping_add(a.size as u32)
+        })
+    }
+
+    /// Whether this instruction wrote the register `reg`.
+    pub fn writes_register(&self, reg: u8) -> bool {
+        matches!(self.reg_write, Some(w) if w.reg == reg)
+    }
+
+    /// Whether this is a jump that links to `ra` or `t0`, i.e. a call.
+    pub fn is_call(&self) -> bool {
+        InstructionClass::of(self.ir) == InstructionClass::Jump && is_link(decode_rd!(self.ir))
+    }
+
+    /// Whether this is a `jalr` through `ra` or `t0` that does not link to
+    /// the same register, i.e. a return.
+    pub fn is_return(&self) -> bool {
+        let rs1 = decode_rs1!(self.ir);
+        decode_opcode!(self.ir) == OPCODE_JALR && is_link(rs1) && rs1 != decode_rd!(self.ir)
+    }
+}
+
+/// Registers used as the link register by the calling convention.
+fn is_link(reg: u8) -> bool {
+    reg == 1 || reg == 5
+}
+
+/// Memory wrapper that translates the data accesses made through it, checks
+/// them against the PMP in the mode they are made in, and records them.
+/// Reads take `&self`, so the memory, the TLB and the logs live in
+/// `RefCell`s.
+pub(crate) struct RecordingMemory<'a> {
+    pub mem: RefCell<&'a mut Memory>,
+    pub pmp: &'a Pmp,
+    pub translation: Translation,
+    pub tlb: RefCell<Option<&'a mut Tlb>>,
+    /// The pc and instruction `Mcu::step` fetched.
+    pub fetched: (u32, u32),
+    pub accesses: RefCell<Vec<MemoryAccess>>,
+    pub pte_updates: RefCell<Vec<MemoryAccess>>,
+}
+
+impl<'a> RecordingMemory<'a> {
+    pub fn new(
+        mem: &'a mut Memory,
+        pmp: &'a Pmp,
+        translation: Translation,
+        tlb: Option<&'a mut Tlb>,
+        fetched: (u32, u32),
+    ) -> Self {
+        RecordingMemory {
+            mem: RefCell::new(mem),
+            pmp,
+            translation,
+            tlb: RefCell::new(tlb),
+            fetched,
+            accesses: RefCell::new(Vec::new()),
+            pte_updates: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Physical address of an access of `size` bytes at `addr`. Under
+    /// translation an access may not cross into another page.
+    fn translate(&self, addr: u32, size: u8, access: Access) -> Result<u32, RiscvError> {
+        if !self.translation.enabled() {
+            return Ok(addr);
+        }
+        let page = PAGE_SIZE as u32;
+        if addr % page + size as u32 > page {
+            return Err(RiscvError::MemoryAlignmentError(addr));
+        }
+        let mut mem = self.mem.borrow_mut();
+        let mut tlb = self.tlb.borrow_mut();
+        mmu::translate(
+            &mut mem,
+            self.pmp,
+            tlb.as_deref_mut(),
+            &self.translation,
+            addr,
+            access,
+            &mut self.pte_updates.borrow_mut(),
+        )
+    }
+
+    fn load(
+        &self,
+        addr: u32,
+        size: u8,
+        read: fn(&mut Memory, u32) -> Result<u32, RiscvError>,
+    ) -> Result<u32, RiscvError> {
+        let paddr = self.translate(addr, size, Access::Read)?;
+        self.pmp
+            .check(paddr, size as u32, Access::Read, self.translation.privilege)
+            .map_err(mmu::at(paddr, addr))?;
+        let r = read(&mut self.mem.borrow_mut(), paddr).map_err(mmu::at(paddr, addr));
+        if let Ok(data) = r {
+            self.accesses.borrow_mut().push(MemoryAccess {
+                kind: AccessKind::Load,
+                addr: paddr,
+                size,
+                data,
+                old: data,
+            });
+        }
+        r
+    }
+
+    /// Store `data` with `write`, which returns whether it wrote anything.
+    fn store(
+        &mut self,
+        addr: u32,
+        size: u8,
+        data: u32,
+        write: fn(&mut Memory, u32, u32) -> Result<bool, RiscvError>,
+    ) -> Result<bool, RiscvError> {
+        let paddr = self.translate(addr, size, Access::Write)?;
+        self.pmp
+            .check(
+                paddr,
+                size as u32,
+                Access::Write,
+                self.translation.privilege,
+            )
+            .map_err(mmu::at(paddr, addr))?;
+        let mem = self.mem.get_mut();
+        let old = mem.peek(paddr, size as usize).unwrap_or(0);
+        if !write(mem, paddr, data).map_err(mmu::at(paddr, addr))? {
+            return Ok(false);
+        }
+        let mask = match size {
+            4 => u32::MAX,
+            n => (1 << (8 * n)) - 1,
+        };
+        self.accesses.get_mut().push(MemoryAccess {
+            kind: AccessKind::Store,
+            addr: paddr,
+            size,
+            data: data & mask,
+            old,
+        });
+        Ok(true)
+    }
+}
+
+impl MemoryTrait for RecordingMemory<'_> {
+    // `Mcu::step` has already translated and checked the fetch, in the
+    // mode instructions run in rather than the one data accesses are made
+    // in. Other fetches come from the host, which only serves machine
+    // mode, where fetches are not translated.
+    fn fetch(&self, pc: u32) -> Result<u32, RiscvError> {
+        match self.fetched {
+            (fetched, ir) if fetched == pc => Ok(ir),
+            _ => self.mem.borrow().fetch(pc),
+        }
+    }
+
+    fn read_word(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.load(addr, 4, |mem, addr| mem.read_word(addr))
+    }
+
+    fn read_half_word(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.load(addr, 2, |mem, addr| mem.read_half_word(addr))
+    }
+
+    fn read_byte(&self, addr: u32) -> Result<u32, RiscvError> {
+        self.load(addr, 1, |mem, addr| mem.read_byte(addr))
+    }
+
+    fn write_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        self.store(addr, 4, data, |mem, addr, data| {
+            mem.write_word(addr, data).map(|_| true)
+        })
+        .map(|_| ())
+    }
+
+    fn write_half_word(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        self.store(addr, 2, data, |mem, addr, data| {
+            mem.write_half_word(addr, data).map(|_| true)
+        })
+        .map(|_| ())
+    }
+
+    fn write_byte(&mut self, addr: u32, data: u32) -> Result<(), RiscvError> {
+        self.store(addr, 1, data, |mem, addr, data| {
+            mem.write_byte(addr, data).map(|_| true)
+        })
+        .map(|_| ())
+    }
+
+    fn load_reserved(&mut self, addr: u32) -> Result<u32, RiscvError> {
+        self.load(addr, 4, |mem, addr| mem.load_reserved(addr))
+    }
+
+    fn store_conditional(&mut self, addr: u32, data: u32) -> Result<bool, RiscvError> {
+        self.store(addr, 4, data, Memory::store_conditional)
+    }
+}
+
+/// Register file wrapper that records the registers and CSR written
+/// through it. Writing the same register twice keeps the value it had
+/// before the first write, and writing two different ones is a bug.
+pub(crate) struct RecordingRegisterFile<'a> {
+    pub rf: &'a mut RegisterFile,
+    pub write: Option<RegisterWrite>,
+    pub fp_write: Option<FpRegisterWrite>,
+    pub csr_write: Option<CsrWrite>,
+}
+
+impl<'a> RecordingRegisterFile<'a> {
+    pub fn new(rf: &'a mut RegisterFile) -> Self {
+        RecordingRegisterFile {
+            rf,
+            write: None,
+            fp_write: None,
+            csr_write: None,
+        }
+    }
+}
+
+impl RegisterFileTrait for RecordingRegisterFile<'_> {
+    fn read(&self, num: u8) -> Result<u32, RiscvError> {
+        self.rf.read(num)
+    }
+
+    fn write(&mut self, num: u8, data: u32) -> Result<(), RiscvError> {
+        let old = self.rf.read(num)?;
+        self.rf.write(num, data)?;
+        if num != 0 {
+            if let Some(old) = first_old(self.write.map(|w| (w.reg, w.old)), num, old) {
+                self.write = Some(RegisterWrite {
+                    reg: num,
+                    old,
+                    new: data,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn read_csr(&self, csr: u16) -> Result<u32, RiscvError> {
+        self.rf.read_csr(csr)
+    }
+
+    fn write_csr(&mut self, csr: u16, data: u32) -> Result<(), RiscvError> {
+        let old = self.rf.read_csr(csr)?;
+        self.rf.write_csr(csr, data)?;
+        // Record what the CSR holds, not what was written to it.
+        let new = self.rf.read_csr(csr)?;
+        if let Some(old) = first_old(self.csr_write.map(|w| (w.csr, w.old)), csr, old) {
+            self.csr_write = Some(CsrWrite { csr, old, new });
+        }
+        Ok(())
+    }
+
+    fn fp(&mut self) -> Option<&mut dyn FpRegisterFileTrait> {
+        Some(self)
+    }
+}
+
+impl FpRegisterFileTrait for RecordingRegisterFile<'_> {
+    fn read(&self, num: u8) -> Result<u64, RiscvError> {
+        self.rf.fp.read(num)
+    }
+
+    fn write(&mut self, num: u8, data: u64) -> Result<(), RiscvError> {
+        let old = self.rf.fp.read(num)?;
+        self.rf.fp.write(num, data)?;
+        if let Some(old) = first_old(self.fp_write.map(|w| (w.reg, w.old)), num, old) {
+            self.fp_write = Some(FpRegisterWrite {
+                reg: num,
+                old,
+                new: data,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The value `reg` had before the instruction, given the earlier write it
+/// recorded, if any, and the value `old` before this write. A step only
+/// records one register of each kind, so if the earlier write was to
+/// another register, it is kept and this one is not recorded.
+fn first_old<R: PartialEq, T>(earlier: Option<(R, T)>, reg: R, old: T) -> Option<T> {
+    match earlier {
+        Some((earlier_reg, earlier_old)) if earlier_reg == reg => Some(earlier_old),
+        Some(_) => None,
+        None => Some(old),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_writes() {
+        let mut rf = RegisterFile::new();
+        RegisterFileTrait::write(&mut rf, 5, 1).unwrap();
+        let mut recording = RecordingRegisterFile::new(&mut rf);
+        RegisterFileTrait::write(&mut recording, 5, 2).unwrap();
+        RegisterFileTrait::write(&mut recording, 5, 3).unwrap();
+        assert_eq!(
+            Some(RegisterWrite {
+                reg: 5,
+                old: 1,
+                new: 3
+            }),
+            recording.write
+        );
+
+        recording.write_csr(CSR_FFLAGS, 0b1).unwrap();
+        recording.write_csr(CSR_FFLAGS, 0b11).unwrap();
+        assert_eq!(
+            Some(CsrWrite {
+                csr: CSR_FFLAGS,
+                old: 0,
+                new: 0b11
+            }),
+            recording.csr_write
+        );
+    }
+
+    #[test]
+    fn test_two_registers() {
+        let mut rf = RegisterFile::new();
+        let mut recording = RecordingRegisterFile::new(&mut rf);
+        RegisterFileTrait::write(&mut recording, 5, 1).unwrap();
+        RegisterFileTrait::write(&mut recording, 6, 2).unwrap();
+        // The first write is the one recorded, and both take effect.
+        let write = recording.write.unwrap();
+        assert_eq!((5, 0, 1), (write.reg, write.old, write.new));
+        assert_eq!(Ok(2), rf.read(6));
+    }
+
+    #[test]
+    fn test_masked_csr_write() {
+        let mut rf = RegisterFile::new();
+        let mut recording = RecordingRegisterFile::new(&mut rf);
+        recording.write_csr(CSR_FRM, u32::MAX).unwrap();
+        assert_eq!(0b111, recording.csr_write.unwrap().new);
+    }
+}
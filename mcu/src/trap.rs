@@ -0,0 +1,1127 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use lib_rv32_isa::{
+    common::{bit_slice, constants::*},
+    decode_func5, decode_opcode, RiscvError,
+};
+
+pub use lib_rv32_isa::common::constants::{
+    CSR_MEPC, CSR_MSTATUS, CSR_SEPC, CSR_SSTATUS, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_MXR,
+    MSTATUS_SPP, MSTATUS_SUM,
+};
+
+use crate::mmu::{Translation, CSR_SATP};
+
+pub const CSR_SIE: u16 = 0x104;
+pub const CSR_STVEC: u16 = 0x105;
+pub const CSR_SCOUNTEREN: u16 = 0x106;
+pub const CSR_SSCRATCH: u16 = 0x140;
+pub const CSR_SCAUSE: u16 = 0x142;
+pub const CSR_STVAL: u16 = 0x143;
+pub const CSR_SIP: u16 = 0x144;
+pub const CSR_MEDELEG: u16 = 0x302;
+pub const CSR_MIDELEG: u16 = 0x303;
+pub const CSR_MTVEC: u16 = 0x305;
+pub const CSR_MIE: u16 = 0x304;
+pub const CSR_MCOUNTEREN: u16 = 0x306;
+pub const CSR_MSCRATCH: u16 = 0x340;
+pub const CSR_MCAUSE: u16 = 0x342;
+pub const CSR_MTVAL: u16 = 0x343;
+pub const CSR_MIP: u16 = 0x344;
+pub const CSR_MHARTID: u16 = 0xf14;
+
+/// Bit of `mcause` that marks an interrupt.
+pub const MCAUSE_INTERRUPT: u32 = 1 << 31;
+
+/// Enable and pending bits of the supervisor interrupts, which machine
+/// mode raises by writing `mip` and may delegate in `mideleg`.
+const SUPERVISOR_INTERRUPTS: u32 = 1 << Interrupt::SupervisorSoftware as u32
+    | 1 << Interrupt::SupervisorTimer as u32
+    | 1 << Interrupt::SupervisorExternal as u32;
+/// Enable bits of every interrupt there is, which are the writable bits of
+/// `mie`.
+const MIE_MASK: u32 = SUPERVISOR_INTERRUPTS
+    | 1 << Interrupt::MachineSoftware as u32
+    | 1 << Interrupt::MachineTimer as u32;
+
+/// Fields of `mstatus` that are writable. `sstatus` is the supervisor
+/// view of it.
+const MSTATUS_MASK: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR;
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+/// Privilege modes. The discriminant is the encoding used in `mstatus.MPP`
+/// and in the privilege bits of CSR numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Mode encoded by `bits`, or `None` for the reserved encoding.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Privilege::User => "user",
+            Privilege::Supervisor => "supervisor",
+            Privilege::Machine => "machine",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Synchronous exceptions. The discriminant is the value written to
+/// `mcause` or `scause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreMisaligned = 6,
+    StoreAccessFault = 7,
+    UserEnvironmentCall = 8,
+    SupervisorEnvironmentCall = 9,
+    MachineEnvironmentCall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// Exception raised when fetching the instruction at `pc` fails with
+    /// `err`, and the value for `mtval`.
+    pub fn of_fetch(err: &RiscvError, pc: u32) -> Option<(Exception, u32)> {
+        match err {
+            RiscvError::MemoryAlignmentError(_) => Some((Exception::InstructionMisaligned, pc)),
+            RiscvError::MemoryOutOfBoundsError(_) | RiscvError::MemoryPermissionError(_) => {
+                Some((Exception::InstructionAccessFault, pc))
+            }
+            RiscvError::PageFaultError(_) => Some((Exception::InstructionPageFault, pc)),
+            _ => None,
+        }
+    }
+
+    /// Exception raised when executing `ir` in `privilege` mode fails with
+    /// `err`, and the value for `mtval`. Errors that are not
+    /// architectural, such as a register number out of range, raise none.
+    pub fn of_error(err: &RiscvError, ir: u32, privilege: Privilege) -> Option<(Exception, u32)> {
+        // Atomics other than `lr.w` raise store faults, even when only
+        // their load fails.
+        let store = match decode_opcode!(ir) {
+            OPCODE_STORE => true,
+            OPCODE_AMO => decode_func5!(ir) != FUNC5_LR,
+            _ => false,
+        };
+        let jump = matches!(decode_opcode!(ir), OPCODE_JAL | OPCODE_JALR | OPCODE_BRANCH);
+        Some(match *err {
+            RiscvError::InvalidOpcodeError(..)
+            | RiscvError::InvalidFunc3Error(..)
+            | RiscvError::InvalidFunc7Error(..)
+            | RiscvError::InvalidCsrError(_) => (Exception::IllegalInstruction, ir),
+            RiscvError::MemoryAlignmentError(addr) if jump => {
+                (Exception::InstructionMisaligned, addr)
+            }
+            RiscvError::MemoryAlignmentError(addr) if store => (Exception::StoreMisaligned, addr),
+            RiscvError::MemoryAlignmentError(addr) => (Exception::LoadMisaligned, addr),
+            RiscvError::MemoryOutOfBoundsError(addr) | RiscvError::MemoryPermissionError(addr)
+                if store =>
+            {
+                (Exception::StoreAccessFault, addr)
+            }
+            RiscvError::MemoryOutOfBoundsError(addr) | RiscvError::MemoryPermissionError(addr) => {
+                (Exception::LoadAccessFault, addr)
+            }
+            RiscvError::PageFaultError(addr) if store => (Exception::StorePageFault, addr),
+            RiscvError::PageFaultError(addr) => (Exception::LoadPageFault, addr),
+            RiscvError::EnvironmentCallError(_) => match privilege {
+                Privilege::User => (Exception::UserEnvironmentCall, 0),
+                Privilege::Supervisor => (Exception::SupervisorEnvironmentCall, 0),
+                Privilege::Machine => (Exception::MachineEnvironmentCall, 0),
+            },
+            RiscvError::BreakpointError(pc) => (Exception::Breakpoint, pc),
+            RiscvError::RegisterOutOfRangeError(_) => return None,
+        })
+    }
+}
+
+/// Interrupts. The discriminant is the exception code written to `mcause`
+/// or `scause` along with `MCAUSE_INTERRUPT`, and the bit in `mie` and
+/// `mip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+}
+
+impl Interrupt {
+    /// Every interrupt, from the highest priority to the lowest.
+    const PRIORITY: [Interrupt; 5] = [
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Interrupt::SupervisorSoftware => "supervisor software interrupt",
+            Interrupt::MachineSoftware => "machine software interrupt",
+            Interrupt::SupervisorTimer => "supervisor timer interrupt",
+            Interrupt::MachineTimer => "machine timer interrupt",
+            Interrupt::SupervisorExternal => "supervisor external interrupt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Exception::InstructionMisaligned => "instruction address misaligned",
+            Exception::InstructionAccessFault => "instruction access fault",
+            Exception::IllegalInstruction => "illegal instruction",
+            Exception::Breakpoint => "breakpoint",
+            Exception::LoadMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreMisaligned => "store address misaligned",
+            Exception::StoreAccessFault => "store access fault",
+            Exception::UserEnvironmentCall => "environment call from user mode",
+            Exception::SupervisorEnvironmentCall => "environment call from supervisor mode",
+            Exception::MachineEnvironmentCall => "environment call from machine mode",
+            Exception::InstructionPageFault => "instruction page fault",
+            Exception::LoadPageFault => "load page fault",
+            Exception::StorePageFault => "store page fault",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An exception taken by `Mcu::step`, with the trap CSRs as they were
+/// before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trap {
+    pub cause: Exception,
+    pub tval: u32,
+    pub old: TrapCsrs,
+}
+
+/// An interrupt taken by `Mcu::take_interrupt` before the instruction at
+/// `pc`, with the trap CSRs as they were before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TakenInterrupt {
+    pub interrupt: Interrupt,
+    pub pc: u32,
+    pub old: TrapCsrs,
+}
+
+/// The current privilege mode and the CSRs for taking traps and switching
+/// modes: `mstatus`/`sstatus`, `mtvec`, `mscratch`, `mepc`, `mcause`,
+/// `mtval`, their supervisor counterparts, `medeleg`, `mideleg`,
+/// `mcounteren` and `scounteren`, `satp`, which selects how supervisor
+/// and user mode addresses are translated, `mie`/`sie`, `mip`/`sip` and
+/// the read-only `mhartid`.
+///
+/// Exceptions are taken in machine mode, unless they are raised in user or
+/// supervisor mode and their bit in `medeleg` delegates them to supervisor
+/// mode. They are only taken once the handler of the mode they go to is
+/// installed, i.e. `mtvec` or `stvec` is not zero. Until then `Mcu::step`
+/// returns them as errors, as it does on a machine without trap support.
+///
+/// The machine software and timer interrupts are raised by a `Soc`'s
+/// CLINT, which sets their bits in `mip`; software cannot write them.
+/// Machine mode raises the supervisor interrupts by writing their bits in
+/// `mip`, and supervisor mode its software interrupt through `sip`.
+/// Interrupts enabled in `mie` are taken in machine mode whenever the hart
+/// is below machine mode or has `mstatus.MIE` set, or, if their bit in
+/// `mideleg` delegates them, in supervisor mode whenever the hart is in
+/// user mode or in supervisor mode with `mstatus.SIE` set. `sie` and `sip`
+/// show the bits of the delegated interrupts. As for exceptions, the
+/// handler must be installed, and in vectored mode the trap vector selects
+/// it by cause.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrapCsrs {
+    privilege: Privilege,
+    mstatus: u32,
+    mtvec: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    medeleg: u32,
+    mideleg: u32,
+    mcounteren: u32,
+    stvec: u32,
+    sscratch: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    scounteren: u32,
+    satp: u32,
+    mie: u32,
+    mip: u32,
+    mhartid: u32,
+}
+
+impl TrapCsrs {
+    /// Start in machine mode, as after reset.
+    pub fn new() -> Self {
+        TrapCsrs::default()
+    }
+
+    /// Whether `csr` is one of the trap CSRs.
+    pub fn contains(csr: u16) -> bool {
+        matches!(
+            csr,
+            CSR_SSTATUS
+                | CSR_SIE
+                | CSR_STVEC
+                | CSR_SCOUNTEREN
+                | CSR_SSCRATCH
+                | CSR_SEPC
+                | CSR_SCAUSE
+                | CSR_STVAL
+                | CSR_SIP
+                | CSR_SATP
+                | CSR_MSTATUS
+                | CSR_MEDELEG
+                | CSR_MIDELEG
+                | CSR_MTVEC
+                | CSR_MCOUNTEREN
+                | CSR_MSCRATCH
+                | CSR_MEPC
+                | CSR_MCAUSE
+                | CSR_MTVAL
+                | CSR_MIE
+                | CSR_MIP
+                | CSR_MHARTID
+        )
+    }
+
+    /// The mode instructions run in.
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    /// The mode loads and stores are checked in: the mode in
+    /// `mstatus.MPP` when machine mode sets `mstatus.MPRV`, otherwise the
+    /// current one.
+    pub fn data_privilege(&self) -> Privilege {
+        match self.privilege {
+            Privilege::Machine if self.mstatus & MSTATUS_MPRV != 0 => self.previous(),
+            p => p,
+        }
+    }
+
+    /// How addresses are translated for fetches, or with `data` for loads
+    /// and stores.
+    pub fn translation(&self, data: bool) -> Translation {
+        Translation {
+            satp: self.satp,
+            privilege: match data {
+                true => self.data_privilege(),
+                false => self.privilege,
+            },
+            sum: self.mstatus & MSTATUS_SUM != 0,
+            mxr: self.mstatus & MSTATUS_MXR != 0,
+        }
+    }
+
+    /// Whether machine-mode traps are taken rather than returned from
+    /// `step`.
+    pub fn handler_installed(&self) -> bool {
+        self.mtvec != 0
+    }
+
+    /// Whether `cause` is taken rather than returned from `step`, which
+    /// depends on the handler of the mode it is delegated to.
+    pub fn takes(&self, cause: Exception) -> bool {
+        match self.delegates(cause) {
+            true => self.stvec != 0,
+            false => self.handler_installed(),
+        }
+    }
+
+    /// Whether `cause` is taken in supervisor mode.
+    fn delegates(&self, cause: Exception) -> bool {
+        self.privilege != Privilege::Machine && self.medeleg & (1 << cause as u32) != 0
+    }
+
+    /// Whether `csr` may be accessed in the current mode: its privilege
+    /// bits must not be above it, and counters below machine mode must be
+    /// enabled in `mcounteren` (and in `scounteren` for user mode).
+    pub fn allows_csr(&self, csr: u16) -> bool {
+        let required = (csr >> 8) & 0b11;
+        if (self.privilege as u16) < required {
+            return false;
+        }
+        let counter = 1 << (csr & 0x1f);
+        match csr {
+            0xc00..=0xc1f | 0xc80..=0xc9f => match self.privilege {
+                Privilege::Machine => true,
+                Privilege::Supervisor => self.mcounteren & counter != 0,
+                Privilege::User => self.mcounteren & self.scounteren & counter != 0,
+            },
+            _ => true,
+        }
+    }
+
+    /// Mode that `ir` returns to if it is an `mret` or `sret`.
+    pub fn returns_to(&self, ir: u32) -> Option<Privilege> {
+        match ir {
+            0x30200073 => Some(self.previous()),
+            0x10200073 => match self.mstatus & MSTATUS_SPP {
+                0 => Some(Privilege::User),
+                _ => Some(Privilege::Supervisor),
+            },
+            _ => None,
+        }
+    }
+
+    pub fn set_hartid(&mut self, hartid: u32) {
+        self.mhartid = hartid;
+    }
+
+    /// Set the pending bit of `interrupt` in `mip`, or clear it.
+    pub fn set_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        let bit = 1 << interrupt as u32;
+        match pending {
+            true => self.mip |= bit,
+            false => self.mip &= !bit,
+        }
+    }
+
+    /// The interrupt to take before the next instruction, if any. Machine
+    /// interrupts have priority over supervisor ones, and within a mode
+    /// the order is external, software, then timer.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let machine = self.privilege < Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let supervisor = match self.privilege {
+            Privilege::User => true,
+            Privilege::Supervisor => self.mstatus & MSTATUS_SIE != 0,
+            Privilege::Machine => false,
+        };
+        Interrupt::PRIORITY.iter().copied().find(|&i| {
+            let bit = 1 << i as u32;
+            if self.mie & self.mip & bit == 0 {
+                return false;
+            }
+            match self.mideleg & bit != 0 {
+                true => supervisor && self.stvec != 0,
+                false => machine && self.handler_installed(),
+            }
+        })
+    }
+
+    /// The mode in `mstatus.MPP`.
+    fn previous(&self) -> Privilege {
+        Privilege::from_bits((self.mstatus & MSTATUS_MPP) >> 11).unwrap()
+    }
+
+    /// Enter the trap handler for `cause`, raised by the instruction at
+    /// `pc`, and return the handler's address.
+    pub fn enter(&mut self, pc: u32, cause: Exception, tval: u32) -> u32 {
+        if self.delegates(cause) {
+            self.enter_supervisor(pc, cause as u32, tval);
+            return self.stvec & !0b11;
+        }
+
+        self.enter_machine(pc, cause as u32, tval);
+        self.mtvec & !0b11
+    }
+
+    /// Enter the handler for `interrupt`, taken before the instruction at
+    /// `pc`, and return the handler's address. The interrupt is taken in
+    /// supervisor mode if `mideleg` delegates it and the hart is below
+    /// machine mode.
+    pub fn interrupt(&mut self, pc: u32, interrupt: Interrupt) -> u32 {
+        let cause = MCAUSE_INTERRUPT | interrupt as u32;
+        let delegated = self.mideleg & (1 << interrupt as u32) != 0;
+        let tvec = match delegated && self.privilege != Privilege::Machine {
+            true => {
+                self.enter_supervisor(pc, cause, 0);
+                self.stvec
+            }
+            false => {
+                self.enter_machine(pc, cause, 0);
+                self.mtvec
+            }
+        };
+        match tvec & 0b11 {
+            1 => (tvec & !0b11).wrapping_add(4 * interrupt as u32),
+            _ => tvec & !0b11,
+        }
+    }
+
+    fn enter_supervisor(&mut self, pc: u32, scause: u32, tval: u32) {
+        let spie = match self.mstatus & MSTATUS_SIE {
+            0 => 0,
+            _ => MSTATUS_SPIE,
+        };
+        let spp = match self.privilege {
+            Privilege::User => 0,
+            _ => MSTATUS_SPP,
+        };
+        self.mstatus = (self.mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp;
+        self.sepc = pc;
+        self.scause = scause;
+        self.stval = tval;
+        self.privilege = Privilege::Supervisor;
+    }
+
+    fn enter_machine(&mut self, pc: u32, mcause: u32, tval: u32) {
+        let mpie = match self.mstatus & MSTATUS_MIE {
+            0 => 0,
+            _ => MSTATUS_MPIE,
+        };
+        let mpp = (self.privilege as u32) << 11;
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+        self.mepc = pc;
+        self.mcause = mcause;
+        self.mtval = tval;
+        self.privilege = Privilege::Machine;
+    }
+
+    /// Read a trap CSR, regardless of the current mode.
+    pub fn read(&self, csr: u16) -> Result<u32, RiscvError> {
+        Ok(match csr {
+            CSR_SSTATUS => self.mstatus & SSTATUS_MASK,
+            CSR_SIE => self.mie & self.mideleg,
+            CSR_STVEC => self.stvec,
+            CSR_SCOUNTEREN => self.scounteren,
+            CSR_SSCRATCH => self.sscratch,
+            CSR_SEPC => self.sepc,
+            CSR_SCAUSE => self.scause,
+            CSR_STVAL => self.stval,
+            CSR_SIP => self.mip & self.mideleg,
+            CSR_SATP => self.satp,
+            CSR_MSTATUS => self.mstatus,
+            CSR_MEDELEG => self.medeleg,
+            CSR_MIDELEG => self.mideleg,
+            CSR_MTVEC => self.mtvec,
+            CSR_MCOUNTEREN => self.mcounteren,
+            CSR_MSCRATCH => self.mscratch,
+            CSR_MEPC => self.mepc,
+            CSR_MCAUSE => self.mcause,
+            CSR_MTVAL => self.mtval,
+            CSR_MIE => self.mie,
+            CSR_MIP => self.mip,
+            CSR_MHARTID => self.mhartid,
+            _ => return Err(RiscvError::InvalidCsrError(csr)),
+        })
+    }
+
+    /// Write a trap CSR, regardless of the current mode. Only the mode
+    /// and interrupt enable fields, MPRV, SUM and MXR of `mstatus` are
+    /// writable, and the reserved mode is not stored in MPP. Exception pcs
+    /// are kept aligned, the upper bit of the mode in the trap vectors,
+    /// which only selects reserved modes, is hardwired to zero, and
+    /// machine-mode environment calls cannot be delegated. `mie` only
+    /// holds the enables of the interrupts there are, and only supervisor
+    /// interrupts can be delegated or have their bits in `mip` written;
+    /// through `sip`, only the software interrupt's, and only if it is
+    /// delegated. `mhartid` is read-only.
+    pub fn write(&mut self, csr: u16, data: u32) -> Result<(), RiscvError> {
+        match csr {
+            CSR_SSTATUS => {
+                self.mstatus = (self.mstatus & !SSTATUS_MASK) | (data & SSTATUS_MASK);
+            }
+            CSR_SIE => self.mie = (self.mie & !self.mideleg) | (data & self.mideleg),
+            CSR_SIP => {
+                let mask = self.mideleg & 1 << Interrupt::SupervisorSoftware as u32;
+                self.mip = (self.mip & !mask) | (data & mask);
+            }
+            CSR_STVEC => self.stvec = data & !0b10,
+            CSR_SCOUNTEREN => self.scounteren = data,
+            CSR_SSCRATCH => self.sscratch = data,
+            CSR_SEPC => self.sepc = data & !0b11,
+            CSR_SCAUSE => self.scause = data,
+            CSR_STVAL => self.stval = data,
+            CSR_SATP => self.satp = data,
+            CSR_MSTATUS => {
+                let mpp = match Privilege::from_bits((data & MSTATUS_MPP) >> 11) {
+                    Some(_) => data & MSTATUS_MPP,
+                    None => self.mstatus & MSTATUS_MPP,
+                };
+                self.mstatus = (data & MSTATUS_MASK & !MSTATUS_MPP) | mpp;
+            }
+            CSR_MEDELEG => self.medeleg = data & !(1 << Exception::MachineEnvironmentCall as u32),
+            CSR_MIDELEG => self.mideleg = data & SUPERVISOR_INTERRUPTS,
+            CSR_MTVEC => self.mtvec = data & !0b10,
+            CSR_MCOUNTEREN => self.mcounteren = data,
+            CSR_MSCRATCH => self.mscratch = data,
+            CSR_MEPC => self.mepc = data & !0b11,
+            CSR_MCAUSE => self.mcause = data,
+            CSR_MTVAL => self.mtval = data,
+            CSR_MIE => self.mie = data & MIE_MASK,
+            // The machine pending bits are the CLINT's.
+            CSR_MIP => {
+                self.mip = (self.mip & !SUPERVISOR_INTERRUPTS) | (data & SUPERVISOR_INTERRUPTS);
+            }
+            _ => return Err(RiscvError::InvalidCsrError(csr)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{
+        pmp::{CSR_PMPADDR0, CSR_PMPCFG0, PMP_L, PMP_R, PMP_W, PMP_X},
+        HpmEvent, Mcu, RegisterFileTrait,
+    };
+
+    const HANDLER: u32 = 0x100;
+
+    /// Encode `csrrw`-style instructions.
+    fn csr_op(func3: u32, rd: u32, csr: u16, rs1: u32) -> u32 {
+        (csr as u32) << 20 | rs1 << 15 | func3 << 12 | rd << 7 | 0b1110011
+    }
+
+    /// An MCU running `program` with a handler at `HANDLER` that skips
+    /// the faulting instruction and returns.
+    fn mcu_with_handler(program: &str) -> Mcu {
+        let mut mcu = Mcu::new(0x1000);
+        // csrrw zero, mtvec, s0
+        let mut words = vec![csr_op(0b001, 0, CSR_MTVEC, 8)];
+        words.extend(assemble_program(program).unwrap());
+        mcu.mem.program_words(&words).unwrap();
+
+        let handler = [
+            // csrrs t3, mepc, zero; addi t3, t3, 4; csrrw zero, mepc, t3
+            csr_op(0b010, 28, CSR_MEPC, 0),
+            assemble_program("addi t3, t3, 4").unwrap()[0],
+            csr_op(0b001, 0, CSR_MEPC, 28),
+            assemble_program("mret").unwrap()[0],
+        ];
+        place(&mut mcu, HANDLER, &handler);
+        mcu.rf.write(8, HANDLER).unwrap();
+        mcu
+    }
+
+    fn place(mcu: &mut Mcu, addr: u32, words: &[u32]) {
+        for (i, word) in words.iter().enumerate() {
+            mcu.mem.poke(addr + 4 * i as u32, *word, 4).unwrap();
+        }
+    }
+
+    /// Let lower modes access everything with a NAPOT entry over the whole
+    /// address space.
+    fn allow_all(mcu: &mut Mcu) {
+        mcu.rf.write_csr(CSR_PMPADDR0, u32::MAX).unwrap();
+        mcu.rf
+            .write_csr(CSR_PMPCFG0, (PMP_R | PMP_W | PMP_X | 0x18) as u32)
+            .unwrap();
+    }
+
+    fn run(mcu: &mut Mcu, n: usize) {
+        for _ in 0..n {
+            mcu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_fault() {
+        let mut mcu = mcu_with_handler(
+            "lui t2, 0x2\n\
+             lw t1, 0(t2)\n\
+             addi a0, zero, 1",
+        );
+        mcu.rf.csrs.set_event(3, HpmEvent::Traps);
+        run(&mut mcu, 3);
+        assert_eq!(HANDLER, mcu.pc);
+        assert_eq!(Ok(8), mcu.rf.read_csr(CSR_MEPC));
+        assert_eq!(Ok(5), mcu.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(Ok(0x2000), mcu.rf.read_csr(CSR_MTVAL));
+
+        run(&mut mcu, 5);
+        assert_eq!(16, mcu.pc);
+        assert_eq!(1, mcu.rf.read(10).unwrap());
+        assert_eq!(1, mcu.rf.csrs.counter(3));
+        // The faulting load counts a cycle but does not retire.
+        assert_eq!(8, mcu.rf.csrs.cycles());
+        assert_eq!(7, mcu.rf.csrs.instret());
+    }
+
+    #[test]
+    fn test_causes() {
+        // Steps before the trapping one, including the write to mtvec.
+        for (program, steps, cause, tval) in [
+            ("ecall", 1, Exception::MachineEnvironmentCall, 0),
+            ("ebreak", 1, Exception::Breakpoint, 4),
+            (
+                ".word 0xffffffff",
+                1,
+                Exception::IllegalInstruction,
+                0xffffffff,
+            ),
+            ("sw zero, 2(zero)", 1, Exception::StoreMisaligned, 2),
+            (
+                "lui t0, 0x2\nsb zero, 0(t0)",
+                2,
+                Exception::StoreAccessFault,
+                0x2000,
+            ),
+            (
+                "lui t0, 0x2\namoadd.w a0, a1, (t0)",
+                2,
+                Exception::StoreAccessFault,
+                0x2000,
+            ),
+            (
+                "lui t0, 0x2\njr t0",
+                3,
+                Exception::InstructionAccessFault,
+                0x2000,
+            ),
+        ] {
+            let mut mcu = mcu_with_handler(program);
+            run(&mut mcu, steps);
+            let step = mcu.step().unwrap();
+            let trap = step.trap.unwrap();
+            assert_eq!((cause, tval), (trap.cause, trap.tval), "{}", program);
+            assert_eq!(Ok(cause as u32), mcu.rf.read_csr(CSR_MCAUSE));
+            assert_eq!(HANDLER, mcu.pc);
+        }
+    }
+
+    #[test]
+    fn test_no_handler() {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program("ecall").unwrap())
+            .unwrap();
+        assert_eq!(
+            Err(RiscvError::EnvironmentCallError(0)),
+            mcu.step().map(|_| ())
+        );
+        assert_eq!(0, mcu.pc);
+    }
+
+    #[test]
+    fn test_mstatus() {
+        let mut csrs = TrapCsrs::new();
+        csrs.write(CSR_MSTATUS, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(MSTATUS_MASK), csrs.read(CSR_MSTATUS));
+        assert_eq!(Ok(SSTATUS_MASK), csrs.read(CSR_SSTATUS));
+        // The reserved mode is not stored in MPP.
+        csrs.write(CSR_MSTATUS, 0b10 << 11).unwrap();
+        assert_eq!(Ok(MSTATUS_MPP), csrs.read(CSR_MSTATUS));
+        csrs.write(CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        csrs.write(CSR_MTVEC, 0x203).unwrap();
+        assert_eq!(0x200, csrs.enter(0x10, Exception::Breakpoint, 0x10));
+        assert_eq!(Ok(MSTATUS_MPIE | MSTATUS_MPP), csrs.read(CSR_MSTATUS));
+        assert_eq!(Ok(0x201), csrs.read(CSR_MTVEC));
+        assert_eq!(Err(RiscvError::InvalidCsrError(0x34a)), csrs.read(0x34a));
+
+        // mret restores MIE from MPIE.
+        let mut mcu = Mcu::new(0x1000);
+        mcu.rf.trap = csrs;
+        mcu.mem
+            .program_words(&assemble_program("mret").unwrap())
+            .unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0x10, mcu.pc);
+        assert_eq!(Ok(MSTATUS_MIE | MSTATUS_MPIE), mcu.rf.read_csr(CSR_MSTATUS));
+        assert_eq!(Privilege::Machine, mcu.rf.trap.privilege());
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut csrs = TrapCsrs::new();
+        csrs.write(CSR_MIE, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(MIE_MASK), csrs.read(CSR_MIE));
+        csrs.write(CSR_MIP, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(SUPERVISOR_INTERRUPTS), csrs.read(CSR_MIP));
+        csrs.set_pending(Interrupt::MachineTimer, true);
+        csrs.set_pending(Interrupt::MachineSoftware, true);
+        assert_eq!(Ok(MIE_MASK), csrs.read(CSR_MIP));
+
+        // Masked by mstatus.MIE in machine mode only.
+        csrs.write(CSR_MTVEC, 0x201).unwrap();
+        assert_eq!(None, csrs.pending_interrupt());
+        csrs.set_privilege(Privilege::User);
+        assert_eq!(Some(Interrupt::MachineSoftware), csrs.pending_interrupt());
+        csrs.set_pending(Interrupt::MachineSoftware, false);
+        assert_eq!(Some(Interrupt::MachineTimer), csrs.pending_interrupt());
+
+        assert_eq!(0x21c, csrs.interrupt(0x10, Interrupt::MachineTimer));
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 7), csrs.read(CSR_MCAUSE));
+        assert_eq!(Ok(0x10), csrs.read(CSR_MEPC));
+        assert_eq!(Privilege::Machine, csrs.privilege());
+        assert_eq!(None, csrs.pending_interrupt());
+        // Direct mode uses the base for every cause.
+        csrs.write(CSR_MTVEC, 0x200).unwrap();
+        assert_eq!(0x200, csrs.interrupt(0x10, Interrupt::MachineTimer));
+
+        csrs.set_hartid(2);
+        assert_eq!(Ok(2), csrs.read(CSR_MHARTID));
+        assert_eq!(
+            Err(RiscvError::InvalidCsrError(CSR_MHARTID)),
+            csrs.write(CSR_MHARTID, 0)
+        );
+    }
+
+    #[test]
+    fn test_pmp_fault() {
+        let mut mcu = mcu_with_handler("sw zero, 0x400(zero)\naddi a0, zero, 1");
+        mcu.mem.poke(0x400, 0x12345678, 4).unwrap();
+        // A locked, read-only NA4 entry over 0x400.
+        mcu.rf.write_csr(CSR_PMPADDR0, 0x400 >> 2).unwrap();
+        mcu.rf
+            .write_csr(CSR_PMPCFG0, (PMP_L | PMP_R | 0x10) as u32)
+            .unwrap();
+        run(&mut mcu, 2);
+        assert_eq!(
+            Ok(Exception::StoreAccessFault as u32),
+            mcu.rf.read_csr(CSR_MCAUSE)
+        );
+        assert_eq!(Ok(0x400), mcu.rf.read_csr(CSR_MTVAL));
+        assert_eq!(Ok(0x12345678), mcu.mem.peek(0x400, 4));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut mcu = mcu_with_handler("ebreak");
+        mcu.enable_undo(8);
+        run(&mut mcu, 2);
+        let trapped = mcu.rf.trap.clone();
+        assert_eq!(HANDLER, mcu.pc);
+
+        mcu.step_back().unwrap();
+        assert_eq!(4, mcu.pc);
+        assert_eq!(Ok(0), mcu.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(1, mcu.rf.csrs.instret());
+
+        mcu.replay().unwrap();
+        assert_eq!(HANDLER, mcu.pc);
+        assert_eq!(trapped, mcu.rf.trap);
+        assert_eq!(1, mcu.rf.csrs.instret());
+    }
+
+    #[test]
+    fn test_user_mode() {
+        // mtvec is set, then mret enters user mode at 0x200.
+        let mut mcu = mcu_with_handler("mret");
+        allow_all(&mut mcu);
+        mcu.rf.trap.write(CSR_MEPC, 0x200).unwrap();
+        place(
+            &mut mcu,
+            0x200,
+            &assemble_program("addi a0, zero, 7\necall").unwrap(),
+        );
+        run(&mut mcu, 3);
+        assert_eq!(Privilege::User, mcu.rf.trap.privilege());
+        assert_eq!(7, mcu.rf.read(10).unwrap());
+
+        // The user ecall goes to the machine handler, even with a host.
+        let step = mcu.step_with_host(&mut crate::Host::new()).unwrap();
+        assert_eq!(Privilege::User, step.privilege);
+        assert_eq!(Privilege::Machine, step.next_privilege);
+        assert_eq!(Ok(8), mcu.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(Ok(0), mcu.rf.read_csr(CSR_MSTATUS).map(|s| s & MSTATUS_MPP));
+
+        // The handler returns to user mode after the ecall.
+        run(&mut mcu, 4);
+        assert_eq!(Privilege::User, mcu.rf.trap.privilege());
+        assert_eq!(0x208, mcu.pc);
+    }
+
+    #[test]
+    fn test_privileged_csrs() {
+        let mut mcu = mcu_with_handler("");
+        allow_all(&mut mcu);
+        place(&mut mcu, 0x200, &[csr_op(0b010, 10, CSR_MSTATUS, 0)]);
+        place(&mut mcu, 0x204, &[csr_op(0b010, 10, 0xc00, 0)]);
+        place(&mut mcu, 0x208, &assemble_program("mret").unwrap());
+        mcu.step().unwrap();
+        mcu.rf.trap.set_privilege(Privilege::User);
+
+        // Reading mstatus from user mode is illegal, and so is reading
+        // cycle until both counter enables allow it.
+        for (pc, enables) in [(0x200, (1, 1)), (0x204, (1, 0)), (0x204, (0, 1))] {
+            mcu.rf.trap.write(CSR_MCOUNTEREN, enables.0).unwrap();
+            mcu.rf.trap.write(CSR_SCOUNTEREN, enables.1).unwrap();
+            mcu.rf.trap.set_privilege(Privilege::User);
+            mcu.pc = pc;
+            let trap = mcu.step().unwrap().trap.unwrap();
+            assert_eq!(Exception::IllegalInstruction, trap.cause);
+            assert_eq!(mcu.mem.peek(pc, 4).unwrap(), trap.tval);
+        }
+        mcu.rf.trap.set_privilege(Privilege::User);
+        mcu.rf.trap.write(CSR_MCOUNTEREN, 1).unwrap();
+        mcu.rf.trap.write(CSR_SCOUNTEREN, 1).unwrap();
+        mcu.pc = 0x204;
+        assert_eq!(None, mcu.step().unwrap().trap);
+
+        // mret is a machine-mode instruction.
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        let trap = mcu.step().unwrap().trap.unwrap();
+        assert_eq!(Exception::IllegalInstruction, trap.cause);
+    }
+
+    #[test]
+    fn test_supervisor_interrupt_csrs() {
+        let mut csrs = TrapCsrs::new();
+        csrs.write(CSR_MIDELEG, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(SUPERVISOR_INTERRUPTS), csrs.read(CSR_MIDELEG));
+
+        // sie and sip are the delegated bits of mie and mip.
+        csrs.write(CSR_MIE, MIE_MASK).unwrap();
+        assert_eq!(Ok(SUPERVISOR_INTERRUPTS), csrs.read(CSR_SIE));
+        csrs.write(CSR_SIE, 0).unwrap();
+        assert_eq!(Ok(MIE_MASK & !SUPERVISOR_INTERRUPTS), csrs.read(CSR_MIE));
+        csrs.set_pending(Interrupt::MachineTimer, true);
+        csrs.write(CSR_MIP, 1 << Interrupt::SupervisorTimer as u32)
+            .unwrap();
+        assert_eq!(Ok(1 << 5), csrs.read(CSR_SIP));
+
+        // Only the software interrupt can be raised or cleared through sip.
+        csrs.write(CSR_SIP, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(1 << 1 | 1 << 5), csrs.read(CSR_SIP));
+        csrs.write(CSR_SIP, 0).unwrap();
+        assert_eq!(Ok(1 << 5 | 1 << 7), csrs.read(CSR_MIP));
+        csrs.write(CSR_MIDELEG, 0).unwrap();
+        csrs.write(CSR_SIP, 0xffff_ffff).unwrap();
+        assert_eq!(Ok(0), csrs.read(CSR_SIP));
+        assert_eq!(Ok(1 << 5 | 1 << 7), csrs.read(CSR_MIP));
+    }
+
+    #[test]
+    fn test_supervisor_interrupt_delivery() {
+        let mut csrs = TrapCsrs::new();
+        csrs.write(CSR_MTVEC, 0x200).unwrap();
+        csrs.write(CSR_STVEC, 0x301).unwrap();
+        csrs.write(CSR_MIE, 1 << Interrupt::SupervisorTimer as u32)
+            .unwrap();
+        csrs.write(CSR_MIP, 1 << Interrupt::SupervisorTimer as u32)
+            .unwrap();
+        csrs.write(CSR_MSTATUS, MSTATUS_MIE).unwrap();
+
+        // Not delegated, the interrupt is taken in machine mode.
+        csrs.set_privilege(Privilege::Supervisor);
+        assert_eq!(Some(Interrupt::SupervisorTimer), csrs.pending_interrupt());
+        let mut machine = csrs.clone();
+        assert_eq!(0x200, machine.interrupt(0x10, Interrupt::SupervisorTimer));
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 5), machine.read(CSR_MCAUSE));
+
+        // Delegated, it is never taken in machine mode, and only with SIE
+        // set in supervisor mode.
+        csrs.write(CSR_MIDELEG, 1 << Interrupt::SupervisorTimer as u32)
+            .unwrap();
+        assert_eq!(None, csrs.pending_interrupt());
+        csrs.set_privilege(Privilege::Machine);
+        assert_eq!(None, csrs.pending_interrupt());
+        csrs.set_privilege(Privilege::User);
+        assert_eq!(Some(Interrupt::SupervisorTimer), csrs.pending_interrupt());
+        csrs.set_privilege(Privilege::Supervisor);
+        csrs.write(CSR_SSTATUS, MSTATUS_SIE).unwrap();
+        assert_eq!(Some(Interrupt::SupervisorTimer), csrs.pending_interrupt());
+
+        assert_eq!(0x314, csrs.interrupt(0x10, Interrupt::SupervisorTimer));
+        assert_eq!(Ok(MCAUSE_INTERRUPT | 5), csrs.read(CSR_SCAUSE));
+        assert_eq!(Ok(0x10), csrs.read(CSR_SEPC));
+        assert_eq!(Ok(0), csrs.read(CSR_MCAUSE));
+        assert_eq!(Ok(MSTATUS_SPIE | MSTATUS_SPP), csrs.read(CSR_SSTATUS));
+        assert_eq!(Privilege::Supervisor, csrs.privilege());
+        assert_eq!(None, csrs.pending_interrupt());
+    }
+
+    #[test]
+    fn test_supervisor_timer_interrupt() {
+        let mut mcu = mcu_with_handler("");
+        allow_all(&mut mcu);
+        // csrrs zero, mip, t1
+        place(&mut mcu, 4, &[csr_op(0b010, 0, CSR_MIP, 6)]);
+        place(
+            &mut mcu,
+            0x200,
+            &assemble_program("addi t0, zero, 1").unwrap(),
+        );
+        // csrrs t4, scause, zero
+        place(&mut mcu, 0x300, &[csr_op(0b010, 29, CSR_SCAUSE, 0)]);
+        let timer = 1 << Interrupt::SupervisorTimer as u32;
+        mcu.rf.trap.write(CSR_MIDELEG, timer).unwrap();
+        mcu.rf.trap.write(CSR_MIE, timer).unwrap();
+        mcu.rf.trap.write(CSR_STVEC, 0x300).unwrap();
+        mcu.rf
+            .trap
+            .write(CSR_MSTATUS, MSTATUS_MIE | MSTATUS_SIE)
+            .unwrap();
+        mcu.rf.write(6, timer).unwrap();
+
+        // Machine mode raises the interrupt, but does not take it.
+        run(&mut mcu, 2);
+        assert_eq!(Ok(timer), mcu.rf.read_csr(CSR_SIP));
+        assert_eq!(None, mcu.take_interrupt());
+
+        // Supervisor mode takes it before its next instruction.
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        mcu.pc = 0x200;
+        assert_eq!(Some(Interrupt::SupervisorTimer), mcu.take_interrupt());
+        assert_eq!(0x300, mcu.pc);
+        assert_eq!(Privilege::Supervisor, mcu.rf.trap.privilege());
+        assert_eq!(Ok(0x200), mcu.rf.read_csr(CSR_SEPC));
+        mcu.step().unwrap();
+        assert_eq!(MCAUSE_INTERRUPT | 5, mcu.rf.read(29).unwrap());
+        assert_eq!(None, mcu.take_interrupt());
+    }
+
+    #[test]
+    fn test_delegation() {
+        let mut mcu = mcu_with_handler("");
+        allow_all(&mut mcu);
+        place(&mut mcu, 0x200, &assemble_program("ecall\necall").unwrap());
+        place(&mut mcu, 0x300, &assemble_program("sret").unwrap());
+        mcu.step().unwrap();
+        let delegate = 1 << Exception::UserEnvironmentCall as u32;
+        mcu.rf.trap.write(CSR_MEDELEG, delegate).unwrap();
+        mcu.rf.trap.write(CSR_STVEC, 0x300).unwrap();
+        mcu.rf.trap.write(CSR_SSTATUS, MSTATUS_SIE).unwrap();
+        mcu.rf.trap.set_privilege(Privilege::User);
+        mcu.pc = 0x200;
+
+        // The user ecall is delegated to supervisor mode.
+        mcu.step().unwrap();
+        assert_eq!(0x300, mcu.pc);
+        assert_eq!(Privilege::Supervisor, mcu.rf.trap.privilege());
+        assert_eq!(Ok(8), mcu.rf.read_csr(CSR_SCAUSE));
+        assert_eq!(Ok(0x200), mcu.rf.read_csr(CSR_SEPC));
+        assert_eq!(Ok(MSTATUS_SPIE), mcu.rf.read_csr(CSR_SSTATUS));
+        assert_eq!(Ok(0), mcu.rf.trap.read(CSR_MCAUSE));
+
+        // sret returns to user mode.
+        mcu.rf.trap.write(CSR_SEPC, 0x204).unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0x204, mcu.pc);
+        assert_eq!(Privilege::User, mcu.rf.trap.privilege());
+        assert_eq!(
+            Ok(MSTATUS_SIE | MSTATUS_SPIE),
+            mcu.rf.trap.read(CSR_SSTATUS)
+        );
+
+        // Supervisor ecalls are not delegated.
+        mcu.rf.trap.set_privilege(Privilege::Supervisor);
+        mcu.step().unwrap();
+        assert_eq!(HANDLER, mcu.pc);
+        assert_eq!(Ok(9), mcu.rf.trap.read(CSR_MCAUSE));
+        assert_eq!(
+            Ok(1 << 11),
+            mcu.rf.trap.read(CSR_MSTATUS).map(|s| s & MSTATUS_MPP)
+        );
+    }
+
+    #[test]
+    fn test_misaligned_jump() {
+        // Steps before the jump, including the write to mtvec.
+        for (program, steps, target) in [
+            ("jal ra, 6", 1, 10),
+            ("addi t0, zero, 0x203\njalr ra, 0(t0)", 2, 0x202),
+            ("beq zero, zero, 6", 1, 10),
+        ] {
+            let mut mcu = mcu_with_handler(program);
+            run(&mut mcu, steps);
+            let pc = mcu.pc;
+            let trap = mcu.step().unwrap().trap.unwrap();
+            // The jump itself traps, without writing the link register.
+            assert_eq!(Exception::InstructionMisaligned, trap.cause, "{}", program);
+            assert_eq!(Ok(pc), mcu.rf.read_csr(CSR_MEPC), "{}", program);
+            assert_eq!(Ok(target), mcu.rf.read_csr(CSR_MTVAL), "{}", program);
+            assert_eq!(Ok(0), mcu.rf.read(1));
+            assert_eq!(HANDLER, mcu.pc);
+        }
+    }
+
+    #[test]
+    fn test_delegation_without_machine_handler() {
+        let mut mcu = Mcu::new(0x1000);
+        allow_all(&mut mcu);
+        place(&mut mcu, 0x200, &assemble_program("ecall").unwrap());
+        let delegate = 1 << Exception::UserEnvironmentCall as u32;
+        mcu.rf.trap.write(CSR_MEDELEG, delegate).unwrap();
+        mcu.rf.trap.set_privilege(Privilege::User);
+        mcu.pc = 0x200;
+
+        // With only mtvec set, the delegated ecall has no handler.
+        mcu.rf.trap.write(CSR_MTVEC, HANDLER).unwrap();
+        assert_eq!(
+            Err(RiscvError::EnvironmentCallError(0x200)),
+            mcu.step().map(|_| ())
+        );
+
+        // With only stvec, it is taken in supervisor mode.
+        mcu.rf.trap.write(CSR_MTVEC, 0).unwrap();
+        mcu.rf.trap.write(CSR_STVEC, 0x300).unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0x300, mcu.pc);
+        assert_eq!(Privilege::Supervisor, mcu.rf.trap.privilege());
+        assert_eq!(Ok(0x200), mcu.rf.read_csr(CSR_SEPC));
+    }
+
+    #[test]
+    fn test_mprv() {
+        let mut mcu = mcu_with_handler("sw zero, 0x400(zero)");
+        // Only user mode is restricted by this entry over 0x400.
+        mcu.rf.write_csr(CSR_PMPADDR0, 0x400 >> 2).unwrap();
+        mcu.rf
+            .write_csr(CSR_PMPCFG0, (PMP_R | 0x10) as u32)
+            .unwrap();
+        mcu.rf.trap.write(CSR_MSTATUS, MSTATUS_MPRV).unwrap();
+        run(&mut mcu, 2);
+        assert_eq!(Ok(7), mcu.rf.read_csr(CSR_MCAUSE));
+        assert_eq!(Privilege::Machine, mcu.rf.trap.privilege());
+    }
+
+    #[test]
+    fn test_step_back_privilege() {
+        let mut mcu = mcu_with_handler("mret");
+        allow_all(&mut mcu);
+        mcu.enable_undo(8);
+        run(&mut mcu, 2);
+        assert_eq!(Privilege::User, mcu.rf.trap.privilege());
+
+        mcu.step_back().unwrap();
+        assert_eq!(Privilege::Machine, mcu.rf.trap.privilege());
+        mcu.replay().unwrap();
+        assert_eq!(Privilege::User, mcu.rf.trap.privilege());
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::VecDeque;
+
+use crate::{trace::AccessKind, Mcu, Step, TakenInterrupt};
+
+/// Bounded history of executed instructions.
+///
+/// Every `Step` holds the previous pc, the overwritten register and CSR
+/// values, the overwritten memory contents and the `lr.w` reservation,
+/// which is all that is needed to undo it, except for the TLB, which is
+/// emptied instead. Interrupts taken after an instruction are kept with
+/// it. Once `capacity` instructions are held, the oldest is dropped.
+/// Instructions that were undone are kept until a new one is executed so
+/// they can be replayed.
+#[derive(Debug, Clone)]
+pub struct UndoLog {
+    capacity: usize,
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+}
+
+impl UndoLog {
+    /// Keep at most `capacity` instructions of history.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+
+        UndoLog {
+            capacity,
+            undo: VecDeque::with_capacity(capacity),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Maximum number of instructions held.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of instructions that can be undone.
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// Whether there is nothing to undo.
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    /// Number of undone instructions that can be replayed.
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Executed instructions, oldest first.
+    pub fn history(&self) -> impl DoubleEndedIterator<Item = &Step> {
+        self.undo.iter()
+    }
+
+    /// The most recent instruction that wrote `reg`.
+    pub fn last_register_write(&self, reg: u8) -> Option<&Step> {
+        self.undo.iter().rev().find(|s| s.writes_register(reg))
+    }
+
+    /// The most recent instruction that wrote any byte of `addr..addr + size`.
+    pub fn last_memory_write(&self, addr: u32, size: u32) -> Option<&Step> {
+        self.undo.iter().rev().find(|s| s.writes_memory(addr, size))
+    }
+
+    /// Record a newly executed instruction. This invalidates the replay
+    /// history.
+    pub(crate) fn push(&mut self, step: Step) {
+        self.redo.clear();
+        if self.undo.len() == self.capacity {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(step);
+    }
+
+    /// Record an interrupt taken after the last instruction. This too
+    /// invalidates the replay history. Interrupts taken before the first
+    /// recorded instruction cannot be undone.
+    pub(crate) fn push_interrupt(&mut self, taken: TakenInterrupt) {
+        self.redo.clear();
+        if let Some(step) = self.undo.back_mut() {
+            step.interrupts.push(taken);
+        }
+    }
+}
+
+impl Mcu {
+    /// Start recording an undo log of up to `capacity` instructions.
+    /// Any previous history is discarded.
+    pub fn enable_undo(&mut self, capacity: usize) {
+        self.undo = Some(UndoLog::new(capacity));
+    }
+
+    /// Stop recording and discard the undo log.
+    pub fn disable_undo(&mut self) {
+        self.undo = None;
+    }
+
+    /// The undo log, if recording is enabled.
+    pub fn undo_log(&self) -> Option<&UndoLog> {
+        self.undo.as_ref()
+    }
+
+    /// Undo the last executed instruction and return it.
+    pub fn step_back(&mut self) -> Option<Step> {
+        let step = self.undo.as_mut()?.undo.pop_back()?;
+
+        if let Some(taken) = step.interrupts.first() {
+            self.rf.trap = taken.old.clone();
+        }
+        for access in step.accesses.iter().rev() {
+            if access.kind == AccessKind::Store {
+                // The location was written successfully, so it is valid.
+                self.mem
+                    .poke(access.addr, access.old, access.size as usize)
+                    .unwrap();
+            }
+        }
+        for update in step.pte_updates.iter().rev() {
+            self.mem.poke(update.addr, update.old, 4).unwrap();
+        }
+        if let Some(w) = step.reg_write {
+            self.rf.restore(w.reg, w.old);
+        }
+        if let Some(w) = step.fp_write {
+            self.rf.fp.restore(w.reg, w.old);
+        }
+        self.rf.csrs.unretire(&step);
+        if let Some(w) = step.csr_write {
+            // The CSR was written successfully, so it is writable.
+            self.rf.restore_csr(w.csr, w.old);
+        }
+        if let Some(trap) = step.trap.as_ref() {
+            self.rf.trap = trap.old.clone();
+        }
+        self.rf.trap.set_privilege(step.privilege);
+        self.mem.set_reservation(step.reservation);
+        if let Some(tlb) = self.tlb.as_mut() {
+            tlb.clear();
+        }
+        self.pc = step.pc;
+        self.instructions -= 1;
+
+        self.undo.as_mut().unwrap().redo.push(step.clone());
+        Some(step)
+    }
+
+    /// Re-apply the last undone instruction and return it. Effects are
+    /// replayed from the log rather than re-executed.
+    pub fn replay(&mut self) -> Option<Step> {
+        let step = self.undo.as_mut()?.redo.pop()?;
+
+        for access in step.accesses.iter() {
+            if access.kind == AccessKind::Store {
+                self.mem
+                    .poke(access.addr, access.data, access.size as usize)
+                    .unwrap();
+            }
+        }
+        for update in step.pte_updates.iter() {
+            self.mem.poke(update.addr, update.data, 4).unwrap();
+        }
+        if let Some(w) = step.reg_write {
+            self.rf.restore(w.reg, w.new);
+        }
+        if let Some(w) = step.fp_write {
+            self.rf.fp.restore(w.reg, w.new);
+        }
+        if let Some(w) = step.csr_write {
+            self.rf.restore_csr(w.csr, w.new);
+        }
+        if let Some(trap) = step.trap.as_ref() {
+            self.rf.trap.enter(step.pc, trap.cause, trap.tval);
+        }
+        self.rf.trap.set_privilege(step.next_privilege);
+        self.mem.set_reservation(step.next_reservation);
+        if let Some(tlb) = self.tlb.as_mut() {
+            tlb.clear();
+        }
+        self.rf.csrs.retire(&step);
+        self.pc = step.next_pc;
+        self.instructions += 1;
+        for taken in step.interrupts.iter() {
+            self.pc = self.rf.trap.interrupt(taken.pc, taken.interrupt);
+        }
+
+        self.undo.as_mut().unwrap().undo.push_back(step.clone());
+        Some(step)
+    }
+
+    /// Run backwards to just before the last instruction that wrote `reg`
+    /// and return that instruction. The MCU is left untouched if no such
+    /// write is in the log.
+    pub fn run_back_to_register_write(&mut self, reg: u8) -> Option<Step> {
+        self.undo_log()?.last_register_write(reg)?;
+        self.run_back_until(|s| s.writes_register(reg))
+    }
+
+    /// Run backwards to just before the last instruction that wrote any
+    /// byte of `addr..addr + size` and return that instruction. The MCU is
+    /// left untouched if no such write is in the log.
+    pub fn run_back_to_memory_write(&mut self, addr: u32, size: u32) -> Option<Step> {
+        self.undo_log()?.last_memory_write(addr, size)?;
+        self.run_back_until(|s| s.writes_memory(addr, size))
+    }
+
+    fn run_back_until<F>(&mut self, found: F) -> Option<Step>
+    where
+        F: Fn(&Step) -> bool,
+    {
+        while let Some(step) = self.step_back() {
+            if found(&step) {
+                return Some(step);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib_rv32_asm::assemble_program;
+
+    use super::*;
+    use crate::{
+        common::constants::*,
+        trap::{CSR_MCAUSE, CSR_MEPC, CSR_MIE, CSR_MSTATUS, CSR_MTVEC, MCAUSE_INTERRUPT},
+        Clint, Interrupt, MemoryTrait, RegisterFileTrait,
+    };
+
+    fn mcu_with_program(program: &str) -> Mcu {
+        let mut mcu = Mcu::new(0x1000);
+        mcu.mem
+            .program_words(&assemble_program(program).unwrap())
+            .unwrap();
+        mcu.enable_undo(16);
+        mcu
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut mcu = mcu_with_program(
+            "addi t0, zero, 0x100\n\
+             addi t1, zero, -1\n\
+             sw t1, 0(t0)\n\
+             sb zero, 1(t0)",
+        );
+        for _ in 0..4 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(0xffff00ff, mcu.mem.read_word(0x100).unwrap());
+
+        let step = mcu.step_back().unwrap();
+        assert_eq!(12, step.pc);
+        assert_eq!(12, mcu.pc);
+        assert_eq!(0xffffffff, mcu.mem.read_word(0x100).unwrap());
+
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.mem.read_word(0x100).unwrap());
+
+        mcu.step_back().unwrap();
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.rf.read(5).unwrap());
+        assert_eq!(0, mcu.pc);
+        assert_eq!(0, mcu.instructions);
+        assert_eq!(None, mcu.step_back());
+    }
+
+    #[test]
+    fn test_step_back_reservation() {
+        let mut mcu = mcu_with_program(
+            "addi t0, zero, 0x100\n\
+             lr.w a0, (t0)\n\
+             sc.w a1, zero, (t0)",
+        );
+        for _ in 0..3 {
+            mcu.step().unwrap();
+        }
+        assert_eq!(None, mcu.mem.reservation());
+
+        mcu.step_back().unwrap();
+        assert_eq!(Some(0x100), mcu.mem.reservation());
+        mcu.step_back().unwrap();
+        assert_eq!(None, mcu.mem.reservation());
+        mcu.replay().unwrap();
+        assert_eq!(Some(0x100), mcu.mem.reservation());
+
+        // The restored reservation lets the sc.w succeed again.
+        mcu.rf.write(11, 1).unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0, mcu.rf.read(11).unwrap());
+        assert_eq!(None, mcu.mem.reservation());
+    }
+
+    #[test]
+    fn test_run_back_to_register_write() {
+        let mut mcu = mcu_with_program(
+            "addi t0, zero, 1\n\
+             addi t1, zero, 2\n\
+             addi t0, t0, 3\n\
+             addi t1, t1, 4\n\
+             addi t2, zero, 5",
+        );
+        for _ in 0..5 {
+            mcu.step().unwrap();
+        }
+
+        let step = mcu.run_back_to_register_write(5).unwrap();
+        assert_eq!(8, step.pc);
+        assert_eq!(1, step.reg_write.unwrap().old);
+        assert_eq!(4, step.reg_write.unwrap().new);
+        assert_eq!(8, mcu.pc);
+        assert_eq!(1, mcu.rf.read(5).unwrap());
+
+        // Nothing wrote s0, so the MCU must not move.
+        assert_eq!(None, mcu.run_back_to_register_write(8));
+        assert_eq!(8, mcu.pc);
+    }
+
+    #[test]
+    fn test_run_back_to_memory_write() {
+        let mut mcu = mcu_with_program(
+            "addi t0, zero, 0x100\n\
+             addi t1, zero, 7\n\
+             sw t1, 0(t0)\n\
+             sh t1, 4(t0)\n\
+             addi t1, zero, 0",
+        );
+        for _ in 0..5 {
+            mcu.step().unwrap();
+        }
+
+        assert_eq!(
+            12,
+            mcu.undo_log()
+                .unwrap()
+                .last_memory_write(0x105, 1)
+                .unwrap()
+                .pc
+        );
+        let step = mcu.run_back_to_memory_write(0x102, 1).unwrap();
+        assert_eq!(8, step.pc);
+        assert_eq!(0, mcu.mem.read_word(0x100).unwrap());
+        assert_eq!(0, mcu.mem.read_word(0x104).unwrap());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut mcu = mcu_with_program(
+            "addi t0, zero, 0x100\n\
+             sw t0, 0(t0)\n\
+             addi t0, t0, 1",
+        );
+        for _ in 0..3 {
+            mcu.step().unwrap();
+        }
+        mcu.step_back().unwrap();
+        mcu.step_back().unwrap();
+        assert_eq!(2, mcu.undo_log().unwrap().redo_len());
+
+        assert_eq!(4, mcu.replay().unwrap().pc);
+        assert_eq!(0x100, mcu.mem.read_word(0x100).unwrap());
+        mcu.replay().unwrap();
+        assert_eq!(0x101, mcu.rf.read(5).unwrap());
+        assert_eq!(12, mcu.pc);
+        assert_eq!(3, mcu.instructions);
+        assert_eq!(None, mcu.replay());
+
+        // Executing anew discards what could be replayed.
+        mcu.step_back().unwrap();
+        mcu.step().unwrap();
+        assert_eq!(0, mcu.undo_log().unwrap().redo_len());
+    }
+
+    #[test]
+    fn test_replay_masked_csr() {
+        let mut mcu = mcu_with_program("addi t0, zero, -1");
+        // csrrw zero, frm, t0
+        mcu.mem.write_word(4, 0x00229073).unwrap();
+        mcu.step().unwrap();
+        mcu.step().unwrap();
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.rf.read_csr(CSR_FRM).unwrap());
+
+        // Only the three bits of frm are restored.
+        mcu.replay().unwrap();
+        assert_eq!(0b111, mcu.rf.read_csr(CSR_FRM).unwrap());
+        assert_eq!(0b111 << 5, mcu.rf.read_csr(CSR_FCSR).unwrap());
+    }
+
+    #[test]
+    fn test_step_back_over_interrupt() {
+        let mut mcu = mcu_with_program("addi t0, zero, 1\naddi t0, t0, 1");
+        let handler = assemble_program("addi t1, zero, 7").unwrap()[0];
+        mcu.mem.poke(0x100, handler, 4).unwrap();
+        mcu.rf.write_csr(CSR_MTVEC, 0x100).unwrap();
+        mcu.rf
+            .write_csr(CSR_MIE, 1 << Interrupt::MachineTimer as u32)
+            .unwrap();
+        mcu.rf.write_csr(CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        let clint = Clint::default();
+        clint.map(&mut mcu.mem);
+
+        mcu.step().unwrap();
+        // mtimecmp is zero, so the timer fires at once.
+        clint.update(&mcu.mem, 0, &mut mcu.rf.trap);
+        assert_eq!(Some(Interrupt::MachineTimer), mcu.take_interrupt());
+        mcu.step().unwrap();
+        assert_eq!(0x104, mcu.pc);
+        assert_eq!(4, mcu.rf.read_csr(CSR_MEPC).unwrap());
+
+        mcu.step_back().unwrap();
+        assert_eq!(0x100, mcu.pc);
+        assert_eq!(0, mcu.rf.read(6).unwrap());
+        assert_eq!(
+            MCAUSE_INTERRUPT | Interrupt::MachineTimer as u32,
+            mcu.rf.read_csr(CSR_MCAUSE).unwrap()
+        );
+
+        mcu.step_back().unwrap();
+        assert_eq!(0, mcu.pc);
+        assert_eq!(0, mcu.rf.read_csr(CSR_MEPC).unwrap());
+        assert_eq!(0, mcu.rf.read_csr(CSR_MCAUSE).unwrap());
+        assert_eq!(
+            MSTATUS_MIE,
+            mcu.rf.read_csr(CSR_MSTATUS).unwrap() & MSTATUS_MIE
+        );
+
+        // Replaying the instruction enters the handler again.
+        mcu.replay().unwrap();
+        assert_eq!(0x100, mcu.pc);
+        assert_eq!(4, mcu.rf.read_csr(CSR_MEPC).unwrap());
+        mcu.replay().unwrap();
+        assert_eq!(7, mcu.rf.read(6).unwrap());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut mcu = mcu_with_program("loop: addi t0, t0, 1\njal zero, loop");
+        mcu.enable_undo(4);
+        for _ in 0..10 {
+            mcu.step().unwrap();
+        }
+
+        assert_eq!(4, mcu.undo_log().unwrap().len());
+        while mcu.step_back().is_some() {}
+        assert_eq!(6, mcu.instructions);
+        assert_eq!(3, mcu.rf.read(5).unwrap());
+    }
+}
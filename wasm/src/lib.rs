@@ -3,7 +3,6 @@ use wasm_bindgen::prelude::*;
 
 use lib_rv32_asm::assemble_program;
 use lib_rv32_common::constants::*;
-use lib_rv32_isa::exec_one;
 use lib_rv32_mcu::*;
 
 mod logger;
@@ -17,15 +16,22 @@ static mut CONSOLE_TEXT: String = String::new();
 #[wasm_bindgen]
 pub fn get_logs() -> String {
     // Not thread-safe. ¯\_(ツ)_/¯
-    unsafe { CONSOLE_TEXT.clone() }
+    unsafe { (*std::ptr::addr_of!(CONSOLE_TEXT)).clone() }
 }
 
 #[wasm_bindgen]
 pub struct State {
     mcu: Mcu,
+    pipeline: Pipeline,
     text_size: usize,
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl State {
     /// Initiate the MCU state.
@@ -40,7 +46,11 @@ impl State {
             mcu.mem.size / 1024
         );
 
-        State { mcu, text_size: 0 }
+        State {
+            mcu,
+            pipeline: Pipeline::default(),
+            text_size: 0,
+        }
     }
 
     /// Program the MCU with a string program.
@@ -49,22 +59,25 @@ impl State {
 
         let words = assemble_program(&program);
 
-        if words.is_err() {
-            info!("Assembler error: {:?}", words);
-            return;
-        } else {
-            info!("Successfully assembled program.\n");
-            let words = words.unwrap();
-            self.mcu.mem.program_words(&words).unwrap();
-            self.text_size = words.len() * 4;
+        match words {
+            Err(why) => info!("Assembler error: {:?}", why),
+            Ok(words) => {
+                info!("Successfully assembled program.\n");
+                self.mcu.mem.program_words(&words).unwrap();
+                self.pipeline = Pipeline::default();
+                self.text_size = words.len() * 4;
+            }
         }
     }
 
     pub fn run(&mut self) {
         while self.mcu.pc < self.text_size as u32 {
-            if let Err(why) = exec_one(&mut self.mcu.pc, &mut self.mcu.mem, &mut self.mcu.rf) {
-                info!("MCU runtime error: {:?}", why);
-                return;
+            match self.mcu.step() {
+                Ok(step) => self.pipeline.retire(&step),
+                Err(why) => {
+                    info!("MCU runtime error: {:?}", why);
+                    return;
+                }
             }
             info!("");
         }
@@ -72,6 +85,11 @@ impl State {
         info!("\nProgram complete.");
     }
 
+    /// Pipeline diagram of the last instructions run, as JSON.
+    pub fn get_pipeline(&self) -> String {
+        self.pipeline.to_json()
+    }
+
     pub fn get_text(&self) -> String {
         let mut text = String::new();
         for wa in 0..self.text_size / 4 {
@@ -83,9 +101,9 @@ impl State {
     pub fn get_state(&self) -> String {
         let mut state = String::new();
 
-        for i in 0..32 {
+        for (i, name) in REG_NAMES.iter().enumerate() {
             let val = self.mcu.rf.read(i as u8).unwrap();
-            state += &format!("{:4} = 0x{:08x} ({})\n", REG_NAMES[i], val, val as i32);
+            state += &format!("{:4} = 0x{:08x} ({})\n", name, val, val as i32);
         }
 
         state
@@ -1,4 +1,4 @@
-pub use log::{Level, LevelFilter, Metadata, Record};
+pub use log::{Level, Metadata, Record};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]